@@ -34,8 +34,16 @@ pub struct VMConfiguration {
     /// Controlls whether the jitted program is to be compiled preflight
     /// or loaded from jit program storage.
     pub jit_compile: bool,
+    /// Index of the entry point exported by the program that the VM should
+    /// start executing. Programs which don't export any entry points always
+    /// start at the beginning of the .text section, which is selected by 0.
+    pub entry_point: u8,
 }
 
+/// Maximum number of entry points that a program can export, the index of
+/// the entry point is encoded using 3 bits of the VM configuration.
+pub const MAX_ENTRY_POINTS: usize = 8;
+
 impl VMConfiguration {
     pub fn new(
         vm_target: TargetVM,
@@ -54,6 +62,7 @@ impl VMConfiguration {
             helper_access_list_source,
             jit,
             jit_compile,
+            entry_point: 0,
         }
     }
 
@@ -71,7 +80,8 @@ impl VMConfiguration {
     /// - bits 1-4: the next four bits specify the SUIT storage slot storing the eBPF program
     ///   bytecode (up to 16 available program slots).
     /// - bits 5-6: the next two bits specify the binary file layout that the VM should
-    ///   expect in the loaded program
+    ///   expect in the loaded program (the third, most significant bit of the
+    ///   layout is stored in bit 12)
     /// - bits 7-8: the next two bits specify the time in the pipeline at which the verification
     ///   of accesses to helper functions should take place.
     /// - bit 9: the next bit specifies whether the list
@@ -81,6 +91,11 @@ impl VMConfiguration {
     /// - bit 10: The next bit specifies whether we should use jit-compiled programs.
     /// - bit 11: The next bit specifies if we should run the jit-compilation or
     ///   use one of the pre-compiled programs that are present in the jit storage.
+    /// - bit 12: the most significant bit of the binary file layout. It was added
+    ///   after all other fields, placing it here keeps the encoding of the
+    ///   remaining fields unchanged.
+    /// - bits 13-15: the index of the entry point that the VM should start
+    ///   executing (up to [`MAX_ENTRY_POINTS`] entry points).
     ///
     /// # Example
     /// ```
@@ -107,6 +122,8 @@ impl VMConfiguration {
         encoding |= (self.helper_access_list_source as u16 & 0b1) << 9;
         encoding |= (self.jit as u16 & 0b1) << 10;
         encoding |= (self.jit_compile as u16 & 0b1) << 11;
+        encoding |= ((self.binary_layout as u16 >> 2) & 0b1) << 12;
+        encoding |= (self.entry_point as u16 & 0b111) << 13;
         encoding
     }
 
@@ -115,13 +132,16 @@ impl VMConfiguration {
         VMConfiguration {
             vm_target: TargetVM::from((encoding & 0b1) as u8),
             suit_slot: ((encoding >> 1) & 0b1111) as usize,
-            binary_layout: BinaryFileLayout::from(((encoding >> 5) & 0b11) as u8),
+            binary_layout: BinaryFileLayout::from(
+                (((encoding >> 5) & 0b11) | ((encoding >> 10) & 0b100)) as u8,
+            ),
             helper_access_verification: HelperAccessVerification::from(
                 ((encoding >> 7) & 0b11) as u8,
             ),
             helper_access_list_source: HelperAccessListSource::from(((encoding >> 9) & 0b1) as u8),
             jit: ((encoding >> 10) & 0b1) == 1,
             jit_compile: ((encoding >> 11) & 0b1) == 1,
+            entry_point: ((encoding >> 13) & 0b111) as u8,
         }
     }
 }
@@ -159,6 +179,18 @@ impl FromStr for TargetVM {
     }
 }
 
+impl TargetVM {
+    /// Returns true if the VM is able to load programs with the given binary
+    /// layout. The instructions supported by each combination of the VM and
+    /// the layout are checked by `micro_bpf_elf_utils::check_compatibility`.
+    pub fn supports_layout(&self, layout: BinaryFileLayout) -> bool {
+        match self {
+            TargetVM::Rbpf => true,
+            TargetVM::FemtoContainer => layout == BinaryFileLayout::FemtoContainersHeader,
+        }
+    }
+}
+
 impl fmt::Display for TargetVM {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -172,7 +204,8 @@ impl fmt::Display for TargetVM {
 /// (this approach was originally used by rbpf implementation of the VM).
 ///
 /// Note:
-/// FemtoContainer VM is only compatible with the FemtoContainersHeader binary layout.
+/// FemtoContainer VM is only compatible with the FemtoContainersHeader binary layout
+/// (see [`TargetVM::supports_layout`]).
 #[repr(u8)]
 #[derive(Eq, PartialEq, Debug, Deserialize, Serialize, Copy, Clone)]
 pub enum BinaryFileLayout {
@@ -193,6 +226,12 @@ pub enum BinaryFileLayout {
     /// there. This allows for maximum compatibility (e.g. .data relocations)
     /// however it comes with a burden of an increased memory requirements.
     RawObjectFile = 3,
+    /// The relocations are resolved on the host up to the address at which the
+    /// program is loaded. The sections of the program are followed by a compact
+    /// table of patches to which the device only needs to add that address.
+    /// This avoids parsing the ELF file on the device while keeping the
+    /// compatibility of the [`BinaryFileLayout::RawObjectFile`] layout.
+    PreResolvedRelocations = 4,
 }
 
 impl FromStr for BinaryFileLayout {
@@ -203,6 +242,7 @@ impl FromStr for BinaryFileLayout {
             "FemtoContainersHeader" => Ok(BinaryFileLayout::FemtoContainersHeader),
             "ExtendedHeader" => Ok(BinaryFileLayout::ExtendedHeader),
             "RawObjectFile" => Ok(BinaryFileLayout::RawObjectFile),
+            "PreResolvedRelocations" => Ok(BinaryFileLayout::PreResolvedRelocations),
             _ => Err(format!("Unknown binary file layout: {}", s)),
         }
     }
@@ -215,6 +255,7 @@ impl From<u8> for BinaryFileLayout {
             1 => BinaryFileLayout::FemtoContainersHeader,
             2 => BinaryFileLayout::ExtendedHeader,
             3 => BinaryFileLayout::RawObjectFile,
+            4 => BinaryFileLayout::PreResolvedRelocations,
             _ => panic!("Unknown binary file layout: {}", val),
         }
     }
@@ -352,66 +393,150 @@ mod tests {
 
         assert_eq!(configuration, decoded);
     }
+
+    #[test]
+    fn decode_after_encode_preserves_all_layouts() {
+        for layout in 0..=4 {
+            let configuration = VMConfiguration::new(
+                TargetVM::Rbpf,
+                15,
+                BinaryFileLayout::from(layout),
+                HelperAccessVerification::Runtime,
+                HelperAccessListSource::ExecuteRequest,
+                false,
+                true,
+            );
+
+            let decoded = VMConfiguration::decode(configuration.encode());
+
+            assert_eq!(configuration, decoded);
+        }
+    }
+
+    #[test]
+    fn decode_after_encode_preserves_entry_point() {
+        for entry_point in 0..MAX_ENTRY_POINTS as u8 {
+            let configuration = VMConfiguration {
+                entry_point,
+                ..VMConfiguration::new(
+                    TargetVM::Rbpf,
+                    15,
+                    BinaryFileLayout::PreResolvedRelocations,
+                    HelperAccessVerification::Runtime,
+                    HelperAccessListSource::BinaryMetadata,
+                    true,
+                    true,
+                )
+            };
+
+            let decoded = VMConfiguration::decode(configuration.encode());
+
+            assert_eq!(configuration, decoded);
+        }
+    }
+
+    #[test]
+    fn helpers_are_found_by_their_symbol_names() {
+        for helper in enum_iterator::all::<HelperFunctionID>() {
+            assert_eq!(
+                HelperFunctionID::from_symbol_name(helper.symbol_name()),
+                Some(helper)
+            );
+        }
+        assert_eq!(HelperFunctionID::from_symbol_name("printf"), None);
+    }
 }
 
-/// This enum defines all available helper IDs. The requirement is that every
-/// single helper function ID is unique, hence we store them in an enum.
-/// Files containing helper definitions should depend on enumeration variants
-/// defined in this file.
-///
-/// In case of the helper functions that were implemented for the FemtoContainer
-/// VM, we use the same set of IDs for compatibility.
-#[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, Sequence, FromPrimitive, PartialEq, Eq, PartialOrd, Ord)]
-pub enum HelperFunctionID {
-    /* Print/debug helper functions */
-    BPF_PRINTF_IDX = 0x01,
-    BPF_DEBUG_PRINT_IDX = 0x03,
-
-    /* Memory copy helper functions */
-    BPF_MEMCPY_IDX = 0x02,
-
-    /* Key/value store functions */
-    BPF_STORE_LOCAL_IDX = 0x10,
-    BPF_STORE_GLOBAL_IDX = 0x11,
-    BPF_FETCH_LOCAL_IDX = 0x12,
-    BPF_FETCH_GLOBAL_IDX = 0x13,
-
-    /* Saul functions */
-    BPF_SAUL_REG_FIND_NTH_IDX = 0x30,
-    BPF_SAUL_REG_FIND_TYPE_IDX = 0x31,
-    BPF_SAUL_REG_READ_IDX = 0x32,
-    BPF_SAUL_REG_WRITE_IDX = 0x33,
-    BPF_SAUL_REG_READ_TEMP = 0x34,
-
-    /* (g)coap functions */
-    BPF_GCOAP_RESP_INIT_IDX = 0x40,
-    BPF_COAP_OPT_FINISH_IDX = 0x41,
-    BPF_COAP_ADD_FORMAT_IDX = 0x42,
-    BPF_COAP_GET_PDU_IDX = 0x43,
-
-    /* Format and string functions */
-    BPF_STRLEN_IDX = 0x52,
-    BPF_FMT_S16_DFP_IDX = 0x50,
-    BPF_FMT_U32_DEC_IDX = 0x51,
-
-    /* Time(r) functions */
-    BPF_NOW_MS_IDX = 0x20,
-
-    /* ZTIMER */
-    BPF_ZTIMER_NOW_IDX = 0x60,
-    BPF_PERIODIC_WAKEUP_IDX = 0x61,
-
-    BPF_GPIO_READ_INPUT = 0x70,
-    BPF_GPIO_READ_RAW = 0x71,
-    BPF_GPIO_WRITE = 0x72,
-
-    /* HD44780 LCD */
-    BPF_HD44780_INIT = 0x80,
-    BPF_HD44780_CLEAR = 0x81,
-    BPF_HD44780_PRINT = 0x82,
-    BPF_HD44780_SET_CURSOR = 0x83,
-    BPF_KEYPAD_GET_INPUT = 0x84,
+/// Defines [`HelperFunctionID`] together with the names of the functions
+/// declaring the helpers in the eBPF programs, so that the table of names is
+/// generated from the same list as the enum and can't get out of sync with it.
+macro_rules! helper_function_ids {
+    ($($variant:ident = $id:literal => $symbol:literal,)*) => {
+        /// This enum defines all available helper IDs. The requirement is that every
+        /// single helper function ID is unique, hence we store them in an enum.
+        /// Files containing helper definitions should depend on enumeration variants
+        /// defined in this file.
+        ///
+        /// In case of the helper functions that were implemented for the FemtoContainer
+        /// VM, we use the same set of IDs for compatibility.
+        #[allow(non_camel_case_types)]
+        #[derive(Debug, Copy, Clone, Sequence, FromPrimitive, PartialEq, Eq, PartialOrd, Ord)]
+        pub enum HelperFunctionID {
+            $($variant = $id,)*
+        }
+
+        impl HelperFunctionID {
+            /// Name of the function declaring the helper in the eBPF programs. The
+            /// programs can call the helpers through undefined `extern` functions with
+            /// these names, the calls are then resolved to the IDs of the helpers when
+            /// the program is processed on the host.
+            pub fn symbol_name(&self) -> &'static str {
+                match self {
+                    $(HelperFunctionID::$variant => $symbol,)*
+                }
+            }
+
+            /// Finds the helper declared by the function with the given name, see
+            /// [`HelperFunctionID::symbol_name`].
+            pub fn from_symbol_name(name: &str) -> Option<Self> {
+                match name {
+                    $($symbol => Some(HelperFunctionID::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+helper_function_ids! {
+    // Print/debug helper functions
+    BPF_PRINTF_IDX = 0x01 => "bpf_printf",
+    BPF_DEBUG_PRINT_IDX = 0x03 => "bpf_print_debug",
+
+    // Memory copy helper functions
+    BPF_MEMCPY_IDX = 0x02 => "bpf_memcpy",
+
+    // Key/value store functions
+    BPF_STORE_LOCAL_IDX = 0x10 => "bpf_store_local",
+    BPF_STORE_GLOBAL_IDX = 0x11 => "bpf_store_global",
+    BPF_FETCH_LOCAL_IDX = 0x12 => "bpf_fetch_local",
+    BPF_FETCH_GLOBAL_IDX = 0x13 => "bpf_fetch_global",
+
+    // Saul functions
+    BPF_SAUL_REG_FIND_NTH_IDX = 0x30 => "bpf_saul_reg_find_nth",
+    BPF_SAUL_REG_FIND_TYPE_IDX = 0x31 => "bpf_saul_reg_find_type",
+    BPF_SAUL_REG_READ_IDX = 0x32 => "bpf_saul_reg_read",
+    BPF_SAUL_REG_WRITE_IDX = 0x33 => "bpf_saul_reg_write",
+    BPF_SAUL_REG_READ_TEMP = 0x34 => "bpf_saul_read_temp",
+
+    // (g)coap functions
+    BPF_GCOAP_RESP_INIT_IDX = 0x40 => "bpf_gcoap_resp_init",
+    BPF_COAP_OPT_FINISH_IDX = 0x41 => "bpf_coap_opt_finish",
+    BPF_COAP_ADD_FORMAT_IDX = 0x42 => "bpf_coap_add_format",
+    BPF_COAP_GET_PDU_IDX = 0x43 => "bpf_coap_get_pdu",
+
+    // Format and string functions
+    BPF_STRLEN_IDX = 0x52 => "bpf_strlen",
+    BPF_FMT_S16_DFP_IDX = 0x50 => "bpf_fmt_s16_dfp",
+    BPF_FMT_U32_DEC_IDX = 0x51 => "bpf_fmt_u32_dec",
+
+    // Time(r) functions
+    BPF_NOW_MS_IDX = 0x20 => "bpf_now_ms",
+
+    // ZTIMER
+    BPF_ZTIMER_NOW_IDX = 0x60 => "bpf_ztimer_now",
+    BPF_PERIODIC_WAKEUP_IDX = 0x61 => "bpf_ztimer_periodic_wakeup",
+
+    BPF_GPIO_READ_INPUT = 0x70 => "bpf_gpio_read_input",
+    BPF_GPIO_READ_RAW = 0x71 => "bpf_gpio_read_raw",
+    BPF_GPIO_WRITE = 0x72 => "bpf_gpio_write",
+
+    // HD44780 LCD
+    BPF_HD44780_INIT = 0x80 => "bpf_hd44780_init",
+    BPF_HD44780_CLEAR = 0x81 => "bpf_hd44780_clear",
+    BPF_HD44780_PRINT = 0x82 => "bpf_hd44780_print",
+    BPF_HD44780_SET_CURSOR = 0x83 => "bpf_hd44780_set_cursor",
+    BPF_KEYPAD_GET_INPUT = 0x84 => "bpf_keypad_get_input",
 }
 
 impl Into<u32> for HelperFunctionID {
@@ -425,3 +550,12 @@ impl Into<u8> for HelperFunctionID {
         self as u8
     }
 }
+
+impl TryFrom<u32> for HelperFunctionID {
+    /// The ID which doesn't correspond to any of the helpers
+    type Error = u32;
+
+    fn try_from(id: u32) -> Result<Self, Self::Error> {
+        num::FromPrimitive::from_u32(id).ok_or(id)
+    }
+}
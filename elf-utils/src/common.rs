@@ -1,23 +1,80 @@
+#[cfg(feature = "elf")]
+use alloc::format;
+#[cfg(feature = "elf")]
 use alloc::string::{String, ToString};
+#[cfg(feature = "elf")]
 use alloc::vec::Vec;
-use goblin::container::{Container, Endian};
-use goblin::elf::{Elf, Reloc, SectionHeader};
+#[cfg(feature = "elf")]
+use core::ops::Range;
+#[cfg(feature = "elf")]
+use goblin::elf::{
+    sym::{STT_FUNC, STT_SECTION},
+    Elf, Reloc, SectionHeader, Sym,
+};
 use log::debug;
 
+use crate::error::ElfUtilsError;
+#[cfg(feature = "elf")]
+use crate::{
+    error::ErrorLocation,
+    model::{Call, Lddw},
+};
+
 pub const INSTRUCTION_SIZE: usize = 8;
-const SYMBOL_SIZE: usize = 6;
+pub const SYMBOL_SIZE: usize = 6;
 
 pub const LDDW_INSTRUCTION_SIZE: usize = 16;
 pub const LDDW_OPCODE: u32 = 0x18;
 pub const CALL_OPCODE: u32 = 0x85;
 
+/// Sections with a larger alignment are rejected so that a malformed object
+/// file can't make the assemblers allocate huge amounts of padding.
+#[cfg(feature = "elf")]
+pub const MAX_SECTION_ALIGNMENT: usize = 4096;
+/// Maximum total size of the zero-initialised sections of a program. It is
+/// larger than the RAM of any of the devices that the VM runs on and prevents
+/// malformed object files from making us allocate huge sections.
+#[cfg(feature = "elf")]
+pub const MAX_ZERO_INITIALISED_SIZE: usize = 1 << 20;
+
+// Relocation types used by LLVM for the BPF target, goblin doesn't define them.
+/// Relocation which doesn't need to be applied, the calls to helpers declared
+/// as extern functions are turned into these once they are resolved
+#[cfg(feature = "elf")]
+pub const R_BPF_NONE: u32 = 0;
+/// Address of the symbol stored in the immediate operands of a `lddw` instruction
+#[cfg(feature = "elf")]
+pub const R_BPF_64_64: u32 = 1;
+/// 64-bit address of the symbol stored in the data sections
+#[cfg(feature = "elf")]
+pub const R_BPF_64_ABS64: u32 = 2;
+/// 32-bit address of the symbol stored in the data sections
+#[cfg(feature = "elf")]
+pub const R_BPF_64_ABS32: u32 = 3;
+/// Like [`R_BPF_64_ABS32`] but used in the debug sections, it doesn't need to
+/// be resolved when the program is loaded
+#[cfg(feature = "elf")]
+pub const R_BPF_64_NODYLD32: u32 = 4;
+/// Address of the function called by a `call` instruction
+#[cfg(feature = "elf")]
+pub const R_BPF_64_32: u32 = 10;
+
+/// The function is one of the entry points exported by the program, see
+/// [`crate::HEADER_FLAG_ENTRY_POINTS`].
+#[cfg(feature = "elf")]
+pub const SYMBOL_FLAG_ENTRY_POINT: u16 = 1 << 0;
+
 /// A symbol struct represents a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct Symbol {
-    // Offset to the name of the function in the .rodata section
+    /// Offset to the name of the function in the .rodata section
     pub name_offset: u16,
+    /// Flags of the function, see [`SYMBOL_FLAG_ENTRY_POINT`]. They are
+    /// maintained for compatibility with the FC implementation, where they
+    /// are always 0.
     pub flags: u16,
-    // Offset of the function in the .text section
+    /// Offset of the function in the .text section
     pub location_offset: u16,
 }
 
@@ -27,6 +84,124 @@ impl<'a> Into<&'a [u8]> for &'a Symbol {
     }
 }
 
+impl Symbol {
+    /// Decodes a symbol struct from the binary using a bounds-checked reader.
+    pub fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        Ok(Symbol {
+            name_offset: reader.read_u16()?,
+            flags: reader.read_u16()?,
+            location_offset: reader.read_u16()?,
+        })
+    }
+}
+
+/// Decodes the names of the function symbols from the .rodata section.
+///
+/// The assemblers append the names without null terminators, so the name of
+/// each function ends where the next name starts, or at the first null byte
+/// (the padding added by [`round_section_length`]) for the last one.
+#[cfg(feature = "elf")]
+pub fn decode_function_names(
+    rodata: &[u8],
+    functions: &[Symbol],
+) -> Result<Vec<String>, ElfUtilsError> {
+    let mut name_offsets: Vec<usize> = functions.iter().map(|f| f.name_offset as usize).collect();
+    name_offsets.sort();
+
+    let mut names = Vec::new();
+    for function in functions {
+        let start = function.name_offset as usize;
+        if start > rodata.len() {
+            return Err(ElfUtilsError::OutOfBounds {
+                offset: start as u64,
+                len: 0,
+                size: rodata.len() as u64,
+            });
+        }
+        let next_name = name_offsets
+            .iter()
+            .find(|offset| **offset > start)
+            .map_or(rodata.len(), |offset| (*offset).min(rodata.len()));
+        let end = rodata[start..next_name]
+            .iter()
+            .position(|byte| *byte == 0)
+            .map_or(next_name, |len| start + len);
+
+        let name = core::str::from_utf8(&rodata[start..end]).map_err(|e| {
+            ElfUtilsError::Malformed(format!("Invalid function name at offset {}: {}", start, e))
+        })?;
+        names.push(name.to_string());
+    }
+    Ok(names)
+}
+
+/// A cursor over a slice of bytes which is used for decoding the binary layouts
+/// produced by this crate. As opposed to casting raw pointers to the packed
+/// structs, all reads are bounds-checked so that malformed or truncated input
+/// results in an error instead of an out-of-bounds access.
+pub struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        ByteReader { bytes, position: 0 }
+    }
+
+    /// Current offset of the reader from the start of the underlying slice.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes that haven't been read yet.
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    /// Returns the next `len` bytes and advances the reader past them.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ElfUtilsError> {
+        if len > self.remaining() {
+            return Err(ElfUtilsError::UnexpectedEnd {
+                offset: self.position,
+                needed: len,
+                available: self.remaining(),
+            });
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ElfUtilsError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ElfUtilsError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ElfUtilsError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ElfUtilsError> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]))
+    }
+
+    /// Returns all bytes that haven't been read yet.
+    pub fn read_to_end(&mut self) -> &'a [u8] {
+        let bytes = &self.bytes[self.position..];
+        self.position = self.bytes.len();
+        bytes
+    }
+}
+
 /// Prints program bytes dividing them into rows of 8 bytes and printing the
 /// row number in hex. This is done to resemble the output of utilities such as
 /// `objdump`.
@@ -61,86 +236,315 @@ pub fn debug_print_program_bytes(bytes: &[u8]) {
 /// (together with the header section). It is very important that the array of
 /// bytes corresponds to an actual ELF file, otherwise the function will not
 /// be able to parse it correctly and the required section will not be found.
+#[cfg(feature = "elf")]
 pub fn extract_section<'a>(
     section_name: &'static str,
     program: &'a [u8],
-) -> Result<&'a [u8], String> {
-    let Ok(binary) = goblin::elf::Elf::parse(&program) else {
-        return Err("Failed to parse the ELF binary".to_string());
-    };
-
-    for section in &binary.section_headers {
-        if let Some(name) = binary.strtab.get_at(section.sh_name) {
-            if name == section_name {
-                let section_start = section.sh_offset as usize;
-                let section_end = (section.sh_offset + section.sh_size) as usize;
-                return Ok(&program[section_start..section_end]);
-            }
-        }
-    }
-
-    return Err("Section not found".to_string());
+) -> Result<&'a [u8], ElfUtilsError> {
+    let binary = parse_elf(program)?;
+    let section = get_section_header(section_name, &binary)?;
+    section_bytes(section, program)
 }
 
+#[cfg(feature = "elf")]
 pub fn get_section_header<'a>(
     section_name: &str,
     binary: &'a Elf<'_>,
-) -> Result<&'a SectionHeader, String> {
-    for section in &binary.section_headers {
-        if Some(section_name) == binary.strtab.get_at(section.sh_name) {
-            return Ok(section);
-        }
+) -> Result<&'a SectionHeader, ElfUtilsError> {
+    Ok(&binary.section_headers[get_section_index(section_name, binary)?])
+}
+
+/// Returns the index of the section with the given name in the section
+/// header table, see [`get_section_header`].
+#[cfg(feature = "elf")]
+pub fn get_section_index(section_name: &str, binary: &Elf<'_>) -> Result<usize, ElfUtilsError> {
+    binary
+        .section_headers
+        .iter()
+        .position(|section| Some(section_name) == binary.strtab.get_at(section.sh_name))
+        .ok_or_else(|| ElfUtilsError::SectionNotFound(section_name.to_string()))
+}
+
+/// Parses the ELF file, goblin validates the headers and the symbol and string
+/// tables, the contents of the sections need to be checked by the callers.
+#[cfg(feature = "elf")]
+pub fn parse_elf(program: &[u8]) -> Result<Elf<'_>, ElfUtilsError> {
+    Ok(Elf::parse(program)?)
+}
+
+/// Returns the contents of the section, checking that it lies within the file.
+/// The sections which don't occupy any space in the file (e.g. .bss) are empty.
+#[cfg(feature = "elf")]
+pub fn section_bytes<'a>(
+    section: &SectionHeader,
+    program: &'a [u8],
+) -> Result<&'a [u8], ElfUtilsError> {
+    let Some(range) = section.file_range() else {
+        return Ok(&[]);
+    };
+    program.get(range).ok_or(ElfUtilsError::OutOfBounds {
+        offset: section.sh_offset,
+        len: section.sh_size,
+        size: program.len() as u64,
+    })
+}
+
+/// Returns the alignment of the section, rejecting the ones which aren't
+/// a power of two or are larger than [`MAX_SECTION_ALIGNMENT`].
+#[cfg(feature = "elf")]
+pub fn section_alignment(section: &SectionHeader) -> Result<usize, ElfUtilsError> {
+    let alignment = section.sh_addralign.max(1);
+    if !alignment.is_power_of_two() || alignment > MAX_SECTION_ALIGNMENT as u64 {
+        return Err(ElfUtilsError::InvalidValue {
+            field: "section alignment",
+            value: alignment,
+        });
+    }
+    Ok(alignment as usize)
+}
+
+/// Returns the range of `len` bytes at `offset` if it is contained in an
+/// input of the given size.
+#[cfg(feature = "elf")]
+pub fn checked_range(
+    offset: usize,
+    len: usize,
+    size: usize,
+) -> Result<Range<usize>, ElfUtilsError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(offset..end),
+        _ => Err(ElfUtilsError::OutOfBounds {
+            offset: offset as u64,
+            len: len as u64,
+            size: size as u64,
+        }),
     }
-    Err("Section not found".to_string())
 }
 
+/// A relocation entry together with the information about the section that
+/// it needs to be applied to.
+#[cfg(feature = "elf")]
+#[derive(Debug, Clone)]
+pub struct Relocation<'a> {
+    /// Index of the section that the relocation applies to
+    pub section_index: usize,
+    /// Name of the section that the relocation applies to
+    pub section_name: &'a str,
+    /// Offset of the section that the relocation applies to in the ELF file
+    pub section_offset: usize,
+    /// Index of the relocation in its relocation section
+    pub index: usize,
+    /// Offset of the relocation entry in the ELF file
+    pub entry_offset: usize,
+    /// The relocation entry itself, its `r_offset` is relative to the start
+    /// of the section that the relocation applies to.
+    pub reloc: Reloc,
+}
 
-/// Copies the bytes contained in a specific section in the ELF file.
-pub fn get_section_bytes(section_name: &str, binary: &Elf<'_>, binary_buffer: &[u8]) -> Vec<u8> {
-    debug!("Extracting section: {} ", section_name);
-    let mut section_bytes: Vec<u8> = alloc::vec![];
-    // Iterate over section headers to find the one with a matching name
-    for section in &binary.section_headers {
-        if Some(section_name) == binary.strtab.get_at(section.sh_name) {
-            section_bytes.extend(
-                &binary_buffer
-                    [section.sh_offset as usize..(section.sh_offset + section.sh_size) as usize],
-            );
+#[cfg(feature = "elf")]
+impl Relocation<'_> {
+    /// Returns the addend of the relocation which needs to be added to the
+    /// relocated value. The `SHT_REL` relocations store the addend in place
+    /// (e.g. in the immediate operand of the relocated instruction), so this
+    /// is only non-zero for `SHT_RELA` relocations.
+    pub fn explicit_addend(&self) -> i64 {
+        self.reloc.r_addend.unwrap_or(0)
+    }
 
-            return section_bytes;
+    /// Describes where the relocation is applied, it is attached to the errors
+    /// encountered while processing the relocation.
+    pub fn location(&self, binary: &Elf<'_>) -> ErrorLocation {
+        let offset = self.reloc.r_offset as usize;
+        ErrorLocation {
+            object: None,
+            section: Some(self.section_name.to_string()),
+            relocation_index: Some(self.index),
+            offset: Some(offset),
+            function: containing_function(binary, self.section_index, offset).map(String::from),
+            symbol: binary
+                .syms
+                .get(self.reloc.r_sym)
+                .and_then(|symbol| symbol_name(binary, &symbol))
+                .map(String::from),
         }
     }
+}
+
+/// Returns the name of the symbol, the section symbols are named after their
+/// sections.
+#[cfg(feature = "elf")]
+pub fn symbol_name<'a>(binary: &Elf<'a>, symbol: &Sym) -> Option<&'a str> {
+    if symbol.st_type() == STT_SECTION {
+        let section = binary.section_headers.get(symbol.st_shndx)?;
+        return binary.strtab.get_at(section.sh_name);
+    }
+    binary
+        .strtab
+        .get_at(symbol.st_name)
+        .filter(|name| !name.is_empty())
+}
 
-    section_bytes
+/// Finds the function in the given section which contains the given offset.
+/// If the size of a function isn't specified, it is assumed to extend until
+/// the next function.
+#[cfg(feature = "elf")]
+pub fn containing_function<'a>(
+    binary: &Elf<'a>,
+    section_index: usize,
+    offset: usize,
+) -> Option<&'a str> {
+    let offset = offset as u64;
+    binary
+        .syms
+        .iter()
+        .filter(|symbol| symbol.st_type() == STT_FUNC && symbol.st_shndx == section_index)
+        .filter(|symbol| {
+            symbol.st_value <= offset
+                && (symbol.st_size == 0 || offset - symbol.st_value < symbol.st_size)
+        })
+        .max_by_key(|symbol| symbol.st_value)
+        .and_then(|symbol| binary.strtab.get_at(symbol.st_name))
 }
 
-pub fn find_relocations(binary: &Elf<'_>, buffer: &[u8]) -> Vec<(usize, Reloc)> {
+/// Collects all relocations specified in the `SHT_REL` and `SHT_RELA` sections
+/// of the ELF file. The section that the relocations apply to is determined
+/// using the `sh_info` field of the relocation section header.
+#[cfg(feature = "elf")]
+pub fn find_relocations<'a>(binary: &Elf<'a>) -> Result<Vec<Relocation<'a>>, ElfUtilsError> {
     let mut relocations = alloc::vec![];
 
-    let context = goblin::container::Ctx::new(Container::Big, Endian::Little);
-
-    for (i, section) in binary.section_headers.iter().enumerate() {
-        if section.sh_type == goblin::elf::section_header::SHT_REL {
-            // Relocations section is always located immediately after the section
-            // that needs to have those relocations applied
-            let preceding_section_offset = binary.section_headers[i - 1].sh_offset as usize;
-            let offset = section.sh_offset as usize;
-            let size = section.sh_size as usize;
-            let relocs =
-                goblin::elf::reloc::RelocSection::parse(&buffer, offset, size, false, context)
-                    .unwrap();
-            relocs
-                .iter()
-                .for_each(|reloc| relocations.push((preceding_section_offset, reloc)));
+    for (index, relocation_section) in &binary.shdr_relocs {
+        let Some(section_header) = binary.section_headers.get(*index) else {
+            return Err(ElfUtilsError::InvalidSectionIndex(*index));
+        };
+        let section_index = section_header.sh_info as usize;
+        let Some(target_section) = binary.section_headers.get(section_index) else {
+            return Err(ElfUtilsError::InvalidSectionIndex(section_index));
+        };
+        let section_name = binary.strtab.get_at(target_section.sh_name).unwrap_or("");
+        debug!(
+            "Found {} relocations for section {}",
+            relocation_section.len(),
+            section_name
+        );
+
+        for (index, reloc) in relocation_section.iter().enumerate() {
+            relocations.push(Relocation {
+                section_index,
+                section_name,
+                section_offset: target_section.sh_offset as usize,
+                index,
+                entry_offset: (section_header.sh_offset as usize)
+                    .saturating_add(index.saturating_mul(section_header.sh_entsize as usize)),
+                reloc,
+            });
         }
     }
 
-    relocations
+    Ok(relocations)
+}
+
+/// Size of the location patched by a relocation of the given type.
+#[cfg(feature = "elf")]
+fn relocated_location_size(relocation_type: u32, offset: usize) -> Result<usize, ElfUtilsError> {
+    match relocation_type {
+        R_BPF_64_64 => Ok(LDDW_INSTRUCTION_SIZE),
+        R_BPF_64_32 | R_BPF_64_ABS64 => Ok(8),
+        R_BPF_64_ABS32 => Ok(4),
+        relocation_type => Err(ElfUtilsError::UnsupportedRelocationType {
+            relocation_type,
+            offset,
+        }),
+    }
+}
+
+/// Returns the addend of a `SHT_REL` relocation of the given type, which is
+/// stored in the relocated location at `offset` in the `section`. Calls to
+/// functions in a different section are relocated against the section symbol
+/// and the immediate contains the offset of the function counted in
+/// instructions after the one preceding the section, the calls relocated
+/// against the function symbols contain `call -1` instead.
+#[cfg(feature = "elf")]
+pub fn implicit_addend(
+    section: &[u8],
+    offset: usize,
+    relocation_type: u32,
+    is_section_symbol: bool,
+) -> Result<i64, ElfUtilsError> {
+    let size = relocated_location_size(relocation_type, offset)?;
+    let location = &section[checked_range(offset, size, section.len())?];
+    Ok(match relocation_type {
+        R_BPF_64_64 => Lddw::from(location).immediate_l as i32 as i64,
+        R_BPF_64_32 if is_section_symbol => {
+            (Call::from(location).immediate as i32 as i64 + 1) * INSTRUCTION_SIZE as i64
+        }
+        R_BPF_64_32 => 0,
+        R_BPF_64_ABS64 => i64::from_le_bytes(location.try_into().unwrap_or_default()),
+        _ => i32::from_le_bytes(location.try_into().unwrap_or_default()) as i64,
+    })
+}
+
+/// Adds `delta` to the addend of a `SHT_REL` relocation against a section
+/// symbol, see [`implicit_addend`]. It is used when the location referenced
+/// by the relocation is moved within its section, e.g. when merging sections.
+#[cfg(feature = "elf")]
+pub fn adjust_implicit_addend(
+    section: &mut [u8],
+    offset: usize,
+    relocation_type: u32,
+    delta: i64,
+) -> Result<(), ElfUtilsError> {
+    let size = relocated_location_size(relocation_type, offset)?;
+    let range = checked_range(offset, size, section.len())?;
+    let location = &mut section[range];
+    match relocation_type {
+        R_BPF_64_64 => {
+            let mut instruction = Lddw::from(&*location);
+            instruction.immediate_l = instruction.immediate_l.wrapping_add(delta as u32);
+            location.copy_from_slice((&instruction).into());
+        }
+        R_BPF_64_32 => {
+            // The immediate of the call is counted in instructions
+            let mut instruction = Call::from(&*location);
+            instruction.immediate = instruction
+                .immediate
+                .wrapping_add((delta / INSTRUCTION_SIZE as i64) as u32);
+            location.copy_from_slice((&instruction).into());
+        }
+        R_BPF_64_ABS64 => {
+            let value = u64::from_le_bytes(location.try_into().unwrap_or_default());
+            location.copy_from_slice(&value.wrapping_add(delta as u64).to_le_bytes());
+        }
+        _ => {
+            let value = u32::from_le_bytes(location.try_into().unwrap_or_default());
+            location.copy_from_slice(&value.wrapping_add(delta as u32).to_le_bytes());
+        }
+    }
+    Ok(())
 }
 
+#[cfg(feature = "elf")]
 pub fn round_section_length(section: &mut Vec<u8>) {
     if section.len() % INSTRUCTION_SIZE != 0 {
         let padding = INSTRUCTION_SIZE - section.len() % INSTRUCTION_SIZE;
         section.extend(alloc::vec![0; padding]);
     }
 }
+
+/// Computes the CRC32 (IEEE 802.3) checksum of the given bytes. It is used
+/// for detecting corrupted binaries after they were transferred to the device.
+///
+/// The bitwise variant is used to avoid storing a 1KB lookup table, which
+/// matters on the constrained devices that need to verify the checksum.
+#[cfg(feature = "elf")]
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
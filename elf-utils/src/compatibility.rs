@@ -0,0 +1,272 @@
+//! Checks whether a processed program can be executed by a given implementation
+//! of the VM.
+//!
+//! The rbpf and Femto-Container VMs support different binary layouts and
+//! different subsets of the eBPF instruction set. Loading an unsupported
+//! program on the device either fails with an unhelpful error or results in
+//! the VM aborting in the middle of the execution, so the programs are checked
+//! on the host before they are sent to the device.
+
+use alloc::{collections::btree_map::BTreeMap, string::ToString, vec::Vec};
+use core::fmt;
+use micro_bpf_common::{BinaryFileLayout, TargetVM};
+
+use crate::{
+    disassembler::text_with_symbols,
+    error::{ElfUtilsError, ErrorLocation},
+    model::{
+        decode_instructions, Instruction, BPF_ALU, BPF_JMP32, BPF_STX, BPF_XADD, CALL_SRC_ABSOLUTE,
+        CALL_SRC_PC_RELATIVE, FC_LDDWD_OPCODE, FC_LDDWR_OPCODE,
+    },
+};
+
+/// Features of the bytecode which aren't supported by all combinations of the
+/// VM and the binary layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BytecodeFeature {
+    /// The 32-bit ALU instructions (`BPF_ALU` class)
+    Alu32,
+    /// The jumps comparing the lower 32 bits of the registers (`BPF_JMP32` class)
+    Jmp32,
+    /// The atomic add instructions (`BPF_XADD` mode)
+    Atomics,
+    /// The `call` instructions with the src register set to
+    /// [`CALL_SRC_PC_RELATIVE`] calling functions of the program
+    PcRelativeCalls,
+    /// The `call` instructions with the src register set to
+    /// [`CALL_SRC_ABSOLUTE`] produced when resolving relocations on the device
+    AbsoluteCalls,
+    /// The Femto-Container specific loads of the addresses of the .data and
+    /// .rodata sections (see [`FC_LDDWD_OPCODE`] and [`FC_LDDWR_OPCODE`])
+    SectionLoads,
+}
+
+impl BytecodeFeature {
+    /// Returns the feature used by the instruction if it isn't supported by all
+    /// VMs.
+    pub fn of(instruction: &Instruction) -> Option<Self> {
+        match instruction.opcode as u32 {
+            FC_LDDWD_OPCODE | FC_LDDWR_OPCODE => return Some(BytecodeFeature::SectionLoads),
+            _ => {}
+        }
+        if instruction.is_call() {
+            return match instruction.src {
+                CALL_SRC_PC_RELATIVE => Some(BytecodeFeature::PcRelativeCalls),
+                CALL_SRC_ABSOLUTE => Some(BytecodeFeature::AbsoluteCalls),
+                _ => None,
+            };
+        }
+        match instruction.class() {
+            BPF_ALU => Some(BytecodeFeature::Alu32),
+            BPF_JMP32 => Some(BytecodeFeature::Jmp32),
+            BPF_STX if instruction.opcode & 0xe0 == BPF_XADD => Some(BytecodeFeature::Atomics),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the VM can execute programs using the feature when
+    /// they are loaded with the given layout.
+    ///
+    /// - Neither of the VMs implements the atomic instructions.
+    /// - The Femto-Container VM only implements the 64-bit ALU and jump
+    ///   instructions and doesn't support calling functions of the program.
+    /// - The default interpreter of rbpf used for the
+    ///   [`BinaryFileLayout::OnlyTextSection`] layout doesn't support
+    ///   PC-relative calls.
+    /// - The calls to absolute addresses are only produced (and understood) by
+    ///   the layouts whose relocations are resolved on the device.
+    /// - The section loads are only understood by the interpreters of the layouts
+    ///   starting with the Femto-Container header.
+    pub fn is_supported(&self, target: TargetVM, layout: BinaryFileLayout) -> bool {
+        match (self, target) {
+            (BytecodeFeature::Atomics, _) => false,
+            (BytecodeFeature::SectionLoads, _) => matches!(
+                layout,
+                BinaryFileLayout::FemtoContainersHeader | BinaryFileLayout::ExtendedHeader
+            ),
+            (_, TargetVM::FemtoContainer) => false,
+            (BytecodeFeature::Alu32 | BytecodeFeature::Jmp32, TargetVM::Rbpf) => true,
+            (BytecodeFeature::PcRelativeCalls, TargetVM::Rbpf) => {
+                layout != BinaryFileLayout::OnlyTextSection
+            }
+            (BytecodeFeature::AbsoluteCalls, TargetVM::Rbpf) => matches!(
+                layout,
+                BinaryFileLayout::RawObjectFile | BinaryFileLayout::PreResolvedRelocations
+            ),
+        }
+    }
+}
+
+impl fmt::Display for BytecodeFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BytecodeFeature::Alu32 => "32-bit ALU instructions",
+            BytecodeFeature::Jmp32 => "32-bit jump instructions",
+            BytecodeFeature::Atomics => "atomic instructions",
+            BytecodeFeature::PcRelativeCalls => "PC-relative function calls",
+            BytecodeFeature::AbsoluteCalls => "function calls to absolute addresses",
+            BytecodeFeature::SectionLoads => "Femto-Container section loads (lddwd/lddwr)",
+        })
+    }
+}
+
+/// A reason why a program can't be executed by a VM, see [`check_compatibility`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    /// The VM can't load programs with the layout
+    Layout {
+        /// The VM implementation
+        target: TargetVM,
+        /// The layout of the program
+        layout: BinaryFileLayout,
+    },
+    /// The program uses a feature which the VM doesn't support
+    Feature {
+        /// The unsupported feature
+        feature: BytecodeFeature,
+        /// Number of the instructions using the feature
+        count: usize,
+        /// Location of the first instruction using the feature
+        location: ErrorLocation,
+    },
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::Layout { target, layout } => write!(
+                f,
+                "The {} VM doesn't support the {:?} binary layout",
+                target, layout
+            ),
+            Incompatibility::Feature {
+                feature,
+                count,
+                location,
+            } => write!(
+                f,
+                "The program uses {} which aren't supported by the VM with this layout (first at {}, {} in total)",
+                feature, location, count
+            ),
+        }
+    }
+}
+
+/// Checks whether the processed program with the given layout can be executed
+/// by the target VM and returns all reasons why it can't. An error is only
+/// returned if the program can't be parsed.
+pub fn check_compatibility(
+    program: &[u8],
+    target: TargetVM,
+    layout: BinaryFileLayout,
+) -> Result<Vec<Incompatibility>, ElfUtilsError> {
+    // The instructions of a program which can't be loaded don't matter.
+    if !target.supports_layout(layout) {
+        return Ok(alloc::vec![Incompatibility::Layout { target, layout }]);
+    }
+
+    let (text, symbols) = text_with_symbols(program, layout)?;
+    let mut features: BTreeMap<BytecodeFeature, (usize, usize)> = BTreeMap::new();
+    for instruction in decode_instructions(&text)? {
+        let Some(feature) = BytecodeFeature::of(&instruction) else {
+            continue;
+        };
+        if feature.is_supported(target, layout) {
+            continue;
+        }
+        features.entry(feature).or_insert((0, instruction.offset)).0 += 1;
+    }
+
+    Ok(features
+        .into_iter()
+        .map(|(feature, (count, offset))| Incompatibility::Feature {
+            feature,
+            count,
+            location: ErrorLocation {
+                section: Some(".text".to_string()),
+                offset: Some(offset),
+                function: symbols.function_containing(offset).map(ToString::to_string),
+                ..Default::default()
+            },
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mov32 r0, 1; call +1; exit; lddwr r1, 0; jeq32 r0, 0, +0; exit
+    const PROGRAM: [u8; 56] = [
+        0xb4, 0, 0, 0, 1, 0, 0, 0, //
+        0x85, 0x10, 0, 0, 1, 0, 0, 0, //
+        0x95, 0, 0, 0, 0, 0, 0, 0, //
+        0xd8, 0x01, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0x16, 0, 0, 0, 0, 0, 0, 0, //
+        0x95, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    #[test]
+    fn unsupported_features_are_reported() {
+        let incompatibilities =
+            check_compatibility(&PROGRAM, TargetVM::Rbpf, BinaryFileLayout::OnlyTextSection)
+                .unwrap();
+        assert_eq!(
+            incompatibilities
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "The program uses PC-relative function calls which aren't supported by the VM with this layout (first at .text+0x8, 1 in total)",
+                "The program uses Femto-Container section loads (lddwd/lddwr) which aren't supported by the VM with this layout (first at .text+0x18, 1 in total)",
+            ]
+        );
+
+        let incompatibilities = check_compatibility(
+            &PROGRAM,
+            TargetVM::FemtoContainer,
+            BinaryFileLayout::OnlyTextSection,
+        )
+        .unwrap();
+        assert_eq!(
+            incompatibilities,
+            [Incompatibility::Layout {
+                target: TargetVM::FemtoContainer,
+                layout: BinaryFileLayout::OnlyTextSection,
+            }]
+        );
+    }
+
+    fn unsupported(target: TargetVM, layout: BinaryFileLayout) -> Vec<BytecodeFeature> {
+        decode_instructions(&PROGRAM)
+            .unwrap()
+            .iter()
+            .filter_map(BytecodeFeature::of)
+            .filter(|feature| !feature.is_supported(target, layout))
+            .collect()
+    }
+
+    #[test]
+    fn features_depend_on_the_vm_and_the_layout() {
+        assert_eq!(
+            unsupported(
+                TargetVM::FemtoContainer,
+                BinaryFileLayout::FemtoContainersHeader
+            ),
+            [
+                BytecodeFeature::Alu32,
+                BytecodeFeature::PcRelativeCalls,
+                BytecodeFeature::Jmp32
+            ]
+        );
+        assert_eq!(
+            unsupported(TargetVM::Rbpf, BinaryFileLayout::ExtendedHeader),
+            []
+        );
+        assert_eq!(
+            unsupported(TargetVM::Rbpf, BinaryFileLayout::PreResolvedRelocations),
+            [BytecodeFeature::SectionLoads]
+        );
+    }
+}
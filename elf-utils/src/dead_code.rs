@@ -0,0 +1,953 @@
+//! Elimination of the functions and read-only data which are never used by
+//! the program.
+//!
+//! The compiler keeps all global functions in the object file as they could be
+//! called from other object files, and the string literals of all functions
+//! are placed in the .rodata sections even if those functions are never called.
+//! Apart from that, the assemblers append the name of each global function to
+//! the .rodata section of the binary (see
+//! [`crate::assemble_binary_specifying_helpers`]). The binaries need to fit in
+//! the SUIT storage slots of the device, so removing the parts that are never
+//! used allows for deploying larger programs.
+use alloc::{
+    collections::{btree_map::BTreeMap as HashMap, btree_set::BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use goblin::elf::{
+    section_header::{
+        SHF_EXECINSTR, SHF_STRINGS, SHF_WRITE, SHN_LORESERVE, SHT_NOBITS, SHT_PROGBITS,
+    },
+    sym::{STB_GLOBAL, STT_FILE, STT_FUNC, STT_OBJECT, STT_SECTION},
+    Elf, SectionHeader,
+};
+use log::debug;
+
+use crate::{
+    common::{
+        adjust_implicit_addend, checked_range, find_relocations, implicit_addend, parse_elf,
+        section_alignment, section_bytes, symbol_name, Relocation, INSTRUCTION_SIZE,
+        MAX_ZERO_INITIALISED_SIZE, R_BPF_NONE,
+    },
+    elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+    error::ElfUtilsError,
+    model::{decode_instructions, Instruction},
+    stripper::is_required_section,
+};
+
+/// Summary of the parts of the program removed by [`eliminate_dead_code`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadCodeReport {
+    /// Names of the functions which aren't reachable from the entry point
+    pub removed_functions: Vec<String>,
+    /// Number of bytes removed from the sections containing executable code
+    pub removed_text_bytes: usize,
+    /// Number of bytes removed from the read-only data sections
+    pub removed_rodata_bytes: usize,
+}
+
+impl DeadCodeReport {
+    /// Number of bytes by which the sections loaded by the VM were reduced.
+    /// It doesn't include the names of the removed global functions, which
+    /// would otherwise be appended to the .rodata section by the assemblers.
+    pub fn saved_bytes(&self) -> usize {
+        self.removed_text_bytes + self.removed_rodata_bytes
+    }
+}
+
+/// Removes the functions which can't be reached from the entry point of the
+/// program and the read-only data which isn't referenced by the remaining
+/// code and data. The entry points are the given global functions, which are
+/// exported using [`crate::assemble_binary_with_entry_points`]. If none are
+/// given, the entry point is the start of the first executable section, which
+/// is where the VM starts executing the merged .text section.
+///
+/// The executable sections are split into functions at the function symbols,
+/// the sections containing mergeable strings (e.g. `.rodata.str1.1`) into the
+/// individual strings and the other read-only sections at the symbols of the
+/// variables defined in them. Each part is kept if it is referenced by a
+/// relocation from a part which is kept or from one of the writable data
+/// sections, or if it is the target of a PC-relative call or jump of a function
+/// which is kept. The remaining parts are moved closer to the start of their
+/// sections, preserving their alignment, and the symbols, relocations and
+/// PC-relative calls and jumps are updated accordingly.
+///
+/// The sections which aren't needed for executing the program (e.g. the debug
+/// information, which refers to the removed code) are removed in the same way
+/// as in [`crate::strip_binary`]. The result is an object file which can be
+/// processed by any of the workflows supported by this crate.
+pub fn eliminate_dead_code(
+    program: &[u8],
+    entry_points: &[&str],
+) -> Result<(Vec<u8>, DeadCodeReport), ElfUtilsError> {
+    let binary = parse_elf(program)?;
+    if !binary.is_64 || !binary.little_endian {
+        return Err(ElfUtilsError::UnsupportedElf(
+            "only 64-bit little-endian ELF files can be optimised",
+        ));
+    }
+    let section_name =
+        |section: &SectionHeader| binary.strtab.get_at(section.sh_name).unwrap_or_default();
+
+    let mut sections: HashMap<usize, CollectedSection> = HashMap::new();
+    for (index, section) in binary.section_headers.iter().enumerate().skip(1) {
+        if is_collected_section(section, section_name(section)) {
+            sections.insert(
+                index,
+                CollectedSection::new(&binary, index, section, program)?,
+            );
+        }
+    }
+
+    let mut relocations: HashMap<usize, Vec<Relocation>> = HashMap::new();
+    for relocation in find_relocations(&binary)? {
+        if relocation.reloc.r_type == R_BPF_NONE {
+            continue;
+        }
+        relocations
+            .entry(relocation.section_index)
+            .or_default()
+            .push(relocation);
+    }
+
+    mark_live_chunks(&binary, program, &mut sections, &relocations, entry_points)?;
+
+    let mut report = DeadCodeReport {
+        removed_functions: Vec::new(),
+        removed_text_bytes: 0,
+        removed_rodata_bytes: 0,
+    };
+    for (index, section) in sections.iter_mut() {
+        section.layout();
+        let removed = section.size() - section.new_size;
+        if removed > 0 {
+            debug!(
+                "Removed {} bytes from {}",
+                removed,
+                section_name(&binary.section_headers[*index])
+            );
+        }
+        if section.is_text {
+            report.removed_text_bytes += removed;
+        } else {
+            report.removed_rodata_bytes += removed;
+        }
+    }
+
+    // The contents of all sections are copied as the addends stored in the
+    // relocated locations need to be adjusted later on.
+    let mut section_indices: HashMap<usize, usize> = HashMap::new();
+    let mut contents: HashMap<usize, Vec<u8>> = HashMap::new();
+    for (index, section) in binary.section_headers.iter().enumerate().skip(1) {
+        if !is_required_section(section, section_name(section)) {
+            continue;
+        }
+        if section.sh_type != SHT_NOBITS {
+            let data = match sections.get(&index) {
+                Some(collected) => collected.live_contents(),
+                None => section_bytes(section, program)?.to_vec(),
+            };
+            contents.insert(index, data);
+        }
+        section_indices.insert(index, section_indices.len() + 1);
+    }
+
+    // Only the symbols which are defined in the kept parts of the sections
+    // are copied, apart from the undefined and absolute symbols.
+    let mut symbols: Vec<ElfSymbol> = Vec::new();
+    let mut symbol_indices: HashMap<usize, u32> = HashMap::new();
+    for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+        let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
+        let (section_index, value) =
+            if symbol.st_shndx == 0 || symbol.st_shndx >= SHN_LORESERVE as usize {
+                if symbol.st_type() == STT_FILE {
+                    continue;
+                }
+                (symbol.st_shndx as u16, symbol.st_value)
+            } else if let Some(new_index) = section_indices.get(&symbol.st_shndx) {
+                let value = match sections.get(&symbol.st_shndx) {
+                    Some(_) if symbol.st_type() == STT_SECTION => Some(symbol.st_value),
+                    Some(section) => section
+                        .new_offset(symbol.st_value as usize)
+                        .map(|offset| offset as u64),
+                    None => Some(symbol.st_value),
+                };
+                let Some(value) = value else {
+                    debug!("Removing symbol {} which is never used", name);
+                    if symbol.st_type() == STT_FUNC {
+                        report.removed_functions.push(name.to_string());
+                    }
+                    continue;
+                };
+                (*new_index as u16, value)
+            } else {
+                continue;
+            };
+        symbols.push(ElfSymbol {
+            name: name.to_string(),
+            info: symbol.st_info,
+            other: symbol.st_other,
+            section_index,
+            value,
+            size: symbol.st_size,
+        });
+        symbol_indices.insert(index, symbols.len() as u32);
+    }
+
+    // The relocations are moved along with the parts they apply to and the
+    // ones applying to the removed parts are dropped.
+    let mut new_relocations: HashMap<(usize, bool), Vec<ElfRelocation>> = HashMap::new();
+    for (index, section_relocations) in &relocations {
+        if !section_indices.contains_key(index) {
+            continue;
+        }
+        for relocation in section_relocations {
+            let new_relocation = move_relocation(
+                &binary,
+                program,
+                relocation,
+                &sections,
+                &symbol_indices,
+                &mut contents,
+            )
+            .map_err(|error| error.at(relocation.location(&binary)))?;
+            if let Some(new_relocation) = new_relocation {
+                let explicit_addends = relocation.reloc.r_addend.is_some();
+                new_relocations
+                    .entry((*index, explicit_addends))
+                    .or_default()
+                    .push(new_relocation);
+            }
+        }
+    }
+
+    for (index, section) in &sections {
+        if let Some(data) = contents.get_mut(index) {
+            section.update_pc_relative_offsets(data, relocations.get(index))?;
+        }
+    }
+
+    let mut writer = ElfWriter::new();
+    for (index, section) in binary.section_headers.iter().enumerate() {
+        if !section_indices.contains_key(&index) {
+            continue;
+        }
+        let name = section_name(section);
+        let alignment = section_alignment(section)? as u64;
+        match contents.remove(&index) {
+            Some(data) => writer.add_section(
+                name,
+                section.sh_type,
+                section.sh_flags,
+                alignment,
+                section.sh_entsize,
+                data,
+            ),
+            None if section.sh_size <= MAX_ZERO_INITIALISED_SIZE as u64 => {
+                writer.add_nobits_section(name, section.sh_flags, alignment, section.sh_size)
+            }
+            None => {
+                return Err(ElfUtilsError::InvalidValue {
+                    field: "size of the zero-initialised sections",
+                    value: section.sh_size,
+                })
+            }
+        };
+    }
+    for symbol in symbols {
+        writer.add_symbol(symbol);
+    }
+    for ((index, explicit_addends), relocations) in new_relocations {
+        let prefix = if explicit_addends { ".rela" } else { ".rel" };
+        writer.add_relocations(
+            &format!("{}{}", prefix, section_name(&binary.section_headers[index])),
+            section_indices[&index],
+            relocations,
+            explicit_addends,
+        );
+    }
+
+    let optimised = writer.write();
+    debug!("Eliminated dead code: {:?}", report);
+    Ok((optimised, report))
+}
+
+/// Returns true for the sections whose unused parts are removed, i.e. the
+/// sections containing code and read-only data. The writable data sections
+/// are always kept as a whole.
+fn is_collected_section(section: &SectionHeader, name: &str) -> bool {
+    is_required_section(section, name)
+        && section.sh_type == SHT_PROGBITS
+        && section.sh_flags & SHF_WRITE as u64 == 0
+}
+
+/// A part of a section which is either kept or removed as a whole, i.e.
+/// a function, a string literal or a variable.
+#[derive(Debug, Clone)]
+struct Chunk {
+    start: usize,
+    end: usize,
+    live: bool,
+    /// Offset of the chunk within the section once the dead chunks are removed
+    new_start: usize,
+}
+
+/// A section whose unused chunks are removed.
+struct CollectedSection<'a> {
+    data: &'a [u8],
+    is_text: bool,
+    alignment: usize,
+    /// Chunks covering the whole section sorted by their offsets
+    chunks: Vec<Chunk>,
+    /// Instructions of the executable sections, empty for the other sections
+    instructions: Vec<Instruction>,
+    /// Size of the section once the dead chunks are removed
+    new_size: usize,
+}
+
+impl<'a> CollectedSection<'a> {
+    fn new(
+        binary: &Elf,
+        index: usize,
+        section: &SectionHeader,
+        program: &'a [u8],
+    ) -> Result<Self, ElfUtilsError> {
+        let data = section_bytes(section, program)?;
+        let is_text = section.sh_flags & SHF_EXECINSTR as u64 != 0;
+        let instructions = if is_text {
+            decode_instructions(data)?
+        } else {
+            Vec::new()
+        };
+
+        let mut starts = BTreeSet::from([0]);
+        if !is_text && section.sh_flags & SHF_STRINGS as u64 != 0 {
+            // Each string ends with a null byte.
+            starts.extend(
+                data.iter()
+                    .enumerate()
+                    .filter(|(_, byte)| **byte == 0)
+                    .map(|(offset, _)| offset + 1),
+            );
+        } else {
+            let symbol_type = if is_text { STT_FUNC } else { STT_OBJECT };
+            starts.extend(
+                binary
+                    .syms
+                    .iter()
+                    .filter(|symbol| symbol.st_shndx == index && symbol.st_type() == symbol_type)
+                    .map(|symbol| symbol.st_value as usize),
+            );
+        }
+        starts.retain(|start| *start < data.len());
+        let ends = starts.iter().skip(1).copied().chain([data.len()]);
+        let chunks = starts
+            .iter()
+            .zip(ends)
+            .map(|(start, end)| Chunk {
+                start: *start,
+                end,
+                live: false,
+                new_start: 0,
+            })
+            .collect();
+
+        Ok(CollectedSection {
+            data,
+            is_text,
+            alignment: section_alignment(section)?,
+            chunks,
+            instructions,
+            new_size: 0,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Index of the chunk containing the given offset.
+    fn chunk_at(&self, offset: usize) -> Option<usize> {
+        let index = self.chunks.partition_point(|chunk| chunk.start <= offset);
+        (index > 0 && offset < self.chunks[index - 1].end).then_some(index - 1)
+    }
+
+    /// Marks the chunks containing any of the offsets between `start` and `end`
+    /// (inclusive) as live and returns the indices of the ones which weren't
+    /// live before. The `end` can point just past the end of the section.
+    fn mark_live(&mut self, start: usize, end: usize) -> Vec<usize> {
+        let mut marked = Vec::new();
+        for (index, chunk) in self.chunks.iter_mut().enumerate() {
+            if chunk.end > start && chunk.start <= end && !chunk.live {
+                chunk.live = true;
+                marked.push(index);
+            }
+        }
+        marked
+    }
+
+    /// Places the live chunks one after another. Each chunk is placed at the
+    /// same offset modulo the alignment of the section, so the alignment of
+    /// everything it contains is preserved.
+    fn layout(&mut self) {
+        let mut cursor = 0;
+        for chunk in self.chunks.iter_mut().filter(|chunk| chunk.live) {
+            let padding = (chunk.start % self.alignment + self.alignment - cursor % self.alignment)
+                % self.alignment;
+            chunk.new_start = cursor + padding;
+            cursor = chunk.new_start + (chunk.end - chunk.start);
+        }
+        self.new_size = cursor;
+    }
+
+    /// Returns the offset that the given offset is moved to, or `None` if it
+    /// is in a chunk which is removed.
+    fn new_offset(&self, offset: usize) -> Option<usize> {
+        if offset == self.size() {
+            return Some(self.new_size);
+        }
+        let chunk = &self.chunks[self.chunk_at(offset)?];
+        chunk
+            .live
+            .then_some(chunk.new_start + (offset - chunk.start))
+    }
+
+    /// Contents of the section with the dead chunks removed.
+    fn live_contents(&self) -> Vec<u8> {
+        let mut contents = alloc::vec![0; self.new_size];
+        for chunk in self.chunks.iter().filter(|chunk| chunk.live) {
+            let len = chunk.end - chunk.start;
+            contents[chunk.new_start..chunk.new_start + len]
+                .copy_from_slice(&self.data[chunk.start..chunk.end]);
+        }
+        contents
+    }
+
+    /// Returns the targets of the PC-relative calls and jumps in the given
+    /// chunk. The calls which are relocated aren't PC-relative.
+    fn pc_relative_targets(
+        &self,
+        chunk: usize,
+        relocations: Option<&Vec<Relocation>>,
+    ) -> Result<Vec<usize>, ElfUtilsError> {
+        let Chunk { start, end, .. } = self.chunks[chunk];
+        let mut targets = Vec::new();
+        for instruction in &self.instructions {
+            if instruction.offset < start || instruction.offset >= end {
+                continue;
+            }
+            if let Some(target) = self.pc_relative_target(instruction, relocations)? {
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+
+    fn pc_relative_target(
+        &self,
+        instruction: &Instruction,
+        relocations: Option<&Vec<Relocation>>,
+    ) -> Result<Option<usize>, ElfUtilsError> {
+        let is_relocated = relocations.is_some_and(|relocations| {
+            relocations
+                .iter()
+                .any(|relocation| relocation.reloc.r_offset == instruction.offset as u64)
+        });
+        if is_relocated {
+            return Ok(None);
+        }
+        let Some(target) = instruction.jump_target().or(instruction.call_target()) else {
+            return Ok(None);
+        };
+        match usize::try_from(target) {
+            Ok(target) if target < self.size() => Ok(Some(target)),
+            _ => Err(ElfUtilsError::Malformed(format!(
+                "The instruction at offset {} transfers control outside of its section",
+                instruction.offset
+            ))),
+        }
+    }
+
+    /// Updates the offsets of the PC-relative calls and jumps in the contents
+    /// of the section once the dead chunks are removed.
+    fn update_pc_relative_offsets(
+        &self,
+        contents: &mut [u8],
+        relocations: Option<&Vec<Relocation>>,
+    ) -> Result<(), ElfUtilsError> {
+        for instruction in &self.instructions {
+            let Some(offset) = self.new_offset(instruction.offset) else {
+                continue;
+            };
+            let Some(target) = self.pc_relative_target(instruction, relocations)? else {
+                continue;
+            };
+            let Some(new_target) = self.new_offset(target) else {
+                return Err(ElfUtilsError::Malformed(format!(
+                    "The instruction at offset {} transfers control to removed code",
+                    instruction.offset
+                )));
+            };
+            let distance = (new_target as i64 - offset as i64) / INSTRUCTION_SIZE as i64 - 1;
+            let range = checked_range(offset, INSTRUCTION_SIZE, contents.len())?;
+            let slot = &mut contents[range];
+            if instruction.is_jump() {
+                let Ok(distance) = i16::try_from(distance) else {
+                    return Err(ElfUtilsError::Malformed(format!(
+                        "The jump at offset {} can't be encoded after removing dead code",
+                        instruction.offset
+                    )));
+                };
+                slot[2..4].copy_from_slice(&distance.to_le_bytes());
+            } else {
+                slot[4..8].copy_from_slice(&(distance as i32).to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The part of a collected section referenced by a relocation.
+struct RelocationTarget {
+    section_index: usize,
+    /// Offset of the symbol that the relocation refers to. The section symbols
+    /// don't belong to any of the chunks, so for them it is the same as the
+    /// offset of the referenced location.
+    symbol_offset: usize,
+    /// Offset of the referenced location, i.e. the offset of the symbol with
+    /// the addend of the relocation added
+    offset: usize,
+}
+
+/// Returns the part of a collected section referenced by the relocation, or
+/// `None` if the symbol isn't defined in any of the collected sections.
+fn relocation_target(
+    binary: &Elf,
+    program: &[u8],
+    relocation: &Relocation,
+    sections: &HashMap<usize, CollectedSection>,
+) -> Result<Option<RelocationTarget>, ElfUtilsError> {
+    let reloc = &relocation.reloc;
+    let Some(symbol) = binary.syms.get(reloc.r_sym) else {
+        return Err(ElfUtilsError::InvalidSymbolIndex(reloc.r_sym));
+    };
+    let Some(section) = sections.get(&symbol.st_shndx) else {
+        return Ok(None);
+    };
+    let addend = match reloc.r_addend {
+        Some(addend) => addend,
+        None => {
+            let Some(location_section) = binary.section_headers.get(relocation.section_index)
+            else {
+                return Err(ElfUtilsError::InvalidSectionIndex(relocation.section_index));
+            };
+            implicit_addend(
+                section_bytes(location_section, program)?,
+                reloc.r_offset as usize,
+                reloc.r_type,
+                symbol.st_type() == STT_SECTION,
+            )?
+        }
+    };
+    let offset = (symbol.st_value as i64).wrapping_add(addend);
+    match usize::try_from(offset) {
+        Ok(offset) if offset <= section.size() && symbol.st_value as usize <= section.size() => {
+            Ok(Some(RelocationTarget {
+                section_index: symbol.st_shndx,
+                symbol_offset: if symbol.st_type() == STT_SECTION {
+                    offset
+                } else {
+                    symbol.st_value as usize
+                },
+                offset,
+            }))
+        }
+        _ => Err(ElfUtilsError::Malformed(
+            "The relocation refers to a location outside of its section".to_string(),
+        )),
+    }
+}
+
+/// Marks the chunks reachable from the entry point of the program and from
+/// the relocations applying to the sections which are kept as a whole.
+fn mark_live_chunks(
+    binary: &Elf,
+    program: &[u8],
+    sections: &mut HashMap<usize, CollectedSection>,
+    relocations: &HashMap<usize, Vec<Relocation>>,
+    entry_points: &[&str],
+) -> Result<(), ElfUtilsError> {
+    let mut worklist: Vec<(usize, usize)> = Vec::new();
+    let mark = |sections: &mut HashMap<usize, CollectedSection>,
+                worklist: &mut Vec<(usize, usize)>,
+                relocation: &Relocation|
+     -> Result<(), ElfUtilsError> {
+        let target = relocation_target(binary, program, relocation, sections)
+            .map_err(|error| error.at(relocation.location(binary)))?;
+        if let Some(target) = target {
+            // A relocation against a symbol with an addend can refer to the
+            // chunks following the one containing the symbol, all chunks in
+            // between need to be kept so that their offsets remain the same.
+            let start = target.symbol_offset.min(target.offset);
+            let end = target.symbol_offset.max(target.offset);
+            let section = sections.get_mut(&target.section_index).unwrap();
+            for chunk in section.mark_live(start, end) {
+                worklist.push((target.section_index, chunk));
+            }
+        }
+        Ok(())
+    };
+
+    if entry_points.is_empty() {
+        if let Some((index, section)) = sections
+            .iter_mut()
+            .find(|(_, section)| section.is_text && section.size() > 0)
+        {
+            section.chunks[0].live = true;
+            worklist.push((*index, 0));
+        }
+    }
+    for entry_point in entry_points {
+        let symbol = binary.syms.iter().find(|symbol| {
+            symbol.st_type() == STT_FUNC
+                && symbol.st_bind() == STB_GLOBAL
+                && symbol_name(binary, symbol) == Some(entry_point)
+                && sections
+                    .get(&symbol.st_shndx)
+                    .is_some_and(|section| section.is_text)
+        });
+        let Some(symbol) = symbol else {
+            return Err(ElfUtilsError::UnknownEntryPoint(entry_point.to_string()));
+        };
+        let offset = symbol.st_value as usize;
+        let section = sections.get_mut(&symbol.st_shndx).unwrap();
+        for chunk in section.mark_live(offset, offset) {
+            worklist.push((symbol.st_shndx, chunk));
+        }
+    }
+    for (index, section_relocations) in relocations {
+        let is_kept = binary.section_headers.get(*index).is_some_and(|section| {
+            let name = binary.strtab.get_at(section.sh_name).unwrap_or_default();
+            is_required_section(section, name)
+        });
+        if !is_kept || sections.contains_key(index) {
+            continue;
+        }
+        for relocation in section_relocations {
+            mark(sections, &mut worklist, relocation)?;
+        }
+    }
+
+    while let Some((index, chunk)) = worklist.pop() {
+        let section_relocations = relocations.get(&index);
+        let Chunk { start, end, .. } = sections[&index].chunks[chunk];
+        for relocation in section_relocations.into_iter().flatten() {
+            let offset = relocation.reloc.r_offset as usize;
+            if offset >= start && offset < end {
+                mark(sections, &mut worklist, relocation)?;
+            }
+        }
+        let section = sections.get_mut(&index).unwrap();
+        for target in section.pc_relative_targets(chunk, section_relocations)? {
+            for marked in section.mark_live(target, target) {
+                worklist.push((index, marked));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the relocation along with the part of the section it applies to and
+/// adjusts its addend if the location it refers to was moved. Returns `None`
+/// if the relocation applies to a part which was removed.
+fn move_relocation(
+    binary: &Elf,
+    program: &[u8],
+    relocation: &Relocation,
+    sections: &HashMap<usize, CollectedSection>,
+    symbol_indices: &HashMap<usize, u32>,
+    contents: &mut HashMap<usize, Vec<u8>>,
+) -> Result<Option<ElfRelocation>, ElfUtilsError> {
+    let reloc = &relocation.reloc;
+    let offset = match sections.get(&relocation.section_index) {
+        Some(section) => match section.new_offset(reloc.r_offset as usize) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        },
+        None => reloc.r_offset as usize,
+    };
+    let Some(symbol_index) = symbol_indices.get(&reloc.r_sym) else {
+        return Err(ElfUtilsError::Malformed(
+            "The relocation refers to a symbol defined in a removed section".to_string(),
+        ));
+    };
+
+    let mut addend = reloc.r_addend.unwrap_or(0);
+    let is_section_symbol = binary
+        .syms
+        .get(reloc.r_sym)
+        .is_some_and(|symbol| symbol.st_type() == STT_SECTION);
+    if let Some(target) = relocation_target(binary, program, relocation, sections)? {
+        // The offsets of the other symbols are updated directly.
+        if is_section_symbol {
+            let section = &sections[&target.section_index];
+            let Some(new_offset) = section.new_offset(target.offset) else {
+                return Err(ElfUtilsError::Malformed(
+                    "The relocation refers to removed data".to_string(),
+                ));
+            };
+            let delta = new_offset as i64 - target.offset as i64;
+            if reloc.r_addend.is_some() {
+                addend = addend.wrapping_add(delta);
+            } else if delta != 0 {
+                let Some(data) = contents.get_mut(&relocation.section_index) else {
+                    return Err(ElfUtilsError::InvalidSectionIndex(relocation.section_index));
+                };
+                adjust_implicit_addend(data, offset, reloc.r_type, delta)?;
+            }
+        }
+    }
+
+    Ok(Some(ElfRelocation {
+        offset: offset as u64,
+        symbol_index: *symbol_index,
+        relocation_type: reloc.r_type,
+        addend,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{extract_section, R_BPF_64_64, R_BPF_64_ABS64},
+        extended_relocations::{assemble_binary, ExtendedBinary},
+        model::{Call, Lddw},
+    };
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_MERGE},
+        sym::{STB_GLOBAL, STB_LOCAL},
+    };
+
+    fn symbol(name: &str, info: u8, section_index: usize, value: u64, size: u64) -> ElfSymbol {
+        ElfSymbol {
+            name: name.to_string(),
+            info,
+            other: 0,
+            section_index: section_index as u16,
+            value,
+            size,
+        }
+    }
+
+    /// Builds an object file with the `main` function calling a static
+    /// function placed after a global function which is never called. Both
+    /// `main` and the unused function load a string literal.
+    fn object_with_unused_function() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        let text = alloc::vec![
+            // main: lddw r1, 7; call +4; exit
+            0x18, 0x01, 0, 0, 7, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x10, 0, 0, 4, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            // unused: lddw r1, 0; exit
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            // helper: mov r0, 1; exit
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let strings_index = writer.add_section(
+            ".rodata.str1.1",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_MERGE | SHF_STRINGS) as u64,
+            1,
+            1,
+            b"unused\0used\0".to_vec(),
+        );
+        let strings = writer.add_symbol(symbol(
+            "",
+            STB_LOCAL << 4 | STT_SECTION,
+            strings_index,
+            0,
+            0,
+        ));
+        writer.add_symbol(symbol(
+            "helper",
+            STB_LOCAL << 4 | STT_FUNC,
+            text_index,
+            0x38,
+            16,
+        ));
+        writer.add_symbol(symbol(
+            "main",
+            STB_GLOBAL << 4 | STT_FUNC,
+            text_index,
+            0,
+            32,
+        ));
+        writer.add_symbol(symbol(
+            "unused",
+            STB_GLOBAL << 4 | STT_FUNC,
+            text_index,
+            0x20,
+            24,
+        ));
+        let relocation = |offset| ElfRelocation {
+            offset,
+            symbol_index: strings,
+            relocation_type: R_BPF_64_64,
+            addend: 0,
+        };
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![relocation(0), relocation(0x20)],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn exported_entry_points_are_kept() {
+        let (_, report) =
+            eliminate_dead_code(&object_with_unused_function(), &["main", "unused"]).unwrap();
+        assert!(report.removed_functions.is_empty());
+        assert_eq!(report.removed_rodata_bytes, 0);
+
+        let error = eliminate_dead_code(&object_with_unused_function(), &["helper"]).unwrap_err();
+        assert_eq!(
+            error,
+            ElfUtilsError::UnknownEntryPoint("helper".to_string())
+        );
+    }
+
+    #[test]
+    fn unreachable_functions_and_strings_are_removed() {
+        let (optimised, report) = eliminate_dead_code(&object_with_unused_function(), &[]).unwrap();
+        assert_eq!(
+            report,
+            DeadCodeReport {
+                removed_functions: alloc::vec!["unused".to_string()],
+                removed_text_bytes: 24,
+                removed_rodata_bytes: 7,
+            }
+        );
+
+        let binary = Elf::parse(&optimised).unwrap();
+        let functions = binary
+            .syms
+            .iter()
+            .filter(|symbol| symbol.st_type() == STT_FUNC)
+            .map(|symbol| {
+                let name = binary.strtab.get_at(symbol.st_name).unwrap();
+                (name, symbol.st_value)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(functions, [("helper", 0x20), ("main", 0)]);
+
+        let text = extract_section(".text", &optimised).unwrap();
+        assert_eq!(text.len(), 48);
+        // The string moved to the start of the section and the call skips
+        // one instruction less.
+        assert_eq!({ Lddw::from(&text[..16]).immediate_l }, 0);
+        assert_eq!({ Call::from(&text[16..24]).immediate }, 1);
+        let strings = extract_section(".rodata.str1.1", &optimised).unwrap();
+        assert_eq!(strings, b"used\0");
+
+        // The name of the removed function isn't appended to .rodata.
+        let assembled = ExtendedBinary::parse(&assemble_binary(&optimised).unwrap()).unwrap();
+        assert_eq!(assembled.function_names().unwrap(), ["main"]);
+    }
+
+    #[test]
+    fn data_referenced_from_writable_sections_is_kept() {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let rodata_index = writer.add_section(
+            ".rodata",
+            SHT_PROGBITS,
+            SHF_ALLOC as u64,
+            8,
+            0,
+            (0..24).collect(),
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![0; 8],
+        );
+        let rodata =
+            writer.add_symbol(symbol("", STB_LOCAL << 4 | STT_SECTION, rodata_index, 0, 0));
+        for (index, name) in ["first", "second", "third"].iter().enumerate() {
+            let info = STB_LOCAL << 4 | STT_OBJECT;
+            writer.add_symbol(symbol(name, info, rodata_index, index as u64 * 8, 8));
+        }
+        writer.add_symbol(symbol(
+            "main",
+            STB_GLOBAL << 4 | STT_FUNC,
+            text_index,
+            0,
+            24,
+        ));
+        let relocation = |offset, relocation_type, addend| ElfRelocation {
+            offset,
+            symbol_index: rodata,
+            relocation_type,
+            addend,
+        };
+        writer.add_relocations(
+            ".rela.text",
+            text_index,
+            alloc::vec![relocation(0, R_BPF_64_64, 8)],
+            true,
+        );
+        writer.add_relocations(
+            ".rela.data",
+            data_index,
+            alloc::vec![relocation(0, R_BPF_64_ABS64, 16)],
+            true,
+        );
+
+        let (optimised, report) = eliminate_dead_code(&writer.write(), &[]).unwrap();
+        assert_eq!(report.removed_rodata_bytes, 8);
+        let rodata = extract_section(".rodata", &optimised).unwrap();
+        assert_eq!(rodata, (8..24).collect::<Vec<u8>>());
+
+        let binary = Elf::parse(&optimised).unwrap();
+        let addends = binary
+            .shdr_relocs
+            .iter()
+            .flat_map(|(_, relocations)| relocations.iter())
+            .map(|relocation| relocation.r_addend)
+            .collect::<Vec<_>>();
+        assert_eq!(addends, [Some(0), Some(8)]);
+    }
+}
@@ -0,0 +1,540 @@
+//! Disassembler for the eBPF bytecode produced by the different post-processing
+//! workflows supported by this crate.
+//!
+//! Apart from the standard eBPF ISA used by rbpf, it understands the custom
+//! load instructions introduced by Femto-Containers (see [`FC_LDDWD_OPCODE`]
+//! and [`FC_LDDWR_OPCODE`]) and the `call` instructions with the src register
+//! set to 3 which are produced when resolving relocations on the device. The
+//! output follows the mnemonics used by the rbpf disassembler, with jump targets
+//! and function calls annotated using the names of the functions if they are
+//! available.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+use goblin::elf64::sym::STT_FUNC;
+use micro_bpf_common::BinaryFileLayout;
+
+use crate::{
+    common::{
+        find_relocations, get_section_header, get_section_index, parse_elf, section_bytes,
+        R_BPF_64_32, R_BPF_64_64,
+    },
+    error::ElfUtilsError,
+    extended_relocations::ExtendedBinary,
+    femtocontainer_relocations::FCBinary,
+    model::{
+        decode_instructions, ImageSection, Instruction, PatchKind, BPF_ABS, BPF_ALU, BPF_ALU64,
+        BPF_B, BPF_DW, BPF_END, BPF_H, BPF_IND, BPF_JA, BPF_JMP, BPF_JMP32, BPF_LD, BPF_LDX,
+        BPF_MEM, BPF_NEG, BPF_ST, BPF_STX, BPF_W, BPF_X, BPF_XADD, CALL_SRC_ABSOLUTE,
+        CALL_SRC_PC_RELATIVE, FC_LDDWD_OPCODE, FC_LDDWR_OPCODE,
+    },
+    pre_resolved_relocations::PreResolvedBinary,
+};
+
+/// Names of the functions defined in the program together with the metadata
+/// about the non-PC-relative calls between them. It is used for annotating
+/// the disassembled instructions.
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    functions: BTreeMap<usize, String>,
+    relocated_calls: BTreeMap<usize, usize>,
+    external_calls: BTreeSet<usize>,
+    data_references: BTreeMap<usize, String>,
+}
+
+impl SymbolMap {
+    /// Creates an empty symbol map, the instructions will be disassembled
+    /// without any annotations.
+    pub fn new() -> Self {
+        SymbolMap::default()
+    }
+
+    /// Registers a function starting at a given offset in the .text section.
+    pub fn add_function(&mut self, text_offset: usize, name: &str) {
+        self.functions.insert(text_offset, name.to_string());
+    }
+
+    /// Registers a `call -1` instruction which is to be replaced with a call
+    /// to the function at a given offset, see [`crate::model::RelocatedCall`].
+    pub fn add_relocated_call(&mut self, instruction_offset: usize, function_text_offset: usize) {
+        self.relocated_calls
+            .insert(instruction_offset, function_text_offset);
+    }
+
+    /// Registers a `lddw` instruction which loads the address of a location
+    /// in the section with a given name once its relocation is applied.
+    pub fn add_data_reference(&mut self, instruction_offset: usize, section_name: &str) {
+        self.data_references
+            .insert(instruction_offset, section_name.to_string());
+    }
+
+    /// Extracts the names of the functions, the function call relocations and
+    /// the sections referenced by the `lddw` relocations from the ELF file.
+    pub fn from_elf(program: &[u8]) -> Result<SymbolMap, ElfUtilsError> {
+        let binary = parse_elf(program)?;
+        let text_index = get_section_index(".text", &binary)?;
+
+        let mut symbols = SymbolMap::new();
+        for symbol in binary.syms.iter() {
+            if symbol.st_type() == STT_FUNC && symbol.st_shndx == text_index {
+                if let Some(name) = binary.strtab.get_at(symbol.st_name) {
+                    symbols.add_function(symbol.st_value as usize, name);
+                }
+            }
+        }
+
+        for relocation in find_relocations(&binary)? {
+            if relocation.section_index != text_index {
+                continue;
+            }
+            let reloc = relocation.reloc;
+            if let Some(symbol) = binary.syms.get(reloc.r_sym) {
+                if reloc.r_type == R_BPF_64_32 && symbol.st_shndx != text_index {
+                    // The called function is placed outside of .text (e.g. when
+                    // compiling with -ffunction-sections) or it is an extern
+                    // helper, so its offset in .text isn't known.
+                    symbols.external_calls.insert(reloc.r_offset as usize);
+                } else if symbol.st_type() == STT_FUNC && symbol.st_shndx == text_index {
+                    symbols.add_relocated_call(reloc.r_offset as usize, symbol.st_value as usize);
+                } else if reloc.r_type == R_BPF_64_64 {
+                    let section_name = binary
+                        .section_headers
+                        .get(symbol.st_shndx)
+                        .and_then(|section| binary.shdr_strtab.get_at(section.sh_name));
+                    if let Some(section_name) = section_name {
+                        symbols.add_data_reference(reloc.r_offset as usize, section_name);
+                    }
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Offsets of the starts of all registered functions in ascending order.
+    pub fn function_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.functions.keys().copied()
+    }
+
+    /// Returns the name of the function containing the given offset, i.e. the
+    /// closest function starting at or before the offset.
+    pub fn function_containing(&self, offset: usize) -> Option<&str> {
+        let (_, name) = self.functions.range(..=offset).next_back()?;
+        Some(name)
+    }
+
+    /// Returns the offset of the function called by the relocated `call -1`
+    /// instruction at the given offset.
+    pub fn relocated_call(&self, instruction_offset: usize) -> Option<usize> {
+        self.relocated_calls.get(&instruction_offset).copied()
+    }
+
+    /// Returns the name of the section whose address is loaded by the `lddw`
+    /// instruction at the given offset.
+    pub fn data_reference(&self, instruction_offset: usize) -> Option<&str> {
+        self.data_references
+            .get(&instruction_offset)
+            .map(String::as_str)
+    }
+
+    /// Offset of the function called by the instruction if it is a call to
+    /// a function of the program whose target is known in the .text section.
+    pub fn called_function(&self, instruction: &Instruction) -> Option<i64> {
+        if !instruction.is_call() {
+            return None;
+        }
+        if let Some(function) = self.relocated_call(instruction.offset) {
+            return Some(function as i64);
+        }
+        if self.external_calls.contains(&instruction.offset) {
+            return None;
+        }
+        // Calls to functions which haven't been relocated yet point to themselves.
+        if instruction.src != CALL_SRC_PC_RELATIVE || instruction.imm == -1 {
+            return None;
+        }
+        instruction.call_target()
+    }
+
+    /// Describes the given offset in the .text section relative to the closest
+    /// preceding function, e.g. `<main+0x10>`.
+    fn describe(&self, offset: usize) -> Option<String> {
+        let (start, name) = self.functions.range(..=offset).next_back()?;
+        if *start == offset {
+            Some(format!("<{}>", name))
+        } else {
+            Some(format!("<{}+{:#x}>", name, offset - start))
+        }
+    }
+}
+
+/// Disassembles the bytecode contained in a binary with a given layout.
+///
+/// For the layouts which preserve the function symbols (all except
+/// [`BinaryFileLayout::OnlyTextSection`] and
+/// [`BinaryFileLayout::PreResolvedRelocations`]), the output is annotated with
+/// the names of the functions.
+pub fn disassemble_binary(
+    program: &[u8],
+    layout: BinaryFileLayout,
+) -> Result<String, ElfUtilsError> {
+    let (text, symbols) = text_with_symbols(program, layout)?;
+    disassemble(&text, &symbols)
+}
+
+/// Extracts the bytecode of a binary with a given layout together with the
+/// metadata about the functions that the layout preserves.
+pub fn text_with_symbols(
+    program: &[u8],
+    layout: BinaryFileLayout,
+) -> Result<(Vec<u8>, SymbolMap), ElfUtilsError> {
+    match layout {
+        BinaryFileLayout::OnlyTextSection => Ok((program.to_vec(), SymbolMap::new())),
+        BinaryFileLayout::FemtoContainersHeader => {
+            let binary = FCBinary::parse(program)?;
+            let mut symbols = SymbolMap::new();
+            for (function, name) in binary.functions.iter().zip(binary.function_names()?) {
+                symbols.add_function(function.location_offset as usize, &name);
+            }
+            Ok((binary.text, symbols))
+        }
+        BinaryFileLayout::ExtendedHeader => {
+            let binary = ExtendedBinary::parse(program)?;
+            let mut symbols = SymbolMap::new();
+            for (function, name) in binary.functions.iter().zip(binary.function_names()?) {
+                symbols.add_function(function.location_offset as usize, &name);
+            }
+            for call in &binary.relocated_calls {
+                symbols.add_relocated_call(
+                    call.instruction_offset as usize,
+                    call.function_text_offset as usize,
+                );
+            }
+            Ok((binary.text, symbols))
+        }
+        BinaryFileLayout::RawObjectFile => {
+            let symbols = SymbolMap::from_elf(program)?;
+            let binary = parse_elf(program)?;
+            let text_section = get_section_header(".text", &binary)?;
+            Ok((section_bytes(text_section, program)?.to_vec(), symbols))
+        }
+        BinaryFileLayout::PreResolvedRelocations => {
+            // The function names aren't preserved, but the targets of the calls
+            // and the sections referenced by the loads can be recovered from
+            // the relocation table.
+            let binary = PreResolvedBinary::parse(program)?;
+            let text_range = binary.header.section_range(ImageSection::Text);
+            let mut symbols = SymbolMap::new();
+            for relocation in &binary.relocations {
+                let instruction_offset =
+                    (relocation.offset as usize).wrapping_sub(text_range.start);
+                match (
+                    PatchKind::try_from(relocation.kind)?,
+                    ImageSection::try_from(relocation.section)?,
+                ) {
+                    (PatchKind::Call, ImageSection::Text) => {
+                        symbols.add_relocated_call(instruction_offset, relocation.addend as usize)
+                    }
+                    (PatchKind::Lddw, ImageSection::Data) => {
+                        symbols.add_data_reference(instruction_offset, ".data")
+                    }
+                    (PatchKind::Lddw, ImageSection::Rodata) => {
+                        symbols.add_data_reference(instruction_offset, ".rodata")
+                    }
+                    _ => {}
+                }
+            }
+            Ok((binary.text, symbols))
+        }
+    }
+}
+
+/// Disassembles the bytecode of the program. Each line of the output contains
+/// the offset of the instruction in bytes, its raw bytes and the decoded
+/// instruction. The start of each function is labeled with its name.
+pub fn disassemble(text: &[u8], symbols: &SymbolMap) -> Result<String, ElfUtilsError> {
+    let instructions = decode_instructions(text)?;
+    let mut output = String::new();
+
+    for instruction in &instructions {
+        if let Some(name) = symbols.functions.get(&instruction.offset) {
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            let _ = writeln!(output, "<{}>:", name);
+        }
+
+        let bytes = &text[instruction.offset..instruction.offset + instruction.size()];
+        let _ = write!(
+            output,
+            "{:>6x}:  {}  {}",
+            instruction.offset,
+            format_bytes(&bytes[..8]),
+            format_instruction(instruction)
+        );
+        if let Some(annotation) = annotate(instruction, symbols) {
+            let _ = write!(output, " {}", annotation);
+        }
+        output.push('\n');
+
+        // The second slot of the 16-byte instructions is printed on its own line.
+        if instruction.is_wide() {
+            let _ = writeln!(
+                output,
+                "{:>6x}:  {}",
+                instruction.offset + 8,
+                format_bytes(&bytes[8..])
+            );
+        }
+    }
+
+    Ok(output)
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn annotate(instruction: &Instruction, symbols: &SymbolMap) -> Option<String> {
+    match instruction.opcode as u32 {
+        FC_LDDWD_OPCODE => return Some(format!("; .data+{:#x}", instruction.imm)),
+        FC_LDDWR_OPCODE => return Some(format!("; .rodata+{:#x}", instruction.imm)),
+        _ => {}
+    }
+
+    if instruction.is_call() {
+        if let Some(function) = symbols.relocated_calls.get(&instruction.offset) {
+            return symbols.describe(*function);
+        }
+        // Calls to functions which haven't been relocated yet point to themselves.
+        if instruction.imm == -1 {
+            return None;
+        }
+        let target = instruction.call_target()?;
+        return symbols.describe(usize::try_from(target).ok()?);
+    }
+
+    let target = instruction.jump_target()?;
+    symbols.describe(usize::try_from(target).ok()?)
+}
+
+/// Formats a single instruction using the mnemonics of the rbpf disassembler.
+pub fn format_instruction(instruction: &Instruction) -> String {
+    let insn = instruction;
+    match insn.opcode as u32 {
+        FC_LDDWD_OPCODE => return format!("lddwd r{}, {:#x}", insn.dst, insn.imm),
+        FC_LDDWR_OPCODE => return format!("lddwr r{}, {:#x}", insn.dst, insn.imm),
+        _ => {}
+    }
+
+    match insn.class() {
+        BPF_LD => format_load(insn),
+        BPF_LDX => {
+            if insn.opcode & 0xe0 != BPF_MEM {
+                return invalid(insn);
+            }
+            format!(
+                "ldx{} r{}, [r{}{}]",
+                size_suffix(insn.opcode),
+                insn.dst,
+                insn.src,
+                format_offset(insn.off)
+            )
+        }
+        BPF_ST => {
+            if insn.opcode & 0xe0 != BPF_MEM {
+                return invalid(insn);
+            }
+            format!(
+                "st{} [r{}{}], {:#x}",
+                size_suffix(insn.opcode),
+                insn.dst,
+                format_offset(insn.off),
+                insn.imm
+            )
+        }
+        BPF_STX => {
+            let name = match insn.opcode & 0xe0 {
+                BPF_MEM => "stx",
+                BPF_XADD if matches!(insn.opcode & 0x18, BPF_W | BPF_DW) => "stxxadd",
+                _ => return invalid(insn),
+            };
+            format!(
+                "{}{} [r{}{}], r{}",
+                name,
+                size_suffix(insn.opcode),
+                insn.dst,
+                format_offset(insn.off),
+                insn.src
+            )
+        }
+        BPF_ALU | BPF_ALU64 => format_alu(insn),
+        BPF_JMP | BPF_JMP32 => format_jump(insn),
+        _ => invalid(insn),
+    }
+}
+
+fn format_load(insn: &Instruction) -> String {
+    let size = size_suffix(insn.opcode);
+    match insn.opcode & 0xe0 {
+        _ if insn.is_wide() => format!("lddw r{}, {:#x}", insn.dst, insn.wide_immediate()),
+        BPF_ABS => format!("ldabs{} {:#x}", size, insn.imm),
+        BPF_IND => format!("ldind{} r{}, {:#x}", size, insn.src, insn.imm),
+        _ => invalid(insn),
+    }
+}
+
+fn format_alu(insn: &Instruction) -> String {
+    let width = if insn.class() == BPF_ALU64 { 64 } else { 32 };
+    let name = match insn.opcode & 0xf0 {
+        0x00 => "add",
+        0x10 => "sub",
+        0x20 => "mul",
+        0x30 => "div",
+        0x40 => "or",
+        0x50 => "and",
+        0x60 => "lsh",
+        0x70 => "rsh",
+        BPF_NEG => return format!("neg{} r{}", width, insn.dst),
+        0x90 => "mod",
+        0xa0 => "xor",
+        0xb0 => "mov",
+        0xc0 => "arsh",
+        BPF_END => {
+            if insn.class() == BPF_ALU64 || !matches!(insn.imm, 16 | 32 | 64) {
+                return invalid(insn);
+            }
+            let order = if insn.opcode & BPF_X == 0 { "le" } else { "be" };
+            return format!("{}{} r{}", order, insn.imm, insn.dst);
+        }
+        _ => return invalid(insn),
+    };
+
+    if insn.opcode & BPF_X == 0 {
+        format!("{}{} r{}, {:#x}", name, width, insn.dst, insn.imm)
+    } else {
+        format!("{}{} r{}, r{}", name, width, insn.dst, insn.src)
+    }
+}
+
+fn format_jump(insn: &Instruction) -> String {
+    if insn.class() == BPF_JMP {
+        match insn.opcode {
+            0x05 => return format!("ja {}", format_offset(insn.off)),
+            0x85 => {
+                return match insn.src {
+                    CALL_SRC_PC_RELATIVE => format!("call {}", format_offset_i32(insn.imm)),
+                    CALL_SRC_ABSOLUTE => format!("call abs {:#x}", insn.imm as u32),
+                    _ => format!("call {:#x}", insn.imm),
+                }
+            }
+            0x8d => return "tail_call".to_string(),
+            0x95 => return "exit".to_string(),
+            _ => {}
+        }
+    }
+
+    let suffix = if insn.class() == BPF_JMP32 { "32" } else { "" };
+    let name = match insn.opcode & 0xf0 {
+        BPF_JA => return invalid(insn),
+        0x10 => "jeq",
+        0x20 => "jgt",
+        0x30 => "jge",
+        0x40 => "jset",
+        0x50 => "jne",
+        0x60 => "jsgt",
+        0x70 => "jsge",
+        0xa0 => "jlt",
+        0xb0 => "jle",
+        0xc0 => "jslt",
+        0xd0 => "jsle",
+        _ => return invalid(insn),
+    };
+
+    if insn.opcode & BPF_X == 0 {
+        format!(
+            "{}{} r{}, {:#x}, {}",
+            name,
+            suffix,
+            insn.dst,
+            insn.imm,
+            format_offset(insn.off)
+        )
+    } else {
+        format!(
+            "{}{} r{}, r{}, {}",
+            name,
+            suffix,
+            insn.dst,
+            insn.src,
+            format_offset(insn.off)
+        )
+    }
+}
+
+fn size_suffix(opcode: u8) -> &'static str {
+    match opcode & 0x18 {
+        BPF_W => "w",
+        BPF_H => "h",
+        BPF_B => "b",
+        _ => "dw",
+    }
+}
+
+fn format_offset(offset: i16) -> String {
+    format_offset_i32(offset as i32)
+}
+
+fn format_offset_i32(offset: i32) -> String {
+    if offset < 0 {
+        format!("-{:#x}", -(offset as i64))
+    } else {
+        format!("+{:#x}", offset)
+    }
+}
+
+fn invalid(insn: &Instruction) -> String {
+    format!("<invalid opcode {:#04x}>", insn.opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_custom_instructions() {
+        let text: Vec<u8> = alloc::vec![
+            0xb8, 0x01, 0, 0, 0x10, 0, 0, 0, // lddwd r1, 0x10
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0xd8, 0x02, 0, 0, 0, 0, 0, 0, // lddwr r2, 0x0
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x30, 0, 0, 0x78, 0x56, 0x34, 0x12, // call abs 0x12345678
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, // call -0x1
+            0x15, 0x01, 0x01, 0, 0, 0, 0, 0, // jeq r1, 0x0, +0x1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        symbols.add_function(0x40, "callee");
+        symbols.add_relocated_call(0x28, 0x40);
+
+        let output = disassemble(&text, &symbols).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "<main>:");
+        assert!(lines[1].ends_with("lddwd r1, 0x10 ; .data+0x10"));
+        assert!(lines[3].ends_with("lddwr r2, 0x0 ; .rodata+0x0"));
+        assert!(lines[5].ends_with("call abs 0x12345678"));
+        assert!(lines[6].ends_with("call -0x1 <callee>"));
+        assert!(lines[7].ends_with("jeq r1, 0x0, +0x1 <callee>"));
+        assert_eq!(lines[10], "<callee>:");
+    }
+}
@@ -0,0 +1,400 @@
+//! Minimal writer of relocatable ELF64 object files for the BPF target.
+//!
+//! It is used for producing object files after some of their sections have
+//! been removed or rearranged, e.g. by the [`crate::strip_binary`] function.
+//! The writer takes care of laying out the sections in the file and generating
+//! the symbol table, the string table and the section header table, so that
+//! the callers only need to specify the contents of the sections.
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use goblin::elf::{
+    header::{ELFCLASS64, ELFDATA2LSB, EM_BPF, ET_REL, EV_CURRENT},
+    section_header::{SHT_NOBITS, SHT_REL, SHT_RELA, SHT_STRTAB, SHT_SYMTAB},
+    sym::STB_LOCAL,
+};
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+const REL_ENTRY_SIZE: usize = 16;
+const RELA_ENTRY_SIZE: usize = 24;
+
+/// A symbol that is written into the symbol table of the output file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfSymbol {
+    /// Name of the symbol, empty for section symbols
+    pub name: String,
+    /// Type and binding of the symbol, as in `st_info`
+    pub info: u8,
+    /// Visibility of the symbol, as in `st_other`
+    pub other: u8,
+    /// Index of the section that the symbol is defined in, it needs to refer
+    /// to the indices returned by [`ElfWriter::add_section`] or be one of the
+    /// special indices such as `SHN_UNDEF` or `SHN_ABS`
+    pub section_index: u16,
+    /// Value of the symbol, for relocatable files it is the offset within its section
+    pub value: u64,
+    /// Size of the object or function that the symbol refers to
+    pub size: u64,
+}
+
+/// A relocation entry written into one of the relocation sections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfRelocation {
+    /// Offset of the relocated location within the target section
+    pub offset: u64,
+    /// Index of the symbol returned by [`ElfWriter::add_symbol`]
+    pub symbol_index: u32,
+    /// Type of the relocation, e.g. `R_BPF_64_64`
+    pub relocation_type: u32,
+    /// Addend of the relocation, only written into `SHT_RELA` sections
+    pub addend: i64,
+}
+
+#[derive(Debug)]
+enum SectionContents {
+    Data(Vec<u8>),
+    NoBits(u64),
+    Relocations {
+        target: usize,
+        relocations: Vec<ElfRelocation>,
+        explicit_addends: bool,
+    },
+}
+
+#[derive(Debug)]
+struct OutputSection {
+    name: String,
+    sh_type: u32,
+    flags: u64,
+    alignment: u64,
+    entry_size: u64,
+    contents: SectionContents,
+}
+
+/// Builder of relocatable ELF64 little-endian files for the BPF target.
+///
+/// The section with index 0 is the null section, the sections added using
+/// [`ElfWriter::add_section`] get subsequent indices and the `.symtab` and
+/// `.strtab` sections are appended after them. A single string table is used
+/// for both the section and the symbol names, which is consistent with the
+/// object files produced by LLVM.
+///
+/// Unlike in the files produced by LLVM, the section header table, the symbol
+/// table and the string table precede the contents of the sections, which are
+/// written in the order in which they were added. If the relocation sections
+/// are added after the sections they apply to, the relocations can be resolved
+/// while the file is being received by the device (see
+/// [`crate::StreamingResolver`]).
+#[derive(Debug, Default)]
+pub struct ElfWriter {
+    sections: Vec<OutputSection>,
+    symbols: Vec<ElfSymbol>,
+}
+
+impl ElfWriter {
+    /// Creates a writer of an empty object file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a section with the given contents and returns its index. The
+    /// `entry_size` is only relevant for sections containing a table of
+    /// fixed-size entries (e.g. mergeable strings), otherwise it should be 0.
+    pub fn add_section(
+        &mut self,
+        name: &str,
+        sh_type: u32,
+        flags: u64,
+        alignment: u64,
+        entry_size: u64,
+        data: Vec<u8>,
+    ) -> usize {
+        self.sections.push(OutputSection {
+            name: name.to_string(),
+            sh_type,
+            flags,
+            alignment,
+            entry_size,
+            contents: SectionContents::Data(data),
+        });
+        self.sections.len()
+    }
+
+    /// Adds a `SHT_NOBITS` section (e.g. `.bss`) which doesn't occupy any
+    /// space in the file and returns its index.
+    pub fn add_nobits_section(
+        &mut self,
+        name: &str,
+        flags: u64,
+        alignment: u64,
+        size: u64,
+    ) -> usize {
+        self.sections.push(OutputSection {
+            name: name.to_string(),
+            sh_type: SHT_NOBITS,
+            flags,
+            alignment,
+            entry_size: 0,
+            contents: SectionContents::NoBits(size),
+        });
+        self.sections.len()
+    }
+
+    /// Adds a relocation section applying to the section with index `target`.
+    /// If `explicit_addends` is set, the section is written as `SHT_RELA`,
+    /// otherwise the addends are ignored and a `SHT_REL` section is produced.
+    pub fn add_relocations(
+        &mut self,
+        name: &str,
+        target: usize,
+        relocations: Vec<ElfRelocation>,
+        explicit_addends: bool,
+    ) -> usize {
+        let (sh_type, entry_size) = if explicit_addends {
+            (SHT_RELA, RELA_ENTRY_SIZE)
+        } else {
+            (SHT_REL, REL_ENTRY_SIZE)
+        };
+        self.sections.push(OutputSection {
+            name: name.to_string(),
+            sh_type,
+            flags: 0,
+            alignment: 8,
+            entry_size: entry_size as u64,
+            contents: SectionContents::Relocations {
+                target,
+                relocations,
+                explicit_addends,
+            },
+        });
+        self.sections.len()
+    }
+
+    /// Adds a symbol to the symbol table and returns its index. All local
+    /// symbols need to be added before the global ones, as required by the
+    /// ELF specification.
+    pub fn add_symbol(&mut self, symbol: ElfSymbol) -> u32 {
+        self.symbols.push(symbol);
+        self.symbols.len() as u32
+    }
+
+    /// Index that the `.symtab` section will have in the output file.
+    pub fn symtab_index(&self) -> usize {
+        self.sections.len() + 1
+    }
+
+    /// Encodes the object file.
+    pub fn write(&self) -> Vec<u8> {
+        let symtab_index = self.symtab_index();
+        let strtab_index = symtab_index + 1;
+
+        let mut strtab = StringTable::new();
+        let section_names = self
+            .sections
+            .iter()
+            .map(|section| strtab.add(&section.name))
+            .collect::<Vec<u32>>();
+        let symtab_name = strtab.add(".symtab");
+        let strtab_name = strtab.add(".strtab");
+
+        let mut symtab = alloc::vec![0; SYMBOL_ENTRY_SIZE];
+        for symbol in &self.symbols {
+            let name = strtab.add(&symbol.name);
+            symtab.extend(name.to_le_bytes());
+            symtab.push(symbol.info);
+            symtab.push(symbol.other);
+            symtab.extend(symbol.section_index.to_le_bytes());
+            symtab.extend(symbol.value.to_le_bytes());
+            symtab.extend(symbol.size.to_le_bytes());
+        }
+        let first_global = 1 + self
+            .symbols
+            .iter()
+            .take_while(|symbol| symbol.info >> 4 == STB_LOCAL)
+            .count();
+
+        // The section header table and the symbol and string tables are placed
+        // right after the ELF header so that a file which is being received in
+        // chunks can be relocated before all of its sections arrive.
+        let section_count = strtab_index + 1;
+        let mut file = alloc::vec![0; ELF_HEADER_SIZE + section_count * SECTION_HEADER_SIZE];
+        let symtab_offset = append_aligned(&mut file, &symtab, 8);
+        let strtab_offset = append_aligned(&mut file, &strtab.bytes, 1);
+
+        let mut headers = alloc::vec![0; SECTION_HEADER_SIZE];
+        for (section, name) in self.sections.iter().zip(section_names) {
+            let (data, size, link, info) = match &section.contents {
+                SectionContents::Data(data) => (data.clone(), data.len() as u64, 0, 0),
+                SectionContents::NoBits(size) => (Vec::new(), *size, 0, 0),
+                SectionContents::Relocations {
+                    target,
+                    relocations,
+                    explicit_addends,
+                } => {
+                    let data = encode_relocations(relocations, *explicit_addends);
+                    let size = data.len() as u64;
+                    (data, size, symtab_index as u32, *target as u32)
+                }
+            };
+            let entry_size = section.entry_size;
+            let offset = append_aligned(&mut file, &data, section.alignment);
+            headers.extend(encode_section_header(&SectionHeaderFields {
+                name,
+                sh_type: section.sh_type,
+                flags: section.flags,
+                offset: offset as u64,
+                size,
+                link,
+                info,
+                alignment: section.alignment,
+                entry_size,
+            }));
+        }
+
+        headers.extend(encode_section_header(&SectionHeaderFields {
+            name: symtab_name,
+            sh_type: SHT_SYMTAB,
+            flags: 0,
+            offset: symtab_offset as u64,
+            size: symtab.len() as u64,
+            link: strtab_index as u32,
+            info: first_global as u32,
+            alignment: 8,
+            entry_size: SYMBOL_ENTRY_SIZE as u64,
+        }));
+        headers.extend(encode_section_header(&SectionHeaderFields {
+            name: strtab_name,
+            sh_type: SHT_STRTAB,
+            flags: 0,
+            offset: strtab_offset as u64,
+            size: strtab.bytes.len() as u64,
+            link: 0,
+            info: 0,
+            alignment: 1,
+            entry_size: 0,
+        }));
+
+        file[ELF_HEADER_SIZE..ELF_HEADER_SIZE + headers.len()].copy_from_slice(&headers);
+        file[..ELF_HEADER_SIZE].copy_from_slice(&encode_elf_header(
+            ELF_HEADER_SIZE as u64,
+            section_count as u16,
+            strtab_index as u16,
+        ));
+        file
+    }
+}
+
+/// String table which reuses the strings that were already added to it.
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable {
+            bytes: alloc::vec![0],
+        }
+    }
+
+    fn add(&mut self, string: &str) -> u32 {
+        if string.is_empty() {
+            return 0;
+        }
+        let mut needle = Vec::from(string.as_bytes());
+        needle.push(0);
+        if let Some(position) = self
+            .bytes
+            .windows(needle.len())
+            .position(|window| window == needle.as_slice())
+        {
+            return position as u32;
+        }
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(needle);
+        offset
+    }
+}
+
+struct SectionHeaderFields {
+    name: u32,
+    sh_type: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: u64,
+}
+
+fn encode_section_header(fields: &SectionHeaderFields) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SECTION_HEADER_SIZE);
+    header.extend(fields.name.to_le_bytes());
+    header.extend(fields.sh_type.to_le_bytes());
+    header.extend(fields.flags.to_le_bytes());
+    // Relocatable files don't specify the virtual address of the sections
+    header.extend(0u64.to_le_bytes());
+    header.extend(fields.offset.to_le_bytes());
+    header.extend(fields.size.to_le_bytes());
+    header.extend(fields.link.to_le_bytes());
+    header.extend(fields.info.to_le_bytes());
+    header.extend(fields.alignment.to_le_bytes());
+    header.extend(fields.entry_size.to_le_bytes());
+    header
+}
+
+fn encode_elf_header(
+    section_headers_offset: u64,
+    section_count: u16,
+    strtab_index: u16,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE);
+    header.extend(b"\x7fELF");
+    header.push(ELFCLASS64);
+    header.push(ELFDATA2LSB);
+    header.push(EV_CURRENT);
+    header.extend([0; 9]);
+    header.extend(ET_REL.to_le_bytes());
+    header.extend(EM_BPF.to_le_bytes());
+    header.extend((EV_CURRENT as u32).to_le_bytes());
+    // Entry point and program headers aren't used by relocatable files
+    header.extend(0u64.to_le_bytes());
+    header.extend(0u64.to_le_bytes());
+    header.extend(section_headers_offset.to_le_bytes());
+    // Flags
+    header.extend(0u32.to_le_bytes());
+    header.extend((ELF_HEADER_SIZE as u16).to_le_bytes());
+    // Size and number of program headers
+    header.extend(0u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    header.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+    header.extend(section_count.to_le_bytes());
+    header.extend(strtab_index.to_le_bytes());
+    header
+}
+
+fn encode_relocations(relocations: &[ElfRelocation], explicit_addends: bool) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for relocation in relocations {
+        let info = ((relocation.symbol_index as u64) << 32) | relocation.relocation_type as u64;
+        bytes.extend(relocation.offset.to_le_bytes());
+        bytes.extend(info.to_le_bytes());
+        if explicit_addends {
+            bytes.extend(relocation.addend.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// Appends the data to the file respecting the required alignment and returns
+/// the offset at which it was placed.
+fn append_aligned(file: &mut Vec<u8>, data: &[u8], alignment: u64) -> usize {
+    let alignment = alignment.max(1) as usize;
+    file.resize(file.len().next_multiple_of(alignment), 0);
+    let offset = file.len();
+    file.extend(data);
+    offset
+}
@@ -0,0 +1,415 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+/// Errors returned by the functions of this crate.
+///
+/// None of the functions processing the object files or the binaries produced
+/// from them panic on malformed input, instead they return one of the below
+/// errors. The [`fmt::Display`] implementation produces a single line message
+/// that can be printed both by the CLI on the host and in the logs on the device.
+///
+/// Errors encountered while processing a particular relocation are wrapped in
+/// [`ElfUtilsError::WithLocation`], which describes where in the program the
+/// relocation is applied, e.g.:
+///
+/// ```text
+/// No lddw instruction at offset 0x10 (relocation 2, .text+0x10, function main, symbol counter)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfUtilsError {
+    /// The input couldn't be parsed as an ELF file, the message is produced by goblin
+    InvalidElf(String),
+    /// The ELF file is valid but it isn't a 64-bit little-endian eBPF object file
+    UnsupportedElf(&'static str),
+    /// The section with the given name required by the operation is missing
+    SectionNotFound(String),
+    /// A section with the given index is referenced but it doesn't exist
+    InvalidSectionIndex(usize),
+    /// A symbol with the given index is referenced but it doesn't exist
+    InvalidSymbolIndex(usize),
+    /// A range referenced by the input lies outside of the input
+    OutOfBounds {
+        /// Offset of the range
+        offset: u64,
+        /// Length of the range
+        len: u64,
+        /// Size of the input that the range should be contained in
+        size: u64,
+    },
+    /// The input ended before all of the expected data was read
+    UnexpectedEnd {
+        /// Offset at which the data was read
+        offset: usize,
+        /// Number of bytes that were needed
+        needed: usize,
+        /// Number of bytes that were left
+        available: usize,
+    },
+    /// The binary doesn't start with the magic number of the expected layout
+    InvalidMagic {
+        /// Magic number found in the header
+        found: u32,
+        /// Magic number of the expected layout
+        expected: u32,
+    },
+    /// The binary uses a newer version of the layout than the one supported
+    UnsupportedVersion {
+        /// Version found in the header
+        found: u32,
+        /// The latest supported version
+        latest: u32,
+    },
+    /// The header specifies flags of features that aren't supported
+    UnsupportedFlags(u32),
+    /// The checksum stored in the header doesn't match the contents of the binary
+    ChecksumMismatch {
+        /// Checksum stored in the header
+        expected: u32,
+        /// Checksum of the contents of the binary
+        actual: u32,
+    },
+    /// A field of the binary has a value outside of its allowed range
+    InvalidValue {
+        /// Name of the field
+        field: &'static str,
+        /// Value of the field
+        value: u64,
+    },
+    /// The relocation type isn't supported
+    UnsupportedRelocationType {
+        /// ELF relocation type, e.g. `R_BPF_64_64`
+        relocation_type: u32,
+        /// Offset of the relocated location
+        offset: usize,
+    },
+    /// The relocated location doesn't contain the instruction expected for
+    /// the type of the relocation
+    InstructionMismatch {
+        /// Mnemonic of the expected instruction
+        expected: &'static str,
+        /// Offset of the relocated location
+        offset: usize,
+    },
+    /// A relocation refers to a symbol in a section which doesn't occupy any
+    /// space in the object file, e.g. .bss
+    SymbolNotAllocated(String),
+    /// A function call refers to an undefined symbol which doesn't match the
+    /// name of any helper function
+    UnknownHelperSymbol(String),
+    /// A global symbol is defined in more than one of the linked object files
+    DuplicateSymbol {
+        /// Name of the symbol
+        name: String,
+        /// Index of the object file which defines the symbol first
+        first_object: usize,
+    },
+    /// A symbol referenced by one of the linked object files isn't defined in
+    /// any of them and doesn't match the name of any helper function
+    UndefinedSymbol(String),
+    /// A function exported as an entry point isn't a global function of the program
+    UnknownEntryPoint(String),
+    /// The program uses a feature which requires a newer version of the layout
+    RequiresNewerVersion {
+        /// Description of the feature
+        feature: &'static str,
+        /// The first version of the layout that supports the feature
+        version: u32,
+    },
+    /// The program uses a feature which isn't supported
+    Unsupported(String),
+    /// The memory provided for the program is too small
+    InsufficientMemory {
+        /// Number of bytes needed
+        required: usize,
+        /// Number of bytes provided
+        available: usize,
+    },
+    /// The program ended before all of its relocations were applied
+    IncompleteProgram {
+        /// Number of bytes received
+        received: usize,
+    },
+    /// The input is malformed in a way not covered by the other variants
+    Malformed(String),
+    /// The stack usage of the program can exceed the allowed budget
+    StackBudgetExceeded {
+        /// Number of bytes needed in the worst case, `None` if the usage is
+        /// unbounded because of recursion
+        required: Option<usize>,
+        /// Number of bytes allowed
+        budget: usize,
+        /// Names of the functions on the call chain with the highest usage
+        call_chain: Vec<String>,
+    },
+    /// An error together with the location in the program at which it occurred
+    WithLocation {
+        /// Location of the relocation or instruction which caused the error,
+        /// it is boxed to keep the size of the errors small
+        location: Box<ErrorLocation>,
+        /// The underlying error, it is never a [`ElfUtilsError::WithLocation`]
+        error: Box<ElfUtilsError>,
+    },
+}
+
+impl ElfUtilsError {
+    /// Attaches the location at which the error occurred. If the error already
+    /// has a location, only its missing parts are filled in, as the location
+    /// attached closer to the cause of the error is more precise.
+    pub fn at(self, location: ErrorLocation) -> Self {
+        match self {
+            ElfUtilsError::WithLocation {
+                location: existing,
+                error,
+            } => ElfUtilsError::WithLocation {
+                location: Box::new(existing.or(location)),
+                error,
+            },
+            error => ElfUtilsError::WithLocation {
+                location: Box::new(location),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Returns the location at which the error occurred, if it is known.
+    pub fn location(&self) -> Option<&ErrorLocation> {
+        match self {
+            ElfUtilsError::WithLocation { location, .. } => Some(location.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Returns the error without the information about its location, this
+    /// allows for matching on the kind of the error.
+    pub fn without_location(&self) -> &ElfUtilsError {
+        match self {
+            ElfUtilsError::WithLocation { error, .. } => error,
+            error => error,
+        }
+    }
+}
+
+/// Describes where in the program an error occurred. All parts are optional
+/// as not all of them are known in each context, e.g. the names aren't
+/// available when resolving relocations from the table of the
+/// PreResolvedRelocations layout.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorLocation {
+    /// Index of the object file among the files passed to [`crate::link_objects`]
+    pub object: Option<usize>,
+    /// Name of the section containing the location
+    pub section: Option<String>,
+    /// Index of the relocation, counted from the start of its relocation
+    /// section or the relocation table of the binary
+    pub relocation_index: Option<usize>,
+    /// Offset of the relocated instruction or data from the start of the section
+    pub offset: Option<usize>,
+    /// Name of the function containing the relocated instruction
+    pub function: Option<String>,
+    /// Name of the symbol that the relocation refers to
+    pub symbol: Option<String>,
+}
+
+impl ErrorLocation {
+    /// Location of the relocation with the given index, the remaining parts
+    /// can be filled in directly.
+    pub fn relocation(index: usize) -> Self {
+        ErrorLocation {
+            relocation_index: Some(index),
+            ..Default::default()
+        }
+    }
+
+    /// Fills in the parts of the location missing in `self` using `other`.
+    fn or(self, other: ErrorLocation) -> Self {
+        ErrorLocation {
+            object: self.object.or(other.object),
+            section: self.section.or(other.section),
+            relocation_index: self.relocation_index.or(other.relocation_index),
+            offset: self.offset.or(other.offset),
+            function: self.function.or(other.function),
+            symbol: self.symbol.or(other.symbol),
+        }
+    }
+}
+
+impl fmt::Display for ErrorLocation {
+    /// Lists the known parts of the location separated with commas, e.g.
+    /// `relocation 2, .text+0x10, function main, symbol counter`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        let mut part = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments<'_>| {
+            let result = write!(f, "{}{}", separator, args);
+            separator = ", ";
+            result
+        };
+        if let Some(object) = self.object {
+            part(f, format_args!("object {}", object))?;
+        }
+        if let Some(index) = self.relocation_index {
+            part(f, format_args!("relocation {}", index))?;
+        }
+        match (&self.section, self.offset) {
+            (Some(section), Some(offset)) => part(f, format_args!("{}+{:#x}", section, offset))?,
+            (Some(section), None) => part(f, format_args!("section {}", section))?,
+            (None, Some(offset)) => part(f, format_args!("offset {:#x}", offset))?,
+            (None, None) => {}
+        }
+        if let Some(function) = &self.function {
+            part(f, format_args!("function {}", function))?;
+        }
+        if let Some(symbol) = &self.symbol {
+            part(f, format_args!("symbol {}", symbol))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ElfUtilsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfUtilsError::InvalidElf(message) => {
+                write!(f, "Failed to parse the ELF binary: {}", message)
+            }
+            ElfUtilsError::UnsupportedElf(reason) => write!(f, "Unsupported ELF file: {}", reason),
+            ElfUtilsError::SectionNotFound(name) => write!(f, "Section {} not found", name),
+            ElfUtilsError::InvalidSectionIndex(index) => {
+                write!(f, "Section {} doesn't exist", index)
+            }
+            ElfUtilsError::InvalidSymbolIndex(index) => write!(f, "Symbol {} doesn't exist", index),
+            ElfUtilsError::OutOfBounds { offset, len, size } => write!(
+                f,
+                "Range of {} bytes at offset {:#x} is outside of the input of {} bytes",
+                len, offset, size
+            ),
+            ElfUtilsError::UnexpectedEnd {
+                offset,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Unexpected end of binary: tried to read {} bytes at offset {} but only {} are left",
+                needed, offset, available
+            ),
+            ElfUtilsError::InvalidMagic { found, expected } => write!(
+                f,
+                "Invalid header magic number: {}, expected: {}",
+                found, expected
+            ),
+            ElfUtilsError::UnsupportedVersion { found, latest } => write!(
+                f,
+                "Unsupported binary layout version: {}, only versions up to {} are supported",
+                found, latest
+            ),
+            ElfUtilsError::UnsupportedFlags(flags) => {
+                write!(f, "Binary requires unsupported features, flags: {:#x}", flags)
+            }
+            ElfUtilsError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:#010x}, got {:#010x}, the binary is corrupted",
+                expected, actual
+            ),
+            ElfUtilsError::InvalidValue { field, value } => {
+                write!(f, "Invalid {}: {}", field, value)
+            }
+            ElfUtilsError::UnsupportedRelocationType {
+                relocation_type,
+                offset,
+            } => write!(
+                f,
+                "Unsupported relocation type {} at offset {:#x}",
+                relocation_type, offset
+            ),
+            ElfUtilsError::InstructionMismatch { expected, offset } => {
+                write!(f, "No {} instruction at offset {:#x}", expected, offset)
+            }
+            ElfUtilsError::SymbolNotAllocated(name) => write!(
+                f,
+                "Symbol {} is defined in a section which isn't allocated, the object file needs to be stripped first",
+                name
+            ),
+            ElfUtilsError::UnknownHelperSymbol(name) => write!(
+                f,
+                "The program calls the undefined function {} which isn't a known helper",
+                name
+            ),
+            ElfUtilsError::DuplicateSymbol { name, first_object } => write!(
+                f,
+                "Symbol {} is already defined in object {}",
+                name, first_object
+            ),
+            ElfUtilsError::UndefinedSymbol(name) => write!(
+                f,
+                "Symbol {} isn't defined in any of the linked objects",
+                name
+            ),
+            ElfUtilsError::UnknownEntryPoint(name) => write!(
+                f,
+                "The entry point {} isn't a global function of the program",
+                name
+            ),
+            ElfUtilsError::RequiresNewerVersion { feature, version } => write!(
+                f,
+                "The program {}, this requires at least version {} of the binary layout",
+                feature, version
+            ),
+            ElfUtilsError::Unsupported(message) | ElfUtilsError::Malformed(message) => {
+                f.write_str(message)
+            }
+            ElfUtilsError::InsufficientMemory {
+                required,
+                available,
+            } => write!(
+                f,
+                "The program needs {} bytes of memory but only {} were allocated",
+                required, available
+            ),
+            ElfUtilsError::IncompleteProgram { received } => write!(
+                f,
+                "The program is incomplete, not all relocations could be applied after receiving {} bytes",
+                received
+            ),
+            ElfUtilsError::StackBudgetExceeded {
+                required,
+                budget,
+                call_chain,
+            } => {
+                let call_chain = call_chain.join(" -> ");
+                match required {
+                    Some(required) => write!(
+                        f,
+                        "The program needs {} bytes of stack in the call chain {}, which exceeds the budget of {} bytes",
+                        required, call_chain, budget
+                    ),
+                    None => write!(
+                        f,
+                        "The stack usage of the program is unbounded because of the recursive call chain {}",
+                        call_chain
+                    ),
+                }
+            }
+            ElfUtilsError::WithLocation { location, error } => {
+                write!(f, "{} ({})", error, location)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "elf")]
+impl From<goblin::error::Error> for ElfUtilsError {
+    fn from(error: goblin::error::Error) -> Self {
+        ElfUtilsError::InvalidElf(error.to_string())
+    }
+}
+
+// The tools crate reports all errors as strings, this allows for using the `?`
+// operator on the results of this crate in functions returning `Result<_, String>`.
+impl From<ElfUtilsError> for String {
+    fn from(error: ElfUtilsError) -> Self {
+        error.to_string()
+    }
+}
@@ -1,22 +1,33 @@
 use alloc::{
     collections::btree_map::BTreeMap as HashMap,
-    ffi::CString,
     string::{String, ToString},
     vec::Vec,
 };
 use goblin::{
-    elf::{Elf, Reloc},
-    elf64::sym::{STB_GLOBAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+    elf::{Elf, SectionHeader},
+    elf64::{
+        section_header::{
+            SHF_EXECINSTR, SHF_MERGE, SHF_STRINGS, SHT_NOBITS, SHT_PROGBITS, SHT_REL, SHT_RELA,
+        },
+        sym::{STB_GLOBAL, STT_FUNC, STT_SECTION},
+    },
 };
 use log::debug;
+use micro_bpf_common::MAX_ENTRY_POINTS;
 
 use crate::{
     common::{
-        find_relocations, get_section_bytes, get_section_header, round_section_length, Symbol,
-        LDDW_OPCODE,
+        checked_range, crc32, decode_function_names, find_relocations, parse_elf,
+        round_section_length, section_alignment, section_bytes, ByteReader, Relocation, Symbol,
+        CALL_OPCODE, INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE, LDDW_OPCODE,
+        MAX_ZERO_INITIALISED_SIZE, SYMBOL_FLAG_ENTRY_POINT, SYMBOL_SIZE,
+    },
+    error::ElfUtilsError,
+    helpers::with_helper_symbols_resolved,
+    model::{
+        Call, DataRegion, DataRelocation, Lddw, RelocatedCall, DATA_RELOCATION_SIZE,
+        FC_LDDWD_OPCODE, FC_LDDWR_OPCODE, RELOCATED_CALL_SIZE,
     },
-    femtocontainer_relocations::{FC_LDDWD_OPCODE, FC_LDDWR_OPCODE},
-    model::{Lddw, RelocatedCall},
 };
 
 /// The binary generated after the relocation script has the following format:
@@ -27,42 +38,233 @@ use crate::{
 /// - Text section: Contains the code of the main entrypoint and the other functions
 /// - Symbol structs: TODO: figure out why we need this
 /// - Relocated function calls: custom metadata specifying how function calls should be relocated
-struct Binary {
-    header: Header,
-    data: Vec<u8>,
-    rodata: Vec<u8>,
-    text: Vec<u8>,
-    functions: Vec<Symbol>,
-    relocated_calls: Vec<RelocatedCall>,
-    allowed_helpers: Vec<u8>,
+/// - Data relocations: pointers stored in the .data and .rodata sections which
+///   need to be set once the addresses of those sections are known (since version 2)
+/// - Allowed helpers: indices of the helper functions that the program is allowed to call
+///
+/// The .bss section isn't stored in the binary, only its length is specified
+/// in the header (since version 3) so that the VM can allocate it.
+///
+/// Binaries in this layout can be decoded using [`ExtendedBinary::parse`],
+/// converting the parsed struct back into a `Vec<u8>` produces the exact
+/// same sequence of bytes.
+#[derive(Debug)]
+pub struct ExtendedBinary {
+    /// Header specifying the lengths of all remaining sections
+    pub header: Header,
+    /// Contents of the .data section
+    pub data: Vec<u8>,
+    /// Contents of the .rodata section together with the string literals
+    /// and function names that were appended to it
+    pub rodata: Vec<u8>,
+    /// Contents of the .text section
+    pub text: Vec<u8>,
+    /// Symbols of all global functions defined in the program
+    pub functions: Vec<Symbol>,
+    /// Non-PC-relative function calls that need to be resolved by the VM
+    pub relocated_calls: Vec<RelocatedCall>,
+    /// Pointers inside of the .data and .rodata sections that need to be resolved by the VM
+    pub data_relocations: Vec<DataRelocation>,
+    /// Indices of the helper functions that the program is allowed to call
+    pub allowed_helpers: Vec<u8>,
 }
 
-pub const HEADER_SIZE: usize = 32;
-impl Into<Vec<u8>> for Binary {
+/// Magic number at the start of every binary in this layout.
+pub const HEADER_MAGIC: u32 = 123;
+
+// Versions of the binary layout. Each revision of the layout increments the
+// version so that a VM which only understands older revisions can reject
+// binaries produced for newer ones instead of misinterpreting them.
+/// The original layout with a 32-byte header and no checksum.
+pub const HEADER_VERSION_LEGACY: u32 = 0;
+/// Adds the `checksum` field at the end of the header.
+pub const HEADER_VERSION_CHECKSUM: u32 = 1;
+/// Adds the `data_relocations` field to the header and the table of
+/// [`DataRelocation`]s after the relocated calls.
+pub const HEADER_VERSION_DATA_RELOCATIONS: u32 = 2;
+/// Adds the `bss_len` field to the header.
+pub const HEADER_VERSION_BSS: u32 = 3;
+/// Adds the [`HEADER_FLAG_ENTRY_POINTS`] flag, the header itself is unchanged.
+pub const HEADER_VERSION_ENTRY_POINTS: u32 = 4;
+/// The latest version of the layout supported by this crate.
+pub const HEADER_VERSION: u32 = HEADER_VERSION_ENTRY_POINTS;
+/// The version of the layout produced by [`assemble_binary_specifying_helpers`].
+/// It stays at the legacy 32-byte header which is understood by all VMs, so the
+/// binaries have no checksum. Programs with a .bss section or pointers in their
+/// data sections need a newer version, which can be requested using
+/// [`assemble_binary_with_version`].
+pub const DEFAULT_HEADER_VERSION: u32 = HEADER_VERSION_LEGACY;
+
+// Meaning of the bits in the `flags` field of the header. The lower 16 bits
+// are reserved for features which change how the binary needs to be interpreted,
+// a reader must reject binaries with any of those bits set that it doesn't
+// recognise. The upper 16 bits are for optional features which can be safely
+// ignored by readers that don't know about them.
+/// The `checksum` field contains the CRC32 of everything following the header.
+/// Only valid from [`HEADER_VERSION_CHECKSUM`] onwards.
+pub const HEADER_FLAG_CHECKSUM: u32 = 1 << 0;
+/// The program exports named entry points, they are the first entries of the
+/// table of function symbols and are marked with [`SYMBOL_FLAG_ENTRY_POINT`].
+/// The VM starts executing the entry point selected by the execution request
+/// instead of the start of the .text section. Only valid from
+/// [`HEADER_VERSION_ENTRY_POINTS`] onwards.
+pub const HEADER_FLAG_ENTRY_POINTS: u32 = 1 << 1;
+/// Mask of the flags that a reader must understand to interpret the binary.
+pub const HEADER_REQUIRED_FLAGS_MASK: u32 = 0x0000_ffff;
+
+impl Into<Vec<u8>> for ExtendedBinary {
     fn into(self) -> Vec<u8> {
-        let header_bytes = unsafe {
-            alloc::slice::from_raw_parts(&self.header as *const _ as *const u8, HEADER_SIZE)
-        };
-        let mut binary = Vec::from(header_bytes);
-        binary.extend(self.data);
-        binary.extend(self.rodata);
-        binary.extend(self.text);
+        let mut binary = self.header.to_bytes();
+        binary.extend(self.payload());
+        binary
+    }
+}
+
+impl ExtendedBinary {
+    /// Decodes a binary produced by [`assemble_binary_specifying_helpers`].
+    ///
+    /// All section lengths specified in the header are checked against the
+    /// size of the input, so a truncated or malformed binary results in an
+    /// error instead of an out-of-bounds read. Binaries using a newer version
+    /// of the layout than [`HEADER_VERSION`] or requiring unknown features
+    /// are rejected and the checksum is verified if it is present.
+    pub fn parse(program: &[u8]) -> Result<ExtendedBinary, ElfUtilsError> {
+        let mut reader = ByteReader::new(program);
+        let header = Header::read(&mut reader)?;
+
+        if header.flags & HEADER_FLAG_CHECKSUM != 0 {
+            let expected = header.checksum;
+            let actual = crc32(&program[reader.position()..]);
+            if actual != expected {
+                return Err(ElfUtilsError::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        let data = reader.read_bytes(header.data_len as usize)?.to_vec();
+        let rodata = reader.read_bytes(header.rodata_len as usize)?.to_vec();
+        let text = reader.read_bytes(header.text_len as usize)?.to_vec();
+
+        let functions_len = header.functions_len as usize;
+        check_table_fits(&reader, functions_len, SYMBOL_SIZE, "function symbols")?;
+        let functions = (0..functions_len)
+            .map(|_| Symbol::read(&mut reader))
+            .collect::<Result<Vec<Symbol>, ElfUtilsError>>()?;
+
+        let relocated_calls_len = header.relocated_calls as usize;
+        check_table_fits(
+            &reader,
+            relocated_calls_len,
+            RELOCATED_CALL_SIZE,
+            "relocated calls",
+        )?;
+        let relocated_calls = (0..relocated_calls_len)
+            .map(|_| RelocatedCall::read(&mut reader))
+            .collect::<Result<Vec<RelocatedCall>, ElfUtilsError>>()?;
+
+        let data_relocations_len = header.data_relocations as usize;
+        check_table_fits(
+            &reader,
+            data_relocations_len,
+            DATA_RELOCATION_SIZE,
+            "data relocations",
+        )?;
+        let data_relocations = (0..data_relocations_len)
+            .map(|_| DataRelocation::read(&mut reader))
+            .collect::<Result<Vec<DataRelocation>, ElfUtilsError>>()?;
+
+        let allowed_helpers = reader.read_to_end().to_vec();
+
+        Ok(ExtendedBinary {
+            header,
+            data,
+            rodata,
+            text,
+            functions,
+            relocated_calls,
+            data_relocations,
+            allowed_helpers,
+        })
+    }
+
+    /// Returns the names of the functions described by the symbol structs
+    /// in the same order as [`ExtendedBinary::functions`].
+    pub fn function_names(&self) -> Result<Vec<String>, ElfUtilsError> {
+        decode_function_names(&self.rodata, &self.functions)
+    }
+
+    /// Returns the names of the entry points exported by the program in the
+    /// order in which they are selected by the execution request, see
+    /// [`HEADER_FLAG_ENTRY_POINTS`]. The list is empty if the program starts
+    /// at the beginning of the .text section.
+    pub fn entry_points(&self) -> Result<Vec<String>, ElfUtilsError> {
+        if self.header.flags & HEADER_FLAG_ENTRY_POINTS == 0 {
+            return Ok(alloc::vec![]);
+        }
+        let names = self.function_names()?;
+        Ok(self
+            .functions
+            .iter()
+            .zip(names)
+            .take_while(|(function, _)| function.flags & SYMBOL_FLAG_ENTRY_POINT != 0)
+            .map(|(_, name)| name)
+            .collect())
+    }
+
+    /// Recomputes the checksum stored in the header. It needs to be called
+    /// after modifying any of the sections of the binary.
+    pub fn update_checksum(&mut self) {
+        if self.header.flags & HEADER_FLAG_CHECKSUM != 0 {
+            self.header.checksum = crc32(&self.payload());
+        }
+    }
+
+    /// Encodes everything that follows the header.
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend(&self.data);
+        payload.extend(&self.rodata);
+        payload.extend(&self.text);
 
         // For some reason this function symbol metadata is appended at the
         // end of the binary in the FC implementation, however their version
         // of the VM doesn't use any of that information.
-        for symbol in self.functions {
-            let symbol: &[u8] = (&symbol).into();
-            binary.extend(symbol);
+        for symbol in &self.functions {
+            let symbol: &[u8] = symbol.into();
+            payload.extend(symbol);
         }
 
-        for call in self.relocated_calls {
-            let call: &[u8] = (&call).into();
-            binary.extend(call);
+        for call in &self.relocated_calls {
+            let call: &[u8] = call.into();
+            payload.extend(call);
         }
 
-        binary.extend(self.allowed_helpers);
-        binary
+        for relocation in &self.data_relocations {
+            let relocation: &[u8] = relocation.into();
+            payload.extend(relocation);
+        }
+
+        payload.extend(&self.allowed_helpers);
+        payload
+    }
+}
+
+/// Ensures that a table of `count` entries of size `entry_size` fits in the
+/// unread part of the binary. This allows for rejecting corrupted headers
+/// before allocating memory for the decoded entries.
+fn check_table_fits(
+    reader: &ByteReader<'_>,
+    count: usize,
+    entry_size: usize,
+    table_name: &str,
+) -> Result<(), ElfUtilsError> {
+    match count.checked_mul(entry_size) {
+        Some(size) if size <= reader.remaining() => Ok(()),
+        _ => Err(ElfUtilsError::Malformed(alloc::format!(
+            "The table of {} {} at offset {} doesn't fit in the binary",
+            count,
+            table_name,
+            reader.position()
+        ))),
     }
 }
 
@@ -71,18 +273,167 @@ impl Into<Vec<u8>> for Binary {
 /// so that the VM executing the code can access the .rodata and .data sections
 /// properly.
 ///
+/// The fields which were introduced in later versions of the layout are only
+/// present in the encoded header if the `version` is high enough, see
+/// [`Header::size`].
+///
 /// TODO: move this and the equivalent definition in rbpf into the shared internal
 /// representaion crate.
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct Header {
-    magic: u32,
-    version: u32,
-    flags: u32,
-    data_len: u32,
-    rodata_len: u32,
-    text_len: u32,
-    functions_len: u32,
-    relocated_calls: u32, /*Number of relocated function calls in the program */
+    /// Magic number identifying the binary layout, see [`HEADER_MAGIC`]
+    pub magic: u32,
+    /// Version of the binary layout, see [`HEADER_VERSION`]
+    pub version: u32,
+    /// Flags specifying optional features of the binary, see [`HEADER_FLAG_CHECKSUM`]
+    pub flags: u32,
+    /// Length of the .data section in bytes
+    pub data_len: u32,
+    /// Length of the .rodata section in bytes
+    pub rodata_len: u32,
+    /// Length of the .text section in bytes
+    pub text_len: u32,
+    /// Number of function symbol structs
+    pub functions_len: u32,
+    /// Number of relocated function calls in the program
+    pub relocated_calls: u32,
+    /// CRC32 of the payload following the header (since version 1)
+    pub checksum: u32,
+    /// Number of pointers in the data sections that need to be relocated (since version 2)
+    pub data_relocations: u32,
+    /// Length of the .bss section in bytes (since version 3). It isn't stored
+    /// in the binary, the VM needs to allocate and zero it directly after the
+    /// .data section. Loads from .bss are relative to the start of .data.
+    pub bss_len: u32,
+}
+
+impl Header {
+    /// Creates a header of the given version with all section lengths set to 0.
+    /// The checksum is enabled for all versions that support it.
+    pub fn new(version: u32) -> Self {
+        Header {
+            magic: HEADER_MAGIC,
+            version,
+            flags: if version >= HEADER_VERSION_CHECKSUM {
+                HEADER_FLAG_CHECKSUM
+            } else {
+                0
+            },
+            data_len: 0,
+            rodata_len: 0,
+            text_len: 0,
+            functions_len: 0,
+            relocated_calls: 0,
+            checksum: 0,
+            data_relocations: 0,
+            bss_len: 0,
+        }
+    }
+
+    /// Size of the encoded header in bytes, it depends on the version of the layout.
+    pub fn size(&self) -> usize {
+        header_size(self.version)
+    }
+
+    fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        let magic = reader.read_u32()?;
+        if magic != HEADER_MAGIC {
+            return Err(ElfUtilsError::InvalidMagic {
+                found: magic,
+                expected: HEADER_MAGIC,
+            });
+        }
+
+        let version = reader.read_u32()?;
+        if version > HEADER_VERSION {
+            return Err(ElfUtilsError::UnsupportedVersion {
+                found: version,
+                latest: HEADER_VERSION,
+            });
+        }
+
+        let flags = reader.read_u32()?;
+        let unknown_flags = flags & HEADER_REQUIRED_FLAGS_MASK & !supported_required_flags(version);
+        if unknown_flags != 0 {
+            return Err(ElfUtilsError::UnsupportedFlags(unknown_flags));
+        }
+
+        Ok(Header {
+            magic,
+            version,
+            flags,
+            data_len: reader.read_u32()?,
+            rodata_len: reader.read_u32()?,
+            text_len: reader.read_u32()?,
+            functions_len: reader.read_u32()?,
+            relocated_calls: reader.read_u32()?,
+            checksum: if version >= HEADER_VERSION_CHECKSUM {
+                reader.read_u32()?
+            } else {
+                0
+            },
+            data_relocations: if version >= HEADER_VERSION_DATA_RELOCATIONS {
+                reader.read_u32()?
+            } else {
+                0
+            },
+            bss_len: if version >= HEADER_VERSION_BSS {
+                reader.read_u32()?
+            } else {
+                0
+            },
+        })
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut fields = alloc::vec![
+            self.magic,
+            self.version,
+            self.flags,
+            self.data_len,
+            self.rodata_len,
+            self.text_len,
+            self.functions_len,
+            self.relocated_calls,
+        ];
+        if self.version >= HEADER_VERSION_CHECKSUM {
+            fields.push(self.checksum);
+        }
+        if self.version >= HEADER_VERSION_DATA_RELOCATIONS {
+            fields.push(self.data_relocations);
+        }
+        if self.version >= HEADER_VERSION_BSS {
+            fields.push(self.bss_len);
+        }
+        fields
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect()
+    }
+}
+
+/// Returns the size of the header used by a given version of the layout.
+pub fn header_size(version: u32) -> usize {
+    match version {
+        HEADER_VERSION_LEGACY => 32,
+        HEADER_VERSION_CHECKSUM => 36,
+        HEADER_VERSION_DATA_RELOCATIONS => 40,
+        _ => 44,
+    }
+}
+
+/// Returns the required flags which can be set in a header of the given
+/// version, a VM which only knows an older version rejects the newer flags.
+fn supported_required_flags(version: u32) -> u32 {
+    let mut flags = 0;
+    if version >= HEADER_VERSION_CHECKSUM {
+        flags |= HEADER_FLAG_CHECKSUM;
+    }
+    if version >= HEADER_VERSION_ENTRY_POINTS {
+        flags |= HEADER_FLAG_ENTRY_POINTS;
+    }
+    flags
 }
 
 /// Applies ahead-of-time modifications to the binary to so that it can be
@@ -94,10 +445,11 @@ pub struct Header {
 /// .data and function calls) without having to resolve relocations after loading
 /// the program into memory on the target microcontroller device.
 ///
-/// It is backwards compatible with the bytecode patching workflow used by Femto
-/// -Containers. This means that programs produced by this script should still
-/// be executable on default version of the Femto-Container eBPF VM.
-pub fn assemble_binary(program: &[u8]) -> Result<Vec<u8>, String> {
+/// The binary is produced using the [`DEFAULT_HEADER_VERSION`] of the layout,
+/// which is understood by the rbpf VM running on the devices. Note that the
+/// layout isn't compatible with the Femto-Container VM, programs targeting
+/// it need to be processed using [`crate::assemble_femtocontainer_binary`].
+pub fn assemble_binary(program: &[u8]) -> Result<Vec<u8>, ElfUtilsError> {
     assemble_binary_specifying_helpers(program, (0..127).into_iter().collect::<Vec<u8>>())
 }
 
@@ -107,107 +459,461 @@ pub fn assemble_binary(program: &[u8]) -> Result<Vec<u8>, String> {
 pub fn assemble_binary_specifying_helpers(
     program: &[u8],
     allowed_helpers: Vec<u8>,
-) -> Result<Vec<u8>, String> {
-    let Ok(binary) = goblin::elf::Elf::parse(&program) else {
-        return Err("Failed to parse the ELF binary".to_string());
-    };
+) -> Result<Vec<u8>, ElfUtilsError> {
+    assemble_binary_with_version(program, allowed_helpers, DEFAULT_HEADER_VERSION)
+}
+
+/// Applies ahead-of-time modifications to the binary similar to
+/// [`assemble_binary_specifying_helpers`] and exports the given functions as
+/// the entry points of the program. The execution request selects one of them
+/// by its index in `entry_points`, which allows for storing e.g. both an
+/// initialisation function and a request handler in a single binary.
+///
+/// The entry points need to be global functions of the program and there can
+/// be at most [`MAX_ENTRY_POINTS`] of them. Exporting them requires at least
+/// [`HEADER_VERSION_ENTRY_POINTS`] of the layout.
+pub fn assemble_binary_with_entry_points(
+    program: &[u8],
+    allowed_helpers: Vec<u8>,
+    version: u32,
+    entry_points: &[&str],
+) -> Result<Vec<u8>, ElfUtilsError> {
+    assemble(program, allowed_helpers, version, entry_points)
+}
 
-    // First extract the bytes contained in all three main sections
-    let mut text: Vec<u8> = get_section_bytes(".text", &binary, &program);
-    let mut data: Vec<u8> = get_section_bytes(".data", &binary, &program);
-    let mut rodata: Vec<u8> = get_section_bytes(".rodata", &binary, &program);
+/// Applies ahead-of-time modifications to the binary similar to
+/// [`assemble_binary_specifying_helpers`] but produces the binary using the
+/// given version of the layout. This allows for targeting VMs which only
+/// support older revisions of the layout, e.g. [`HEADER_VERSION_LEGACY`].
+pub fn assemble_binary_with_version(
+    program: &[u8],
+    allowed_helpers: Vec<u8>,
+    version: u32,
+) -> Result<Vec<u8>, ElfUtilsError> {
+    assemble(program, allowed_helpers, version, &[])
+}
 
-    // Now handle all string literals that aren't placed in .rodata
-    // section by default. We need to append them to the .rodata section
-    // and maintain the information about the offsets at which they are
-    // stored so that we can relocate loads from them later on.
-    let str_section_offsets = append_string_literals(&mut rodata, &binary, &program);
+fn assemble(
+    program: &[u8],
+    allowed_helpers: Vec<u8>,
+    version: u32,
+    entry_points: &[&str],
+) -> Result<Vec<u8>, ElfUtilsError> {
+    if version > HEADER_VERSION {
+        return Err(ElfUtilsError::UnsupportedVersion {
+            found: version,
+            latest: HEADER_VERSION,
+        });
+    }
+
+    let program = &with_helper_symbols_resolved(program)?;
+    let binary = parse_elf(program)?;
+
+    // First merge all sections containing executable code into a single
+    // text section, programs compiled with -ffunction-sections or using
+    // custom sections place their functions outside of .text.
+    let mut text: Vec<u8> = alloc::vec![];
+    let text_offsets = merge_text_sections(&mut text, &binary, program)?;
+
+    // Now merge all sections containing the data used by the program (e.g.
+    // string literals placed in .rodata.str1.1) into the .data and .rodata
+    // sections. We need to maintain the information about the offsets at
+    // which they are stored so that we can relocate loads from them later on.
+    let mut data: Vec<u8> = alloc::vec![];
+    let mut rodata: Vec<u8> = alloc::vec![];
+    let mut placements = merge_data_sections(&mut data, &mut rodata, &binary, program)?;
+    round_section_length(&mut data);
+    let bss_len = merge_bss_sections(data.len(), &binary, &mut placements)?;
+    if bss_len > 0 && version < HEADER_VERSION_BSS {
+        return Err(ElfUtilsError::RequiresNewerVersion {
+            feature: "has zero-initialised variables",
+            version: HEADER_VERSION_BSS,
+        });
+    }
 
     // Now we need to collect all global functions and append their names
     // to the rodata section. We also need to maintain the information
     // about the offsets at which the function names are stored.
     // This is maintained for compatibility with the rbpf bytecode patching
     // script. It isn't actually used by their VM.
-    let symbol_structs: Vec<Symbol> = extract_function_symbols(&mut rodata, &binary);
+    let mut symbol_structs: Vec<Symbol> =
+        extract_function_symbols(&mut rodata, &binary, &text_offsets)?;
+    if !entry_points.is_empty() {
+        if version < HEADER_VERSION_ENTRY_POINTS {
+            return Err(ElfUtilsError::RequiresNewerVersion {
+                feature: "exports entry points",
+                version: HEADER_VERSION_ENTRY_POINTS,
+            });
+        }
+        export_entry_points(&mut symbol_structs, &rodata, entry_points)?;
+    }
 
-    let relocated_calls: Vec<RelocatedCall> = find_relocated_calls(&binary, &program);
+    let relocations = find_relocations(&binary)?;
+    let relocated_calls: Vec<RelocatedCall> =
+        find_relocated_calls(&text, &relocations, &binary, &text_offsets);
 
-    resolve_rodata_relocations(&mut text, &binary, &program, &str_section_offsets);
+    resolve_load_relocations(&mut text, &relocations, &binary, &text_offsets, &placements)?;
+
+    let data_relocations =
+        resolve_pointer_relocations(&mut data, &mut rodata, &relocations, &binary, &placements)?;
+    if !data_relocations.is_empty() && version < HEADER_VERSION_DATA_RELOCATIONS {
+        return Err(ElfUtilsError::RequiresNewerVersion {
+            feature: "stores pointers in its data sections",
+            version: HEADER_VERSION_DATA_RELOCATIONS,
+        });
+    }
 
-    round_section_length(&mut data);
     round_section_length(&mut rodata);
 
     // Now we write the new binary file
-    let header = Header {
-        magic: 123,
-        version: 0,
-        flags: 0,
+    let mut header = Header {
         data_len: data.len() as u32,
         rodata_len: rodata.len() as u32,
         text_len: text.len() as u32,
         functions_len: symbol_structs.len() as u32,
         relocated_calls: relocated_calls.len() as u32,
+        data_relocations: data_relocations.len() as u32,
+        bss_len: bss_len as u32,
+        ..Header::new(version)
     };
+    if !entry_points.is_empty() {
+        header.flags |= HEADER_FLAG_ENTRY_POINTS;
+    }
 
-    let output_binary: Binary = Binary {
+    let mut output_binary = ExtendedBinary {
         header,
         data,
         rodata,
         text,
         functions: symbol_structs,
         relocated_calls,
+        data_relocations,
         allowed_helpers: allowed_helpers.clone(),
     };
+    output_binary.update_checksum();
 
     let assembled_program: Vec<u8> = output_binary.into();
     Ok(assembled_program)
 }
 
-/// String literals used in e.g. calls to printf are loaded into the
-/// .rodata.str.1 section, we need to move them over to the rodata section.
+/// Location of a section from the input ELF file inside of the output binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionPlacement {
+    /// Section of the output binary that the input section was merged into
+    pub region: DataRegion,
+    /// Offset of the start of the input section relative to the start of the region
+    pub offset: usize,
+    /// Entries of a mergeable section (e.g. string literals) sorted by their
+    /// offset in the input section, which don't need to be contiguous in the
+    /// output binary as identical entries are stored only once. Empty if the
+    /// section was copied as a whole.
+    pub entries: Vec<MergedEntry>,
+}
+
+/// A string literal or a constant from a section with the `SHF_MERGE` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MergedEntry {
+    /// Offset of the entry relative to the start of the input section
+    pub section_offset: usize,
+    /// Offset of the entry relative to the start of the region
+    pub region_offset: usize,
+}
+
+impl SectionPlacement {
+    /// Returns the offset relative to the start of the region of the data
+    /// stored at the given offset of the input section. The offsets pointing
+    /// inside of an entry of a merged section (e.g. to a suffix of a string)
+    /// are translated relative to the start of that entry.
+    pub fn region_offset(&self, section_offset: u64) -> u64 {
+        let index = self
+            .entries
+            .partition_point(|entry| entry.section_offset as u64 <= section_offset);
+        match index.checked_sub(1).map(|index| self.entries[index]) {
+            Some(entry) => (entry.region_offset as u64)
+                .wrapping_add(section_offset - entry.section_offset as u64),
+            None => (self.offset as u64).wrapping_add(section_offset),
+        }
+    }
+}
+
+/// Returns the region of the output binary that a section with a given name
+/// should be merged into, or `None` if the section doesn't contain data.
+fn data_region(section_name: &str) -> Option<DataRegion> {
+    if section_name == ".data" || section_name.starts_with(".data.") {
+        Some(DataRegion::Data)
+    } else if section_name == ".rodata" || section_name.starts_with(".rodata.") {
+        Some(DataRegion::Rodata)
+    } else {
+        None
+    }
+}
+
+/// Merges all sections containing the data used by the program into the
+/// .data and .rodata sections of the output binary. Apart from the two main
+/// sections, the compiler can place string literals used in e.g. calls to
+/// printf in the .rodata.str1.1 section and variables in sections such as
+/// .data.rel or .rodata.cst16. Those are appended to the main sections
+/// respecting their alignment.
+///
+/// The sections with the `SHF_MERGE` flag (e.g. .rodata.str1.1 and
+/// .rodata.cst8) are split into their entries and the entries identical to
+/// the ones already stored in the output binary are not appended again, so
+/// that e.g. the format strings used in multiple places are stored only once.
+///
 /// In order to perform relocations properly later on, we need to maintain
-/// the map from the name of the additional rodata section to the offset
-/// to it relative to the original rodata section. This map is returned from this
-/// functio.
-pub fn append_string_literals<'a>(
+/// the map from the name of the input section to its placement in the
+/// output binary. This map is returned from this function.
+pub fn merge_data_sections<'a>(
+    data: &mut Vec<u8>,
     rodata: &mut Vec<u8>,
     binary: &Elf<'a>,
     buffer: &[u8],
-) -> HashMap<&'a str, usize> {
-    let mut str_section_offsets = HashMap::new();
-
-    for section in &binary.section_headers {
-        if let Some(section_name) = binary.strtab.get_at(section.sh_name) {
-            // The string literals are stored in the .rodata.str.1 section
-            if section_name.contains(".rodata.") {
-                str_section_offsets.insert(section_name, rodata.len());
-                rodata.extend(
-                    &buffer[section.sh_offset as usize
-                        ..(section.sh_offset + section.sh_size) as usize],
-                );
+) -> Result<HashMap<&'a str, SectionPlacement>, ElfUtilsError> {
+    let mut placements = HashMap::new();
+    let mut merged_entries = HashMap::new();
+
+    // The entries of the sections with relocations applied to them can't be
+    // moved without adjusting those relocations, so they are copied as a whole.
+    let relocated_sections = binary
+        .section_headers
+        .iter()
+        .filter(|section| matches!(section.sh_type, SHT_REL | SHT_RELA))
+        .map(|section| section.sh_info as usize)
+        .collect::<Vec<_>>();
+
+    // The .data and .rodata sections need to be placed first so that the
+    // offsets of the symbols defined inside of them remain unchanged.
+    let mut sections = binary
+        .section_headers
+        .iter()
+        .enumerate()
+        .filter(|(_, section)| section.sh_type == SHT_PROGBITS)
+        .filter_map(|(index, section)| {
+            let name = binary.strtab.get_at(section.sh_name)?;
+            Some((index, name, data_region(name)?, section))
+        })
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|(_, name, region, _)| *name != region.section_name());
+
+    let mut merged_bytes = 0;
+    for (index, name, region, section) in sections {
+        let target = match region {
+            DataRegion::Data => &mut *data,
+            DataRegion::Rodata => &mut *rodata,
+        };
+        let alignment = section_alignment(section)?;
+        align_section_length(target, alignment);
+        let bytes = section_bytes(section, buffer)?;
+        let entries = match region {
+            DataRegion::Rodata if !relocated_sections.contains(&index) => {
+                mergeable_entries(section, bytes)
             }
+            _ => None,
+        };
+        let mut placement = SectionPlacement {
+            region,
+            offset: target.len(),
+            entries: alloc::vec![],
+        };
+        let Some(entries) = entries else {
+            target.extend(bytes);
+            placements.insert(name, placement);
+            continue;
+        };
+        // Entries can only be shared by the sections of the same kind, i.e.
+        // strings with strings and constants with constants of the same size.
+        let kind = (section.sh_flags & SHF_STRINGS as u64, section.sh_entsize);
+        for (section_offset, entry) in entries {
+            let region_offset = match merged_entries.get(&(kind, entry)) {
+                Some(offset) if offset % alignment == 0 => {
+                    merged_bytes += entry.len();
+                    *offset
+                }
+                _ => {
+                    align_section_length(target, alignment);
+                    merged_entries.insert((kind, entry), target.len());
+                    target.extend(entry);
+                    target.len() - entry.len()
+                }
+            };
+            placement.entries.push(MergedEntry {
+                section_offset,
+                region_offset,
+            });
         }
+        placements.insert(name, placement);
     }
 
-    debug!(
-        "Additional read-only string sections: {:?}",
-        str_section_offsets
-    );
+    debug!("Placements of the data sections: {:?}", placements);
+    if merged_bytes > 0 {
+        debug!("Merged {} bytes of duplicate constants", merged_bytes);
+    }
+
+    Ok(placements)
+}
 
-    str_section_offsets
+/// Appends zeroes to the section until its length is a multiple of `alignment`.
+fn align_section_length(section: &mut Vec<u8>, alignment: usize) {
+    section.resize(section.len().next_multiple_of(alignment), 0);
 }
 
-fn extract_function_symbols(rodata: &mut Vec<u8>, binary: &Elf<'_>) -> Vec<Symbol> {
+/// Splits the contents of a section with the `SHF_MERGE` flag into its entries
+/// together with their offsets. The entries are either null-terminated strings
+/// of characters of `sh_entsize` bytes (if the section has the `SHF_STRINGS`
+/// flag) or constants of `sh_entsize` bytes.
+///
+/// Returns `None` if the section isn't mergeable or its contents don't match
+/// the size of the entries, in which case it needs to be copied as a whole.
+fn mergeable_entries<'a>(
+    section: &SectionHeader,
+    bytes: &'a [u8],
+) -> Option<Vec<(usize, &'a [u8])>> {
+    if section.sh_flags & SHF_MERGE as u64 == 0 {
+        return None;
+    }
+    let entry_size = usize::try_from(section.sh_entsize).ok()?;
+    if entry_size == 0 || !bytes.chunks_exact(entry_size).remainder().is_empty() {
+        return None;
+    }
+    if section.sh_flags & SHF_STRINGS as u64 == 0 {
+        return Some(
+            bytes
+                .chunks_exact(entry_size)
+                .enumerate()
+                .map(|(index, entry)| (index * entry_size, entry))
+                .collect(),
+        );
+    }
+    let mut entries = alloc::vec![];
+    let mut start = 0;
+    for (index, character) in bytes.chunks_exact(entry_size).enumerate() {
+        if character.iter().all(|byte| *byte == 0) {
+            let end = (index + 1) * entry_size;
+            entries.push((start, &bytes[start..end]));
+            start = end;
+        }
+    }
+    // The last string in the section needs to be terminated.
+    (start == bytes.len()).then_some(entries)
+}
+
+/// Returns true if the section contains zero-initialised variables of the program.
+fn is_bss_section(section: &SectionHeader, name: &str) -> bool {
+    section.sh_type == SHT_NOBITS && (name == ".bss" || name.starts_with(".bss."))
+}
+
+/// Places the .bss sections, which contain zero-initialised variables and take
+/// up no space in the ELF file, directly after the end of the .data section.
+/// The placements are added to the map returned from [`merge_data_sections`]
+/// so that loads from .bss are relocated relative to the start of .data.
+///
+/// The length of the .data section needs to be final (i.e. rounded) when this
+/// function is called. Returns the total length of the .bss sections, which
+/// the VM needs to allocate and zero after the .data section.
+pub fn merge_bss_sections<'a>(
+    data_len: usize,
+    binary: &Elf<'a>,
+    placements: &mut HashMap<&'a str, SectionPlacement>,
+) -> Result<usize, ElfUtilsError> {
+    let mut bss_len = 0;
+    for section in binary.section_headers.iter() {
+        let Some(name) = binary.strtab.get_at(section.sh_name) else {
+            continue;
+        };
+        if !is_bss_section(section, name) {
+            continue;
+        }
+        let alignment = section_alignment(section)?;
+        bss_len = (data_len + bss_len).next_multiple_of(alignment) - data_len;
+        placements.insert(
+            name,
+            SectionPlacement {
+                region: DataRegion::Data,
+                offset: data_len + bss_len,
+                entries: alloc::vec![],
+            },
+        );
+        // The size is limited so that the offsets computed above can't overflow.
+        bss_len = match usize::try_from(section.sh_size) {
+            Ok(size) if size <= MAX_ZERO_INITIALISED_SIZE - bss_len => bss_len + size,
+            _ => {
+                return Err(ElfUtilsError::InvalidValue {
+                    field: "size of the zero-initialised sections",
+                    value: section.sh_size,
+                })
+            }
+        };
+    }
+    if bss_len > 0 {
+        debug!("Placed {} bytes of .bss after .data", bss_len);
+    }
+    Ok(bss_len)
+}
+
+/// Returns true if the section contains executable code of the program.
+fn is_text_section(section: &SectionHeader) -> bool {
+    section.sh_type == SHT_PROGBITS && section.sh_flags & SHF_EXECINSTR as u64 != 0
+}
+
+/// Merges all executable sections of the ELF file (e.g. `.text`, `.text.*`
+/// produced by -ffunction-sections or custom sections) into a single text
+/// section. The sections are laid out in the order in which they appear in
+/// the ELF file so that the function defined first remains at the start of
+/// the text section.
+///
+/// Returns the map from the index of each merged section to its offset in
+/// the output text section, which is needed to adjust the offsets of the
+/// relocations and the function symbols.
+pub fn merge_text_sections(
+    text: &mut Vec<u8>,
+    binary: &Elf<'_>,
+    buffer: &[u8],
+) -> Result<HashMap<usize, usize>, ElfUtilsError> {
+    let mut text_offsets = HashMap::new();
+    for (index, section) in binary.section_headers.iter().enumerate() {
+        if !is_text_section(section) {
+            continue;
+        }
+        round_section_length(text);
+        debug!(
+            "Placing text section {} at offset {}",
+            binary.strtab.get_at(section.sh_name).unwrap_or_default(),
+            text.len()
+        );
+        text_offsets.insert(index, text.len());
+        text.extend(section_bytes(section, buffer)?);
+    }
+    Ok(text_offsets)
+}
+
+/// Collects all global functions and appends their names to the rodata section.
+/// The offsets of the functions are relative to the start of the merged text
+/// section, see [`merge_text_sections`].
+pub fn extract_function_symbols(
+    rodata: &mut Vec<u8>,
+    binary: &Elf<'_>,
+    text_offsets: &HashMap<usize, usize>,
+) -> Result<Vec<Symbol>, ElfUtilsError> {
     let mut symbol_structs: Vec<Symbol> = alloc::vec![];
     for symbol in binary.syms.iter() {
         if symbol.st_type() == STT_FUNC && symbol.st_bind() == STB_GLOBAL {
-            let symbol_name = binary.strtab.get_at(symbol.st_name).unwrap();
+            // The names in the string table end with a null byte, so they
+            // can't contain one.
+            let Some(symbol_name) = binary.strtab.get_at(symbol.st_name) else {
+                return Err(ElfUtilsError::Malformed(alloc::format!(
+                    "Invalid name of the function symbol at {}",
+                    symbol.st_value
+                )));
+            };
 
+            let Some(section_offset) = text_offsets.get(&symbol.st_shndx) else {
+                debug!("Function {} isn't defined in a text section", symbol_name);
+                continue;
+            };
             debug!("Found global function: {}", symbol_name);
-            let offset_within_text = symbol.st_value as usize;
+            let offset_within_text = section_offset.wrapping_add(symbol.st_value as usize);
             let offset = rodata.len();
-            let name_cstr = CString::new(symbol_name).unwrap();
-            rodata.extend(name_cstr.to_bytes().iter());
+            rodata.extend(symbol_name.as_bytes());
             // Added flags for compatiblity with rbpf
             let flags = 0;
             symbol_structs.push(Symbol {
@@ -217,169 +923,779 @@ fn extract_function_symbols(rodata: &mut Vec<u8>, binary: &Elf<'_>) -> Vec<Symbo
             });
         }
     }
-    symbol_structs
+    Ok(symbol_structs)
 }
 
-fn find_relocated_calls(binary: &Elf<'_>, buffer: &[u8]) -> Vec<RelocatedCall> {
+/// Moves the symbols of the functions exported as entry points to the start
+/// of the table of function symbols in the order in which they are given and
+/// marks them with [`SYMBOL_FLAG_ENTRY_POINT`].
+fn export_entry_points(
+    functions: &mut Vec<Symbol>,
+    rodata: &[u8],
+    entry_points: &[&str],
+) -> Result<(), ElfUtilsError> {
+    if entry_points.len() > MAX_ENTRY_POINTS {
+        return Err(ElfUtilsError::InvalidValue {
+            field: "number of entry points",
+            value: entry_points.len() as u64,
+        });
+    }
+    let names = decode_function_names(rodata, functions)?;
+    let mut exported: Vec<usize> = alloc::vec![];
+    for entry_point in entry_points {
+        let Some(index) = names.iter().position(|name| name == entry_point) else {
+            return Err(ElfUtilsError::UnknownEntryPoint(entry_point.to_string()));
+        };
+        if exported.contains(&index) {
+            return Err(ElfUtilsError::Unsupported(alloc::format!(
+                "The entry point {} is exported more than once",
+                entry_point
+            )));
+        }
+        exported.push(index);
+    }
+    debug!("Exporting entry points: {:?}", entry_points);
+
+    let mut symbols = exported
+        .iter()
+        .map(|index| Symbol {
+            flags: SYMBOL_FLAG_ENTRY_POINT,
+            ..functions[*index]
+        })
+        .collect::<Vec<Symbol>>();
+    symbols.extend(
+        functions
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !exported.contains(index))
+            .map(|(_, symbol)| *symbol),
+    );
+    *functions = symbols;
+    Ok(())
+}
+
+/// Finds all calls to the functions defined in the program which need to be
+/// resolved by the VM. Both the offsets of the call instructions and the
+/// called functions are relative to the start of the merged text section.
+pub fn find_relocated_calls(
+    text: &[u8],
+    relocations: &[Relocation<'_>],
+    binary: &Elf<'_>,
+    text_offsets: &HashMap<usize, usize>,
+) -> Vec<RelocatedCall> {
     let mut relocated_calls: Vec<RelocatedCall> = alloc::vec![];
-    let relocations = find_relocations(binary, buffer);
-    let text_section = get_section_header(".text", binary).unwrap();
-    for (preceding_section_offset, reloc) in relocations {
-        // We only patch inside of .text section
-        if preceding_section_offset != text_section.sh_offset as usize {
+    for relocation in relocations {
+        // We only patch inside of the text sections
+        let Some(section_offset) = text_offsets.get(&relocation.section_index) else {
             continue;
-        }
+        };
+        let reloc = &relocation.reloc;
         debug!("Relocation found : {:?}", reloc);
-        if let Some(symbol) = binary.syms.get(reloc.r_sym) {
-            if symbol.st_type() == STT_FUNC {
-                let name = binary.strtab.get_at(symbol.st_name).unwrap();
+        let instruction_offset = section_offset.saturating_add(reloc.r_offset as usize);
+        let Some(symbol) = binary.syms.get(reloc.r_sym) else {
+            continue;
+        };
+        let Some(function_section_offset) = text_offsets.get(&symbol.st_shndx) else {
+            continue;
+        };
+        match symbol.st_type() {
+            STT_FUNC => {
+                let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
                 debug!(
                     "Relocation at instruction {} for function {} at {}",
-                    reloc.r_offset, name, symbol.st_value
+                    instruction_offset, name, symbol.st_value
                 );
                 relocated_calls.push(RelocatedCall {
-                    instruction_offset: reloc.r_offset as u32,
-                    function_text_offset: symbol.st_value as u32,
+                    instruction_offset: instruction_offset as u32,
+                    function_text_offset: (*function_section_offset as u64)
+                        .wrapping_add(symbol.st_value)
+                        as u32,
                 });
             }
+            // Calls to static functions defined in a different section are
+            // relocated against the section symbol, the offset of the function
+            // within that section is encoded in the immediate operand as the
+            // number of instructions after the one preceding the section.
+            STT_SECTION if text.get(instruction_offset) == Some(&(CALL_OPCODE as u8)) => {
+                let call = Call::from(&text[instruction_offset..]);
+                let immediate = call.immediate as i32 as i64;
+                let function_offset = (*function_section_offset as i64)
+                    .wrapping_add((immediate + 1) * INSTRUCTION_SIZE as i64)
+                    .wrapping_add(relocation.explicit_addend())
+                    as u32;
+                debug!(
+                    "Relocation at instruction {} for a static function at {}",
+                    instruction_offset, function_offset
+                );
+                relocated_calls.push(RelocatedCall {
+                    instruction_offset: instruction_offset as u32,
+                    function_text_offset: function_offset,
+                });
+            }
+            _ => {}
         }
     }
     relocated_calls
 }
 
-/// Responsible for handling relocations for the read-only data used by the program.
+/// Responsible for handling relocations for the data used by the program.
 /// It works by introducing two custom load-double-word (LDDW) instructions (
 /// see [`Lddw`]) that indicate that the particular load instruction is supposed
 /// to target the .rodata or .data section. This is coupled with the implementation
 /// of the VM and not compatible with the default eBPF standard.
-pub fn resolve_rodata_relocations(
+pub fn resolve_load_relocations(
     text: &mut Vec<u8>,
+    relocations: &[Relocation<'_>],
     binary: &Elf<'_>,
-    buffer: &[u8],
-    str_section_offsets: &HashMap<&str, usize>,
-) {
-    let relocations = find_relocations(binary, buffer);
-    let text_section = get_section_header(".text", binary).unwrap();
-    for (preceding_section_offset, relocation) in relocations {
-        // We only patch inside of .text section
-        if preceding_section_offset != text_section.sh_offset as usize {
+    text_offsets: &HashMap<usize, usize>,
+    placements: &HashMap<&str, SectionPlacement>,
+) -> Result<(), ElfUtilsError> {
+    for relocation in relocations {
+        // We only patch inside of the text sections
+        let Some(section_offset) = text_offsets.get(&relocation.section_index) else {
             continue;
-        }
-        if let Some(symbol) = binary.syms.get(relocation.r_sym) {
-            let section = binary.section_headers.get(symbol.st_shndx).unwrap();
-            let section_name = binary.strtab.get_at(section.sh_name).unwrap();
+        };
+        let reloc = &relocation.reloc;
+        if let Some(symbol) = binary.syms.get(reloc.r_sym) {
+            let section_name = binary
+                .section_headers
+                .get(symbol.st_shndx)
+                .and_then(|section| binary.strtab.get_at(section.sh_name))
+                .unwrap_or_default();
             match symbol.st_type() {
                 STT_SECTION => {
                     debug!(
                         "Relocation at instruction {} for section {} at {}",
-                        relocation.r_offset, section_name, symbol.st_value
+                        reloc.r_offset, section_name, symbol.st_value
                     )
                 }
                 STT_FUNC => continue, // We don't patch for functions
                 _ => {
-                    let symbol_name = binary.strtab.get_at(symbol.st_name).unwrap();
+                    let symbol_name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
                     debug!(
                         "Relocation at instruction {} for symbol {} in {} at {}",
-                        relocation.r_offset, symbol_name, section_name, symbol.st_value
+                        reloc.r_offset, symbol_name, section_name, symbol.st_value
                     )
                 }
             }
         }
 
-        patch_text(text, binary, relocation, &str_section_offsets);
+        patch_text(text, binary, relocation, *section_offset, placements)
+            .map_err(|error| error.at(relocation.location(binary)))?;
     }
+    Ok(())
 }
 
-/// Responsible for extracting the allowed helper function indices that are
-/// specified at the end of the program binary.
+/// Responsible for handling pointers stored inside of the .data and .rodata
+/// sections, e.g. arrays of string literals. The address of the pointed-to
+/// data is only known once the program is loaded into memory, so instead of
+/// patching the binary here, we emit a [`DataRelocation`] for each pointer
+/// that the VM needs to resolve before executing the program.
 ///
-/// Note: This can only be used if the input slice of bytes comes from a program
-/// which has been preprocessed with the [`micro_bpf_common::BinaryFileLayout:ExtendedHeader`]
-pub fn extract_allowed_helpers(prog: &[u8]) -> Vec<u8> {
-    const FUNCTION_STRUCT_SIZE: u32 = 6;
-    const RELOCATED_CALL_STRUCT_SIZE: u32 = 8;
+/// The pointer slots are overwritten with the offset of the target relative
+/// to the start of its section, so that the VM only needs to add the address
+/// of that section.
+pub fn resolve_pointer_relocations(
+    data: &mut [u8],
+    rodata: &mut [u8],
+    relocations: &[Relocation<'_>],
+    binary: &Elf<'_>,
+    placements: &HashMap<&str, SectionPlacement>,
+) -> Result<Vec<DataRelocation>, ElfUtilsError> {
+    let mut data_relocations = alloc::vec![];
+    for relocation in relocations {
+        // We are only interested in relocations inside of the data sections
+        let Some(location) = placements.get(relocation.section_name) else {
+            continue;
+        };
+        let section = match location.region {
+            DataRegion::Data => &mut *data,
+            DataRegion::Rodata => &mut *rodata,
+        };
+        let data_relocation = resolve_pointer(section, location, relocation, binary, placements)
+            .map_err(|error| error.at(relocation.location(binary)))?;
+        data_relocations.push(data_relocation);
+    }
+    Ok(data_relocations)
+}
 
-    let header_size = 32;
+/// Resolves a single pointer stored in the `section` placed at `location`.
+fn resolve_pointer(
+    section: &mut [u8],
+    location: &SectionPlacement,
+    relocation: &Relocation<'_>,
+    binary: &Elf<'_>,
+    placements: &HashMap<&str, SectionPlacement>,
+) -> Result<DataRelocation, ElfUtilsError> {
+    let reloc = &relocation.reloc;
+    let Some(symbol) = binary.syms.get(reloc.r_sym) else {
+        return Err(ElfUtilsError::InvalidSymbolIndex(reloc.r_sym));
+    };
+    let section_name = binary
+        .section_headers
+        .get(symbol.st_shndx)
+        .and_then(|section| binary.strtab.get_at(section.sh_name))
+        .unwrap_or_default();
+    if symbol.st_type() == STT_FUNC {
+        return Err(ElfUtilsError::Unsupported(alloc::format!(
+            "Function pointers stored in the {} section are not supported",
+            location.region.section_name()
+        )));
+    }
+    let Some(target) = placements.get(section_name) else {
+        return Err(ElfUtilsError::Unsupported(alloc::format!(
+            "Unsupported target section of a pointer stored in the {} section: {}",
+            location.region.section_name(),
+            section_name
+        )));
+    };
 
-    unsafe {
-        let header = prog.as_ptr() as *const Header;
+    let location_offset = location.offset.saturating_add(reloc.r_offset as usize);
+    let slot_range = checked_range(location_offset, 8, section.len())?;
+    let slot = &mut section[slot_range];
 
-        let allowed_helpers_offset: u32 = header_size
-            + (*header).data_len
-            + (*header).rodata_len
-            + (*header).text_len
-            + (*header).functions_len * FUNCTION_STRUCT_SIZE
-            + (*header).relocated_calls * RELOCATED_CALL_STRUCT_SIZE;
+    // The SHT_REL relocations don't have an explicit addend, instead the
+    // offset relative to the symbol is stored in the pointer itself.
+    let addend = match reloc.r_addend {
+        Some(addend) => addend as u64,
+        None => u64::from_le_bytes(slot.try_into().unwrap_or_default()),
+    };
+    let target_offset = target.region_offset(symbol.st_value.wrapping_add(addend));
+    slot.copy_from_slice(&target_offset.to_le_bytes());
 
-        let mut allowed_helpers = Vec::new();
-        for byte in &prog[allowed_helpers_offset as usize..] {
-            allowed_helpers.push(*byte);
-        }
-        debug!("Allowed helpers: {:?}", allowed_helpers);
+    debug!(
+        "Pointer at {}+{} points to {}+{}",
+        location.region.section_name(),
+        location_offset,
+        target.region.section_name(),
+        target_offset
+    );
+    Ok(DataRelocation {
+        location_region: location.region as u16,
+        target_region: target.region as u16,
+        location_offset: location_offset as u32,
+        target_offset: target_offset as u32,
+    })
+}
 
-        allowed_helpers
-    }
+/// Responsible for extracting the allowed helper function indices that are
+/// specified at the end of the program binary.
+///
+/// Note: This can only be used if the input slice of bytes comes from a program
+/// which has been preprocessed with the [`micro_bpf_common::BinaryFileLayout:ExtendedHeader`]
+pub fn extract_allowed_helpers(prog: &[u8]) -> Result<Vec<u8>, ElfUtilsError> {
+    let binary = ExtendedBinary::parse(prog)?;
+    debug!("Allowed helpers: {:?}", binary.allowed_helpers);
+    Ok(binary.allowed_helpers)
 }
 
 pub fn patch_text(
     text: &mut [u8],
     binary: &Elf<'_>,
-    reloc: Reloc,
-    str_section_offsets: &HashMap<&str, usize>,
-) {
-    if (reloc.r_offset as usize) >= text.len() {
+    relocation: &Relocation<'_>,
+    section_offset: usize,
+    placements: &HashMap<&str, SectionPlacement>,
+) -> Result<(), ElfUtilsError> {
+    let reloc = &relocation.reloc;
+    // Offset of the relocated instruction in the merged text section
+    let instruction_offset = section_offset.saturating_add(reloc.r_offset as usize);
+    let Ok(instr_range) = checked_range(instruction_offset, LDDW_INSTRUCTION_SIZE, text.len())
+    else {
         debug!("We only patch inside the .text section, returning early");
-        return;
-    }
+        return Ok(());
+    };
     debug!("Patching text for relocation symbol: {:?}", reloc);
-    let symbol = binary.syms.get(reloc.r_sym).unwrap();
-    let section = binary.section_headers.get(symbol.st_shndx).unwrap();
-    let section_name = binary.strtab.get_at(section.sh_name).unwrap();
-    let mut offset = 0;
+    let Some(symbol) = binary.syms.get(reloc.r_sym) else {
+        return Err(ElfUtilsError::InvalidSymbolIndex(reloc.r_sym));
+    };
+    let Some(section) = binary.section_headers.get(symbol.st_shndx) else {
+        return Err(ElfUtilsError::InvalidSectionIndex(symbol.st_shndx));
+    };
+    let section_name = binary.strtab.get_at(section.sh_name).unwrap_or_default();
 
     // We don't do eny relocations in case of functions as they are handled
     // in a custom way by the VM (we append their relocation structs at the end of the binary
     // file)
     if symbol.st_type() == STT_FUNC {
         debug!("No patching is performed for function calls.");
-        return;
+        return Ok(());
     }
 
-    if symbol.st_type() == STT_SECTION {
-        if let Some(off) = str_section_offsets.get(section_name) {
-            offset = *off;
-        } else {
-            debug!("No offset found for section: {}", section_name);
-            return;
-        }
-    } else if symbol.st_type() == STT_OBJECT {
-        offset = symbol.st_value as usize;
-    }
+    let Some(placement) = placements.get(section_name) else {
+        debug!("No placement found for section: {}", section_name);
+        return Ok(());
+    };
 
     // We only patch LDDW instructions inside .text section
-    if text[reloc.r_offset as usize] != LDDW_OPCODE as u8 {
-        debug!("No LDDW instruction at {}", reloc.r_offset);
-        return;
+    if text[instruction_offset] != LDDW_OPCODE as u8 {
+        debug!("No LDDW instruction at {}", instruction_offset);
+        return Ok(());
     }
 
-    let opcode = if section_name.contains(".rodata.str") {
-        FC_LDDWD_OPCODE
-    } else {
-        FC_LDDWR_OPCODE
+    let opcode = match placement.region {
+        DataRegion::Data => FC_LDDWD_OPCODE,
+        DataRegion::Rodata => FC_LDDWR_OPCODE,
     };
 
     // We instantiate the instruction struct to modify it
-    let instr_bytes = &text[reloc.r_offset as usize..reloc.r_offset as usize + 16];
+    let instr_bytes = &text[instr_range.clone()];
     debug!(
         "Replacing {:?} at {} with {} at {}",
-        instr_bytes, reloc.r_offset, opcode, reloc.r_offset
+        instr_bytes, instruction_offset, opcode, instruction_offset
     );
 
     let mut instr: Lddw = Lddw::from(instr_bytes);
     instr.opcode = opcode as u8;
-    let instr_imm = instr.immediate_l;
-    debug!("Adding offset {} to instr immediate {}", offset, instr_imm);
-    instr.immediate_l += offset as u32;
+    // For section symbols the value is 0 and the offset within the section
+    // is stored in the immediate operand of the instruction, or in the addend
+    // in case of SHT_RELA relocations.
+    let section_offset = (instr.immediate_l as i32 as i64)
+        .wrapping_add(symbol.st_value as i64)
+        .wrapping_add(relocation.explicit_addend());
+    let offset = placement.region_offset(section_offset as u64);
+    debug!(
+        "Replacing offset {} with {} in the region",
+        section_offset, offset
+    );
+    instr.immediate_l = offset as u32;
+
+    text[instr_range].copy_from_slice((&instr).into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::R_BPF_64_64,
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+    };
+    use alloc::string::ToString;
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_WRITE},
+        sym::STT_OBJECT,
+    };
+
+    fn sample_binary() -> ExtendedBinary {
+        let mut binary = unchecked_sample_binary();
+        binary.update_checksum();
+        binary
+    }
+
+    fn unchecked_sample_binary() -> ExtendedBinary {
+        let data = alloc::vec![1, 2, 3, 4, 0, 0, 0, 0];
+        let rodata = alloc::vec![b'm', b'a', b'i', b'n', 0, 0, 0, 0];
+        // mov r0, 0; exit
+        let text = alloc::vec![
+            0xb7, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let functions = alloc::vec![Symbol {
+            name_offset: 0,
+            flags: 0,
+            location_offset: 0,
+        }];
+        let relocated_calls = alloc::vec![RelocatedCall {
+            instruction_offset: 8,
+            function_text_offset: 0,
+        }];
+        let data_relocations = alloc::vec![DataRelocation {
+            location_region: DataRegion::Data as u16,
+            target_region: DataRegion::Rodata as u16,
+            location_offset: 0,
+            target_offset: 4,
+        }];
+        ExtendedBinary {
+            header: Header {
+                data_len: data.len() as u32,
+                rodata_len: rodata.len() as u32,
+                text_len: text.len() as u32,
+                functions_len: functions.len() as u32,
+                relocated_calls: relocated_calls.len() as u32,
+                data_relocations: data_relocations.len() as u32,
+                bss_len: 16,
+                ..Header::new(HEADER_VERSION)
+            },
+            data,
+            rodata,
+            text,
+            functions,
+            relocated_calls,
+            data_relocations,
+            allowed_helpers: alloc::vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn parse_after_encode_is_identity() {
+        let bytes: Vec<u8> = sample_binary().into();
+
+        let parsed = ExtendedBinary::parse(&bytes).unwrap();
+        let expected = sample_binary();
+        assert_eq!(parsed.data, expected.data);
+        assert_eq!(parsed.rodata, expected.rodata);
+        assert_eq!(parsed.text, expected.text);
+        assert_eq!(parsed.functions, expected.functions);
+        assert_eq!(parsed.relocated_calls, expected.relocated_calls);
+        assert_eq!(parsed.data_relocations, expected.data_relocations);
+        assert_eq!(parsed.allowed_helpers, expected.allowed_helpers);
+        assert_eq!({ parsed.header.bss_len }, 16);
+
+        let encoded: Vec<u8> = parsed.into();
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn parse_rejects_truncated_binary() {
+        let bytes: Vec<u8> = sample_binary().into();
+        // Cutting off the allowed helpers is still a valid binary, anything
+        // shorter than that has to be rejected.
+        let metadata_end = bytes.len() - 3;
+        for len in 0..metadata_end {
+            assert!(ExtendedBinary::parse(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_rejects_section_lengths_exceeding_binary() {
+        let mut binary = sample_binary();
+        binary.header.text_len = u32::MAX;
+        let bytes: Vec<u8> = binary.into();
+        assert!(ExtendedBinary::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_corrupted_payload() {
+        let mut bytes: Vec<u8> = sample_binary().into();
+        let header_size = header_size(HEADER_VERSION);
+        bytes[header_size] ^= 0xff;
+        let error = ExtendedBinary::parse(&bytes).unwrap_err();
+        assert!(matches!(error, ElfUtilsError::ChecksumMismatch { .. }));
+    }
+
+    #[test]
+    fn parse_rejects_newer_version() {
+        let mut binary = sample_binary();
+        binary.header.version = HEADER_VERSION + 1;
+        let bytes: Vec<u8> = binary.into();
+        let error = ExtendedBinary::parse(&bytes).unwrap_err();
+        assert_eq!(
+            error,
+            ElfUtilsError::UnsupportedVersion {
+                found: HEADER_VERSION + 1,
+                latest: HEADER_VERSION
+            }
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_required_flags() {
+        let mut binary = sample_binary();
+        binary.header.flags |= 1 << 15;
+        let bytes: Vec<u8> = binary.into();
+        assert!(ExtendedBinary::parse(&bytes).is_err());
+
+        // Optional flags can be ignored by the reader.
+        let mut binary = sample_binary();
+        binary.header.flags |= 1 << 16;
+        let bytes: Vec<u8> = binary.into();
+        assert!(ExtendedBinary::parse(&bytes).is_ok());
+    }
+
+    #[test]
+    fn parse_accepts_bss_version_without_entry_points() {
+        let mut binary = sample_binary();
+        binary.header.version = HEADER_VERSION_BSS;
+        let bytes: Vec<u8> = binary.into();
+        let parsed = ExtendedBinary::parse(&bytes).unwrap();
+        assert_eq!({ parsed.header.version }, HEADER_VERSION_BSS);
+
+        // The entry points were only introduced in the following version.
+        let mut binary = sample_binary();
+        binary.header.version = HEADER_VERSION_BSS;
+        binary.header.flags |= HEADER_FLAG_ENTRY_POINTS;
+        let bytes: Vec<u8> = binary.into();
+        assert_eq!(
+            ExtendedBinary::parse(&bytes).unwrap_err(),
+            ElfUtilsError::UnsupportedFlags(HEADER_FLAG_ENTRY_POINTS)
+        );
+    }
 
-    text[reloc.r_offset as usize..reloc.r_offset as usize + 16].copy_from_slice((&instr).into());
+    #[test]
+    fn parse_accepts_legacy_version() {
+        let mut binary = unchecked_sample_binary();
+        binary.data_relocations.clear();
+        binary.header = Header {
+            version: HEADER_VERSION_LEGACY,
+            flags: 0,
+            data_relocations: 0,
+            ..binary.header
+        };
+        let bytes: Vec<u8> = binary.into();
+        assert_eq!(&bytes[4..8], &HEADER_VERSION_LEGACY.to_le_bytes());
+        assert_eq!(bytes[header_size(HEADER_VERSION_LEGACY)], 1);
+
+        let parsed = ExtendedBinary::parse(&bytes).unwrap();
+        assert_eq!(parsed.allowed_helpers, alloc::vec![1, 2, 3]);
+    }
+
+    /// Builds an object file with a load of a zero-initialised counter which
+    /// is placed in the .bss section after an initialised variable in .data.
+    fn object_with_bss() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            4,
+            0,
+            alloc::vec![5, 0, 0, 0],
+        );
+        let bss_index = writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 8, 24);
+        let counter = writer.add_symbol(ElfSymbol {
+            name: "counter".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: bss_index as u16,
+            value: 16,
+            size: 4,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: counter,
+                relocation_type: R_BPF_64_64,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_places_bss_after_data() {
+        let bytes =
+            assemble_binary_with_version(&object_with_bss(), alloc::vec![], HEADER_VERSION_BSS)
+                .unwrap();
+        let binary = ExtendedBinary::parse(&bytes).unwrap();
+
+        assert_eq!(binary.data, [5, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!({ binary.header.bss_len }, 24);
+        let lddw = Lddw::from(&binary.text[..LDDW_INSTRUCTION_SIZE]);
+        assert_eq!(lddw.opcode, FC_LDDWD_OPCODE as u8);
+        assert_eq!({ lddw.immediate_l }, 8 + 16);
+
+        let error = assemble_binary_with_version(
+            &object_with_bss(),
+            alloc::vec![],
+            HEADER_VERSION_DATA_RELOCATIONS,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            ElfUtilsError::RequiresNewerVersion {
+                feature: "has zero-initialised variables",
+                version: HEADER_VERSION_BSS
+            }
+        );
+    }
+
+    /// Builds an object file with loads of duplicate string literals and
+    /// constants, the first two loads point to the second "hello" string and
+    /// to its suffix "llo", the third one to the second of the two constants.
+    fn object_with_duplicate_constants() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 12; lddw r2, 14; lddw r3, 8; exit
+        let mut text = alloc::vec![];
+        for (register, offset) in [(1, 12), (2, 14), (3, 8)] {
+            text.extend([0x18, register, 0, 0, offset, 0, 0, 0]);
+            text.extend([0; INSTRUCTION_SIZE]);
+        }
+        text.extend([0x95, 0, 0, 0, 0, 0, 0, 0]);
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let strings_index = writer.add_section(
+            ".rodata.str1.1",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_MERGE | SHF_STRINGS) as u64,
+            1,
+            1,
+            b"hello\0world\0hello\0".to_vec(),
+        );
+        let constants_index = writer.add_section(
+            ".rodata.cst8",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_MERGE) as u64,
+            8,
+            8,
+            [42u64.to_le_bytes(), 42u64.to_le_bytes()].concat(),
+        );
+        let mut relocations = alloc::vec![];
+        for (index, section_index) in [strings_index, strings_index, constants_index]
+            .into_iter()
+            .enumerate()
+        {
+            let symbol_index = writer.add_symbol(ElfSymbol {
+                name: String::new(),
+                info: STT_SECTION,
+                other: 0,
+                section_index: section_index as u16,
+                value: 0,
+                size: 0,
+            });
+            relocations.push(ElfRelocation {
+                offset: (index * LDDW_INSTRUCTION_SIZE) as u64,
+                symbol_index,
+                relocation_type: R_BPF_64_64,
+                addend: 0,
+            });
+        }
+        writer.add_relocations(".rel.text", text_index, relocations, false);
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_merges_duplicate_constants() {
+        let bytes =
+            assemble_binary_specifying_helpers(&object_with_duplicate_constants(), alloc::vec![])
+                .unwrap();
+        let binary = ExtendedBinary::parse(&bytes).unwrap();
+
+        let mut expected_rodata = b"hello\0world\0\0\0\0\0".to_vec();
+        expected_rodata.extend(42u64.to_le_bytes());
+        assert_eq!(binary.rodata, expected_rodata);
+        let immediates = binary
+            .text
+            .chunks(LDDW_INSTRUCTION_SIZE)
+            .take(3)
+            .map(|instruction| {
+                let lddw = Lddw::from(instruction);
+                assert_eq!(lddw.opcode, FC_LDDWR_OPCODE as u8);
+                lddw.immediate_l
+            })
+            .collect::<Vec<u32>>();
+        assert_eq!(immediates, [0, 2, 16]);
+    }
+
+    /// Builds an object file with two global functions, `init` at the start
+    /// of the .text section and `handle_request` after it.
+    fn object_with_two_functions() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // mov r0, 0; exit; mov r0, 1; exit
+        let text = alloc::vec![
+            0xb7, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        for (name, offset) in [("init", 0), ("handle_request", 16)] {
+            writer.add_symbol(ElfSymbol {
+                name: name.to_string(),
+                info: STB_GLOBAL << 4 | STT_FUNC,
+                other: 0,
+                section_index: text_index as u16,
+                value: offset,
+                size: 16,
+            });
+        }
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_exports_entry_points_in_given_order() {
+        let program = object_with_two_functions();
+        let bytes = assemble_binary_with_entry_points(
+            &program,
+            alloc::vec![],
+            HEADER_VERSION,
+            &["handle_request", "init"],
+        )
+        .unwrap();
+        let binary = ExtendedBinary::parse(&bytes).unwrap();
+
+        assert_ne!(binary.header.flags & HEADER_FLAG_ENTRY_POINTS, 0);
+        assert_eq!(binary.entry_points().unwrap(), ["handle_request", "init"]);
+        let offsets = binary
+            .functions
+            .iter()
+            .map(|function| function.location_offset)
+            .collect::<Vec<u16>>();
+        assert_eq!(offsets, [16, 0]);
+
+        let bytes = assemble_binary_specifying_helpers(&program, alloc::vec![]).unwrap();
+        let binary = ExtendedBinary::parse(&bytes).unwrap();
+        assert!(binary.entry_points().unwrap().is_empty());
+
+        let error = assemble_binary_with_entry_points(
+            &program,
+            alloc::vec![],
+            HEADER_VERSION_BSS,
+            &["init"],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error,
+            ElfUtilsError::RequiresNewerVersion {
+                feature: "exports entry points",
+                version: HEADER_VERSION_ENTRY_POINTS
+            }
+        );
+    }
+
+    #[test]
+    fn assemble_produces_legacy_header_by_default() {
+        let bytes = assemble_binary_specifying_helpers(&object_with_two_functions(), alloc::vec![])
+            .unwrap();
+        let binary = ExtendedBinary::parse(&bytes).unwrap();
+
+        assert_eq!({ binary.header.version }, HEADER_VERSION_LEGACY);
+        assert_eq!(header_size(binary.header.version), 32);
+    }
+
+    #[test]
+    fn assemble_rejects_unknown_entry_points() {
+        let error = assemble_binary_with_entry_points(
+            &object_with_two_functions(),
+            alloc::vec![],
+            HEADER_VERSION,
+            &["main"],
+        )
+        .unwrap_err();
+        assert_eq!(error, ElfUtilsError::UnknownEntryPoint("main".to_string()));
+    }
+
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
 }
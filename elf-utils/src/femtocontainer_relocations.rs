@@ -14,38 +14,36 @@
 //!   for formatting). Those strings are inserted into the `.rodata.str.1` section
 //!   and aren't handled by this implementation. The extended version of the patching
 //!   script that I implemented supports those strings.
-//! - No support for calling functions inside of the program that aren't PC-relative.
+//! - No support for calling functions inside of the program, the Femto-Container
+//!   VM only implements calls to helpers. Programs calling their own functions
+//!   which weren't inlined are therefore rejected.
 //! - Extra instructions that aren't present in the eBPF ISA are introduced which
 //!   couples the VM implementation with the behaviour of this script.
 
-use alloc::{
-    ffi::CString,
-    string::{String, ToString},
-    vec::Vec,
-};
-use goblin::{
-    elf::Elf,
-    elf64::sym::{STB_GLOBAL, STT_FUNC},
-};
-use log::debug;
+use alloc::{format, string::String, vec::Vec};
 
 use crate::{
-    common::{get_section_bytes, round_section_length, Symbol},
-    extended_relocations::{append_string_literals, resolve_rodata_relocations},
+    common::{
+        decode_function_names, find_relocations, parse_elf, round_section_length, ByteReader,
+        Symbol, CALL_OPCODE, INSTRUCTION_SIZE, SYMBOL_SIZE,
+    },
+    error::ElfUtilsError,
+    extended_relocations::{
+        extract_function_symbols, find_relocated_calls, merge_bss_sections, merge_data_sections,
+        merge_text_sections, resolve_load_relocations, resolve_pointer_relocations,
+    },
+    helpers::with_helper_symbols_resolved,
+    model::{Call, RelocatedCall, CALL_SRC_PC_RELATIVE},
 };
 
 // In this module a prefix 'FC' is used to indicate that the structs and constants
 // used here are specific to the Femto-Container implementation of the VM.
 pub const FC_HEADER_SIZE: usize = 28;
-
-// Opcodes of the new instructions introduced into the eBPF ISA by the Femto-Containers
-// contribution. The purpose of those instructions is to indicate that a LDDW
-// instruction is supposed to treat the immediate operand as the offset relative
-// to the start of the .data or .rodata section respectively. This is done
-// because the exact memory address of those sections is not known before we
-// load the program into the memory of the microcontroller running the VM.
-pub const FC_LDDWD_OPCODE: u32 = 0xB8;
-pub const FC_LDDWR_OPCODE: u32 = 0xD8;
+pub const FC_HEADER_MAGIC: u32 = 123;
+/// The header layout is dictated by the Femto-Container VM which only supports
+/// this single version and defines no flags, hence unlike the
+/// [`crate::extended_relocations::Header`] it cannot carry a checksum.
+pub const FC_HEADER_VERSION: u32 = 0;
 
 /// The binary generated after the relocation script has the following format:
 /// - Header: Contains the information about the lengths of the remaining sections
@@ -57,27 +55,123 @@ pub const FC_LDDWR_OPCODE: u32 = 0xD8;
 ///   defined inside of the program. Those are maintained by the original
 ///   Femto-Container relocation script, however this metadata isn't used by
 ///   their version of the VM when executing the programs.
+///
+/// The Femto-Container VM doesn't allocate the .bss section, so it is stored
+/// as zeroes at the end of the .data section instead.
+///
+/// Binaries in this layout can be decoded using [`FCBinary::parse`], converting
+/// the parsed struct back into a `Vec<u8>` produces the exact same sequence
+/// of bytes.
+#[derive(Debug)]
 pub struct FCBinary {
-    header: FCHeader,
-    data: Vec<u8>,
-    rodata: Vec<u8>,
-    text: Vec<u8>,
-    functions: Vec<Symbol>,
+    /// Header specifying the lengths of all remaining sections
+    pub header: FCHeader,
+    /// Contents of the .data section
+    pub data: Vec<u8>,
+    /// Contents of the .rodata section together with the string literals
+    /// and function names that were appended to it
+    pub rodata: Vec<u8>,
+    /// Contents of the .text section
+    pub text: Vec<u8>,
+    /// Symbols of all global functions defined in the program
+    pub functions: Vec<Symbol>,
 }
 
 /// A header that is appended at the start of the generated binary. Contains
 /// information about the length of the corresponsing sections in the binary
 /// so that the VM executing the code can access the .rodata and .data sections
 /// properly.
+#[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 pub struct FCHeader {
-    magic: u32,
-    version: u32,
-    flags: u32,
-    data_len: u32,
-    rodata_len: u32,
-    text_len: u32,
-    functions_len: u32,
+    /// Magic number identifying the binary layout
+    pub magic: u32,
+    /// Version of the binary layout
+    pub version: u32,
+    /// Flags, unused by the Femto-Container VM
+    pub flags: u32,
+    /// Length of the .data section in bytes
+    pub data_len: u32,
+    /// Length of the .rodata section in bytes
+    pub rodata_len: u32,
+    /// Length of the .text section in bytes
+    pub text_len: u32,
+    /// Number of function symbol structs
+    pub functions_len: u32,
+}
+
+impl FCHeader {
+    fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        Ok(FCHeader {
+            magic: reader.read_u32()?,
+            version: reader.read_u32()?,
+            flags: reader.read_u32()?,
+            data_len: reader.read_u32()?,
+            rodata_len: reader.read_u32()?,
+            text_len: reader.read_u32()?,
+            functions_len: reader.read_u32()?,
+        })
+    }
+}
+
+impl FCBinary {
+    /// Decodes a binary produced by [`assemble_femtocontainer_binary`].
+    ///
+    /// The magic number, version and flags in the header are validated and all
+    /// section lengths are checked against the size of the input. The binary
+    /// is expected to end immediately after the function symbols.
+    pub fn parse(program: &[u8]) -> Result<FCBinary, ElfUtilsError> {
+        let mut reader = ByteReader::new(program);
+        let header = FCHeader::read(&mut reader)?;
+
+        let (magic, version, flags) = (header.magic, header.version, header.flags);
+        if magic != FC_HEADER_MAGIC {
+            return Err(ElfUtilsError::InvalidMagic {
+                found: magic,
+                expected: FC_HEADER_MAGIC,
+            });
+        }
+        if version != FC_HEADER_VERSION {
+            return Err(ElfUtilsError::UnsupportedVersion {
+                found: version,
+                latest: FC_HEADER_VERSION,
+            });
+        }
+        if flags != 0 {
+            return Err(ElfUtilsError::UnsupportedFlags(flags));
+        }
+
+        let data = reader.read_bytes(header.data_len as usize)?.to_vec();
+        let rodata = reader.read_bytes(header.rodata_len as usize)?.to_vec();
+        let text = reader.read_bytes(header.text_len as usize)?.to_vec();
+
+        let functions_len = header.functions_len as usize;
+        if functions_len.checked_mul(SYMBOL_SIZE) != Some(reader.remaining()) {
+            return Err(ElfUtilsError::Malformed(format!(
+                "Expected {} function symbols at offset {}, found {} bytes",
+                functions_len,
+                reader.position(),
+                reader.remaining()
+            )));
+        }
+        let functions = (0..functions_len)
+            .map(|_| Symbol::read(&mut reader))
+            .collect::<Result<Vec<Symbol>, ElfUtilsError>>()?;
+
+        Ok(FCBinary {
+            header,
+            data,
+            rodata,
+            text,
+            functions,
+        })
+    }
+
+    /// Returns the names of the functions described by the symbol structs
+    /// in the same order as [`FCBinary::functions`].
+    pub fn function_names(&self) -> Result<Vec<String>, ElfUtilsError> {
+        decode_function_names(&self.rodata, &self.functions)
+    }
 }
 
 impl Into<Vec<u8>> for FCBinary {
@@ -105,38 +199,69 @@ impl Into<Vec<u8>> for FCBinary {
 /// Responsible for transforming the raw ELF files generated by llc into the
 /// bespoke format that is used by the Femto-Container implementation of the
 /// eBPF VM. Refer to [`FCBinary`] for more details.
-pub fn assemble_femtocontainer_binary(program: &[u8]) -> Result<Vec<u8>, String> {
-    let Ok(binary) = goblin::elf::Elf::parse(&program) else {
-        return Err("Failed to parse the ELF binary".to_string());
-    };
+///
+/// The fixed header of the layout can't record the size of .bss, so the VM
+/// doesn't allocate it. Instead, the zero-initialised variables are stored as
+/// zeroes at the end of the .data section, which increases the binary size.
+///
+/// Programs which call their own functions are rejected with
+/// [`ElfUtilsError::Unsupported`] as the Femto-Container VM can only call helpers.
+pub fn assemble_femtocontainer_binary(program: &[u8]) -> Result<Vec<u8>, ElfUtilsError> {
+    let program = &with_helper_symbols_resolved(program)?;
+    let binary = parse_elf(program)?;
 
-    // First extract the bytes contained in all three main sections
-    let mut text: Vec<u8> = get_section_bytes(".text", &binary, &program);
-    let mut data: Vec<u8> = get_section_bytes(".data", &binary, &program);
-    let mut rodata: Vec<u8> = get_section_bytes(".rodata", &binary, &program);
+    // First merge all sections containing executable code into a single
+    // text section, programs compiled with -ffunction-sections or using
+    // custom sections place their functions outside of .text.
+    let mut text: Vec<u8> = alloc::vec![];
+    let text_offsets = merge_text_sections(&mut text, &binary, program)?;
 
-    // Now handle all string literals that aren't placed in .rodata
-    // section by default. We need to append them to the .rodata section
-    // and maintain the information about the offsets at which they are
-    // stored so that we can relocate loads from them later on.
-    let str_section_offsets = append_string_literals(&mut rodata, &binary, &program);
+    // Now merge all sections containing the data used by the program (e.g.
+    // string literals placed in .rodata.str1.1) into the .data and .rodata
+    // sections. We need to maintain the information about the offsets at
+    // which they are stored so that we can relocate loads from them later on.
+    let mut data: Vec<u8> = alloc::vec![];
+    let mut rodata: Vec<u8> = alloc::vec![];
+    let mut placements = merge_data_sections(&mut data, &mut rodata, &binary, program)?;
+
+    // The header of the layout is fixed by the Femto-Container VM and can't
+    // specify the length of .bss, so it is stored as zeroes at the end of .data.
+    round_section_length(&mut data);
+    let bss_len = merge_bss_sections(data.len(), &binary, &mut placements)?;
+    data.resize(data.len() + bss_len, 0);
+    round_section_length(&mut data);
 
     // Now we need to collect all global functions and append their names
     // to the rodata section. We also need to maintain the information
     // about the offsets at which the function names are stored.
     // This is maintained for compatibility with the bytecode patching
     // script used by FemtoContainers. It isn't actually used by their VM.
-    let symbol_structs: Vec<Symbol> = extract_function_symbols(&mut rodata, &binary);
+    let symbol_structs: Vec<Symbol> =
+        extract_function_symbols(&mut rodata, &binary, &text_offsets)?;
 
-    resolve_rodata_relocations(&mut text, &binary, &program, &str_section_offsets);
+    let relocations = find_relocations(&binary)?;
+    let relocated_calls = find_relocated_calls(&text, &relocations, &binary, &text_offsets);
+    reject_function_calls(&text, &relocated_calls)?;
+    resolve_load_relocations(&mut text, &relocations, &binary, &text_offsets, &placements)?;
+
+    // The Femto-Container VM has no way of resolving pointers stored in the
+    // data sections at load time, so we reject such programs instead of
+    // producing a binary that would dereference invalid addresses.
+    let data_relocations =
+        resolve_pointer_relocations(&mut data, &mut rodata, &relocations, &binary, &placements)?;
+    if !data_relocations.is_empty() {
+        return Err(ElfUtilsError::Unsupported(
+            "Pointers stored in .data or .rodata aren't supported by the FemtoContainersHeader layout"
+                .into(),
+        ));
+    }
 
-    round_section_length(&mut data);
     round_section_length(&mut rodata);
 
     // Now we write the new binary file
     let header = FCHeader {
-        magic: 123,
-        version: 0,
+        magic: FC_HEADER_MAGIC,
+        version: FC_HEADER_VERSION,
         flags: 0,
         data_len: data.len() as u32,
         rodata_len: rodata.len() as u32,
@@ -155,25 +280,269 @@ pub fn assemble_femtocontainer_binary(program: &[u8]) -> Result<Vec<u8>, String>
     Ok(output_binary.into())
 }
 
-fn extract_function_symbols(rodata: &mut Vec<u8>, binary: &Elf<'_>) -> Vec<Symbol> {
-    let mut symbol_structs: Vec<Symbol> = alloc::vec![];
-    for symbol in binary.syms.iter() {
-        if symbol.st_type() == STT_FUNC && symbol.st_bind() == STB_GLOBAL {
-            let symbol_name = binary.strtab.get_at(symbol.st_name).unwrap();
-
-            debug!("Found global function: {}", symbol_name);
-            let offset_within_text = symbol.st_value as usize;
-            let offset = rodata.len();
-            let name_cstr = CString::new(symbol_name).unwrap();
-            rodata.extend(name_cstr.to_bytes().iter());
-            // Added flags for compatiblity with rbpf
-            let flags = 0;
-            symbol_structs.push(Symbol {
-                name_offset: offset as u16,
-                flags: flags as u16,
-                location_offset: offset_within_text as u16,
-            });
+/// Rejects programs calling their own functions, either through PC-relative
+/// calls or through calls which are relocated because the called function is
+/// global or defined in a different section. The Femto-Container VM only
+/// implements calls to helpers, so such programs would fail once executed.
+fn reject_function_calls(
+    text: &[u8],
+    relocated_calls: &[RelocatedCall],
+) -> Result<(), ElfUtilsError> {
+    // Loads of function addresses are relocated against the same symbols,
+    // those are handled along with the other loads.
+    let relocated = relocated_calls
+        .iter()
+        .map(|relocated_call| relocated_call.instruction_offset as usize)
+        .find(|offset| text.get(*offset) == Some(&(CALL_OPCODE as u8)));
+    let pc_relative = text
+        .chunks(INSTRUCTION_SIZE)
+        .position(|instruction| {
+            instruction[0] == CALL_OPCODE as u8
+                && Call::from(instruction).registers >> 4 == CALL_SRC_PC_RELATIVE
+        })
+        .map(|index| index * INSTRUCTION_SIZE);
+    match relocated.or(pc_relative) {
+        Some(offset) => Err(ElfUtilsError::Unsupported(format!(
+            "The call at offset {} calls a function of the program, which isn't supported \
+             by the FemtoContainersHeader layout as the Femto-Container VM can only call helpers",
+            offset
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{LDDW_INSTRUCTION_SIZE, R_BPF_64_32, R_BPF_64_64},
+        compatibility::check_compatibility,
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+        model::{Lddw, FC_LDDWD_OPCODE},
+    };
+    use alloc::string::ToString;
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS},
+        sym::{STB_GLOBAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+    };
+    use micro_bpf_common::{BinaryFileLayout, TargetVM};
+
+    fn sample_binary() -> FCBinary {
+        let data = alloc::vec![0; 8];
+        let mut rodata = alloc::vec![b'%', b'd', b'\n', 0];
+        rodata.extend(b"mainhelper");
+        round_section_length(&mut rodata);
+        // mov r0, 0; exit
+        let text = alloc::vec![
+            0xb7, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let functions = alloc::vec![
+            Symbol {
+                name_offset: 4,
+                flags: 0,
+                location_offset: 0,
+            },
+            Symbol {
+                name_offset: 8,
+                flags: 0,
+                location_offset: 8,
+            },
+        ];
+        FCBinary {
+            header: FCHeader {
+                magic: FC_HEADER_MAGIC,
+                version: FC_HEADER_VERSION,
+                flags: 0,
+                data_len: data.len() as u32,
+                rodata_len: rodata.len() as u32,
+                text_len: text.len() as u32,
+                functions_len: functions.len() as u32,
+            },
+            data,
+            rodata,
+            text,
+            functions,
+        }
+    }
+
+    #[test]
+    fn encode_after_decode_is_identity() {
+        let bytes: Vec<u8> = sample_binary().into();
+
+        let decoded = FCBinary::parse(&bytes).unwrap();
+        assert_eq!(decoded.functions, sample_binary().functions);
+        assert_eq!(decoded.function_names().unwrap(), ["main", "helper"]);
+
+        let encoded: Vec<u8> = decoded.into();
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn decode_rejects_invalid_header() {
+        let mut binary = sample_binary();
+        binary.header.magic = 0;
+        let bytes: Vec<u8> = binary.into();
+        assert!(FCBinary::parse(&bytes).is_err());
+
+        let mut binary = sample_binary();
+        binary.header.version = FC_HEADER_VERSION + 1;
+        let bytes: Vec<u8> = binary.into();
+        assert!(FCBinary::parse(&bytes).is_err());
+    }
+
+    /// Builds an object file compiled with -ffunction-sections, where `main`
+    /// in .text calls the static function at the start of .text.helper and
+    /// the global function `other` defined in .text.other.
+    fn object_with_function_sections() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        let flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+        // call -1; call -1; exit
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(".text", SHT_PROGBITS, flags, 8, 0, text);
+        // mov r0, 1; exit
+        let helper = alloc::vec![
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let helper_index = writer.add_section(".text.helper", SHT_PROGBITS, flags, 8, 0, helper);
+        // mov r0, 2; exit
+        let other = alloc::vec![
+            0xb7, 0, 0, 0, 2, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let other_index = writer.add_section(".text.other", SHT_PROGBITS, flags, 8, 0, other);
+
+        let helper_section = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STT_SECTION,
+            other: 0,
+            section_index: helper_index as u16,
+            value: 0,
+            size: 0,
+        });
+        let other_function = writer.add_symbol(ElfSymbol {
+            name: "other".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: other_index as u16,
+            value: 0,
+            size: 16,
+        });
+        let relocations = [(0, helper_section), (8, other_function)]
+            .into_iter()
+            .map(|(offset, symbol_index)| ElfRelocation {
+                offset,
+                symbol_index,
+                relocation_type: R_BPF_64_32,
+                addend: 0,
+            })
+            .collect();
+        writer.add_relocations(".rel.text", text_index, relocations, false);
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_rejects_calls_between_function_sections() {
+        let error = assemble_femtocontainer_binary(&object_with_function_sections()).unwrap_err();
+
+        assert!(matches!(error, ElfUtilsError::Unsupported(_)));
+        assert!(error.to_string().contains("offset 0"));
+    }
+
+    #[test]
+    fn assemble_rejects_pc_relative_calls() {
+        let mut writer = ElfWriter::new();
+        // call 1; exit; mov r0, 1; exit
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+        writer.add_section(".text", SHT_PROGBITS, flags, 8, 0, text);
+
+        let error = assemble_femtocontainer_binary(&writer.write()).unwrap_err();
+
+        assert!(matches!(error, ElfUtilsError::Unsupported(_)));
+    }
+
+    /// Builds an object file whose only variable `counter` is zero-initialised
+    /// and placed at offset 8 of the .bss section.
+    fn object_with_bss() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+        let text_index = writer.add_section(".text", SHT_PROGBITS, flags, 8, 0, text);
+        let bss_index = writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 8, 16);
+        let counter = writer.add_symbol(ElfSymbol {
+            name: "counter".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: bss_index as u16,
+            value: 8,
+            size: 8,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: counter,
+                relocation_type: R_BPF_64_64,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_stores_bss_as_zeroes_in_data() {
+        let bytes = assemble_femtocontainer_binary(&object_with_bss()).unwrap();
+        let binary = FCBinary::parse(&bytes).unwrap();
+
+        assert_eq!({ binary.header.version }, FC_HEADER_VERSION);
+        assert_eq!(binary.data, [0; 16]);
+        let lddw = Lddw::from(&binary.text[..LDDW_INSTRUCTION_SIZE]);
+        assert_eq!(lddw.opcode, FC_LDDWD_OPCODE as u8);
+        assert_eq!({ lddw.immediate_l }, 8);
+    }
+
+    // Everything produced by the assembler has to be accepted by the
+    // compatibility check which is run before the program is deployed.
+    #[test]
+    fn assembled_binary_is_compatible_with_the_vm() {
+        let bytes = assemble_femtocontainer_binary(&object_with_bss()).unwrap();
+        let incompatibilities = check_compatibility(
+            &bytes,
+            TargetVM::FemtoContainer,
+            BinaryFileLayout::FemtoContainersHeader,
+        )
+        .unwrap();
+
+        assert!(incompatibilities.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_mismatched_section_lengths() {
+        let mut bytes: Vec<u8> = sample_binary().into();
+        bytes.push(0);
+        assert!(FCBinary::parse(&bytes).is_err());
+
+        let bytes: Vec<u8> = sample_binary().into();
+        for len in 0..bytes.len() {
+            assert!(FCBinary::parse(&bytes[..len]).is_err());
         }
     }
-    symbol_structs
 }
@@ -0,0 +1,466 @@
+//! Inference of the helper functions called by a program. The result can be
+//! used as the list of helpers that the program is allowed to call instead
+//! of specifying it manually, which follows the principle of least privilege.
+//!
+//! The calls to the helpers declared as `extern` functions are also resolved
+//! here, which replaces the pointers initialised with the helper IDs.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::ToString,
+    vec::Vec,
+};
+use goblin::elf::section_header::{SHF_EXECINSTR, SHN_UNDEF};
+use log::debug;
+use micro_bpf_common::{BinaryFileLayout, HelperFunctionID};
+
+use crate::{
+    common::{
+        checked_range, find_relocations, parse_elf, section_bytes, CALL_OPCODE, INSTRUCTION_SIZE,
+        R_BPF_64_32, R_BPF_NONE,
+    },
+    error::{ElfUtilsError, ErrorLocation},
+    extended_relocations::ExtendedBinary,
+    femtocontainer_relocations::FCBinary,
+    model::{decode_instructions, Call, ImageSection, PatchKind},
+    pre_resolved_relocations::PreResolvedBinary,
+};
+
+/// Helper functions called by a program, see [`infer_required_helpers`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequiredHelpers {
+    /// Helpers called by the program, sorted by their IDs
+    pub helpers: Vec<HelperFunctionID>,
+    /// Calls to the IDs which don't correspond to any [`HelperFunctionID`],
+    /// sorted by their IDs
+    pub unknown: Vec<UnknownHelperCall>,
+}
+
+/// A call to a helper with an ID which isn't defined in [`HelperFunctionID`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownHelperCall {
+    /// The immediate operand of the `call` instruction
+    pub id: u32,
+    /// Offset of the first call with this ID in its text section
+    pub offset: usize,
+}
+
+impl RequiredHelpers {
+    /// Indices of the called helpers in the format expected by the CLI and
+    /// [`crate::assemble_binary_specifying_helpers`].
+    pub fn indices(&self) -> Vec<u8> {
+        self.helpers.iter().map(|helper| (*helper).into()).collect()
+    }
+
+    /// Returns an error listing the unknown helper IDs if there are any. The
+    /// VM would reject calls to those helpers at runtime anyway.
+    pub fn check_unknown(&self) -> Result<(), ElfUtilsError> {
+        let Some(first) = self.unknown.first() else {
+            return Ok(());
+        };
+        let ids = self
+            .unknown
+            .iter()
+            .map(|call| alloc::format!("{:#x}", call.id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(ElfUtilsError::Unsupported(alloc::format!(
+            "The program calls unknown helpers: {}",
+            ids
+        ))
+        .at(ErrorLocation {
+            offset: Some(first.offset),
+            ..Default::default()
+        }))
+    }
+}
+
+/// Collects the IDs of all helper functions called by a program in a binary
+/// with the given layout (the object file produced by the compiler uses the
+/// [`BinaryFileLayout::RawObjectFile`] layout).
+///
+/// A `call imm` instruction with the src register set to 0 calls the helper
+/// with the ID `imm`. The calls with the src register set to 1 (PC-relative
+/// calls) and 3 (calls resolved on the device) call the functions of the
+/// program instead. The same applies to the `call -1` instructions which are
+/// the targets of function call relocations, those are excluded based on the
+/// relocations of the object file or the [`crate::model::RelocatedCall`]
+/// entries of the [`BinaryFileLayout::ExtendedHeader`] layout.
+pub fn infer_required_helpers(
+    program: &[u8],
+    layout: BinaryFileLayout,
+) -> Result<RequiredHelpers, ElfUtilsError> {
+    let mut calls = HelperCalls::default();
+    match layout {
+        BinaryFileLayout::OnlyTextSection => calls.collect(program, &BTreeSet::new())?,
+        BinaryFileLayout::FemtoContainersHeader => {
+            let binary = FCBinary::parse(program)?;
+            calls.collect(&binary.text, &BTreeSet::new())?
+        }
+        BinaryFileLayout::ExtendedHeader => {
+            let binary = ExtendedBinary::parse(program)?;
+            let relocated_calls = binary
+                .relocated_calls
+                .iter()
+                .map(|call| call.instruction_offset as usize)
+                .collect();
+            calls.collect(&binary.text, &relocated_calls)?
+        }
+        BinaryFileLayout::RawObjectFile => {
+            let program = &with_helper_symbols_resolved(program)?;
+            let binary = parse_elf(program)?;
+            let relocations = find_relocations(&binary)?;
+            for (index, section) in binary.section_headers.iter().enumerate() {
+                if section.sh_flags & SHF_EXECINSTR as u64 == 0 {
+                    continue;
+                }
+                let relocated_calls = relocations
+                    .iter()
+                    .filter(|relocation| {
+                        relocation.section_index == index && relocation.reloc.r_type == R_BPF_64_32
+                    })
+                    .map(|relocation| relocation.reloc.r_offset as usize)
+                    .collect();
+                calls.collect(section_bytes(section, program)?, &relocated_calls)?
+            }
+        }
+        BinaryFileLayout::PreResolvedRelocations => {
+            let binary = PreResolvedBinary::parse(program)?;
+            let text_start = binary.header.section_range(ImageSection::Text).start;
+            let relocated_calls = binary
+                .relocations
+                .iter()
+                .filter(|relocation| relocation.kind == PatchKind::Call as u8)
+                .map(|relocation| (relocation.offset as usize).wrapping_sub(text_start))
+                .collect();
+            calls.collect(&binary.text, &relocated_calls)?
+        }
+    }
+    debug!("Helper calls found in the program: {:?}", calls.ids);
+
+    let mut required = RequiredHelpers::default();
+    for (id, offset) in calls.ids {
+        match HelperFunctionID::try_from(id) {
+            Ok(helper) => required.helpers.push(helper),
+            Err(id) => required.unknown.push(UnknownHelperCall { id, offset }),
+        }
+    }
+    required.helpers.sort();
+    Ok(required)
+}
+
+/// IDs of the called helpers mapped to the offset of the first call.
+#[derive(Default)]
+struct HelperCalls {
+    ids: BTreeMap<u32, usize>,
+}
+
+impl HelperCalls {
+    fn collect(
+        &mut self,
+        text: &[u8],
+        relocated_calls: &BTreeSet<usize>,
+    ) -> Result<(), ElfUtilsError> {
+        for instruction in decode_instructions(text)? {
+            if !instruction.is_call()
+                || instruction.src != 0
+                || relocated_calls.contains(&instruction.offset)
+            {
+                continue;
+            }
+            self.ids
+                .entry(instruction.imm as u32)
+                .or_insert(instruction.offset);
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the calls to the helper functions declared as undefined `extern`
+/// functions, e.g. `extern void bpf_printf(const char *fmt, ...);`, instead of
+/// pointers initialised with the IDs of the helpers. This way the IDs are
+/// only known to [`HelperFunctionID`] and they can be changed without
+/// recompiling the programs.
+///
+/// The compiler emits such calls as `call -1` instructions with a function
+/// call relocation against the undefined symbol. Each of them is rewritten in
+/// place to call the helper whose [`HelperFunctionID::symbol_name`] matches the
+/// name of the symbol, and its relocation is turned into an [`R_BPF_NONE`]
+/// relocation which is ignored when the object file is processed further.
+/// Calls to undefined functions which aren't helpers are rejected.
+///
+/// Returns the number of resolved calls.
+pub fn resolve_helper_symbols(program: &mut [u8]) -> Result<usize, ElfUtilsError> {
+    let binary = parse_elf(program)?;
+
+    // The locations are validated before anything is modified, so that the
+    // object file is left untouched if any of the calls can't be resolved.
+    let mut calls = Vec::new();
+    for relocation in find_relocations(&binary)? {
+        if relocation.reloc.r_type != R_BPF_64_32 {
+            continue;
+        }
+        let Some(symbol) = binary.syms.get(relocation.reloc.r_sym) else {
+            continue;
+        };
+        if symbol.st_shndx != SHN_UNDEF as usize {
+            continue;
+        }
+        let location = || relocation.location(&binary);
+        let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
+        let Some(helper) = HelperFunctionID::from_symbol_name(name) else {
+            return Err(ElfUtilsError::UnknownHelperSymbol(name.to_string()).at(location()));
+        };
+        if !binary.is_64 || !binary.little_endian {
+            return Err(ElfUtilsError::UnsupportedElf(
+                "only 64-bit little-endian ELF files are supported",
+            ));
+        }
+        let offset = relocation
+            .section_offset
+            .saturating_add(relocation.reloc.r_offset as usize);
+        let instruction = checked_range(offset, INSTRUCTION_SIZE, program.len())
+            .map_err(|error| error.at(location()))?;
+        if program[instruction.start] != CALL_OPCODE as u8 {
+            return Err(ElfUtilsError::InstructionMismatch {
+                expected: "call",
+                offset,
+            }
+            .at(location()));
+        }
+        // The type of the relocation is stored in the lower half of the r_info
+        // field which follows r_offset in both REL and RELA entries.
+        let info = checked_range(relocation.entry_offset.saturating_add(8), 4, program.len())
+            .map_err(|error| error.at(location()))?;
+        debug!(
+            "Resolving the call of {} at offset {:#x} to helper {:?}",
+            name, offset, helper
+        );
+        calls.push((instruction, info, helper));
+    }
+
+    for (instruction, info, helper) in &calls {
+        let mut call = Call::from(&program[instruction.clone()]);
+        call.registers = 0;
+        call.immediate = *helper as u32;
+        program[instruction.clone()].copy_from_slice((&call).into());
+        program[info.clone()].copy_from_slice(&R_BPF_NONE.to_le_bytes());
+    }
+    Ok(calls.len())
+}
+
+/// Returns a copy of the object file with the calls to the helpers declared as
+/// `extern` functions resolved, see [`resolve_helper_symbols`]. It is used by
+/// the functions processing the object files on the host.
+pub fn with_helper_symbols_resolved(program: &[u8]) -> Result<Vec<u8>, ElfUtilsError> {
+    let mut program = program.to_vec();
+    resolve_helper_symbols(&mut program)?;
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::extract_section,
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+        extended_relocations::assemble_binary,
+        pre_resolved_relocations::assemble_pre_resolved_binary,
+    };
+    use alloc::string::ToString;
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHT_PROGBITS},
+        sym::{STB_GLOBAL, STT_FUNC, STT_NOTYPE},
+    };
+
+    /// Builds an object file calling bpf_printf twice, bpf_memcpy, an unknown
+    /// helper and a global function, once through a relocated `call -1` with
+    /// the src register set to 0 and once through a PC-relative call.
+    fn sample_object() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // call 1; call 2; call 1; call 0x99; call -1; call 1 (src=1); exit;
+        // mov r0, 0; exit
+        let text = alloc::vec![
+            0x85, 0, 0, 0, 1, 0, 0, 0, //
+            0x85, 0, 0, 0, 2, 0, 0, 0, //
+            0x85, 0, 0, 0, 1, 0, 0, 0, //
+            0x85, 0, 0, 0, 0x99, 0, 0, 0, //
+            0x85, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x85, 0x10, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            0xb7, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 56,
+        });
+        let function = writer.add_symbol(ElfSymbol {
+            name: "function".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 56,
+            size: 16,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 32,
+                symbol_index: function,
+                relocation_type: R_BPF_64_32,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    fn expected() -> RequiredHelpers {
+        RequiredHelpers {
+            helpers: alloc::vec![
+                HelperFunctionID::BPF_PRINTF_IDX,
+                HelperFunctionID::BPF_MEMCPY_IDX
+            ],
+            unknown: alloc::vec![UnknownHelperCall {
+                id: 0x99,
+                offset: 24
+            }],
+        }
+    }
+
+    #[test]
+    fn helpers_are_inferred_from_object_files() {
+        let required =
+            infer_required_helpers(&sample_object(), BinaryFileLayout::RawObjectFile).unwrap();
+        assert_eq!(required, expected());
+        assert_eq!(required.indices(), [1, 2]);
+    }
+
+    #[test]
+    fn helpers_are_inferred_from_all_layouts() {
+        let object = sample_object();
+        let binaries = [
+            (
+                assemble_binary(&object).unwrap(),
+                BinaryFileLayout::ExtendedHeader,
+            ),
+            (
+                assemble_pre_resolved_binary(&object).unwrap(),
+                BinaryFileLayout::PreResolvedRelocations,
+            ),
+        ];
+        for (binary, layout) in binaries {
+            assert_eq!(infer_required_helpers(&binary, layout).unwrap(), expected());
+        }
+
+        // Without the relocations, the `call -1` looks like a call to a helper.
+        let text = extract_section(".text", &object).unwrap();
+        let required = infer_required_helpers(text, BinaryFileLayout::OnlyTextSection).unwrap();
+        assert_eq!(required.unknown.len(), 2);
+    }
+
+    #[test]
+    fn unknown_helpers_are_reported() {
+        let error = expected().check_unknown().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The program calls unknown helpers: 0x99 (offset 0x18)"
+        );
+        assert!(RequiredHelpers::default().check_unknown().is_ok());
+    }
+
+    /// Builds an object file calling the given undefined function through a
+    /// relocated `call -1`, as produced for the helpers declared as `extern`.
+    fn object_calling_extern(name: &str) -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // call -1; exit
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 16,
+        });
+        let helper = writer.add_symbol(ElfSymbol {
+            name: name.to_string(),
+            info: STB_GLOBAL << 4 | STT_NOTYPE,
+            other: 0,
+            section_index: SHN_UNDEF as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: helper,
+                relocation_type: R_BPF_64_32,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn extern_helpers_are_resolved_by_name() {
+        let mut object = object_calling_extern("bpf_saul_reg_read");
+        assert_eq!(resolve_helper_symbols(&mut object).unwrap(), 1);
+        // Once resolved, the relocation is ignored and nothing is left to resolve.
+        assert_eq!(resolve_helper_symbols(&mut object).unwrap(), 0);
+
+        let text = extract_section(".text", &object).unwrap();
+        assert_eq!(&text[..8], [0x85, 0, 0, 0, 0x32, 0, 0, 0]);
+        let binary = parse_elf(&object).unwrap();
+        let relocations = find_relocations(&binary).unwrap();
+        assert_eq!(relocations[0].reloc.r_type, R_BPF_NONE);
+
+        let object = object_calling_extern("bpf_printf");
+        let binary = assemble_pre_resolved_binary(&object).unwrap();
+        let required =
+            infer_required_helpers(&binary, BinaryFileLayout::PreResolvedRelocations).unwrap();
+        assert_eq!(required.helpers, [HelperFunctionID::BPF_PRINTF_IDX]);
+        assert_eq!(PreResolvedBinary::parse(&binary).unwrap().relocations, []);
+    }
+
+    #[test]
+    fn unknown_extern_functions_are_rejected() {
+        let mut object = object_calling_extern("printf");
+        let original = object.clone();
+        let error = resolve_helper_symbols(&mut object).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The program calls the undefined function printf which isn't a known helper (relocation 0, .text+0x0, function main, symbol printf)"
+        );
+        assert_eq!(object, original);
+        assert!(assemble_binary(&object).is_err());
+    }
+}
@@ -25,16 +25,60 @@
 //!   in the eBPF ISA.
 //! - Applying extended AOT relocations to allow for calling non-static functions
 //!   inside of the eBPF programs (adds support for non-PC-relative function calls)
+//!   and for pointers stored inside of the `.data` and `.rodata` sections, which
+//!   are resolved by the VM when the program is loaded
 //!
 //! The second workflow that supported by the library involves sending raw ELF
 //! object files to the target microcontroller device and performing relocations
 //! there once the program memory address is known. This allows for achieving
 //! the best compatibility, however it comes with a performance overhead of
 //! parsing the ELF file on the device each time the program is loaded.
+//! The relocations can also be resolved for a program which stays in flash
+//! with only its writable sections copied into RAM (see
+//! [`resolve_relocations_split`]). Devices with little memory can use the
+//! [`StreamingResolver`] to apply the relocations as the program is received.
 //!
 //! In order to support the second type of the relocation workflow, this library
 //! supports `no_std`.
 //!
+//! Programs split across multiple source files can be linked into a single
+//! object file using [`link_objects`] before they are processed using any of
+//! the above workflows. The functions which are never called and the read-only
+//! data which is never used can be removed from the object file using
+//! [`eliminate_dead_code`] to reduce the size of the resulting binary.
+//! A program can also export multiple named entry points (see
+//! [`assemble_binary_with_entry_points`]), one of which is then selected when
+//! the program is executed.
+//!
+//! The third workflow is a compromise between the two: the relocations are
+//! resolved on the host up to the address at which the program is loaded and
+//! stored in a compact table appended to the sections of the program. The device
+//! then only needs to add that address to each entry of the table, which doesn't
+//! require parsing the ELF file (see [`resolve_pre_resolved_relocations`]).
+//!
+//! The helper functions called by a program can be inferred from its bytecode
+//! (see [`infer_required_helpers`]), which allows for restricting the program
+//! to only the helpers it actually needs. The programs can declare the helpers
+//! as `extern` functions, the calls to them are resolved by name when the
+//! object file is processed (see [`resolve_helper_symbols`]).
+//!
+//! Before a program is deployed, the control flow of its bytecode can be
+//! checked using [`verify_binary`] and [`check_compatibility`] checks that the
+//! target VM supports its layout and all of its instructions. The worst case stack usage of its call
+//! graph can be computed using [`analyse_binary_stack_usage`] to make sure
+//! that it fits in the fixed-size stack of the VM. The memory regions accessed
+//! by the program can be determined using [`analyse_binary_memory_access`],
+//! which allows for allowing the program to access only those regions.
+//!
+//! All functions processing the object files and the binaries report malformed
+//! input using [`ElfUtilsError`] instead of panicking, as the parsing on the
+//! device is performed on programs received over the network.
+//!
+//! Everything that needs to parse ELF files is gated behind the `elf` feature,
+//! which is enabled by default. Without it, the crate doesn't depend on goblin
+//! and only provides the model of the binary layouts together with
+//! [`resolve_pre_resolved_relocations`], which is all that the VM needs.
+//!
 //! The crate has been instrumented with debug print statements which can be
 //! controlled by using an implementation of a logging library such as e.g.
 //! env_logger. The reason raw print statements aren't used is to maintain the
@@ -43,16 +87,107 @@ extern crate alloc;
 extern crate rbpf;
 
 mod common;
+#[cfg(feature = "elf")]
+mod compatibility;
+#[cfg(feature = "elf")]
+mod dead_code;
+#[cfg(feature = "elf")]
+mod disassembler;
+#[cfg(feature = "elf")]
+mod elf_writer;
+mod error;
+#[cfg(feature = "elf")]
 mod extended_relocations;
+#[cfg(feature = "elf")]
 mod femtocontainer_relocations;
+#[cfg(feature = "elf")]
+mod helpers;
+#[cfg(feature = "elf")]
+mod linker;
+#[cfg(feature = "elf")]
+mod memory_access;
 mod model;
+mod pre_resolved_relocations;
+#[cfg(feature = "elf")]
 mod relocation_resolution;
+#[cfg(feature = "elf")]
+mod stack_usage;
+#[cfg(feature = "elf")]
+mod streaming_resolution;
+#[cfg(feature = "elf")]
+mod stripper;
+#[cfg(feature = "elf")]
+mod verifier;
 
 // Only the below functions are exposed to the users of this library.
 pub use common::debug_print_program_bytes;
+#[cfg(feature = "elf")]
 pub use common::extract_section;
+#[cfg(feature = "elf")]
+pub use compatibility::{check_compatibility, BytecodeFeature, Incompatibility};
+#[cfg(feature = "elf")]
+pub use dead_code::{eliminate_dead_code, DeadCodeReport};
+#[cfg(feature = "elf")]
+pub use disassembler::{
+    disassemble, disassemble_binary, format_instruction, text_with_symbols, SymbolMap,
+};
+#[cfg(feature = "elf")]
+pub use elf_writer::{ElfRelocation, ElfSymbol, ElfWriter};
+pub use error::{ElfUtilsError, ErrorLocation};
+#[cfg(feature = "elf")]
 pub use extended_relocations::assemble_binary;
+#[cfg(feature = "elf")]
 pub use extended_relocations::assemble_binary_specifying_helpers;
+#[cfg(feature = "elf")]
+pub use extended_relocations::assemble_binary_with_entry_points;
+#[cfg(feature = "elf")]
+pub use extended_relocations::assemble_binary_with_version;
+#[cfg(feature = "elf")]
 pub use extended_relocations::extract_allowed_helpers;
+#[cfg(feature = "elf")]
+pub use extended_relocations::{
+    header_size, ExtendedBinary, Header, DEFAULT_HEADER_VERSION, HEADER_FLAG_CHECKSUM,
+    HEADER_FLAG_ENTRY_POINTS, HEADER_MAGIC, HEADER_VERSION, HEADER_VERSION_BSS,
+    HEADER_VERSION_CHECKSUM, HEADER_VERSION_DATA_RELOCATIONS, HEADER_VERSION_ENTRY_POINTS,
+    HEADER_VERSION_LEGACY,
+};
+#[cfg(feature = "elf")]
 pub use femtocontainer_relocations::assemble_femtocontainer_binary;
-pub use relocation_resolution::resolve_relocations;
+#[cfg(feature = "elf")]
+pub use femtocontainer_relocations::{FCBinary, FCHeader};
+#[cfg(feature = "elf")]
+pub use helpers::{
+    infer_required_helpers, resolve_helper_symbols, RequiredHelpers, UnknownHelperCall,
+};
+#[cfg(feature = "elf")]
+pub use linker::link_objects;
+#[cfg(feature = "elf")]
+pub use memory_access::{
+    analyse_binary_memory_access, analyse_memory_access, MemoryAccessReport, MemoryRegion,
+    RegionAccess, UnknownAccess,
+};
+pub use model::{
+    decode_instructions, DataRegion, DataRelocation, ImageSection, Instruction, PatchKind,
+    PreResolvedRelocation,
+};
+#[cfg(feature = "elf")]
+pub use pre_resolved_relocations::assemble_pre_resolved_binary;
+pub use pre_resolved_relocations::{
+    resolve_pre_resolved_relocations, resolve_pre_resolved_relocations_at, PreResolvedBinary,
+    PreResolvedHeader, PRE_RESOLVED_HEADER_MAGIC, PRE_RESOLVED_HEADER_SIZE,
+    PRE_RESOLVED_HEADER_VERSION,
+};
+#[cfg(feature = "elf")]
+pub use relocation_resolution::{
+    resolve_relocations, resolve_relocations_split, LoadAddresses, RamRegion, SectionCopy,
+};
+#[cfg(feature = "elf")]
+pub use stack_usage::{
+    analyse_binary_stack_usage, analyse_stack_usage, FunctionStackUsage, StackUsage, VM_STACK_SIZE,
+};
+#[cfg(feature = "elf")]
+pub use streaming_resolution::StreamingResolver;
+#[cfg(feature = "elf")]
+pub use stripper::{strip_binary, StripReport};
+#[cfg(feature = "elf")]
+pub use verifier::{verify, verify_binary, Diagnostic, DiagnosticKind};
@@ -0,0 +1,641 @@
+//! Static linking of multiple eBPF object files into a single one.
+//!
+//! Each program is compiled from a single source file into a relocatable
+//! object file, so the code shared between the programs (e.g. checksums or
+//! formatting utilities) would otherwise need to be included in each of the
+//! source files. The linker merges the sections of the object files with the
+//! same names and resolves the symbols referenced across the files. The result
+//! is a relocatable object file which can be processed in the same way as the
+//! files produced by the compiler, e.g. using [`crate::assemble_binary`].
+use alloc::{
+    collections::btree_map::BTreeMap as HashMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use goblin::elf::{
+    section_header::{SHN_ABS, SHN_COMMON, SHN_UNDEF, SHT_NOBITS},
+    sym::{Sym, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_NOTYPE, STT_SECTION},
+    Elf,
+};
+use log::debug;
+use micro_bpf_common::HelperFunctionID;
+
+use crate::{
+    common::{
+        adjust_implicit_addend, find_relocations, parse_elf, section_alignment, section_bytes,
+        Relocation, MAX_ZERO_INITIALISED_SIZE, R_BPF_NONE,
+    },
+    elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+    error::{ElfUtilsError, ErrorLocation},
+    stripper::is_required_section,
+};
+
+/// Links the given relocatable object files into a single object file.
+///
+/// The sections which are loaded into memory are concatenated with the
+/// sections of the same name from the other files, respecting their alignment,
+/// and the sections which aren't needed for executing the program (e.g. the
+/// debug information) are removed, as in [`crate::strip_binary`]. The symbols
+/// and relocations are moved along with their sections. The relocations
+/// against section symbols store their offset within the section in the
+/// relocated location, so it is adjusted by the offset at which the section
+/// was placed in the merged section.
+///
+/// Each undefined symbol is resolved to the global symbol with the same name
+/// defined in one of the files, a weak definition is only used if there is no
+/// other one. The undefined symbols named after the helper functions are kept,
+/// they are resolved when the object file is processed further (see
+/// [`crate::resolve_helper_symbols`]). The global symbols defined in more than
+/// one file and the symbols which aren't defined in any of them are reported
+/// as errors, with the location pointing to the index of the object file.
+pub fn link_objects(objects: &[&[u8]]) -> Result<Vec<u8>, ElfUtilsError> {
+    let binaries = objects
+        .iter()
+        .enumerate()
+        .map(|(object, program)| {
+            let binary = parse_elf(program).map_err(|error| error.at(in_object(object)))?;
+            if !binary.is_64 || !binary.little_endian {
+                return Err(ElfUtilsError::UnsupportedElf(
+                    "only 64-bit little-endian ELF files can be linked",
+                )
+                .at(in_object(object)));
+            }
+            Ok(binary)
+        })
+        .collect::<Result<Vec<Elf>, ElfUtilsError>>()?;
+
+    let mut sections = OutputSections::default();
+    for (object, (binary, program)) in binaries.iter().zip(objects).enumerate() {
+        sections
+            .add_object(object, binary, program)
+            .map_err(|error| error.at(in_object(object)))?;
+    }
+    let symbols = link_symbols(&binaries, &sections)?;
+
+    for (object, binary) in binaries.iter().enumerate() {
+        for relocation in find_relocations(binary).map_err(|error| error.at(in_object(object)))? {
+            sections
+                .add_relocation(object, binary, &relocation, &symbols)
+                .map_err(|error| error.at(relocation.location(binary)).at(in_object(object)))?;
+        }
+    }
+
+    let mut writer = ElfWriter::new();
+    for section in &sections.sections {
+        if section.sh_type == SHT_NOBITS {
+            writer.add_nobits_section(
+                &section.name,
+                section.flags,
+                section.alignment,
+                section.size,
+            );
+        } else {
+            writer.add_section(
+                &section.name,
+                section.sh_type,
+                section.flags,
+                section.alignment,
+                section.entry_size,
+                section.data.clone(),
+            );
+        }
+    }
+    for symbol in symbols.symbols {
+        writer.add_symbol(symbol);
+    }
+    for (index, section) in sections.sections.into_iter().enumerate() {
+        if section.relocations.is_empty() {
+            continue;
+        }
+        let explicit_addends = section.explicit_addends == Some(true);
+        let prefix = if explicit_addends { ".rela" } else { ".rel" };
+        writer.add_relocations(
+            &format!("{}{}", prefix, section.name),
+            index + 1,
+            section.relocations,
+            explicit_addends,
+        );
+    }
+    Ok(writer.write())
+}
+
+fn in_object(object: usize) -> ErrorLocation {
+    ErrorLocation {
+        object: Some(object),
+        ..Default::default()
+    }
+}
+
+/// A section of the output file together with the relocations applying to it.
+struct OutputSection {
+    name: String,
+    sh_type: u32,
+    flags: u64,
+    alignment: u64,
+    entry_size: u64,
+    data: Vec<u8>,
+    size: u64,
+    relocations: Vec<ElfRelocation>,
+    /// Whether the relocations are `SHT_RELA` ones, it is `None` until the
+    /// first relocation is added.
+    explicit_addends: Option<bool>,
+}
+
+/// The sections of the output file, the section with index `i` has index
+/// `i + 1` in the output file as the null section comes first.
+#[derive(Default)]
+struct OutputSections {
+    sections: Vec<OutputSection>,
+    /// Maps the object index and the index of a section in that object to
+    /// the index of the output section and the offset within it.
+    placements: HashMap<(usize, usize), (usize, u64)>,
+}
+
+impl OutputSections {
+    /// Appends the sections of the object file needed for executing the
+    /// program to the output sections with the same names.
+    fn add_object(
+        &mut self,
+        object: usize,
+        binary: &Elf,
+        program: &[u8],
+    ) -> Result<(), ElfUtilsError> {
+        for (index, section) in binary.section_headers.iter().enumerate().skip(1) {
+            let name = binary.strtab.get_at(section.sh_name).unwrap_or_default();
+            if !is_required_section(section, name) {
+                continue;
+            }
+            let alignment = section_alignment(section)? as u64;
+            let output_index = match self.sections.iter().position(|output| output.name == name) {
+                Some(output_index) => output_index,
+                None => {
+                    self.sections.push(OutputSection {
+                        name: name.to_string(),
+                        sh_type: section.sh_type,
+                        flags: section.sh_flags,
+                        alignment,
+                        entry_size: section.sh_entsize,
+                        data: Vec::new(),
+                        size: 0,
+                        relocations: Vec::new(),
+                        explicit_addends: None,
+                    });
+                    self.sections.len() - 1
+                }
+            };
+            let output = &mut self.sections[output_index];
+            if output.sh_type != section.sh_type {
+                return Err(ElfUtilsError::Malformed(format!(
+                    "The section {} has different types in the linked objects",
+                    name
+                )));
+            }
+            output.flags |= section.sh_flags;
+            output.alignment = output.alignment.max(alignment);
+            if output.entry_size != section.sh_entsize {
+                output.entry_size = 0;
+            }
+
+            let offset = output.size.next_multiple_of(alignment);
+            if section.sh_type == SHT_NOBITS {
+                if offset.saturating_add(section.sh_size) > MAX_ZERO_INITIALISED_SIZE as u64 {
+                    return Err(ElfUtilsError::InvalidValue {
+                        field: "size of the zero-initialised sections",
+                        value: section.sh_size,
+                    });
+                }
+                output.size = offset + section.sh_size;
+            } else {
+                output.data.resize(offset as usize, 0);
+                output.data.extend(section_bytes(section, program)?);
+                output.size = output.data.len() as u64;
+            }
+            debug!(
+                "Placing section {} of object {} at offset {:#x} of the merged section",
+                name, object, offset
+            );
+            self.placements
+                .insert((object, index), (output_index, offset));
+        }
+        Ok(())
+    }
+
+    /// Copies the relocation into the output section containing its location.
+    /// The relocations applying to the removed sections are dropped.
+    fn add_relocation(
+        &mut self,
+        object: usize,
+        binary: &Elf,
+        relocation: &Relocation,
+        symbols: &LinkedSymbols,
+    ) -> Result<(), ElfUtilsError> {
+        let reloc = &relocation.reloc;
+        let Some(&(output_index, section_offset)) =
+            self.placements.get(&(object, relocation.section_index))
+        else {
+            return Ok(());
+        };
+        if reloc.r_type == R_BPF_NONE {
+            return Ok(());
+        }
+        let Some(target) = symbols.targets.get(&(object, reloc.r_sym)) else {
+            return Err(if binary.syms.get(reloc.r_sym).is_none() {
+                ElfUtilsError::InvalidSymbolIndex(reloc.r_sym)
+            } else {
+                ElfUtilsError::Malformed(
+                    "The relocation refers to a symbol defined in a removed section".to_string(),
+                )
+            });
+        };
+
+        let output = &mut self.sections[output_index];
+        let explicit_addends = reloc.r_addend.is_some();
+        if *output.explicit_addends.get_or_insert(explicit_addends) != explicit_addends {
+            return Err(ElfUtilsError::Unsupported(format!(
+                "The section {} is relocated by both SHT_REL and SHT_RELA relocations",
+                output.name
+            )));
+        }
+        let offset = section_offset.saturating_add(reloc.r_offset);
+        let mut addend = reloc.r_addend.unwrap_or(0);
+        if target.offset != 0 {
+            if explicit_addends {
+                addend = addend.wrapping_add(target.offset as i64);
+            } else {
+                let offset = offset as usize;
+                adjust_implicit_addend(
+                    &mut output.data,
+                    offset,
+                    reloc.r_type,
+                    target.offset as i64,
+                )?;
+            }
+        }
+        output.relocations.push(ElfRelocation {
+            offset,
+            symbol_index: target.symbol_index,
+            relocation_type: reloc.r_type,
+            addend,
+        });
+        Ok(())
+    }
+}
+
+/// Symbol of the output file that a symbol of one of the linked files refers to.
+struct SymbolTarget {
+    symbol_index: u32,
+    /// Offset which needs to be added to the addends of the relocations against
+    /// the symbol, it is only non-zero for the section symbols.
+    offset: u64,
+}
+
+/// The symbol table of the output file.
+struct LinkedSymbols {
+    symbols: Vec<ElfSymbol>,
+    /// Maps the object index and the index of a symbol in that object to the
+    /// symbol of the output file.
+    targets: HashMap<(usize, usize), SymbolTarget>,
+}
+
+impl LinkedSymbols {
+    fn add(&mut self, symbol: ElfSymbol) -> u32 {
+        self.symbols.push(symbol);
+        self.symbols.len() as u32
+    }
+}
+
+/// Builds the symbol table of the output file. The local symbols need to be
+/// added before the global ones, so the output file starts with the section
+/// symbols, followed by the local symbols of all files, the definitions of the
+/// global symbols and the undefined helper functions.
+fn link_symbols(
+    binaries: &[Elf],
+    sections: &OutputSections,
+) -> Result<LinkedSymbols, ElfUtilsError> {
+    let mut linked = LinkedSymbols {
+        symbols: Vec::new(),
+        targets: HashMap::new(),
+    };
+    for index in 1..=sections.sections.len() {
+        linked.add(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: index as u16,
+            value: 0,
+            size: 0,
+        });
+    }
+
+    // Symbols defined in the removed sections don't have a counterpart in
+    // the output file.
+    let placement = |object: usize, symbol: &Sym| -> Option<(u16, u64)> {
+        if symbol.st_shndx >= SHN_ABS as usize {
+            return Some((symbol.st_shndx as u16, 0));
+        }
+        sections
+            .placements
+            .get(&(object, symbol.st_shndx))
+            .map(|(index, offset)| ((index + 1) as u16, *offset))
+    };
+    let output_symbol = |binary: &Elf, symbol: &Sym, section_index: u16, offset: u64| ElfSymbol {
+        name: binary
+            .strtab
+            .get_at(symbol.st_name)
+            .unwrap_or_default()
+            .to_string(),
+        info: symbol.st_info,
+        other: symbol.st_other,
+        section_index,
+        value: symbol.st_value.wrapping_add(offset),
+        size: symbol.st_size,
+    };
+
+    for (object, binary) in binaries.iter().enumerate() {
+        // The relocations which don't refer to any symbol use the null symbol.
+        linked.targets.insert(
+            (object, 0),
+            SymbolTarget {
+                symbol_index: 0,
+                offset: 0,
+            },
+        );
+        for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+            if symbol.st_bind() != STB_LOCAL || symbol.st_shndx == SHN_UNDEF as usize {
+                continue;
+            }
+            let Some((section_index, offset)) = placement(object, &symbol) else {
+                continue;
+            };
+            let target = if symbol.st_type() == STT_SECTION {
+                SymbolTarget {
+                    symbol_index: section_index as u32,
+                    offset,
+                }
+            } else {
+                SymbolTarget {
+                    symbol_index: linked.add(output_symbol(binary, &symbol, section_index, offset)),
+                    offset: 0,
+                }
+            };
+            linked.targets.insert((object, index), target);
+        }
+    }
+
+    // A weak definition is replaced by the first non-weak one.
+    let mut definitions: HashMap<&str, (usize, usize)> = HashMap::new();
+    for (object, binary) in binaries.iter().enumerate() {
+        for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+            if symbol.st_bind() == STB_LOCAL || symbol.st_shndx == SHN_UNDEF as usize {
+                continue;
+            }
+            let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
+            let location = ErrorLocation {
+                object: Some(object),
+                symbol: Some(name.to_string()),
+                ..Default::default()
+            };
+            if symbol.st_shndx == SHN_COMMON as usize {
+                return Err(ElfUtilsError::Unsupported(
+                    "Common symbols aren't supported, the program needs to be compiled with -fno-common"
+                        .to_string(),
+                )
+                .at(location));
+            }
+            if placement(object, &symbol).is_none() {
+                continue;
+            }
+            match definitions.get(name) {
+                None => {
+                    definitions.insert(name, (object, index));
+                }
+                Some(&(first_object, first_index)) => {
+                    let is_weak = |object: usize, index: usize| {
+                        binaries[object]
+                            .syms
+                            .get(index)
+                            .is_some_and(|symbol| symbol.st_bind() == STB_WEAK)
+                    };
+                    if symbol.st_bind() == STB_WEAK {
+                        continue;
+                    }
+                    if !is_weak(first_object, first_index) {
+                        return Err(ElfUtilsError::DuplicateSymbol {
+                            name: name.to_string(),
+                            first_object,
+                        }
+                        .at(location));
+                    }
+                    definitions.insert(name, (object, index));
+                }
+            }
+        }
+    }
+
+    let mut global_indices: HashMap<&str, u32> = HashMap::new();
+    for (object, binary) in binaries.iter().enumerate() {
+        for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+            let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
+            if definitions.get(name) != Some(&(object, index)) {
+                continue;
+            }
+            let Some((section_index, offset)) = placement(object, &symbol) else {
+                continue;
+            };
+            let symbol_index = linked.add(output_symbol(binary, &symbol, section_index, offset));
+            global_indices.insert(name, symbol_index);
+        }
+    }
+
+    for (object, binary) in binaries.iter().enumerate() {
+        for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+            if symbol.st_bind() == STB_LOCAL {
+                continue;
+            }
+            let name = binary.strtab.get_at(symbol.st_name).unwrap_or_default();
+            let symbol_index = match global_indices.get(name) {
+                Some(symbol_index) => *symbol_index,
+                None if symbol.st_shndx != SHN_UNDEF as usize => continue,
+                None if HelperFunctionID::from_symbol_name(name).is_some() => {
+                    let symbol_index = linked.add(ElfSymbol {
+                        name: name.to_string(),
+                        info: STB_GLOBAL << 4 | STT_NOTYPE,
+                        other: 0,
+                        section_index: SHN_UNDEF as u16,
+                        value: 0,
+                        size: 0,
+                    });
+                    global_indices.insert(name, symbol_index);
+                    symbol_index
+                }
+                None => {
+                    return Err(ElfUtilsError::UndefinedSymbol(name.to_string()).at(
+                        ErrorLocation {
+                            object: Some(object),
+                            symbol: Some(name.to_string()),
+                            ..Default::default()
+                        },
+                    ))
+                }
+            };
+            linked.targets.insert(
+                (object, index),
+                SymbolTarget {
+                    symbol_index,
+                    offset: 0,
+                },
+            );
+        }
+    }
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{extract_section, R_BPF_64_32, R_BPF_64_64},
+        extended_relocations::assemble_binary,
+    };
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS},
+        sym::STT_FUNC,
+    };
+
+    /// Builds an object file with a function `name` which loads a variable at
+    /// offset 8 of its .data section relative to the section symbol and calls
+    /// the global function `callee`, which may be defined in another file.
+    fn object(name: &str, callee: &str, define_callee: bool) -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 8; call -1; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 8, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![1; 16],
+        );
+        let data_symbol = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: name.to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 32,
+        });
+        let callee = writer.add_symbol(ElfSymbol {
+            name: callee.to_string(),
+            info: STB_GLOBAL << 4 | if define_callee { STT_FUNC } else { STT_NOTYPE },
+            other: 0,
+            section_index: if define_callee { text_index as u16 } else { 0 },
+            value: 0,
+            size: 0,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![
+                ElfRelocation {
+                    offset: 0,
+                    symbol_index: data_symbol,
+                    relocation_type: R_BPF_64_64,
+                    addend: 0,
+                },
+                ElfRelocation {
+                    offset: 16,
+                    symbol_index: callee,
+                    relocation_type: R_BPF_64_32,
+                    addend: 0,
+                },
+            ],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn objects_are_merged_and_symbols_resolved() {
+        let first = object("main", "function", false);
+        let second = object("function", "bpf_printf", false);
+        let linked = link_objects(&[&first, &second]).unwrap();
+
+        assert_eq!(extract_section(".text", &linked).unwrap().len(), 64);
+        assert_eq!(extract_section(".data", &linked).unwrap().len(), 32);
+        let binary = parse_elf(&linked).unwrap();
+        let function = binary
+            .syms
+            .iter()
+            .find(|symbol| binary.strtab.get_at(symbol.st_name) == Some("function"))
+            .unwrap();
+        assert_eq!(function.st_value, 32);
+        assert_eq!(function.st_type(), STT_FUNC);
+
+        // The load of the second file is relative to its part of .data.
+        let text = extract_section(".text", &linked).unwrap();
+        assert_eq!(text[4], 8);
+        assert_eq!(text[36], 24);
+
+        // The call to the extern helper is resolved when the binary is assembled.
+        let binary = assemble_binary(&linked).unwrap();
+        let binary = crate::ExtendedBinary::parse(&binary).unwrap();
+        assert_eq!(binary.relocated_calls.len(), 1);
+        assert_eq!(
+            binary.text[48..56],
+            [
+                0x85,
+                0,
+                0,
+                0,
+                HelperFunctionID::BPF_PRINTF_IDX as u8,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_and_undefined_symbols_are_reported() {
+        let first = object("main", "function", false);
+        let error = link_objects(&[&first]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Symbol function isn't defined in any of the linked objects (object 0, symbol function)"
+        );
+
+        let second = object("function", "main", false);
+        let third = object("function", "other", true);
+        let error = link_objects(&[&first, &second, &third]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Symbol function is already defined in object 1 (object 2, symbol function)"
+        );
+
+        let error = link_objects(&[&first, &second[..64]]).unwrap_err();
+        assert_eq!(error.location().unwrap().object, Some(1));
+    }
+}
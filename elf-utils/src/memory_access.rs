@@ -0,0 +1,454 @@
+//! Classification of the memory accessed by the loads and stores of eBPF
+//! programs.
+//!
+//! The VM checks each memory access of a program against the list of regions
+//! that the program is allowed to access, so the length of that list directly
+//! affects the execution time. The analysis tracks which registers hold
+//! pointers into the regions available to the program and reports the regions
+//! that it accesses, which is the smallest list of regions that the program
+//! needs. The accesses which can't be classified are reported separately, as
+//! the VM has to allow them by keeping the other regions in the list.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::ToString,
+    vec::Vec,
+};
+use core::fmt;
+use micro_bpf_common::BinaryFileLayout;
+
+use crate::{
+    disassembler::{text_with_symbols, SymbolMap},
+    error::{ElfUtilsError, ErrorLocation},
+    model::{
+        decode_instructions, Instruction, BPF_ABS, BPF_ADD, BPF_ALU64, BPF_IND, BPF_JA, BPF_JMP,
+        BPF_JMP32, BPF_LD, BPF_LDX, BPF_MEM, BPF_MOV, BPF_ST, BPF_STX, BPF_SUB, BPF_X, BPF_XADD,
+        FC_LDDWD_OPCODE, FC_LDDWR_OPCODE,
+    },
+};
+
+/// Index of the register holding the pointer to the context of the program
+/// (e.g. the `bpf_coap_ctx_t` of the CoAP handlers) when it starts.
+const CONTEXT_REGISTER: u8 = 1;
+/// Index of the read-only frame pointer register.
+const FRAME_POINTER: u8 = 10;
+
+/// Memory regions which can be accessed by the programs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MemoryRegion {
+    /// The stack of the VM
+    Stack,
+    /// The context passed to the program in r1
+    Context,
+    /// The writable .data section
+    Data,
+    /// The read-only .rodata section
+    Rodata,
+    /// The zero-initialised .bss section, it is only distinguished from the
+    /// .data section for the binaries which keep it separate (i.e. the object
+    /// files)
+    Bss,
+}
+
+impl MemoryRegion {
+    /// Returns the region corresponding to a section of the program, the
+    /// compiler can place the data in sections such as `.rodata.str1.1`.
+    pub fn from_section_name(name: &str) -> Option<Self> {
+        if name.starts_with(".rodata") {
+            Some(MemoryRegion::Rodata)
+        } else if name.starts_with(".data") {
+            Some(MemoryRegion::Data)
+        } else if name.starts_with(".bss") {
+            Some(MemoryRegion::Bss)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MemoryRegion::Stack => "stack",
+            MemoryRegion::Context => "context",
+            MemoryRegion::Data => ".data",
+            MemoryRegion::Rodata => ".rodata",
+            MemoryRegion::Bss => ".bss",
+        })
+    }
+}
+
+/// A memory region accessed by the program together with the kinds of the
+/// accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionAccess {
+    /// The accessed region
+    pub region: MemoryRegion,
+    /// The program loads from the region
+    pub read: bool,
+    /// The program stores to the region
+    pub write: bool,
+}
+
+impl fmt::Display for RegionAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match (self.read, self.write) {
+            (true, true) => "read, write",
+            (false, true) => "write",
+            _ => "read",
+        };
+        write!(f, "{} ({})", self.region, access)
+    }
+}
+
+/// A load or store whose target couldn't be attributed to any region, e.g.
+/// because it uses a pointer returned by a helper or loaded from memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownAccess {
+    /// True for stores, false for loads
+    pub write: bool,
+    /// Offset of the instruction in the .text section and the name of the
+    /// function containing it if it is known
+    pub location: ErrorLocation,
+}
+
+impl fmt::Display for UnknownAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.write { "Store to" } else { "Load from" };
+        write!(f, "{} an unknown memory region ({})", access, self.location)
+    }
+}
+
+/// Memory regions accessed by a program, see [`analyse_memory_access`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryAccessReport {
+    /// Regions accessed by the program, sorted by the region
+    pub regions: Vec<RegionAccess>,
+    /// Accesses which couldn't be classified, sorted by their offsets
+    pub unknown: Vec<UnknownAccess>,
+}
+
+/// Classifies the memory accesses of the bytecode contained in a binary with
+/// a given layout, see [`analyse_memory_access`].
+pub fn analyse_binary_memory_access(
+    program: &[u8],
+    layout: BinaryFileLayout,
+) -> Result<MemoryAccessReport, ElfUtilsError> {
+    let (text, symbols) = text_with_symbols(program, layout)?;
+    analyse_memory_access(&text, &symbols)
+}
+
+/// Classifies each load and store of the program as an access to one of the
+/// [`MemoryRegion`]s or as an unknown access.
+///
+/// The pointers originate from r10 (the stack), from r1 at the start of the
+/// program (the context, the entry point is assumed to be at the start of the
+/// .text section) and from the loads of the addresses of the sections, either
+/// the `lddw` instructions with relocations registered in the symbol map or the
+/// Femto-Container specific loads. The regions of the pointers are propagated
+/// through the pointer arithmetic, all paths of the control flow graph and the
+/// arguments of the calls to the functions of the program. A register holds
+/// a pointer to a region only if it does so on all paths reaching an
+/// instruction, so the reported regions are never missing an access. The
+/// instructions which can't be reached are analysed as if they started a new
+/// function.
+pub fn analyse_memory_access(
+    text: &[u8],
+    symbols: &SymbolMap,
+) -> Result<MemoryAccessReport, ElfUtilsError> {
+    let instructions = decode_instructions(text)?;
+    let indices: BTreeMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.offset, index))
+        .collect();
+    let index_of = |target: i64| {
+        usize::try_from(target)
+            .ok()
+            .and_then(|offset| indices.get(&offset).copied())
+    };
+    let called_functions: BTreeSet<usize> = instructions
+        .iter()
+        .filter_map(|instruction| index_of(symbols.called_function(instruction)?))
+        .collect();
+
+    let mut states: Vec<Option<Registers>> = alloc::vec![None; instructions.len()];
+    let mut pending = Vec::new();
+
+    if !instructions.is_empty() {
+        let mut entry = Registers::default();
+        entry.set(CONTEXT_REGISTER, Some(MemoryRegion::Context));
+        propagate(&mut states, &mut pending, 0, entry);
+    }
+    loop {
+        while let Some(index) = pending.pop() {
+            let instruction = &instructions[index];
+            if instruction.is_exit() {
+                continue;
+            }
+            let mut state = states[index].clone().unwrap_or_default();
+            if let Some(target) = symbols.called_function(instruction).and_then(index_of) {
+                propagate(&mut states, &mut pending, target, state.arguments());
+            }
+            let jump_target = instruction.jump_target().and_then(index_of);
+            state.update(instruction, symbols);
+            if let Some(target) = jump_target {
+                propagate(&mut states, &mut pending, target, state.clone());
+                // Only the conditional jumps can fall through to the next instruction.
+                if instruction.opcode == BPF_JMP | BPF_JA {
+                    continue;
+                }
+            }
+            if index + 1 < instructions.len() {
+                propagate(&mut states, &mut pending, index + 1, state);
+            }
+        }
+
+        // The functions which aren't called by the program are analysed first,
+        // so that the called ones are reached with their arguments.
+        let unreached = |index: &usize| states[*index].is_none();
+        let next = (0..instructions.len())
+            .filter(|index| !called_functions.contains(index))
+            .find(unreached)
+            .or_else(|| (0..instructions.len()).find(unreached));
+        match next {
+            Some(index) => propagate(&mut states, &mut pending, index, Registers::default()),
+            None => break,
+        }
+    }
+
+    let mut regions: BTreeMap<MemoryRegion, RegionAccess> = BTreeMap::new();
+    let mut unknown = Vec::new();
+    for (instruction, state) in instructions.iter().zip(&states) {
+        let Some((base, read, write)) = memory_access(instruction) else {
+            continue;
+        };
+        let region = match base {
+            Some(register) => state.as_ref().and_then(|state| state.region(register)),
+            None => Some(MemoryRegion::Context),
+        };
+        match region {
+            Some(region) => {
+                let access = regions.entry(region).or_insert(RegionAccess {
+                    region,
+                    read: false,
+                    write: false,
+                });
+                access.read |= read;
+                access.write |= write;
+            }
+            None => unknown.push(UnknownAccess {
+                write,
+                location: ErrorLocation {
+                    section: Some(".text".to_string()),
+                    offset: Some(instruction.offset),
+                    function: symbols
+                        .function_containing(instruction.offset)
+                        .map(ToString::to_string),
+                    ..Default::default()
+                },
+            }),
+        }
+    }
+
+    Ok(MemoryAccessReport {
+        regions: regions.into_values().collect(),
+        unknown,
+    })
+}
+
+/// Joins the state reaching the instruction at the given index with the
+/// current one and schedules the instruction to be analysed again if it changed.
+fn propagate(
+    states: &mut [Option<Registers>],
+    pending: &mut Vec<usize>,
+    index: usize,
+    state: Registers,
+) {
+    let joined = match &states[index] {
+        Some(current) => current.join(&state),
+        None => state,
+    };
+    if states[index].as_ref() != Some(&joined) {
+        states[index] = Some(joined);
+        pending.push(index);
+    }
+}
+
+/// Returns the base register of the memory access performed by the instruction
+/// and whether it reads and writes the memory. The legacy packet loads don't
+/// use a base register, they always access the context.
+fn memory_access(instruction: &Instruction) -> Option<(Option<u8>, bool, bool)> {
+    let mode = instruction.opcode & 0xe0;
+    match instruction.class() {
+        BPF_LD if matches!(mode, BPF_ABS | BPF_IND) => Some((None, true, false)),
+        BPF_LDX if mode == BPF_MEM => Some((Some(instruction.src), true, false)),
+        BPF_ST | BPF_STX if mode == BPF_MEM => Some((Some(instruction.dst), false, true)),
+        BPF_STX if mode == BPF_XADD => Some((Some(instruction.dst), true, true)),
+        _ => None,
+    }
+}
+
+/// Regions pointed to by the registers at a given instruction, the registers
+/// which aren't known to hold a pointer are absent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Registers {
+    pointers: BTreeMap<u8, MemoryRegion>,
+}
+
+impl Registers {
+    fn region(&self, register: u8) -> Option<MemoryRegion> {
+        if register == FRAME_POINTER {
+            return Some(MemoryRegion::Stack);
+        }
+        self.pointers.get(&register).copied()
+    }
+
+    fn set(&mut self, register: u8, region: Option<MemoryRegion>) {
+        match region {
+            Some(region) => self.pointers.insert(register, region),
+            None => self.pointers.remove(&register),
+        };
+    }
+
+    /// Keeps only the pointers which are the same in both states.
+    fn join(&self, other: &Registers) -> Registers {
+        let pointers = self
+            .pointers
+            .iter()
+            .filter(|(register, region)| other.pointers.get(register) == Some(region))
+            .map(|(register, region)| (*register, *region))
+            .collect();
+        Registers { pointers }
+    }
+
+    /// State at the start of a function called with the registers of this
+    /// state as its arguments (r1-r5).
+    fn arguments(&self) -> Registers {
+        let pointers = self
+            .pointers
+            .range(1..=5)
+            .map(|(register, region)| (*register, *region))
+            .collect();
+        Registers { pointers }
+    }
+
+    fn update(&mut self, instruction: &Instruction, symbols: &SymbolMap) {
+        let dst = instruction.dst;
+        match instruction.class() {
+            // Stores don't modify the registers.
+            BPF_ST | BPF_STX => {}
+            BPF_LD if instruction.is_wide() => {
+                let region = match instruction.opcode as u32 {
+                    FC_LDDWD_OPCODE => Some(MemoryRegion::Data),
+                    FC_LDDWR_OPCODE => Some(MemoryRegion::Rodata),
+                    _ => symbols
+                        .data_reference(instruction.offset)
+                        .and_then(MemoryRegion::from_section_name),
+                };
+                self.set(dst, region);
+            }
+            BPF_ALU64 => {
+                let operation = instruction.opcode & 0xf0;
+                let uses_register = instruction.opcode & BPF_X != 0;
+                let region = match (operation, uses_register) {
+                    (BPF_MOV, true) => self.region(instruction.src),
+                    // Adding or subtracting an offset keeps the pointer within
+                    // its region (otherwise the VM rejects the access anyway).
+                    (BPF_ADD | BPF_SUB, false) => self.region(dst),
+                    (BPF_ADD, true) => self.region(dst).or(self.region(instruction.src)),
+                    (BPF_SUB, true) if self.region(instruction.src).is_none() => self.region(dst),
+                    _ => None,
+                };
+                self.set(dst, region);
+            }
+            BPF_JMP if instruction.is_call() => {
+                for register in 0..=5 {
+                    self.set(register, None);
+                }
+            }
+            // The legacy packet loads write their result to r0.
+            BPF_LD => self.set(0, None),
+            BPF_JMP | BPF_JMP32 => {}
+            _ => self.set(dst, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(region: MemoryRegion, read: bool, write: bool) -> RegionAccess {
+        RegionAccess {
+            region,
+            read,
+            write,
+        }
+    }
+
+    #[test]
+    fn accesses_are_classified() {
+        let text = alloc::vec![
+            0x61, 0x16, 0x04, 0, 0, 0, 0, 0, // ldxw r6, [r1+0x4]
+            0xb8, 0x02, 0, 0, 0x10, 0, 0, 0, // lddwd r2, 0x10
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x18, 0x03, 0, 0, 0, 0, 0, 0, // lddw r3, 0x0
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x15, 0x06, 0x02, 0, 0, 0, 0, 0, // jeq r6, 0, +2
+            0x63, 0x62, 0, 0, 0, 0, 0, 0, // stxw [r2+0x0], r6
+            0x05, 0, 0x01, 0, 0, 0, 0, 0, // ja +1
+            0xbf, 0xa2, 0, 0, 0, 0, 0, 0, // mov64 r2, r10
+            0x71, 0x27, 0xf8, 0xff, 0, 0, 0, 0, // ldxb r7, [r2-0x8]
+            0x71, 0x37, 0, 0, 0, 0, 0, 0, // ldxb r7, [r3+0x0]
+            0x79, 0x67, 0, 0, 0, 0, 0, 0, // ldxdw r7, [r6+0x0]
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        symbols.add_data_reference(0x18, ".rodata.str1.1");
+        let report = analyse_memory_access(&text, &symbols).unwrap();
+
+        // r2 points to .data or the stack depending on the path.
+        assert_eq!(
+            report.regions,
+            [
+                access(MemoryRegion::Context, true, false),
+                access(MemoryRegion::Data, false, true),
+                access(MemoryRegion::Rodata, true, false),
+            ]
+        );
+        let unknown = report
+            .unknown
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unknown,
+            [
+                "Load from an unknown memory region (.text+0x48, function main)",
+                "Load from an unknown memory region (.text+0x58, function main)",
+            ]
+        );
+    }
+
+    #[test]
+    fn pointers_are_passed_to_functions() {
+        let text = alloc::vec![
+            0xbf, 0xa1, 0, 0, 0, 0, 0, 0, // mov64 r1, r10
+            0x07, 0x01, 0, 0, 0xf0, 0xff, 0xff, 0xff, // add64 r1, -0x10
+            0x85, 0x10, 0, 0, 1, 0, 0, 0, // call +1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            0x7a, 0x01, 0, 0, 1, 0, 0, 0, // stdw [r1+0x0], 1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            // Never called, the pointer can't be classified.
+            0x7a, 0x01, 0, 0, 1, 0, 0, 0, // stdw [r1+0x0], 1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let report = analyse_memory_access(&text, &SymbolMap::new()).unwrap();
+        assert_eq!(report.regions, [access(MemoryRegion::Stack, false, true)]);
+        assert_eq!(report.unknown.len(), 1);
+        assert_eq!(report.unknown[0].location.offset, Some(0x30));
+        assert!(report.unknown[0].write);
+    }
+}
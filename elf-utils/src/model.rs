@@ -1,7 +1,21 @@
 // This module contains constants and abstractions used for modelling
 // the binary file.
 
-use crate::common::{INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE};
+use alloc::vec::Vec;
+
+use crate::{
+    common::{ByteReader, INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE, LDDW_OPCODE},
+    error::ElfUtilsError,
+};
+
+// Opcodes of the new instructions introduced into the eBPF ISA by the Femto-Containers
+// contribution. The purpose of those instructions is to indicate that a LDDW
+// instruction is supposed to treat the immediate operand as the offset relative
+// to the start of the .data or .rodata section respectively. This is done
+// because the exact memory address of those sections is not known before we
+// load the program into the memory of the microcontroller running the VM.
+pub const FC_LDDWD_OPCODE: u32 = 0xB8;
+pub const FC_LDDWR_OPCODE: u32 = 0xD8;
 
 /// Load-double-word instruction, needed for bytecode patching for loads from
 /// .data and .rodata sections.
@@ -18,8 +32,10 @@ pub struct Lddw {
 }
 
 impl From<&[u8]> for Lddw {
+    /// Decodes the instruction from its first 16 bytes, missing bytes are zero.
     fn from(bytes: &[u8]) -> Self {
-        unsafe { core::ptr::read(bytes.as_ptr() as *const _) }
+        let raw: [u8; LDDW_INSTRUCTION_SIZE] = padded(bytes);
+        unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const _) }
     }
 }
 
@@ -40,11 +56,22 @@ pub struct Call {
 }
 
 impl From<&[u8]> for Call {
+    /// Decodes the instruction from its first 8 bytes, missing bytes are zero.
     fn from(bytes: &[u8]) -> Self {
-        unsafe { core::ptr::read(bytes.as_ptr() as *const _) }
+        let raw: [u8; INSTRUCTION_SIZE] = padded(bytes);
+        unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const _) }
     }
 }
 
+/// Copies the bytes into an array of a fixed size so that the instructions
+/// can be decoded from slices which are too short without reading past them.
+fn padded<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut raw = [0; N];
+    let len = bytes.len().min(N);
+    raw[..len].copy_from_slice(&bytes[..len]);
+    raw
+}
+
 impl<'a> Into<&'a [u8]> for &'a Call {
     fn into(self) -> &'a [u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, INSTRUCTION_SIZE) }
@@ -56,15 +83,410 @@ pub const RELOCATED_CALL_SIZE: usize = 8;
 /// `call -1` should be replaced with a call the function at a given offset
 /// in the .text section. It is used by the extended relocation scripts to allow
 /// for using calls to functions inside of the program which aren't PC relative.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct RelocatedCall {
+    /// Offset of the `call -1` instruction in the .text section
     pub instruction_offset: u32,
+    /// Offset of the called function in the .text section
     pub function_text_offset: u32,
 }
 
+impl RelocatedCall {
+    /// Decodes a relocated call struct from the binary using a bounds-checked reader.
+    pub fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        Ok(RelocatedCall {
+            instruction_offset: reader.read_u32()?,
+            function_text_offset: reader.read_u32()?,
+        })
+    }
+}
+
 impl<'a> Into<&'a [u8]> for &'a RelocatedCall {
     fn into(self) -> &'a [u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, RELOCATED_CALL_SIZE) }
     }
 }
+
+/// Section of the output binary which holds some data used by the program.
+/// Loads from those sections are patched to use the custom LDDWD/LDDWR
+/// instructions which are relative to the start of the section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum DataRegion {
+    /// The writable .data section
+    Data = 0,
+    /// The read-only .rodata section
+    Rodata = 1,
+}
+
+impl DataRegion {
+    /// Name of the section corresponding to the region in the output binary
+    pub fn section_name(&self) -> &'static str {
+        match self {
+            DataRegion::Data => ".data",
+            DataRegion::Rodata => ".rodata",
+        }
+    }
+}
+
+impl TryFrom<u16> for DataRegion {
+    type Error = ElfUtilsError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DataRegion::Data),
+            1 => Ok(DataRegion::Rodata),
+            _ => Err(ElfUtilsError::InvalidValue {
+                field: "data region",
+                value: value as u64,
+            }),
+        }
+    }
+}
+
+pub const DATA_RELOCATION_SIZE: usize = 12;
+/// A custom struct indicating that a pointer stored in the .data or .rodata
+/// section needs to be set to the address of some other location in one of
+/// those sections. The addresses are only known after the program is loaded
+/// into memory, so the VM needs to write them before executing the program.
+/// An example of such pointer is an array of string literals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct DataRelocation {
+    /// Section containing the 8-byte pointer that needs to be patched, see [`DataRegion`]
+    pub location_region: u16,
+    /// Section containing the data that the pointer points to, see [`DataRegion`]
+    pub target_region: u16,
+    /// Offset of the pointer relative to the start of its section
+    pub location_offset: u32,
+    /// Offset of the pointed-to data relative to the start of its section
+    pub target_offset: u32,
+}
+
+impl DataRelocation {
+    /// Decodes a data relocation struct from the binary using a bounds-checked
+    /// reader. The regions are validated so that the VM doesn't need to.
+    pub fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        let relocation = DataRelocation {
+            location_region: reader.read_u16()?,
+            target_region: reader.read_u16()?,
+            location_offset: reader.read_u32()?,
+            target_offset: reader.read_u32()?,
+        };
+        DataRegion::try_from(relocation.location_region)?;
+        DataRegion::try_from(relocation.target_region)?;
+        Ok(relocation)
+    }
+}
+
+impl<'a> Into<&'a [u8]> for &'a DataRelocation {
+    fn into(self) -> &'a [u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, DATA_RELOCATION_SIZE) }
+    }
+}
+
+/// Sections of the image stored in the [`crate::PreResolvedBinary`] layout.
+/// They are concatenated in this order directly after the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ImageSection {
+    /// The writable .data section
+    Data = 0,
+    /// The read-only .rodata section
+    Rodata = 1,
+    /// The merged executable sections of the program
+    Text = 2,
+}
+
+impl TryFrom<u8> for ImageSection {
+    type Error = ElfUtilsError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ImageSection::Data),
+            1 => Ok(ImageSection::Rodata),
+            2 => Ok(ImageSection::Text),
+            _ => Err(ElfUtilsError::InvalidValue {
+                field: "image section",
+                value: value as u64,
+            }),
+        }
+    }
+}
+
+impl From<DataRegion> for ImageSection {
+    fn from(region: DataRegion) -> Self {
+        match region {
+            DataRegion::Data => ImageSection::Data,
+            DataRegion::Rodata => ImageSection::Rodata,
+        }
+    }
+}
+
+/// Specifies how the resolved address is written into the patched location
+/// of a [`PreResolvedRelocation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PatchKind {
+    /// The address is split into both immediate operands of a `lddw` instruction.
+    Lddw = 0,
+    /// The address becomes the immediate operand of a `call` instruction
+    /// and the src register is set to [`CALL_SRC_ABSOLUTE`].
+    Call = 1,
+    /// The address is stored as an 8-byte pointer in the .data or .rodata section.
+    Pointer = 2,
+}
+
+impl TryFrom<u8> for PatchKind {
+    type Error = ElfUtilsError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(PatchKind::Lddw),
+            1 => Ok(PatchKind::Call),
+            2 => Ok(PatchKind::Pointer),
+            _ => Err(ElfUtilsError::InvalidValue {
+                field: "patch kind",
+                value: value as u64,
+            }),
+        }
+    }
+}
+
+pub const PRE_RESOLVED_RELOCATION_SIZE: usize = 12;
+/// A relocation which was resolved on the host up to the address at which the
+/// program is loaded. The device computes the address of the target as the
+/// address of the image plus the start of the `section` plus the `addend` and
+/// writes it to the location at `offset` as specified by the `kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C, packed)]
+pub struct PreResolvedRelocation {
+    /// Offset of the patched location relative to the start of the image
+    pub offset: u32,
+    /// How the address is written into the patched location, see [`PatchKind`]
+    pub kind: u8,
+    /// Section containing the target of the relocation, see [`ImageSection`]
+    pub section: u8,
+    /// Always 0, keeps the addend aligned
+    pub reserved: u16,
+    /// Offset of the target relative to the start of its section
+    pub addend: u32,
+}
+
+impl PreResolvedRelocation {
+    /// Decodes a relocation entry from the binary using a bounds-checked
+    /// reader. The kind and the section are validated.
+    pub fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        let relocation = PreResolvedRelocation {
+            offset: reader.read_u32()?,
+            kind: reader.read_u8()?,
+            section: reader.read_u8()?,
+            reserved: reader.read_u16()?,
+            addend: reader.read_u32()?,
+        };
+        PatchKind::try_from(relocation.kind)?;
+        ImageSection::try_from(relocation.section)?;
+        Ok(relocation)
+    }
+}
+
+impl<'a> Into<&'a [u8]> for &'a PreResolvedRelocation {
+    fn into(self) -> &'a [u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as *const u8, PRE_RESOLVED_RELOCATION_SIZE)
+        }
+    }
+}
+
+// Instruction classes and opcode fields of the eBPF ISA, they are used for
+// decoding the bytecode in the disassembler and the static analyses.
+#[cfg(feature = "elf")]
+pub const BPF_LD: u8 = 0x00;
+#[cfg(feature = "elf")]
+pub const BPF_LDX: u8 = 0x01;
+#[cfg(feature = "elf")]
+pub const BPF_ST: u8 = 0x02;
+#[cfg(feature = "elf")]
+pub const BPF_STX: u8 = 0x03;
+#[cfg(feature = "elf")]
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+#[cfg(feature = "elf")]
+pub const BPF_ALU64: u8 = 0x07;
+
+#[cfg(feature = "elf")]
+pub const BPF_W: u8 = 0x00;
+#[cfg(feature = "elf")]
+pub const BPF_H: u8 = 0x08;
+#[cfg(feature = "elf")]
+pub const BPF_B: u8 = 0x10;
+#[cfg(feature = "elf")]
+pub const BPF_DW: u8 = 0x18;
+
+#[cfg(feature = "elf")]
+pub const BPF_ABS: u8 = 0x20;
+#[cfg(feature = "elf")]
+pub const BPF_IND: u8 = 0x40;
+#[cfg(feature = "elf")]
+pub const BPF_MEM: u8 = 0x60;
+#[cfg(feature = "elf")]
+pub const BPF_XADD: u8 = 0xc0;
+
+pub const BPF_X: u8 = 0x08;
+
+#[cfg(feature = "elf")]
+pub const BPF_ADD: u8 = 0x00;
+#[cfg(feature = "elf")]
+pub const BPF_SUB: u8 = 0x10;
+#[cfg(feature = "elf")]
+pub const BPF_NEG: u8 = 0x80;
+#[cfg(feature = "elf")]
+pub const BPF_MOV: u8 = 0xb0;
+#[cfg(feature = "elf")]
+pub const BPF_END: u8 = 0xd0;
+
+pub const BPF_JA: u8 = 0x00;
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+
+/// Value of the src register of a `call` instruction which indicates that the
+/// immediate is the PC-relative offset of a function inside of the program.
+pub const CALL_SRC_PC_RELATIVE: u8 = 1;
+/// Value of the src register of a `call` instruction which indicates that the
+/// immediate is the absolute memory address of the called function. Those calls
+/// are produced when resolving relocations on the device.
+pub const CALL_SRC_ABSOLUTE: u8 = 3;
+
+/// A single decoded eBPF instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    /// Offset of the instruction from the start of the .text section in bytes
+    pub offset: usize,
+    /// Opcode of the instruction
+    pub opcode: u8,
+    /// Destination register
+    pub dst: u8,
+    /// Source register
+    pub src: u8,
+    /// Signed offset operand
+    pub off: i16,
+    /// Immediate operand
+    pub imm: i32,
+    /// Upper half of the immediate operand of the 16-byte load instructions,
+    /// 0 for all other instructions
+    pub imm_high: i32,
+}
+
+impl Instruction {
+    /// Returns the class of the instruction, see e.g. [`BPF_ALU64`].
+    pub fn class(&self) -> u8 {
+        self.opcode & 0x07
+    }
+
+    /// Returns true for instructions spanning two instruction slots, i.e. the
+    /// LDDW instruction and its Femto-Container counterparts.
+    pub fn is_wide(&self) -> bool {
+        is_wide_opcode(self.opcode)
+    }
+
+    /// Size of the instruction in bytes.
+    pub fn size(&self) -> usize {
+        if self.is_wide() {
+            LDDW_INSTRUCTION_SIZE
+        } else {
+            INSTRUCTION_SIZE
+        }
+    }
+
+    /// The full 64 bit immediate operand of the 16-byte load instructions.
+    pub fn wide_immediate(&self) -> u64 {
+        ((self.imm_high as u32 as u64) << 32) | self.imm as u32 as u64
+    }
+
+    /// Returns true if the instruction is a `call`.
+    pub fn is_call(&self) -> bool {
+        self.class() == BPF_JMP && self.opcode & 0xf0 == BPF_CALL && self.opcode & BPF_X == 0
+    }
+
+    /// Returns true if the instruction is an `exit`.
+    pub fn is_exit(&self) -> bool {
+        self.class() == BPF_JMP && self.opcode & 0xf0 == BPF_EXIT
+    }
+
+    /// Returns true for conditional and unconditional jumps. Calls and exit
+    /// instructions aren't considered to be jumps.
+    pub fn is_jump(&self) -> bool {
+        match self.class() {
+            BPF_JMP => !matches!(self.opcode & 0xf0, BPF_CALL | BPF_EXIT),
+            BPF_JMP32 => !matches!(self.opcode & 0xf0, BPF_JA | BPF_CALL | BPF_EXIT),
+            _ => false,
+        }
+    }
+
+    /// Offset in bytes of the instruction to which the jump transfers control,
+    /// it can be negative or outside of the program if the program is malformed.
+    pub fn jump_target(&self) -> Option<i64> {
+        if !self.is_jump() {
+            return None;
+        }
+        Some(self.offset as i64 + (self.off as i64 + 1) * INSTRUCTION_SIZE as i64)
+    }
+
+    /// Offset in bytes of the function called by a PC-relative `call`.
+    pub fn call_target(&self) -> Option<i64> {
+        if !self.is_call() || self.src != CALL_SRC_PC_RELATIVE {
+            return None;
+        }
+        Some(self.offset as i64 + (self.imm as i64 + 1) * INSTRUCTION_SIZE as i64)
+    }
+}
+
+pub fn is_wide_opcode(opcode: u8) -> bool {
+    opcode as u32 == LDDW_OPCODE
+        || opcode as u32 == FC_LDDWD_OPCODE
+        || opcode as u32 == FC_LDDWR_OPCODE
+}
+
+/// Decodes the eBPF bytecode into a list of instructions. The 16-byte load
+/// instructions are decoded as a single instruction.
+pub fn decode_instructions(text: &[u8]) -> Result<Vec<Instruction>, ElfUtilsError> {
+    if text.len() % INSTRUCTION_SIZE != 0 {
+        return Err(ElfUtilsError::InvalidValue {
+            field: "program length, it needs to be a multiple of the instruction size",
+            value: text.len() as u64,
+        });
+    }
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < text.len() {
+        let slot = &text[offset..offset + INSTRUCTION_SIZE];
+        let mut instruction = Instruction {
+            offset,
+            opcode: slot[0],
+            dst: slot[1] & 0x0f,
+            src: slot[1] >> 4,
+            off: i16::from_le_bytes([slot[2], slot[3]]),
+            imm: i32::from_le_bytes([slot[4], slot[5], slot[6], slot[7]]),
+            imm_high: 0,
+        };
+
+        if instruction.is_wide() {
+            if offset + LDDW_INSTRUCTION_SIZE > text.len() {
+                return Err(ElfUtilsError::UnexpectedEnd {
+                    offset,
+                    needed: LDDW_INSTRUCTION_SIZE,
+                    available: text.len() - offset,
+                });
+            }
+            let next = &text[offset + INSTRUCTION_SIZE..offset + LDDW_INSTRUCTION_SIZE];
+            instruction.imm_high = i32::from_le_bytes([next[4], next[5], next[6], next[7]]);
+        }
+
+        offset += instruction.size();
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
@@ -0,0 +1,797 @@
+use alloc::{format, vec::Vec};
+use core::ops::Range;
+
+use crate::{
+    common::{ByteReader, CALL_OPCODE, INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE, LDDW_OPCODE},
+    error::{ElfUtilsError, ErrorLocation},
+    model::{
+        Call, ImageSection, Lddw, PatchKind, PreResolvedRelocation, CALL_SRC_ABSOLUTE,
+        PRE_RESOLVED_RELOCATION_SIZE,
+    },
+};
+
+// Only the assembler needs to parse the ELF files, the resolver used on the
+// device is available without the `elf` feature.
+#[cfg(feature = "elf")]
+use alloc::collections::btree_map::BTreeMap as HashMap;
+#[cfg(feature = "elf")]
+use goblin::{
+    elf::{Elf, Sym},
+    elf64::sym::STT_SECTION,
+};
+#[cfg(feature = "elf")]
+use log::debug;
+
+#[cfg(feature = "elf")]
+use crate::{
+    common::{
+        checked_range, find_relocations, parse_elf, round_section_length, Relocation, R_BPF_64_32,
+        R_BPF_64_64, R_BPF_64_ABS64, R_BPF_64_NODYLD32, R_BPF_NONE,
+    },
+    extended_relocations::{
+        merge_bss_sections, merge_data_sections, merge_text_sections, SectionPlacement,
+    },
+    helpers::with_helper_symbols_resolved,
+};
+
+/// The binary produced for the pre-resolved relocations workflow has the
+/// following format:
+/// - Header: Contains the lengths of the sections and the number of the
+///   relocation entries. See [`PreResolvedHeader`] for more details
+/// - Image: the .data, .rodata and .text sections concatenated in this order,
+///   each one of them padded to a multiple of 8 bytes. The zero-initialised
+///   .bss section is appended to .data
+/// - Relocations: the table of [`PreResolvedRelocation`]s
+///
+/// All of the work that requires parsing the ELF file is performed on the host.
+/// Once the program is loaded into memory, the device only needs to add the
+/// address of the image to each entry of the relocation table, see
+/// [`resolve_pre_resolved_relocations`]. This avoids the RAM and load time
+/// overhead of parsing the whole ELF file on the device as it is done for the
+/// RawObjectFile layout, while still producing standard eBPF bytecode with
+/// absolute addresses of the data and the called functions.
+#[derive(Debug)]
+pub struct PreResolvedBinary {
+    /// Header specifying the lengths of all remaining sections
+    pub header: PreResolvedHeader,
+    /// Contents of the .data section
+    pub data: Vec<u8>,
+    /// Contents of the .rodata section
+    pub rodata: Vec<u8>,
+    /// Contents of the .text section
+    pub text: Vec<u8>,
+    /// Locations in the image that need to be patched once its address is known
+    pub relocations: Vec<PreResolvedRelocation>,
+}
+
+/// Magic number at the start of every binary in this layout, it is different
+/// from [`crate::HEADER_MAGIC`] so that the two layouts can't be confused.
+pub const PRE_RESOLVED_HEADER_MAGIC: u32 = 124;
+/// The latest version of the layout, produced by default by the assembler.
+pub const PRE_RESOLVED_HEADER_VERSION: u32 = 0;
+/// Size of the encoded [`PreResolvedHeader`] in bytes.
+pub const PRE_RESOLVED_HEADER_SIZE: usize = 24;
+
+impl From<PreResolvedBinary> for Vec<u8> {
+    fn from(program: PreResolvedBinary) -> Self {
+        let mut binary = program.header.to_bytes();
+        binary.extend(&program.data);
+        binary.extend(&program.rodata);
+        binary.extend(&program.text);
+        for relocation in &program.relocations {
+            let relocation: &[u8] = relocation.into();
+            binary.extend(relocation);
+        }
+        binary
+    }
+}
+
+impl PreResolvedBinary {
+    /// Decodes a binary produced by [`assemble_pre_resolved_binary`]. The section
+    /// lengths and the relocation entries are validated against the size of the
+    /// input.
+    pub fn parse(program: &[u8]) -> Result<PreResolvedBinary, ElfUtilsError> {
+        let mut reader = ByteReader::new(program);
+        let header = PreResolvedHeader::read(&mut reader)?;
+
+        let data = reader.read_bytes(header.data_len as usize)?.to_vec();
+        let rodata = reader.read_bytes(header.rodata_len as usize)?.to_vec();
+        let text = reader.read_bytes(header.text_len as usize)?.to_vec();
+
+        let relocations_len = header.relocations_len as usize;
+        if relocations_len.checked_mul(PRE_RESOLVED_RELOCATION_SIZE) != Some(reader.remaining()) {
+            return Err(ElfUtilsError::Malformed(format!(
+                "The table of {} relocations doesn't match the remaining {} bytes of the binary",
+                relocations_len,
+                reader.remaining()
+            )));
+        }
+        let relocations = (0..relocations_len)
+            .map(|_| PreResolvedRelocation::read(&mut reader))
+            .collect::<Result<Vec<PreResolvedRelocation>, ElfUtilsError>>()?;
+
+        Ok(PreResolvedBinary {
+            header,
+            data,
+            rodata,
+            text,
+            relocations,
+        })
+    }
+}
+
+/// Header at the start of the [`PreResolvedBinary`] layout. The offsets of all
+/// sections relative to the start of the image can be derived from it, see
+/// [`PreResolvedHeader::section_range`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct PreResolvedHeader {
+    /// Magic number identifying the binary layout, see [`PRE_RESOLVED_HEADER_MAGIC`]
+    pub magic: u32,
+    /// Version of the binary layout, see [`PRE_RESOLVED_HEADER_VERSION`]
+    pub version: u32,
+    /// Length of the .data section in bytes
+    pub data_len: u32,
+    /// Length of the .rodata section in bytes
+    pub rodata_len: u32,
+    /// Length of the .text section in bytes
+    pub text_len: u32,
+    /// Number of entries in the relocation table
+    pub relocations_len: u32,
+}
+
+impl PreResolvedHeader {
+    /// Decodes the header and checks that the binary uses this layout.
+    pub fn read(reader: &mut ByteReader<'_>) -> Result<Self, ElfUtilsError> {
+        let magic = reader.read_u32()?;
+        if magic != PRE_RESOLVED_HEADER_MAGIC {
+            return Err(ElfUtilsError::InvalidMagic {
+                found: magic,
+                expected: PRE_RESOLVED_HEADER_MAGIC,
+            });
+        }
+        let version = reader.read_u32()?;
+        if version > PRE_RESOLVED_HEADER_VERSION {
+            return Err(ElfUtilsError::UnsupportedVersion {
+                found: version,
+                latest: PRE_RESOLVED_HEADER_VERSION,
+            });
+        }
+        Ok(PreResolvedHeader {
+            magic,
+            version,
+            data_len: reader.read_u32()?,
+            rodata_len: reader.read_u32()?,
+            text_len: reader.read_u32()?,
+            relocations_len: reader.read_u32()?,
+        })
+    }
+
+    /// Range of the given section relative to the start of the image.
+    pub fn section_range(&self, section: ImageSection) -> Range<usize> {
+        let data_len = self.data_len as usize;
+        let rodata_end = data_len.saturating_add(self.rodata_len as usize);
+        match section {
+            ImageSection::Data => 0..data_len,
+            ImageSection::Rodata => data_len..rodata_end,
+            ImageSection::Text => rodata_end..rodata_end.saturating_add(self.text_len as usize),
+        }
+    }
+
+    /// Length of the image, i.e. all sections following the header.
+    pub fn image_len(&self) -> usize {
+        self.section_range(ImageSection::Text).end
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [
+            self.magic,
+            self.version,
+            self.data_len,
+            self.rodata_len,
+            self.text_len,
+            self.relocations_len,
+        ]
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect()
+    }
+}
+
+/// Transforms the raw ELF file generated by llc into the [`PreResolvedBinary`]
+/// layout. All executable sections are merged into the .text section and
+/// the data sections into .data and .rodata in the same way as for the
+/// ExtendedHeader layout (see [`crate::assemble_binary`]). Then each relocation
+/// is resolved to the offset of its target relative to the start of the section
+/// containing it, so that the device only needs to add the address of the image.
+///
+/// Supported relocations are loads of addresses of data with `lddw`, calls
+/// to functions defined in the program and pointers stored in the data sections.
+#[cfg(feature = "elf")]
+pub fn assemble_pre_resolved_binary(program: &[u8]) -> Result<Vec<u8>, ElfUtilsError> {
+    let program = &with_helper_symbols_resolved(program)?;
+    let binary = parse_elf(program)?;
+
+    let mut text: Vec<u8> = alloc::vec![];
+    let text_offsets = merge_text_sections(&mut text, &binary, program)?;
+
+    let mut data: Vec<u8> = alloc::vec![];
+    let mut rodata: Vec<u8> = alloc::vec![];
+    let mut placements = merge_data_sections(&mut data, &mut rodata, &binary, program)?;
+
+    // The image is patched in place in writable memory, so instead of requiring
+    // the device to allocate .bss, it is stored as zeroes at the end of .data.
+    round_section_length(&mut data);
+    let bss_len = merge_bss_sections(data.len(), &binary, &mut placements)?;
+    data.resize(data.len() + bss_len, 0);
+    round_section_length(&mut data);
+    round_section_length(&mut rodata);
+    round_section_length(&mut text);
+
+    let header = PreResolvedHeader {
+        magic: PRE_RESOLVED_HEADER_MAGIC,
+        version: PRE_RESOLVED_HEADER_VERSION,
+        data_len: data.len() as u32,
+        rodata_len: rodata.len() as u32,
+        text_len: text.len() as u32,
+        relocations_len: 0,
+    };
+
+    let sections = ImageSections {
+        text_offsets: &text_offsets,
+        placements: &placements,
+        header,
+        data: &data,
+        rodata: &rodata,
+        text: &text,
+    };
+
+    let mut relocations = alloc::vec![];
+    for relocation in find_relocations(&binary)? {
+        let entry = sections
+            .pre_resolve(&relocation, &binary)
+            .map_err(|error| error.at(relocation.location(&binary)))?;
+        relocations.extend(entry);
+    }
+
+    let output_binary = PreResolvedBinary {
+        header: PreResolvedHeader {
+            relocations_len: relocations.len() as u32,
+            ..header
+        },
+        data,
+        rodata,
+        text,
+        relocations,
+    };
+    Ok(output_binary.into())
+}
+
+/// Placements of the sections of the input ELF file inside of the image
+/// together with the contents of the image.
+#[cfg(feature = "elf")]
+struct ImageSections<'a, 'b> {
+    text_offsets: &'a HashMap<usize, usize>,
+    placements: &'a HashMap<&'b str, SectionPlacement>,
+    header: PreResolvedHeader,
+    data: &'a [u8],
+    rodata: &'a [u8],
+    text: &'a [u8],
+}
+
+#[cfg(feature = "elf")]
+impl ImageSections<'_, '_> {
+    /// Computes the entry of the relocation table for the given relocation,
+    /// `None` is returned for the relocations that don't need to be applied.
+    fn pre_resolve(
+        &self,
+        relocation: &Relocation<'_>,
+        binary: &Elf<'_>,
+    ) -> Result<Option<PreResolvedRelocation>, ElfUtilsError> {
+        if matches!(relocation.reloc.r_type, R_BPF_NONE | R_BPF_64_NODYLD32) {
+            return Ok(None);
+        }
+        let Some((location_section, location_offset)) = self.locate_patch(relocation, binary)
+        else {
+            debug!(
+                "Skipping relocation in section {} which isn't loaded",
+                relocation.section_name
+            );
+            return Ok(None);
+        };
+        let Some(symbol) = binary.syms.get(relocation.reloc.r_sym) else {
+            return Err(ElfUtilsError::InvalidSymbolIndex(relocation.reloc.r_sym));
+        };
+        let (kind, size) = match relocation.reloc.r_type {
+            R_BPF_64_64 => (PatchKind::Lddw, LDDW_INSTRUCTION_SIZE),
+            R_BPF_64_32 => (PatchKind::Call, INSTRUCTION_SIZE),
+            R_BPF_64_ABS64 => (PatchKind::Pointer, 8),
+            relocation_type => {
+                return Err(ElfUtilsError::UnsupportedRelocationType {
+                    relocation_type,
+                    offset: location_offset,
+                })
+            }
+        };
+        let location_bytes = match location_section {
+            ImageSection::Data => self.data,
+            ImageSection::Rodata => self.rodata,
+            ImageSection::Text => self.text,
+        };
+        let location = &location_bytes[checked_range(location_offset, size, location_bytes.len())?];
+
+        // The implicit addends of the SHT_REL relocations are stored in the
+        // patched location, how they need to be interpreted depends on the kind
+        // of the instruction or data that is being relocated.
+        let expected_opcode = match kind {
+            PatchKind::Lddw => Some(LDDW_OPCODE as u8),
+            PatchKind::Call => Some(CALL_OPCODE as u8),
+            PatchKind::Pointer => None,
+        };
+        let in_text = location_section == ImageSection::Text;
+        if in_text != expected_opcode.is_some()
+            || expected_opcode.is_some_and(|opcode| location[0] != opcode)
+        {
+            return Err(ElfUtilsError::Malformed(format!(
+                "Relocation of kind {:?} doesn't match the location at offset {} of the {:?} section",
+                kind, location_offset, location_section
+            )));
+        }
+        let implicit_addend = match kind {
+            PatchKind::Lddw => Lddw::from(location).immediate_l as i32 as i64,
+            // Calls to functions in a different section are relocated against
+            // the section symbol and the immediate contains the offset of the
+            // function counted in instructions after the one preceding
+            // the section. Calls relocated against the function symbols
+            // contain `call -1` instead.
+            PatchKind::Call if symbol.st_type() == STT_SECTION => {
+                (Call::from(location).immediate as i32 as i64 + 1) * INSTRUCTION_SIZE as i64
+            }
+            PatchKind::Call => 0,
+            PatchKind::Pointer => i64::from_le_bytes(location.try_into().unwrap_or_default()),
+        };
+        let addend = match relocation.reloc.r_addend {
+            Some(addend) => addend,
+            None => implicit_addend,
+        };
+
+        let (target_section, target_offset) = self.locate_target(&symbol, addend, binary)?;
+        let target_len = self.header.section_range(target_section).len() as i64;
+        if target_offset < 0 || target_offset > target_len {
+            return Err(ElfUtilsError::Malformed(format!(
+                "Relocation target {} is outside of the {:?} section",
+                target_offset, target_section
+            )));
+        }
+
+        let image_offset = self.header.section_range(location_section).start + location_offset;
+        debug!(
+            "Relocation at {} of kind {:?} pointing to {:?}+{}",
+            image_offset, kind, target_section, target_offset
+        );
+        Ok(Some(PreResolvedRelocation {
+            offset: image_offset as u32,
+            kind: kind as u8,
+            section: target_section as u8,
+            reserved: 0,
+            addend: target_offset as u32,
+        }))
+    }
+
+    /// Returns the section of the image containing the location patched by
+    /// the relocation and the offset of the location relative to that section.
+    fn locate_patch(
+        &self,
+        relocation: &Relocation<'_>,
+        binary: &Elf<'_>,
+    ) -> Option<(ImageSection, usize)> {
+        let (section, offset) = self.locate_section(relocation.section_index, binary)?;
+        Some((
+            section,
+            offset.saturating_add(relocation.reloc.r_offset as usize),
+        ))
+    }
+
+    /// Returns the section of the image containing the target of a relocation
+    /// against the symbol with the given addend and the offset of the target
+    /// relative to that section.
+    fn locate_target(
+        &self,
+        symbol: &Sym,
+        addend: i64,
+        binary: &Elf<'_>,
+    ) -> Result<(ImageSection, i64), ElfUtilsError> {
+        let section_offset = symbol.st_value.wrapping_add(addend as u64);
+        if let Some(offset) = self.text_offsets.get(&symbol.st_shndx) {
+            let target_offset = (*offset as u64).wrapping_add(section_offset);
+            return Ok((ImageSection::Text, target_offset as i64));
+        }
+        // The entries of the merged data sections aren't contiguous, so the
+        // offset needs to be translated using the placement of the section.
+        match self.placement(symbol.st_shndx, binary) {
+            Some(placement) => Ok((
+                ImageSection::from(placement.region),
+                placement.region_offset(section_offset) as i64,
+            )),
+            None => Err(ElfUtilsError::Unsupported(format!(
+                "Relocations against the symbol {} aren't supported, it isn't defined in the .text, .data or .rodata sections",
+                binary.strtab.get_at(symbol.st_name).unwrap_or_default()
+            ))),
+        }
+    }
+
+    fn locate_section(&self, index: usize, binary: &Elf<'_>) -> Option<(ImageSection, usize)> {
+        if let Some(offset) = self.text_offsets.get(&index) {
+            return Some((ImageSection::Text, *offset));
+        }
+        let placement = self.placement(index, binary)?;
+        Some((ImageSection::from(placement.region), placement.offset))
+    }
+
+    fn placement(&self, index: usize, binary: &Elf<'_>) -> Option<&SectionPlacement> {
+        let section = binary.section_headers.get(index)?;
+        let name = binary.strtab.get_at(section.sh_name)?;
+        self.placements.get(name)
+    }
+}
+
+/// Resolves the relocations of a program in the [`PreResolvedBinary`] layout
+/// after it has been loaded into memory on the device. The address of the
+/// image is derived from the location of the program slice.
+///
+/// As opposed to [`crate::resolve_relocations`], this doesn't parse the ELF file
+/// and doesn't allocate any memory (apart from the error messages). It only
+/// walks the relocation table and writes the addresses in place.
+pub fn resolve_pre_resolved_relocations(program: &mut [u8]) -> Result<(), ElfUtilsError> {
+    let image_address = (program.as_ptr() as u64).wrapping_add(PRE_RESOLVED_HEADER_SIZE as u64);
+    resolve_pre_resolved_relocations_at(program, image_address)
+}
+
+/// Resolves the relocations of a program in the [`PreResolvedBinary`] layout
+/// assuming that its image (the sections following the header) will be
+/// located at the given address.
+///
+/// Each entry of the relocation table is checked to lie within the image and
+/// to point to the instruction that it is supposed to patch, so a corrupted
+/// binary results in an error instead of a write outside of the program.
+pub fn resolve_pre_resolved_relocations_at(
+    program: &mut [u8],
+    image_address: u64,
+) -> Result<(), ElfUtilsError> {
+    let header = PreResolvedHeader::read(&mut ByteReader::new(program))?;
+    let image_start = PRE_RESOLVED_HEADER_SIZE;
+    // The lengths are checked before computing any offsets so that they can't
+    // overflow on the 32-bit targets.
+    let image_len = header.data_len as u64 + header.rodata_len as u64 + header.text_len as u64;
+    if image_start as u64 + image_len > program.len() as u64 {
+        return Err(ElfUtilsError::OutOfBounds {
+            offset: image_start as u64,
+            len: image_len,
+            size: program.len() as u64,
+        });
+    }
+    let table_start = image_start + header.image_len();
+    let relocations_len = header.relocations_len as usize;
+    let table_fits = relocations_len
+        .checked_mul(PRE_RESOLVED_RELOCATION_SIZE)
+        .is_some_and(|len| len <= program.len() - table_start);
+    if !table_fits {
+        return Err(ElfUtilsError::Malformed(format!(
+            "The table of {} relocations doesn't fit in the binary of length {}",
+            relocations_len,
+            program.len()
+        )));
+    }
+
+    for i in 0..relocations_len {
+        let entry_start = table_start + i * PRE_RESOLVED_RELOCATION_SIZE;
+        let relocation = PreResolvedRelocation::read(&mut ByteReader::new(
+            &program[entry_start..entry_start + PRE_RESOLVED_RELOCATION_SIZE],
+        ))?;
+        let image = &mut program[image_start..table_start];
+        apply_pre_resolved_relocation(image, &header, image_address, &relocation).map_err(
+            |error| {
+                error.at(ErrorLocation {
+                    relocation_index: Some(i),
+                    offset: Some(relocation.offset as usize),
+                    ..Default::default()
+                })
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Applies a single entry of the relocation table to the image, the offsets
+/// in the entry are relative to the start of the image.
+fn apply_pre_resolved_relocation(
+    image: &mut [u8],
+    header: &PreResolvedHeader,
+    image_address: u64,
+    relocation: &PreResolvedRelocation,
+) -> Result<(), ElfUtilsError> {
+    let offset = relocation.offset as usize;
+    let target_section = ImageSection::try_from(relocation.section)?;
+    let target_range = header.section_range(target_section);
+    if relocation.addend as usize > target_range.len() {
+        return Err(ElfUtilsError::Malformed(format!(
+            "Relocation target {} is outside of the {:?} section",
+            { relocation.addend },
+            target_section
+        )));
+    }
+    let address =
+        image_address.wrapping_add((target_range.start + relocation.addend as usize) as u64);
+
+    let kind = PatchKind::try_from(relocation.kind)?;
+    let (allowed_range, size) = match kind {
+        PatchKind::Lddw => (
+            header.section_range(ImageSection::Text),
+            LDDW_INSTRUCTION_SIZE,
+        ),
+        PatchKind::Call => (header.section_range(ImageSection::Text), INSTRUCTION_SIZE),
+        PatchKind::Pointer => (
+            header.section_range(ImageSection::Data).start
+                ..header.section_range(ImageSection::Rodata).end,
+            8,
+        ),
+    };
+    if offset < allowed_range.start || offset > allowed_range.end.saturating_sub(size) {
+        return Err(ElfUtilsError::Malformed(format!(
+            "Relocation of kind {:?} at offset {} is outside of the allowed sections",
+            kind, offset
+        )));
+    }
+
+    let location = &mut image[offset..offset + size];
+    match kind {
+        PatchKind::Lddw => {
+            if location[0] != LDDW_OPCODE as u8 {
+                return Err(ElfUtilsError::InstructionMismatch {
+                    expected: "lddw",
+                    offset,
+                });
+            }
+            let mut instr = Lddw::from(&*location);
+            instr.immediate_l = address as u32;
+            instr.immediate_h = (address >> 32) as u32;
+            location.copy_from_slice((&instr).into());
+        }
+        PatchKind::Call => {
+            if location[0] != CALL_OPCODE as u8 {
+                return Err(ElfUtilsError::InstructionMismatch {
+                    expected: "call",
+                    offset,
+                });
+            }
+            let mut instr = Call::from(&*location);
+            instr.registers = CALL_SRC_ABSOLUTE << 4;
+            instr.immediate = address as u32;
+            location.copy_from_slice((&instr).into());
+        }
+        PatchKind::Pointer => location.copy_from_slice(&address.to_le_bytes()),
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "elf"))]
+mod tests {
+    use super::*;
+    use crate::elf_writer::{ElfRelocation, ElfSymbol, ElfWriter};
+    use alloc::string::{String, ToString};
+    use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS};
+    use goblin::elf::sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT};
+
+    const IMAGE_ADDRESS: u64 = 0x2000_1000;
+
+    /// Builds an object file similar to the ones produced by LLVM. The main
+    /// function loads the address of a string literal in .rodata and calls
+    /// a global function, .data contains a pointer to the same string.
+    fn sample_object() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 4; call -1; exit; mov r0, 1; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 4, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x95, 0, 0, 0, 0, 0, 0, 0, //
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![4, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let rodata_index = writer.add_section(
+            ".rodata",
+            SHT_PROGBITS,
+            SHF_ALLOC as u64,
+            1,
+            0,
+            alloc::vec![b'a', b'b', b'c', 0, b'x', b'y', 0],
+        );
+        let rodata_symbol = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: rodata_index as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 32,
+        });
+        let function = writer.add_symbol(ElfSymbol {
+            name: "function".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 32,
+            size: 16,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: "pointer".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 8,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![
+                ElfRelocation {
+                    offset: 0,
+                    symbol_index: rodata_symbol,
+                    relocation_type: R_BPF_64_64,
+                    addend: 0,
+                },
+                ElfRelocation {
+                    offset: 16,
+                    symbol_index: function,
+                    relocation_type: R_BPF_64_32,
+                    addend: 0,
+                },
+            ],
+            false,
+        );
+        writer.add_relocations(
+            ".rel.data",
+            data_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: rodata_symbol,
+                relocation_type: R_BPF_64_ABS64,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn assemble_produces_relocation_table() {
+        let bytes = assemble_pre_resolved_binary(&sample_object()).unwrap();
+        let binary = PreResolvedBinary::parse(&bytes).unwrap();
+
+        assert_eq!(binary.data.len(), 8);
+        assert_eq!(binary.rodata.len(), 8);
+        assert_eq!(binary.text.len(), 48);
+        assert_eq!(
+            binary.relocations,
+            [
+                PreResolvedRelocation {
+                    offset: 16,
+                    kind: PatchKind::Lddw as u8,
+                    section: ImageSection::Rodata as u8,
+                    reserved: 0,
+                    addend: 4,
+                },
+                PreResolvedRelocation {
+                    offset: 32,
+                    kind: PatchKind::Call as u8,
+                    section: ImageSection::Text as u8,
+                    reserved: 0,
+                    addend: 32,
+                },
+                PreResolvedRelocation {
+                    offset: 0,
+                    kind: PatchKind::Pointer as u8,
+                    section: ImageSection::Rodata as u8,
+                    reserved: 0,
+                    addend: 4,
+                },
+            ]
+        );
+
+        let encoded: Vec<u8> = binary.into();
+        assert_eq!(encoded, bytes);
+    }
+
+    #[test]
+    fn resolve_writes_absolute_addresses() {
+        let mut bytes = assemble_pre_resolved_binary(&sample_object()).unwrap();
+        resolve_pre_resolved_relocations_at(&mut bytes, IMAGE_ADDRESS).unwrap();
+        let image = &bytes[PRE_RESOLVED_HEADER_SIZE..];
+
+        let rodata_address = IMAGE_ADDRESS + 8;
+        let text_address = IMAGE_ADDRESS + 16;
+        let lddw = Lddw::from(&image[16..32]);
+        assert_eq!(
+            { lddw.immediate_l } as u64,
+            (rodata_address + 4) & 0xffff_ffff
+        );
+        assert_eq!({ lddw.immediate_h } as u64, (rodata_address + 4) >> 32);
+        let call = Call::from(&image[32..40]);
+        assert_eq!(call.registers, CALL_SRC_ABSOLUTE << 4);
+        assert_eq!({ call.immediate } as u64, text_address + 32);
+        assert_eq!(&image[0..8], &(rodata_address + 4).to_le_bytes());
+    }
+
+    #[test]
+    fn resolve_rejects_corrupted_relocations() {
+        let bytes = assemble_pre_resolved_binary(&sample_object()).unwrap();
+        let table_start = bytes.len() - 3 * PRE_RESOLVED_RELOCATION_SIZE;
+
+        // Relocated location outside of the image
+        let mut corrupted = bytes.clone();
+        corrupted[table_start..table_start + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(resolve_pre_resolved_relocations_at(&mut corrupted, IMAGE_ADDRESS).is_err());
+
+        // Lddw relocation pointing at the call instruction
+        let mut corrupted = bytes.clone();
+        corrupted[table_start..table_start + 4].copy_from_slice(&32u32.to_le_bytes());
+        let error = resolve_pre_resolved_relocations_at(&mut corrupted, IMAGE_ADDRESS).unwrap_err();
+        assert_eq!(
+            error.without_location(),
+            &ElfUtilsError::InstructionMismatch {
+                expected: "lddw",
+                offset: 32
+            }
+        );
+        assert_eq!(
+            error.location(),
+            Some(&ErrorLocation {
+                relocation_index: Some(0),
+                offset: Some(32),
+                ..Default::default()
+            })
+        );
+
+        // Target outside of its section
+        let mut corrupted = bytes.clone();
+        corrupted[table_start + 8..table_start + 12].copy_from_slice(&64u32.to_le_bytes());
+        assert!(resolve_pre_resolved_relocations_at(&mut corrupted, IMAGE_ADDRESS).is_err());
+
+        // Truncated relocation table
+        let mut truncated = bytes[..bytes.len() - 1].to_vec();
+        assert!(resolve_pre_resolved_relocations_at(&mut truncated, IMAGE_ADDRESS).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_other_layouts() {
+        let mut bytes = assemble_pre_resolved_binary(&sample_object()).unwrap();
+        bytes[0] = crate::HEADER_MAGIC as u8;
+        assert!(PreResolvedBinary::parse(&bytes).is_err());
+        assert!(resolve_pre_resolved_relocations_at(&mut bytes, IMAGE_ADDRESS).is_err());
+    }
+}
@@ -1,12 +1,19 @@
-use alloc::{
-    string::{String, ToString},
-    vec,
+use alloc::{collections::btree_map::BTreeMap as HashMap, vec, vec::Vec};
+use goblin::elf::{
+    section_header::{SectionHeader, SHF_ALLOC, SHF_WRITE, SHT_NOBITS},
+    sym::{Sym, STT_SECTION},
+    Elf,
 };
-use log::{debug, error};
+use log::debug;
 
 use crate::{
-    common::{find_relocations, CALL_OPCODE, INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE, LDDW_OPCODE},
-    model::{Call, Lddw},
+    common::{
+        checked_range, find_relocations, parse_elf, section_alignment, CALL_OPCODE,
+        INSTRUCTION_SIZE, LDDW_INSTRUCTION_SIZE, LDDW_OPCODE, R_BPF_64_32, R_BPF_64_64,
+        R_BPF_64_ABS32, R_BPF_64_ABS64, R_BPF_64_NODYLD32, R_BPF_NONE,
+    },
+    error::ElfUtilsError,
+    model::{Call, Lddw, CALL_SRC_ABSOLUTE},
 };
 
 /// Applies relocations to the given program binary.
@@ -20,6 +27,9 @@ use crate::{
 /// This way, we are able to support the `.data` relocations and achieve good
 /// compatibility with respect to the types of programs that can be supported.
 ///
+/// The way in which each location is patched is determined by the type of
+/// the relocation, relocations of unsupported types result in an error.
+///
 /// Limitations of this approach are:
 /// - the relocations in the ELF file need to be resolved each time we want to
 ///   load the program and execute it in the VM. This can be slow if the program
@@ -27,69 +37,661 @@ use crate::{
 /// - the size of raw ELF files can be up to 10x larger than the size of binaries
 ///   produced using alternative approaches (e.g. extracting just the `.text` section)
 ///   because of this, it is recommended that the object file is pre-processed
-///   with the `strip` command to remove the redundant debug information before
+///   with [`crate::strip_binary`] to remove the redundant debug information before
 ///   it is sent to the microcontroller where the actual relocations take place.
-pub fn resolve_relocations(program: &mut [u8]) -> Result<(), String> {
-    let program_addr = program.as_ptr() as usize;
-    let Ok(binary) = goblin::elf::Elf::parse(&program) else {
-        return Err("Failed to parse the ELF binary".to_string());
+///   Stripping is required for programs with a .bss section as it allocates
+///   the memory for the zero-initialised variables inside of the object file.
+pub fn resolve_relocations(program: &mut [u8]) -> Result<(), ElfUtilsError> {
+    let program_addr = program.as_ptr() as u64;
+    let binary = parse_elf(program)?;
+
+    let relocations_to_patch = find_patches(&binary, |section, symbol| {
+        // Sections such as .bss don't occupy any space in the file, so
+        // there is no memory in the program for the symbols defined there.
+        if section.sh_type == SHT_NOBITS {
+            return Err(ElfUtilsError::SymbolNotAllocated(
+                binary
+                    .strtab
+                    .get_at(symbol.st_name)
+                    .unwrap_or_default()
+                    .into(),
+            ));
+        }
+        Ok(program_addr
+            .wrapping_add(section.sh_offset)
+            .wrapping_add(symbol.st_value))
+    })?;
+
+    apply_patches(program, &relocations_to_patch)?;
+
+    Ok(())
+}
+
+/// Addresses at which the two parts of the program are located when it is
+/// executed by the VM, see [`resolve_relocations_split`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadAddresses {
+    /// Address of the first byte of the object file, the code and the read-only
+    /// sections are accessed directly from there (e.g. in flash or SUIT storage).
+    pub flash_address: u64,
+    /// Address of the RAM region into which the writable sections are copied,
+    /// it needs to be aligned to 8 bytes.
+    pub ram_address: u64,
+}
+
+/// Describes how the RAM region holding the writable sections of the program
+/// (.data and .bss) needs to be initialised before the program is executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RamRegion {
+    /// Total number of bytes that need to be allocated in RAM
+    pub size: usize,
+    /// Parts of the object file that need to be copied into the region, all
+    /// remaining bytes of the region are zero-initialised.
+    pub copies: Vec<SectionCopy>,
+}
+
+/// Copy of the initial contents of a single writable section into RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionCopy {
+    /// Offset of the section contents in the object file
+    pub image_offset: usize,
+    /// Offset of the section from the start of the RAM region
+    pub ram_offset: usize,
+    /// Number of bytes to copy
+    pub len: usize,
+}
+
+impl RamRegion {
+    /// Writes the initial contents of the writable sections of the patched
+    /// object file `image` into the memory allocated for the region.
+    pub fn initialise(&self, image: &[u8], ram: &mut [u8]) -> Result<(), ElfUtilsError> {
+        let Some(ram) = ram.get_mut(..self.size) else {
+            return Err(ElfUtilsError::InsufficientMemory {
+                required: self.size,
+                available: ram.len(),
+            });
+        };
+        ram.fill(0);
+        for copy in &self.copies {
+            let contents = &image[checked_range(copy.image_offset, copy.len, image.len())?];
+            ram[checked_range(copy.ram_offset, copy.len, self.size)?].copy_from_slice(contents);
+        }
+        Ok(())
+    }
+}
+
+/// Applies relocations to the given program binary assuming that the writable
+/// sections are executed from a copy in RAM while the rest of the program stays
+/// in place.
+///
+/// Unlike [`resolve_relocations`], the object file doesn't need to be located in
+/// writable memory when the program is executed. The code and the read-only
+/// sections are referenced at `addresses.flash_address` and the `.data` and `.bss`
+/// sections are referenced in a RAM region starting at `addresses.ram_address`.
+/// This allows for running programs that don't fit into RAM on the devices with
+/// little memory (e.g. the nucleo boards) without duplicating the whole ELF file.
+///
+/// The relocations are applied in-place to the given object file, which then
+/// needs to be stored at the flash address. The returned [`RamRegion`] describes
+/// how the region at the RAM address needs to be initialised, which can be
+/// done using [`RamRegion::initialise`]. The pointers stored in the `.data`
+/// section are patched before it is copied, so the object file needs to be
+/// patched first. The `.bss` sections don't need to be stripped as they are
+/// allocated in RAM.
+pub fn resolve_relocations_split(
+    program: &mut [u8],
+    addresses: LoadAddresses,
+) -> Result<RamRegion, ElfUtilsError> {
+    let binary = parse_elf(program)?;
+
+    // The writable sections are placed in RAM one after the other in the order
+    // in which they appear in the object file.
+    let mut ram_offsets: HashMap<usize, usize> = HashMap::new();
+    let mut ram_region = RamRegion {
+        size: 0,
+        copies: vec![],
     };
+    for (index, section) in binary.section_headers.iter().enumerate() {
+        let flags = section.sh_flags as u32;
+        if flags & SHF_ALLOC == 0 || flags & SHF_WRITE == 0 {
+            continue;
+        }
+        let alignment = section_alignment(section)?;
+        let invalid_size = ElfUtilsError::InvalidValue {
+            field: "size of the writable sections",
+            value: section.sh_size,
+        };
+        let Some(ram_offset) = ram_region.size.checked_next_multiple_of(alignment) else {
+            return Err(invalid_size);
+        };
+        debug!(
+            "Placing section {} at offset {:x} in RAM",
+            binary.strtab.get_at(section.sh_name).unwrap_or_default(),
+            ram_offset
+        );
+        if section.sh_type != SHT_NOBITS {
+            ram_region.copies.push(SectionCopy {
+                image_offset: section.sh_offset as usize,
+                ram_offset,
+                len: section.sh_size as usize,
+            });
+        }
+        ram_offsets.insert(index, ram_offset);
+        ram_region.size = usize::try_from(section.sh_size)
+            .ok()
+            .and_then(|size| ram_offset.checked_add(size))
+            .ok_or(invalid_size)?;
+    }
+
+    let relocations_to_patch = find_patches(&binary, |section, symbol| {
+        let address = match ram_offsets.get(&symbol.st_shndx) {
+            Some(ram_offset) => addresses.ram_address.wrapping_add(*ram_offset as u64),
+            None => addresses.flash_address.wrapping_add(section.sh_offset),
+        };
+        Ok(address.wrapping_add(symbol.st_value))
+    })?;
 
-    let relocations = find_relocations(&binary, &program);
+    apply_patches(program, &relocations_to_patch)?;
+
+    Ok(ram_region)
+}
+
+/// Finds all relocations in the program, the address of the symbol that each
+/// relocation refers to is computed by `symbol_address`. Each patch is returned
+/// together with the index of its relocation in the output of [`find_relocations`].
+fn find_patches(
+    binary: &Elf,
+    mut symbol_address: impl FnMut(&SectionHeader, &Sym) -> Result<u64, ElfUtilsError>,
+) -> Result<Vec<(usize, Patch)>, ElfUtilsError> {
+    let relocations = find_relocations(binary)?;
     let mut relocations_to_patch = vec![];
-    for (section_offset, relocation) in relocations {
+    for (index, relocation) in relocations.iter().enumerate() {
         debug!("Relocation found: {:?}", relocation);
-        if let Some(symbol) = binary.syms.get(relocation.r_sym) {
+        if let Some(symbol) = binary.syms.get(relocation.reloc.r_sym) {
             // Here the value of the relocation tells us the offset in the binary
             // where the data that needs to be relocated is located.
             debug!("Relocation symbol found: {:?}", symbol);
-            let section = binary.section_headers.get(symbol.st_shndx).unwrap();
+            let Some(section) = binary.section_headers.get(symbol.st_shndx) else {
+                return Err(ElfUtilsError::InvalidSectionIndex(symbol.st_shndx)
+                    .at(relocation.location(binary)));
+            };
             debug!(
                 "Symbol is located in section at offset {:x}",
                 section.sh_offset
             );
 
-            let relocated_addr = (program_addr as u64 + section.sh_offset + symbol.st_value) as u32;
+            let symbol_addr = symbol_address(section, &symbol)
+                .map_err(|error| error.at(relocation.location(binary)))?;
             relocations_to_patch.push((
-                section_offset + relocation.r_offset as usize,
-                relocated_addr,
+                index,
+                Patch {
+                    offset: relocation
+                        .section_offset
+                        .saturating_add(relocation.reloc.r_offset as usize),
+                    relocation_type: relocation.reloc.r_type,
+                    symbol_addr,
+                    addend: relocation.reloc.r_addend,
+                    is_section_symbol: symbol.st_type() == STT_SECTION,
+                },
             ));
         }
     }
+    Ok(relocations_to_patch)
+}
 
-    for (offset, value) in relocations_to_patch {
-        debug!(
-            "Patching program at offset: {:x} with new immediate value: {:x}",
-            offset, value
-        );
-        match program[offset] as u32 {
-            LDDW_OPCODE => {
-                let mut instr: Lddw = Lddw::from(&program[offset..offset + LDDW_INSTRUCTION_SIZE]);
-                instr.immediate_l += value;
-                program[offset..offset + LDDW_INSTRUCTION_SIZE].copy_from_slice((&instr).into());
-            }
-            CALL_OPCODE => {
-                let mut instr: Call = Call::from(&program[offset..offset + INSTRUCTION_SIZE]);
-                // Both src and dst registers are specified usign one field so we
-                // need to set it like this. The src register value 3 tells the
-                // vm to treat the immediate operand of the call as the actual
-                // memory address of the function call.
-                instr.registers = 0x3 << 4;
-                instr.immediate = value;
-                program[offset..offset + INSTRUCTION_SIZE].copy_from_slice((&instr).into());
-            }
-            0 => {
-                // When dealing with data relocations, the opcode is 0
-                let value_bytes = unsafe {
-                    core::slice::from_raw_parts(&value as *const _ as *const u8, INSTRUCTION_SIZE)
-                };
-                program[offset..offset + INSTRUCTION_SIZE].copy_from_slice(value_bytes);
+/// Applies the patches found by [`find_patches`]. The locations of the
+/// relocations are only needed when one of them fails, so they aren't
+/// computed upfront and the ELF file is parsed again instead.
+fn apply_patches(program: &mut [u8], patches: &[(usize, Patch)]) -> Result<(), ElfUtilsError> {
+    for (index, patch) in patches {
+        if let Err(error) = apply_patch(program, patch) {
+            let Ok(binary) = parse_elf(program) else {
+                return Err(error);
+            };
+            return Err(match find_relocations(&binary)?.get(*index) {
+                Some(relocation) => error.at(relocation.location(&binary)),
+                None => error,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A relocation which needs to be applied to the loaded program.
+pub struct Patch {
+    /// Offset of the patched location from the start of the program
+    pub offset: usize,
+    /// ELF relocation type, e.g. [`R_BPF_64_64`]
+    pub relocation_type: u32,
+    /// Address of the symbol that the relocation refers to
+    pub symbol_addr: u64,
+    /// The explicit addend of the SHT_RELA relocations. For SHT_REL relocations
+    /// the addend is stored in the patched location.
+    pub addend: Option<i64>,
+    /// Calls to static functions in a different section are relocated against
+    /// the section symbol and the immediate specifies the offset of the function.
+    pub is_section_symbol: bool,
+}
+
+impl Patch {
+    /// Number of bytes of the program modified by the patch, `None` is returned
+    /// for the relocations that don't need to be applied.
+    pub fn size(&self) -> Result<Option<usize>, ElfUtilsError> {
+        match self.relocation_type {
+            R_BPF_64_64 => Ok(Some(LDDW_INSTRUCTION_SIZE)),
+            R_BPF_64_32 | R_BPF_64_ABS64 => Ok(Some(8)),
+            R_BPF_64_ABS32 => Ok(Some(4)),
+            R_BPF_NONE | R_BPF_64_NODYLD32 => Ok(None),
+            relocation_type => Err(ElfUtilsError::UnsupportedRelocationType {
+                relocation_type,
+                offset: self.offset,
+            }),
+        }
+    }
+}
+
+/// Patches the location in the program specified by the relocation, the way
+/// in which it is patched is determined by the type of the relocation.
+pub fn apply_patch(program: &mut [u8], patch: &Patch) -> Result<(), ElfUtilsError> {
+    let offset = patch.offset;
+    let Some(size) = patch.size()? else {
+        debug!("Skipping the relocation at offset {:x}", offset);
+        return Ok(());
+    };
+    let range = checked_range(offset, size, program.len())?;
+    let location = &mut program[range];
+    let addend = |implicit_addend: i64| patch.addend.unwrap_or(implicit_addend);
+
+    match patch.relocation_type {
+        R_BPF_64_64 => {
+            if location[0] != LDDW_OPCODE as u8 {
+                return Err(ElfUtilsError::InstructionMismatch {
+                    expected: "lddw",
+                    offset,
+                });
             }
-            _ => {
-                error!("Unsupported relocation opcode at offset: {:x}", offset);
+            let mut instr: Lddw = Lddw::from(&*location);
+            let value = patch
+                .symbol_addr
+                .wrapping_add(addend(instr.immediate_l as i32 as i64) as u64);
+            debug!(
+                "Patching lddw at offset: {:x} with new immediate value: {:x}",
+                offset, value
+            );
+            instr.immediate_l = value as u32;
+            location.copy_from_slice((&instr).into());
+        }
+        R_BPF_64_32 => {
+            if location[0] != CALL_OPCODE as u8 {
+                return Err(ElfUtilsError::InstructionMismatch {
+                    expected: "call",
+                    offset,
+                });
             }
+            let mut instr: Call = Call::from(&*location);
+            let implicit_addend = if patch.is_section_symbol {
+                (instr.immediate as i32 as i64 + 1) * INSTRUCTION_SIZE as i64
+            } else {
+                0
+            };
+            let value = patch
+                .symbol_addr
+                .wrapping_add(addend(implicit_addend) as u64);
+            debug!(
+                "Patching call at offset: {:x} with function address: {:x}",
+                offset, value
+            );
+            // Both src and dst registers are specified usign one field so we
+            // need to set it like this. The src register value 3 tells the
+            // vm to treat the immediate operand of the call as the actual
+            // memory address of the function call.
+            instr.registers = CALL_SRC_ABSOLUTE << 4;
+            instr.immediate = value as u32;
+            location.copy_from_slice((&instr).into());
+        }
+        R_BPF_64_ABS64 => {
+            let implicit_addend = i64::from_le_bytes(location.try_into().unwrap_or_default());
+            let value = patch
+                .symbol_addr
+                .wrapping_add(addend(implicit_addend) as u64);
+            debug!("Patching data at offset: {:x} with {:x}", offset, value);
+            location.copy_from_slice(&value.to_le_bytes());
+        }
+        R_BPF_64_ABS32 => {
+            let implicit_addend =
+                i32::from_le_bytes(location.try_into().unwrap_or_default()) as i64;
+            let value = patch
+                .symbol_addr
+                .wrapping_add(addend(implicit_addend) as u64) as u32;
+            debug!("Patching data at offset: {:x} with {:x}", offset, value);
+            location.copy_from_slice(&value.to_le_bytes());
+        }
+        relocation_type => {
+            return Err(ElfUtilsError::UnsupportedRelocationType {
+                relocation_type,
+                offset,
+            })
         }
     }
-
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+        error::ErrorLocation,
+    };
+    use alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS},
+        sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+        Elf,
+    };
+
+    /// Builds an object file with a `lddw` instruction loading the address of
+    /// .data and a .data section which starts with bytes that look like the
+    /// opcodes of `lddw` and `call` instructions.
+    fn object_with_data_relocations(data_relocation_type: u32) -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 8; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 8, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![0x18, 0, 0, 0, 0, 0, 0, 0, 0x85, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0xdd],
+        );
+        let data_symbol = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: data_symbol,
+                relocation_type: R_BPF_64_64,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.add_relocations(
+            ".rel.data",
+            data_index,
+            alloc::vec![ElfRelocation {
+                offset: 8,
+                symbol_index: data_symbol,
+                relocation_type: data_relocation_type,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    fn section_offset(program: &[u8], name: &str) -> usize {
+        let binary = Elf::parse(program).unwrap();
+        binary
+            .section_headers
+            .iter()
+            .find(|section| binary.strtab.get_at(section.sh_name) == Some(name))
+            .unwrap()
+            .sh_offset as usize
+    }
+
+    #[test]
+    fn resolve_dispatches_on_relocation_type() {
+        let mut program = object_with_data_relocations(R_BPF_64_ABS32);
+        let text = section_offset(&program, ".text");
+        let data = section_offset(&program, ".data");
+        let data_addr = program.as_ptr() as u64 + data as u64;
+
+        resolve_relocations(&mut program).unwrap();
+
+        let lddw = Lddw::from(&program[text..text + LDDW_INSTRUCTION_SIZE]);
+        assert_eq!({ lddw.immediate_l }, (data_addr + 8) as u32);
+        // The first 8 bytes of .data aren't relocated even though they look
+        // like a lddw instruction.
+        assert_eq!(program[data..data + 8], [0x18, 0, 0, 0, 0, 0, 0, 0]);
+        // Only 4 bytes are written for R_BPF_64_ABS32, the implicit addend
+        // stored in the patched location is preserved.
+        assert_eq!(
+            program[data + 8..data + 12],
+            ((data_addr + 0x85) as u32).to_le_bytes()
+        );
+        assert_eq!(program[data + 12..data + 16], [0xaa, 0xbb, 0xcc, 0xdd]);
+    }
+
+    #[test]
+    fn resolve_writes_64_bit_pointers() {
+        let mut program = object_with_data_relocations(R_BPF_64_ABS64);
+        let data = section_offset(&program, ".data");
+        let data_addr = program.as_ptr() as u64 + data as u64;
+
+        resolve_relocations(&mut program).unwrap();
+
+        let implicit_addend = 0xddcc_bbaa_0000_0085u64;
+        assert_eq!(
+            program[data + 8..data + 16],
+            data_addr.wrapping_add(implicit_addend).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn resolve_allocates_bss_after_stripping() {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let bss_index = writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 4, 8);
+        let counter = writer.add_symbol(ElfSymbol {
+            name: "counter".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: bss_index as u16,
+            value: 4,
+            size: 4,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 24,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: counter,
+                relocation_type: R_BPF_64_64,
+                addend: 0,
+            }],
+            false,
+        );
+        let mut program = writer.write();
+        let error = resolve_relocations(&mut program).unwrap_err();
+        assert_eq!(
+            error.without_location(),
+            &ElfUtilsError::SymbolNotAllocated("counter".into())
+        );
+        assert_eq!(
+            error.to_string(),
+            "Symbol counter is defined in a section which isn't allocated, the object file \
+             needs to be stripped first (relocation 0, .text+0x0, function main, symbol counter)"
+        );
+
+        let (mut program, _) = crate::strip_binary(&program).unwrap();
+        let text = section_offset(&program, ".text");
+        let bss = section_offset(&program, ".bss");
+        resolve_relocations(&mut program).unwrap();
+
+        let lddw = Lddw::from(&program[text..text + LDDW_INSTRUCTION_SIZE]);
+        let counter_addr = program.as_ptr() as u64 + bss as u64 + 4;
+        assert_eq!({ lddw.immediate_l }, counter_addr as u32);
+        assert_eq!(program[bss..bss + 8], [0; 8]);
+    }
+
+    #[test]
+    fn resolve_split_places_writable_sections_in_ram() {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; lddw r2, 0; lddw r3, 0; exit
+        let mut text = alloc::vec![0; 3 * LDDW_INSTRUCTION_SIZE];
+        for lddw in text.chunks_mut(LDDW_INSTRUCTION_SIZE) {
+            lddw[0] = LDDW_OPCODE as u8;
+        }
+        text.extend([0x95, 0, 0, 0, 0, 0, 0, 0]);
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let rodata_index = writer.add_section(
+            ".rodata",
+            SHT_PROGBITS,
+            SHF_ALLOC as u64,
+            1,
+            0,
+            alloc::vec![b'h', b'i', 0],
+        );
+        // A 4-byte counter followed by a pointer to the second byte of .rodata
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let bss_index = writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 4, 8);
+        let mut section_symbol = |section_index: usize| {
+            writer.add_symbol(ElfSymbol {
+                name: String::new(),
+                info: STB_LOCAL << 4 | STT_SECTION,
+                other: 0,
+                section_index: section_index as u16,
+                value: 0,
+                size: 0,
+            })
+        };
+        let rodata = section_symbol(rodata_index);
+        let data = section_symbol(data_index);
+        let bss = section_symbol(bss_index);
+        let relocation = |offset, symbol_index, relocation_type| ElfRelocation {
+            offset,
+            symbol_index,
+            relocation_type,
+            addend: 0,
+        };
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![
+                relocation(0, rodata, R_BPF_64_64),
+                relocation(16, data, R_BPF_64_64),
+                relocation(32, bss, R_BPF_64_64),
+            ],
+            false,
+        );
+        writer.add_relocations(
+            ".rel.data",
+            data_index,
+            alloc::vec![relocation(8, rodata, R_BPF_64_ABS64)],
+            false,
+        );
+        let mut program = writer.write();
+        let text = section_offset(&program, ".text");
+        let rodata = section_offset(&program, ".rodata");
+
+        let addresses = LoadAddresses {
+            flash_address: 0x0800_0000,
+            ram_address: 0x2000_0000,
+        };
+        let ram_region = resolve_relocations_split(&mut program, addresses).unwrap();
+
+        // .data is placed at the start of RAM and .bss right after it.
+        assert_eq!(ram_region.size, 24);
+        assert_eq!(ram_region.copies.len(), 1);
+        let immediates = program[text..text + 3 * LDDW_INSTRUCTION_SIZE]
+            .chunks(LDDW_INSTRUCTION_SIZE)
+            .map(|lddw| Lddw::from(lddw).immediate_l)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            immediates,
+            [0x0800_0000 + rodata as u32, 0x2000_0000, 0x2000_0010]
+        );
+
+        let mut ram = [0xff; 32];
+        ram_region.initialise(&program, &mut ram).unwrap();
+        assert_eq!(ram[..8], [7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ram[8..16], (0x0800_0001 + rodata as u64).to_le_bytes());
+        assert_eq!(ram[16..24], [0; 8]);
+        assert_eq!(ram[24..], [0xff; 8]);
+        assert!(ram_region.initialise(&program, &mut ram[..16]).is_err());
+    }
+
+    #[test]
+    fn resolve_rejects_unsupported_relocation_types() {
+        let mut program = object_with_data_relocations(7);
+        let error = resolve_relocations(&mut program).unwrap_err();
+        assert!(matches!(
+            error.without_location(),
+            ElfUtilsError::UnsupportedRelocationType {
+                relocation_type: 7,
+                ..
+            }
+        ));
+        // The section symbol of .data is named after the section.
+        assert_eq!(
+            error.location(),
+            Some(&ErrorLocation {
+                object: None,
+                section: Some(".data".into()),
+                relocation_index: Some(0),
+                offset: Some(8),
+                function: None,
+                symbol: Some(".data".into()),
+            })
+        );
+    }
+}
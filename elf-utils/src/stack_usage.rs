@@ -0,0 +1,422 @@
+//! Analysis of the stack usage of eBPF programs.
+//!
+//! The VM executes the programs with a fixed-size stack shared by all of the
+//! functions on the call chain, so a deep chain of calls can overflow it even
+//! if each function only uses a small frame. The analysis computes the size of
+//! the frame of each function from its accesses relative to the frame pointer
+//! (r10) and the worst case usage over the call graph of the program.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use micro_bpf_common::BinaryFileLayout;
+
+use crate::{
+    disassembler::{text_with_symbols, SymbolMap},
+    error::ElfUtilsError,
+    model::{
+        decode_instructions, Instruction, BPF_ADD, BPF_ALU64, BPF_LDX, BPF_MEM, BPF_MOV, BPF_ST,
+        BPF_STX, BPF_SUB, BPF_X, BPF_XADD,
+    },
+};
+
+/// Size of the stack that the VM allocates for the programs in bytes.
+pub const VM_STACK_SIZE: usize = 512;
+
+/// Index of the read-only frame pointer register.
+const FRAME_POINTER: u8 = 10;
+
+/// Stack usage of a single function of the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionStackUsage {
+    /// Offset of the start of the function in the .text section
+    pub offset: usize,
+    /// Name of the function if it is known
+    pub name: Option<String>,
+    /// Size of the stack frame of the function in bytes
+    pub frame_size: usize,
+    /// Offsets of the functions called by this function
+    pub callees: Vec<usize>,
+    /// Stack usage of the function including its callees in the worst case,
+    /// `None` if it is unbounded because the function is recursive
+    pub max_depth: Option<usize>,
+}
+
+impl FunctionStackUsage {
+    /// Name of the function used in the reports, functions without a symbol
+    /// are named after their offset.
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("<{:#x}>", self.offset),
+        }
+    }
+}
+
+/// Stack usage of all functions of a program, see [`analyse_stack_usage`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StackUsage {
+    /// Stack usage of each function sorted by their offsets
+    pub functions: Vec<FunctionStackUsage>,
+}
+
+impl StackUsage {
+    /// Stack usage of the program in the worst case, `None` if it is unbounded
+    /// because of recursion.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.functions
+            .iter()
+            .try_fold(0, |max, function| Some(max.max(function.max_depth?)))
+    }
+
+    /// Names of the functions on the call chain with the highest stack usage,
+    /// starting at its outermost function. For recursive programs the chain
+    /// ends once it reaches a function which is already on it.
+    pub fn deepest_call_chain(&self) -> Vec<String> {
+        let functions: BTreeMap<usize, &FunctionStackUsage> = self
+            .functions
+            .iter()
+            .map(|function| (function.offset, function))
+            .collect();
+        // Unbounded functions are ordered after all bounded ones.
+        let depth = |function: &FunctionStackUsage| function.max_depth.unwrap_or(usize::MAX);
+
+        let mut chain = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut current = self.functions.iter().max_by_key(|function| depth(function));
+        while let Some(function) = current {
+            chain.push(function.display_name());
+            if !visited.insert(function.offset) {
+                break;
+            }
+            current = function
+                .callees
+                .iter()
+                .filter_map(|callee| functions.get(callee).copied())
+                .max_by_key(|callee| depth(callee));
+        }
+        chain
+    }
+
+    /// Returns an error if the stack usage of the program can exceed the given
+    /// budget in bytes.
+    pub fn check_budget(&self, budget: usize) -> Result<(), ElfUtilsError> {
+        let required = self.max_depth();
+        if required.is_some_and(|required| required <= budget) {
+            return Ok(());
+        }
+        Err(ElfUtilsError::StackBudgetExceeded {
+            required,
+            budget,
+            call_chain: self.deepest_call_chain(),
+        })
+    }
+}
+
+/// Analyses the stack usage of the bytecode contained in a binary with a given
+/// layout, see [`analyse_stack_usage`].
+pub fn analyse_binary_stack_usage(
+    program: &[u8],
+    layout: BinaryFileLayout,
+) -> Result<StackUsage, ElfUtilsError> {
+    let (text, symbols) = text_with_symbols(program, layout)?;
+    analyse_stack_usage(&text, &symbols)
+}
+
+/// Computes the size of the stack frame of each function of the program and
+/// the worst case stack usage including the functions that it calls.
+///
+/// The functions start at the offsets registered in the symbol map, at the
+/// targets of the calls and at the start of the program, each one of them
+/// extends up to the start of the next one. As the binaries without symbols
+/// don't specify where the functions which are never called start (e.g. the
+/// entry point placed after the other functions), a function is also assumed
+/// to start after each `exit` instruction which isn't the target of any jump.
+///
+/// The frame of a function spans the lowest address relative to r10 that the
+/// function accesses, either directly or through a register holding an address
+/// derived from r10 (e.g. a pointer to a local buffer passed to a helper). The
+/// registers are tracked linearly, so the analysis is an approximation for
+/// functions reusing the same register for different addresses on different
+/// paths.
+pub fn analyse_stack_usage(text: &[u8], symbols: &SymbolMap) -> Result<StackUsage, ElfUtilsError> {
+    let instructions = decode_instructions(text)?;
+
+    let jump_targets: BTreeSet<i64> = instructions
+        .iter()
+        .filter_map(|instruction| instruction.jump_target())
+        .collect();
+    let mut entries: BTreeSet<usize> = symbols.function_offsets().collect();
+    for (instruction, next) in instructions.iter().zip(instructions.iter().skip(1)) {
+        if instruction.is_exit() && !jump_targets.contains(&(next.offset as i64)) {
+            entries.insert(next.offset);
+        }
+    }
+    for instruction in &instructions {
+        if let Some(target) = symbols.called_function(instruction) {
+            entries.extend(usize::try_from(target).ok());
+        }
+    }
+    entries.insert(0);
+    entries.retain(|offset| *offset < text.len());
+
+    let names: BTreeMap<usize, &str> = entries
+        .iter()
+        .filter_map(|offset| {
+            let name = symbols.function_containing(*offset)?;
+            // Only the functions starting exactly at a symbol are named.
+            symbols
+                .function_offsets()
+                .any(|start| start == *offset)
+                .then_some((*offset, name))
+        })
+        .collect();
+    let mut functions: Vec<FunctionStackUsage> = entries
+        .iter()
+        .map(|offset| FunctionStackUsage {
+            offset: *offset,
+            name: names.get(offset).map(|name| name.to_string()),
+            frame_size: 0,
+            callees: Vec::new(),
+            max_depth: None,
+        })
+        .collect();
+
+    let mut frame = FrameTracker::default();
+    let mut current = 0;
+    for instruction in &instructions {
+        while current + 1 < functions.len() && functions[current + 1].offset <= instruction.offset {
+            current += 1;
+            frame = FrameTracker::default();
+        }
+        let function = &mut functions[current];
+        frame.update(instruction);
+        function.frame_size = function.frame_size.max(frame.frame_size);
+        if let Some(target) = symbols.called_function(instruction) {
+            let target = target as usize;
+            if entries.contains(&target) && !function.callees.contains(&target) {
+                function.callees.push(target);
+            }
+        }
+    }
+
+    let mut max_depths = BTreeMap::new();
+    for function in &functions {
+        compute_max_depth(
+            function.offset,
+            &functions,
+            &mut max_depths,
+            &mut Vec::new(),
+        );
+    }
+    for function in &mut functions {
+        function.max_depth = max_depths.get(&function.offset).copied().flatten();
+    }
+    Ok(StackUsage { functions })
+}
+
+/// Computes the worst case stack usage of the function at the given offset
+/// and of all functions that it calls. The usage of the recursive functions
+/// and the functions calling them is unbounded.
+fn compute_max_depth(
+    offset: usize,
+    functions: &[FunctionStackUsage],
+    max_depths: &mut BTreeMap<usize, Option<usize>>,
+    call_chain: &mut Vec<usize>,
+) -> Option<usize> {
+    if let Some(max_depth) = max_depths.get(&offset) {
+        return *max_depth;
+    }
+    if call_chain.contains(&offset) {
+        return None;
+    }
+    let Ok(index) = functions.binary_search_by_key(&offset, |function| function.offset) else {
+        return Some(0);
+    };
+    let function = &functions[index];
+
+    call_chain.push(offset);
+    let mut max_callee_depth = Some(0);
+    for callee in &function.callees {
+        let callee_depth = compute_max_depth(*callee, functions, max_depths, call_chain);
+        max_callee_depth = match (max_callee_depth, callee_depth) {
+            (Some(max), Some(depth)) => Some(max.max(depth)),
+            _ => None,
+        };
+    }
+    call_chain.pop();
+
+    let max_depth = max_callee_depth.map(|depth| depth.saturating_add(function.frame_size));
+    max_depths.insert(offset, max_depth);
+    max_depth
+}
+
+/// Keeps track of the registers holding addresses relative to the frame
+/// pointer and the lowest address accessed so far.
+#[derive(Default)]
+struct FrameTracker {
+    /// Offsets relative to r10 of the addresses held by the registers
+    registers: BTreeMap<u8, i64>,
+    frame_size: usize,
+}
+
+impl FrameTracker {
+    fn update(&mut self, instruction: &Instruction) {
+        let base_offset = |register: u8, registers: &BTreeMap<u8, i64>| {
+            if register == FRAME_POINTER {
+                Some(0)
+            } else {
+                registers.get(&register).copied()
+            }
+        };
+
+        let is_memory_access = matches!(instruction.opcode & 0xe0, BPF_MEM | BPF_XADD);
+        match instruction.class() {
+            BPF_LDX if is_memory_access => {
+                if let Some(base) = base_offset(instruction.src, &self.registers) {
+                    self.access(base + instruction.off as i64);
+                }
+                self.registers.remove(&instruction.dst);
+                return;
+            }
+            BPF_ST | BPF_STX if is_memory_access => {
+                if let Some(base) = base_offset(instruction.dst, &self.registers) {
+                    self.access(base + instruction.off as i64);
+                }
+                return;
+            }
+            BPF_ALU64 => {
+                let operation = instruction.opcode & 0xf0;
+                let uses_register = instruction.opcode & BPF_X != 0;
+                // mov64 rX, r10 / mov64 rX, rY with rY derived from r10
+                if operation == BPF_MOV && uses_register {
+                    match base_offset(instruction.src, &self.registers) {
+                        Some(base) => self.registers.insert(instruction.dst, base),
+                        None => self.registers.remove(&instruction.dst),
+                    };
+                    return;
+                }
+                // add64 rX, imm / sub64 rX, imm for rX derived from r10
+                if matches!(operation, BPF_ADD | BPF_SUB) && !uses_register {
+                    if let Some(base) = self.registers.get(&instruction.dst).copied() {
+                        let imm = instruction.imm as i64;
+                        let offset = if operation == BPF_ADD {
+                            base + imm
+                        } else {
+                            base - imm
+                        };
+                        self.registers.insert(instruction.dst, offset);
+                        self.access(offset);
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // Any other instruction writing to a register invalidates it, calls
+        // clobber the registers used for passing the arguments.
+        if instruction.is_call() {
+            for register in 0..=5 {
+                self.registers.remove(&register);
+            }
+        } else {
+            self.registers.remove(&instruction.dst);
+        }
+    }
+
+    /// Records an access at the given offset relative to r10.
+    fn access(&mut self, offset: i64) {
+        if offset < 0 {
+            self.frame_size = self.frame_size.max(offset.unsigned_abs() as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program where main calls two functions, one of which calls
+    /// the other one through a relocated call.
+    fn sample_program() -> (Vec<u8>, SymbolMap) {
+        let text = alloc::vec![
+            // main: frame of 16 bytes
+            0x7b, 0x1a, 0xf0, 0xff, 0, 0, 0, 0, // stxdw [r10-0x10], r1
+            0x85, 0x10, 0, 0, 2, 0, 0, 0, // call +2 (first)
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, // call -1 (second)
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            // first: buffer of 64 bytes passed to a helper
+            0xbf, 0xa1, 0, 0, 0, 0, 0, 0, // mov64 r1, r10
+            0x07, 0x01, 0, 0, 0xc0, 0xff, 0xff, 0xff, // add64 r1, -0x40
+            0x85, 0, 0, 0, 1, 0, 0, 0, // call 1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            // second: frame of 8 bytes, calls first
+            0x62, 0x0a, 0xf8, 0xff, 0, 0, 0, 0, // stw [r10-0x8], 0
+            0x85, 0x10, 0, 0, 0xfa, 0xff, 0xff, 0xff, // call -6 (first)
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        symbols.add_function(0x40, "second");
+        symbols.add_relocated_call(0x10, 0x40);
+        (text, symbols)
+    }
+
+    #[test]
+    fn frames_and_call_chains_are_computed() {
+        let (text, symbols) = sample_program();
+        let usage = analyse_stack_usage(&text, &symbols).unwrap();
+
+        let frames = usage
+            .functions
+            .iter()
+            .map(|function| {
+                (
+                    function.display_name(),
+                    function.frame_size,
+                    function.max_depth,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [
+                ("main".to_string(), 16, Some(88)),
+                ("<0x20>".to_string(), 64, Some(64)),
+                ("second".to_string(), 8, Some(72)),
+            ]
+        );
+        assert_eq!(usage.max_depth(), Some(88));
+        assert_eq!(usage.deepest_call_chain(), ["main", "second", "<0x20>"]);
+        assert!(usage.check_budget(VM_STACK_SIZE).is_ok());
+        assert_eq!(
+            usage.check_budget(80).unwrap_err().to_string(),
+            "The program needs 88 bytes of stack in the call chain main -> second -> <0x20>, which exceeds the budget of 80 bytes"
+        );
+    }
+
+    #[test]
+    fn recursion_is_unbounded() {
+        let text = alloc::vec![
+            0x7a, 0x0a, 0xf8, 0xff, 0, 0, 0, 0, // stdw [r10-0x8], 0
+            0x85, 0x10, 0, 0, 0xfe, 0xff, 0xff, 0xff, // call -2
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        let usage = analyse_stack_usage(&text, &symbols).unwrap();
+        assert_eq!(usage.max_depth(), None);
+        assert_eq!(
+            usage.check_budget(VM_STACK_SIZE),
+            Err(ElfUtilsError::StackBudgetExceeded {
+                required: None,
+                budget: VM_STACK_SIZE,
+                call_chain: alloc::vec!["main".to_string(), "main".to_string()],
+            })
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use alloc::format;
+use goblin::elf::{
+    header::{ELFCLASS64, ELFDATA2LSB, ELFMAG},
+    section_header::{SHT_NOBITS, SHT_REL, SHT_RELA},
+    sym::STT_SECTION,
+};
+use log::debug;
+
+use crate::{
+    common::{checked_range, ByteReader},
+    error::{ElfUtilsError, ErrorLocation},
+    relocation_resolution::{apply_patch, Patch},
+};
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+const REL_ENTRY_SIZE: usize = 16;
+const RELA_ENTRY_SIZE: usize = 24;
+
+/// Applies relocations to a program which is received in chunks, e.g. when it
+/// is read from SUIT storage or transferred using CoAP block-wise transfers.
+///
+/// The chunks are copied into the memory in which the program is going to be
+/// executed and each relocation is applied as soon as its entry, the symbol it
+/// refers to and the patched location have been received. The output is the
+/// same as the one produced by [`crate::resolve_relocations`] called on the
+/// complete program stored at the same address.
+///
+/// As opposed to [`crate::resolve_relocations`], the ELF file isn't parsed
+/// upfront and the relocations aren't collected, the resolver only keeps track
+/// of the next relocation entry that needs to be applied. Because of this, the
+/// bookkeeping of the resolver has a constant size, the memory receiving the
+/// program still needs to hold all of it.
+///
+/// The relocations are applied in the order in which they appear in the file
+/// and only once the section header table has been received. The object files
+/// produced by LLVM store that table at the end, so all relocations are applied
+/// after the last chunk arrives. The files produced by [`crate::strip_binary`]
+/// store it at the start, which allows for applying the relocations while the
+/// rest of the file is being received.
+pub struct StreamingResolver<'a> {
+    /// Memory into which the program is received
+    program: &'a mut [u8],
+    /// Number of bytes of the program received so far
+    received: usize,
+    /// Location of the section header table, known once the ELF header arrives
+    section_table: Option<SectionTable>,
+    /// Index of the section that is checked for relocations
+    section_index: usize,
+    /// Index of the next relocation entry to apply in that section
+    relocation_index: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SectionTable {
+    offset: usize,
+    count: usize,
+}
+
+/// Fields of a section header which are needed for applying the relocations.
+struct Section {
+    sh_type: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+}
+
+impl<'a> StreamingResolver<'a> {
+    /// Creates a resolver which receives the program into the given memory,
+    /// which needs to be large enough to hold the whole program.
+    pub fn new(program: &'a mut [u8]) -> Self {
+        StreamingResolver {
+            program,
+            received: 0,
+            section_table: None,
+            section_index: 0,
+            relocation_index: 0,
+        }
+    }
+
+    /// Number of bytes of the program received so far.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Appends the next chunk of the program and applies all relocations
+    /// which can be resolved using the bytes received so far.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), ElfUtilsError> {
+        let Ok(destination) = checked_range(self.received, chunk.len(), self.program.len()) else {
+            return Err(ElfUtilsError::InsufficientMemory {
+                required: self.received.saturating_add(chunk.len()),
+                available: self.program.len(),
+            });
+        };
+        self.program[destination].copy_from_slice(chunk);
+        self.received += chunk.len();
+        self.apply_received_relocations()
+    }
+
+    /// Checks that all relocations have been applied once the last chunk of
+    /// the program was received and returns the length of the program.
+    pub fn finish(mut self) -> Result<usize, ElfUtilsError> {
+        self.apply_received_relocations()?;
+        match self.section_table {
+            Some(table) if self.section_index == table.count => Ok(self.received),
+            _ => Err(ElfUtilsError::IncompleteProgram {
+                received: self.received,
+            }),
+        }
+    }
+
+    fn apply_received_relocations(&mut self) -> Result<(), ElfUtilsError> {
+        let table = match self.section_table {
+            Some(table) => table,
+            None => {
+                let Some(header) = self.received_bytes(0, ELF_HEADER_SIZE)? else {
+                    return Ok(());
+                };
+                let table = read_section_table(header)?;
+                self.section_table = Some(table);
+                table
+            }
+        };
+        if self
+            .received_bytes(table.offset, table.count * SECTION_HEADER_SIZE)?
+            .is_none()
+        {
+            return Ok(());
+        }
+
+        while self.section_index < table.count {
+            let section = self.section(self.section_index)?;
+            let entry_size = match section.sh_type {
+                SHT_REL => REL_ENTRY_SIZE,
+                SHT_RELA => RELA_ENTRY_SIZE,
+                _ => 0,
+            };
+            if entry_size == 0 || self.relocation_index >= section.size / entry_size {
+                self.section_index += 1;
+                self.relocation_index = 0;
+                continue;
+            }
+
+            let applied = self
+                .apply_relocation(&section, entry_size)
+                .map_err(|error| error.at(self.relocation_location(&section, entry_size)))?;
+            if !applied {
+                return Ok(());
+            }
+            self.relocation_index += 1;
+        }
+        Ok(())
+    }
+
+    /// Applies the current relocation of the given relocation section, returns
+    /// `false` if the relocation can't be applied until more of the program
+    /// is received.
+    fn apply_relocation(
+        &mut self,
+        section: &Section,
+        entry_size: usize,
+    ) -> Result<bool, ElfUtilsError> {
+        let entry_offset = section
+            .offset
+            .saturating_add(self.relocation_index * entry_size);
+        let Some(entry) = self.received_bytes(entry_offset, entry_size)? else {
+            return Ok(false);
+        };
+        let mut reader = ByteReader::new(entry);
+        let r_offset = reader.read_u64()? as usize;
+        let r_info = reader.read_u64()?;
+        let addend = match section.sh_type {
+            SHT_RELA => Some(reader.read_u64()? as i64),
+            _ => None,
+        };
+        let symbol_index = (r_info >> 32) as usize;
+
+        let symtab = self.section(section.link)?;
+        if symbol_index >= symtab.size / SYMBOL_ENTRY_SIZE {
+            return Err(ElfUtilsError::InvalidSymbolIndex(symbol_index));
+        }
+        let symbol_offset = symtab
+            .offset
+            .saturating_add(symbol_index * SYMBOL_ENTRY_SIZE);
+        let Some(symbol) = self.received_bytes(symbol_offset, SYMBOL_ENTRY_SIZE)? else {
+            return Ok(false);
+        };
+        let mut reader = ByteReader::new(symbol);
+        reader.read_u32()?;
+        let st_info = reader.read_u8()?;
+        reader.read_u8()?;
+        let st_shndx = reader.read_u16()? as usize;
+        let st_value = reader.read_u64()?;
+
+        // Sections such as .bss don't occupy any space in the file, so
+        // there is no memory in the program for the symbols defined there.
+        let symbol_section = self.section(st_shndx)?;
+        if symbol_section.sh_type == SHT_NOBITS {
+            return Err(ElfUtilsError::SymbolNotAllocated(format!(
+                "{}",
+                symbol_index
+            )));
+        }
+
+        let patch = Patch {
+            offset: self.section(section.info)?.offset.saturating_add(r_offset),
+            relocation_type: r_info as u32,
+            symbol_addr: (self.program.as_ptr() as u64)
+                .wrapping_add(symbol_section.offset as u64)
+                .wrapping_add(st_value),
+            addend,
+            is_section_symbol: st_info & 0xf == STT_SECTION,
+        };
+        if let Some(size) = patch.size()? {
+            if self.received_bytes(patch.offset, size)?.is_none() {
+                return Ok(false);
+            }
+        }
+        debug!(
+            "Applying relocation {} of section {}",
+            self.relocation_index, self.section_index
+        );
+        apply_patch(self.program, &patch)?;
+        Ok(true)
+    }
+
+    /// Location of the current relocation used in the error messages. The names
+    /// of the sections aren't included as the string table may not have been
+    /// received yet.
+    fn relocation_location(&self, section: &Section, entry_size: usize) -> ErrorLocation {
+        let entry_offset = section
+            .offset
+            .saturating_add(self.relocation_index * entry_size);
+        let offset = match self.received_bytes(entry_offset, 8) {
+            Ok(Some(r_offset)) => ByteReader::new(r_offset).read_u64().ok(),
+            _ => None,
+        };
+        ErrorLocation {
+            offset: offset.map(|offset| offset as usize),
+            ..ErrorLocation::relocation(self.relocation_index)
+        }
+    }
+
+    /// Returns the given range of the program if it has already been received.
+    fn received_bytes(&self, offset: usize, len: usize) -> Result<Option<&[u8]>, ElfUtilsError> {
+        let range = checked_range(offset, len, self.program.len())?;
+        Ok((range.end <= self.received).then(|| &self.program[range]))
+    }
+
+    /// Reads the header of the section with the given index, the section
+    /// header table needs to be received before calling this function.
+    fn section(&self, index: usize) -> Result<Section, ElfUtilsError> {
+        let Some(table) = self.section_table.filter(|table| index < table.count) else {
+            return Err(ElfUtilsError::InvalidSectionIndex(index));
+        };
+        let offset = table.offset.saturating_add(index * SECTION_HEADER_SIZE);
+        let range = checked_range(offset, SECTION_HEADER_SIZE, self.program.len())?;
+        let mut reader = ByteReader::new(&self.program[range]);
+        reader.read_u32()?;
+        let sh_type = reader.read_u32()?;
+        // Flags and the virtual address aren't needed
+        reader.read_bytes(16)?;
+        Ok(Section {
+            sh_type,
+            offset: reader.read_u64()? as usize,
+            size: reader.read_u64()? as usize,
+            link: reader.read_u32()? as usize,
+            info: reader.read_u32()? as usize,
+        })
+    }
+}
+
+fn read_section_table(header: &[u8]) -> Result<SectionTable, ElfUtilsError> {
+    let mut reader = ByteReader::new(header);
+    let identification = reader.read_bytes(6)?;
+    if identification[..4] != *ELFMAG
+        || identification[4] != ELFCLASS64
+        || identification[5] != ELFDATA2LSB
+    {
+        return Err(ElfUtilsError::UnsupportedElf(
+            "only 64-bit little-endian ELF files are supported",
+        ));
+    }
+    // Skip the rest of the identification, type, machine, version, entry
+    // point and the program header offset.
+    reader.read_bytes(34)?;
+    let offset = reader.read_u64()? as usize;
+    // Skip the flags, sizes of the headers and the number of program headers.
+    reader.read_bytes(10)?;
+    let entry_size = reader.read_u16()? as usize;
+    let count = reader.read_u16()? as usize;
+    if entry_size != SECTION_HEADER_SIZE || count == 0 {
+        return Err(ElfUtilsError::Unsupported(format!(
+            "Unsupported section header table with {} entries of size {}",
+            count, entry_size
+        )));
+    }
+    Ok(SectionTable { offset, count })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::{R_BPF_64_32, R_BPF_64_64, R_BPF_64_ABS64},
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+        resolve_relocations, strip_binary,
+    };
+    use alloc::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS},
+        sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT},
+        Elf,
+    };
+
+    /// Builds an object file with a call to a static function in another
+    /// section, loads of global variables and a pointer stored in .data.
+    fn sample_object() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; call -1; lddw r2, 4; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+            0x18, 0x02, 0, 0, 4, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        // mov r0, 1; exit
+        let function = alloc::vec![
+            0xb7, 0, 0, 0, 1, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let function_index = writer.add_section(
+            ".text.function",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            function,
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            8,
+            0,
+            alloc::vec![5, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let function_section = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: function_index as u16,
+            value: 0,
+            size: 0,
+        });
+        let data_section = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 48,
+        });
+        let counter = writer.add_symbol(ElfSymbol {
+            name: "counter".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 8,
+        });
+        let relocation = |offset, symbol_index, relocation_type| ElfRelocation {
+            offset,
+            symbol_index,
+            relocation_type,
+            addend: 0,
+        };
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![
+                relocation(0, counter, R_BPF_64_64),
+                relocation(16, function_section, R_BPF_64_32),
+                relocation(24, data_section, R_BPF_64_64),
+            ],
+            false,
+        );
+        writer.add_relocations(
+            ".rel.data",
+            data_index,
+            alloc::vec![relocation(8, data_section, R_BPF_64_ABS64)],
+            false,
+        );
+        writer.write()
+    }
+
+    /// Moves the section header table to the end of the file, which is where
+    /// LLVM places it.
+    fn with_section_headers_at_end(program: &[u8]) -> Vec<u8> {
+        let binary = Elf::parse(program).unwrap();
+        let offset = binary.header.e_shoff as usize;
+        let len = binary.section_headers.len() * SECTION_HEADER_SIZE;
+        let mut moved = program.to_vec();
+        moved.resize(moved.len().next_multiple_of(8), 0);
+        let new_offset = moved.len() as u64;
+        moved.extend_from_slice(&program[offset..offset + len]);
+        moved[0x28..0x30].copy_from_slice(&new_offset.to_le_bytes());
+        moved
+    }
+
+    /// Resolves the relocations of the program in the same memory using both
+    /// the streaming and the in-place resolver and checks that the output is
+    /// identical for different chunk sizes.
+    fn assert_streaming_matches_in_place_resolution(original: &[u8]) {
+        let mut memory = original.to_vec();
+        resolve_relocations(&mut memory).unwrap();
+        let expected = memory.clone();
+        assert_ne!(expected, original);
+
+        for chunk_size in [1, 3, 8, 50, 64, 200, original.len()] {
+            memory.fill(0);
+            let mut resolver = StreamingResolver::new(&mut memory);
+            for chunk in original.chunks(chunk_size) {
+                resolver.push(chunk).unwrap();
+            }
+            assert_eq!(resolver.finish().unwrap(), original.len());
+            assert_eq!(memory, expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn streaming_matches_in_place_resolution() {
+        assert_streaming_matches_in_place_resolution(&sample_object());
+    }
+
+    #[test]
+    fn streaming_matches_in_place_resolution_with_section_headers_at_end() {
+        assert_streaming_matches_in_place_resolution(
+            &with_section_headers_at_end(&sample_object()),
+        );
+    }
+
+    #[test]
+    fn streaming_applies_relocations_before_the_end() {
+        let original = sample_object();
+        let binary = Elf::parse(&original).unwrap();
+        let section = |name| {
+            binary
+                .section_headers
+                .iter()
+                .find(|section| binary.strtab.get_at(section.sh_name) == Some(name))
+                .unwrap()
+        };
+        let text = section(".text").sh_offset as usize;
+        let rel_text = section(".rel.text");
+        let received = (rel_text.sh_offset + rel_text.sh_size) as usize;
+        assert!(received < original.len());
+
+        let mut memory = alloc::vec![0; original.len()];
+        let program_addr = memory.as_ptr() as u64;
+        let mut resolver = StreamingResolver::new(&mut memory);
+        resolver.push(&original[..received]).unwrap();
+        assert_eq!(resolver.received(), received);
+
+        // The relocations of .text are applied before the relocations of .data
+        // are received, so the pointer stored in .data isn't patched yet.
+        let data = section(".data").sh_offset as usize;
+        let immediate = u32::from_le_bytes(memory[text + 4..text + 8].try_into().unwrap());
+        assert_eq!(immediate, (program_addr + data as u64) as u32);
+        assert_eq!(memory[data + 8..data + 16], 8u64.to_le_bytes());
+    }
+
+    #[test]
+    fn streaming_applies_relocations_of_stripped_binary_before_the_last_chunk() {
+        let (stripped, _) = strip_binary(&with_section_headers_at_end(&sample_object())).unwrap();
+        let binary = Elf::parse(&stripped).unwrap();
+        let section_offset = |name| {
+            binary
+                .section_headers
+                .iter()
+                .find(|section| binary.strtab.get_at(section.sh_name) == Some(name))
+                .unwrap()
+                .sh_offset as usize
+        };
+        let text = section_offset(".text");
+        let data = section_offset(".data");
+
+        let mut memory = alloc::vec![0; stripped.len()];
+        let program_addr = memory.as_ptr() as u64;
+        let mut resolver = StreamingResolver::new(&mut memory);
+        let chunks = stripped.chunks(8).collect::<Vec<&[u8]>>();
+        let (last, chunks) = chunks.split_last().unwrap();
+        for chunk in chunks {
+            resolver.push(chunk).unwrap();
+        }
+
+        // The section header table is at the start of the stripped binary, so
+        // the load is patched before the last chunk of the program arrives.
+        let program = &resolver.program;
+        assert_eq!(
+            u32::from_le_bytes(program[text + 4..text + 8].try_into().unwrap()),
+            (program_addr + data as u64) as u32
+        );
+        resolver.push(last).unwrap();
+        assert_eq!(resolver.finish().unwrap(), stripped.len());
+    }
+
+    #[test]
+    fn streaming_rejects_incomplete_programs() {
+        let original = with_section_headers_at_end(&sample_object());
+        let mut memory = alloc::vec![0; original.len()];
+        let mut resolver = StreamingResolver::new(&mut memory);
+        resolver.push(&original[..original.len() - 1]).unwrap();
+        assert!(resolver.finish().is_err());
+
+        let mut too_small = alloc::vec![0; original.len() - 1];
+        let mut resolver = StreamingResolver::new(&mut too_small);
+        assert!(resolver.push(&original).is_err());
+    }
+}
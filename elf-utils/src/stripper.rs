@@ -0,0 +1,366 @@
+use alloc::{
+    collections::btree_map::BTreeMap as HashMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use goblin::elf::{
+    section_header::{
+        SHF_ALLOC, SHN_LORESERVE, SHT_NOBITS, SHT_PROGBITS, SHT_REL, SHT_RELA, SHT_STRTAB,
+        SHT_SYMTAB,
+    },
+    sym::STT_FILE,
+    SectionHeader,
+};
+use log::debug;
+
+use crate::{
+    common::{
+        containing_function, parse_elf, section_alignment, section_bytes, symbol_name,
+        MAX_ZERO_INITIALISED_SIZE, R_BPF_NONE,
+    },
+    elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+    error::{ElfUtilsError, ErrorLocation},
+    helpers::with_helper_symbols_resolved,
+};
+
+/// Summary of the sections removed by [`strip_binary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripReport {
+    /// Size of the input object file in bytes
+    pub original_size: usize,
+    /// Size of the stripped object file in bytes
+    pub stripped_size: usize,
+    /// Names of all sections that were removed from the file
+    pub removed_sections: Vec<String>,
+}
+
+impl StripReport {
+    /// Number of bytes saved by stripping the object file.
+    pub fn saved_bytes(&self) -> usize {
+        self.original_size.saturating_sub(self.stripped_size)
+    }
+}
+
+/// Removes all sections which aren't needed for resolving relocations of the
+/// program once it is loaded on the target device. This includes the debug
+/// information, the .BTF and .BTF.ext sections and the .eh_frame section
+/// generated by the compiler.
+///
+/// Only the sections that are loaded into memory (e.g. .text, .data, .rodata),
+/// relocation sections applying to them and the symbol and string tables are
+/// kept. The symbol table is reduced to the symbols defined in the kept
+/// sections and the string table is rebuilt to only contain the names that
+/// are still in use. The output always uses a single string table for both
+/// the section and symbol names, which is what the relocation resolution
+/// performed on the device expects (see [`crate::resolve_relocations`]).
+///
+/// The .bss sections are converted into zero-filled sections. The relocations
+/// are resolved in place in the memory holding the object file, so the
+/// zero-initialised variables need to be allocated as a part of it.
+///
+/// It replaces the `strip -d -R .BTF -R .BTF.ext` command that was used before
+/// so that the RawObjectFile layout doesn't depend on binutils being installed.
+pub fn strip_binary(program: &[u8]) -> Result<(Vec<u8>, StripReport), ElfUtilsError> {
+    let original_size = program.len();
+    let program = &with_helper_symbols_resolved(program)?;
+    let binary = parse_elf(program)?;
+    if !binary.is_64 || !binary.little_endian {
+        return Err(ElfUtilsError::UnsupportedElf(
+            "only 64-bit little-endian ELF files can be stripped",
+        ));
+    }
+
+    let section_name =
+        |section: &SectionHeader| binary.strtab.get_at(section.sh_name).unwrap_or_default();
+
+    let mut writer = ElfWriter::new();
+    let mut removed_sections = Vec::new();
+
+    // First copy all sections that are loaded into memory and keep track of
+    // their new indices to update the symbols and relocations later on.
+    let mut section_indices: HashMap<usize, usize> = HashMap::new();
+    for (index, section) in binary.section_headers.iter().enumerate().skip(1) {
+        let name = section_name(section);
+        if !is_required_section(section, name) {
+            // The symbol and string tables are rebuilt by the writer and the
+            // relocation sections are copied later if their target section is kept.
+            let is_rebuilt = match section.sh_type {
+                SHT_SYMTAB | SHT_STRTAB => true,
+                SHT_REL | SHT_RELA => binary
+                    .section_headers
+                    .get(section.sh_info as usize)
+                    .is_some_and(|target| is_required_section(target, section_name(target))),
+                _ => false,
+            };
+            if !is_rebuilt {
+                removed_sections.push(name.to_string());
+            }
+            continue;
+        }
+        // The alignment is validated as the writer pads the sections to it.
+        let alignment = section_alignment(section)? as u64;
+        let new_index = if section.sh_type == SHT_NOBITS {
+            if section.sh_size > MAX_ZERO_INITIALISED_SIZE as u64 {
+                return Err(ElfUtilsError::InvalidValue {
+                    field: "size of the zero-initialised sections",
+                    value: section.sh_size,
+                });
+            }
+            writer.add_section(
+                name,
+                SHT_PROGBITS,
+                section.sh_flags,
+                alignment,
+                section.sh_entsize,
+                alloc::vec![0; section.sh_size as usize],
+            )
+        } else {
+            let data = section_bytes(section, program)?;
+            writer.add_section(
+                name,
+                section.sh_type,
+                section.sh_flags,
+                alignment,
+                section.sh_entsize,
+                data.to_vec(),
+            )
+        };
+        section_indices.insert(index, new_index);
+    }
+
+    // Now we only keep the symbols which are defined in the copied sections,
+    // the undefined ones and absolute symbols apart from the file names.
+    let mut symbol_indices: HashMap<usize, u32> = HashMap::new();
+    for (index, symbol) in binary.syms.iter().enumerate().skip(1) {
+        let section_index = if symbol.st_shndx == 0 || symbol.st_shndx >= SHN_LORESERVE as usize {
+            if symbol.st_type() == STT_FILE {
+                continue;
+            }
+            symbol.st_shndx as u16
+        } else if let Some(new_index) = section_indices.get(&symbol.st_shndx) {
+            *new_index as u16
+        } else {
+            continue;
+        };
+        let new_index = writer.add_symbol(ElfSymbol {
+            name: binary
+                .strtab
+                .get_at(symbol.st_name)
+                .unwrap_or_default()
+                .to_string(),
+            info: symbol.st_info,
+            other: symbol.st_other,
+            section_index,
+            value: symbol.st_value,
+            size: symbol.st_size,
+        });
+        symbol_indices.insert(index, new_index);
+    }
+
+    // Finally we copy the relocation sections applying to the copied sections.
+    for (index, relocation_section) in &binary.shdr_relocs {
+        let section = &binary.section_headers[*index];
+        let Some(target) = section_indices.get(&(section.sh_info as usize)) else {
+            continue;
+        };
+        let mut relocations = Vec::new();
+        for (relocation_index, reloc) in relocation_section.iter().enumerate() {
+            // The calls to the helpers resolved above don't need to be relocated.
+            if reloc.r_type == R_BPF_NONE {
+                continue;
+            }
+            let Some(symbol_index) = symbol_indices.get(&reloc.r_sym) else {
+                let target_index = section.sh_info as usize;
+                let offset = reloc.r_offset as usize;
+                let symbol = binary.syms.get(reloc.r_sym);
+                let location = ErrorLocation {
+                    object: None,
+                    section: binary
+                        .section_headers
+                        .get(target_index)
+                        .map(|target| section_name(target).to_string()),
+                    relocation_index: Some(relocation_index),
+                    offset: Some(offset),
+                    function: containing_function(&binary, target_index, offset)
+                        .map(ToString::to_string),
+                    symbol: symbol
+                        .and_then(|symbol| symbol_name(&binary, &symbol))
+                        .map(ToString::to_string),
+                };
+                return Err(ElfUtilsError::Malformed(
+                    "The relocation refers to a symbol defined in a removed section".to_string(),
+                )
+                .at(location));
+            };
+            relocations.push(ElfRelocation {
+                offset: reloc.r_offset,
+                symbol_index: *symbol_index,
+                relocation_type: reloc.r_type,
+                addend: reloc.r_addend.unwrap_or(0),
+            });
+        }
+        writer.add_relocations(
+            section_name(section),
+            *target,
+            relocations,
+            section.sh_type == SHT_RELA,
+        );
+    }
+
+    let stripped = writer.write();
+    let report = StripReport {
+        original_size,
+        stripped_size: stripped.len(),
+        removed_sections,
+    };
+    debug!("Stripped the binary: {:?}", report);
+    Ok((stripped, report))
+}
+
+/// Sections which are loaded into memory are needed for executing the program,
+/// the only exception is the .eh_frame section which contains the call frame
+/// information used by debuggers. The symbol table, string table and relocation
+/// sections aren't copied directly as they are rebuilt by the [`ElfWriter`].
+pub fn is_required_section(section: &SectionHeader, name: &str) -> bool {
+    section.sh_flags & SHF_ALLOC as u64 != 0 && name != ".eh_frame"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use goblin::elf::{
+        section_header::{SHF_EXECINSTR, SHF_WRITE},
+        sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+        Elf,
+    };
+
+    /// Builds an object file similar to the ones produced by LLVM, with a
+    /// load from .data relocated against a global variable and a section
+    /// containing debug information.
+    fn sample_object() -> Vec<u8> {
+        let mut writer = ElfWriter::new();
+        // lddw r1, 0; exit
+        let text = alloc::vec![
+            0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x95, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let debug_info =
+            writer.add_section(".debug_info", SHT_PROGBITS, 0, 1, 0, alloc::vec![0; 64]);
+        let text_index = writer.add_section(
+            ".text",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_EXECINSTR) as u64,
+            8,
+            0,
+            text,
+        );
+        let data_index = writer.add_section(
+            ".data",
+            SHT_PROGBITS,
+            (SHF_ALLOC | SHF_WRITE) as u64,
+            4,
+            0,
+            alloc::vec![5, 0, 0, 0],
+        );
+        writer.add_section(".BTF", SHT_PROGBITS, 0, 4, 0, alloc::vec![0; 128]);
+        writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: debug_info as u16,
+            value: 0,
+            size: 0,
+        });
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 24,
+        });
+        let counter = writer.add_symbol(ElfSymbol {
+            name: "counter".to_string(),
+            info: STB_GLOBAL << 4 | STT_OBJECT,
+            other: 0,
+            section_index: data_index as u16,
+            value: 0,
+            size: 4,
+        });
+        writer.add_relocations(
+            ".rel.text",
+            text_index,
+            alloc::vec![ElfRelocation {
+                offset: 0,
+                symbol_index: counter,
+                relocation_type: 1,
+                addend: 0,
+            }],
+            false,
+        );
+        writer.write()
+    }
+
+    #[test]
+    fn strip_removes_debug_sections() {
+        let object = sample_object();
+        let (stripped, report) = strip_binary(&object).unwrap();
+
+        assert_eq!(report.removed_sections, [".debug_info", ".BTF"]);
+        assert_eq!(report.stripped_size, stripped.len());
+        assert!(report.saved_bytes() > 0);
+
+        let binary = Elf::parse(&stripped).unwrap();
+        let names = binary
+            .section_headers
+            .iter()
+            .map(|section| binary.strtab.get_at(section.sh_name).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["", ".text", ".data", ".rel.text", ".symtab", ".strtab"]
+        );
+    }
+
+    #[test]
+    fn strip_updates_symbol_and_relocation_indices() {
+        let (stripped, _) = strip_binary(&sample_object()).unwrap();
+        let binary = Elf::parse(&stripped).unwrap();
+
+        // The section symbol of the removed debug section is dropped.
+        assert_eq!(binary.syms.len(), 3);
+        let (_, relocations) = &binary.shdr_relocs[0];
+        let relocation = relocations.iter().next().unwrap();
+        let symbol = binary.syms.get(relocation.r_sym).unwrap();
+        assert_eq!(binary.strtab.get_at(symbol.st_name), Some("counter"));
+        let section = &binary.section_headers[symbol.st_shndx];
+        assert_eq!(binary.strtab.get_at(section.sh_name), Some(".data"));
+        assert_eq!(
+            binary.section_headers[binary.shdr_relocs[0].0].sh_info as usize,
+            1
+        );
+    }
+
+    #[test]
+    fn strip_allocates_bss_sections() {
+        let mut writer = ElfWriter::new();
+        writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 4, 12);
+        let (stripped, _) = strip_binary(&writer.write()).unwrap();
+
+        let binary = Elf::parse(&stripped).unwrap();
+        let bss = &binary.section_headers[1];
+        assert_eq!(binary.strtab.get_at(bss.sh_name), Some(".bss"));
+        assert_eq!(bss.sh_type, SHT_PROGBITS);
+        let contents = &stripped[bss.file_range().unwrap()];
+        assert_eq!(contents, [0; 12]);
+    }
+
+    #[test]
+    fn strip_is_idempotent() {
+        let (stripped, _) = strip_binary(&sample_object()).unwrap();
+        let (stripped_again, report) = strip_binary(&stripped).unwrap();
+        assert_eq!(stripped, stripped_again);
+        assert!(report.removed_sections.is_empty());
+    }
+}
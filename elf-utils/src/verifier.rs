@@ -0,0 +1,408 @@
+//! Static verification of the control flow of eBPF programs.
+//!
+//! The verifier builds the control flow graph of the bytecode and checks that
+//! the program can't jump outside of itself or into the middle of an
+//! instruction, that each function ends with an `exit` on every path and that
+//! there are no unreachable instructions. This allows for rejecting broken
+//! programs on the host instead of finding out once they are deployed.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    string::ToString,
+    vec::Vec,
+};
+use core::fmt;
+use micro_bpf_common::BinaryFileLayout;
+
+use crate::{
+    disassembler::{text_with_symbols, SymbolMap},
+    error::{ElfUtilsError, ErrorLocation},
+    model::{decode_instructions, BPF_JA, BPF_JMP},
+};
+
+/// A problem with the control flow of the program found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Kind of the problem
+    pub kind: DiagnosticKind,
+    /// Offset of the offending instruction in the .text section and the name
+    /// of the function containing it if it is known
+    pub location: ErrorLocation,
+}
+
+/// Kinds of the problems reported by the verifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// A jump or a call transfers control outside of the program
+    TargetOutOfBounds {
+        /// Offset of the target in bytes, it can be negative
+        target: i64,
+    },
+    /// A jump or a call transfers control to the second half of a 16-byte
+    /// load instruction
+    TargetInsideInstruction {
+        /// Offset of the target in bytes
+        target: usize,
+    },
+    /// Execution can continue past the end of the function (or the program)
+    /// without reaching an `exit` instruction
+    MissingExit,
+    /// The instructions starting at the location can't be reached from any
+    /// of the functions of the program
+    Unreachable {
+        /// Number of consecutive unreachable instructions
+        count: usize,
+    },
+    /// A jump backwards which may result in an unbounded loop. It is only
+    /// reported in the strict mode.
+    BackEdge {
+        /// Offset of the target in bytes
+        target: usize,
+    },
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::TargetOutOfBounds { target } => {
+                write!(f, "Branch target {} is outside of the program", target)
+            }
+            DiagnosticKind::TargetInsideInstruction { target } => write!(
+                f,
+                "Branch target {:#x} is in the middle of a 16-byte instruction",
+                target
+            ),
+            DiagnosticKind::MissingExit => write!(
+                f,
+                "Execution continues past the end of the function without reaching an exit"
+            ),
+            DiagnosticKind::Unreachable { count } => {
+                write!(f, "{} unreachable instructions", count)
+            }
+            DiagnosticKind::BackEdge { target } => write!(
+                f,
+                "Backward jump to {:#x} may result in an unbounded loop",
+                target
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.kind, self.location)
+    }
+}
+
+/// Verifies the control flow of the bytecode contained in a binary with
+/// a given layout, see [`verify`].
+pub fn verify_binary(
+    program: &[u8],
+    layout: BinaryFileLayout,
+    strict: bool,
+) -> Result<Vec<Diagnostic>, ElfUtilsError> {
+    let (text, symbols) = text_with_symbols(program, layout)?;
+    verify(&text, &symbols, strict)
+}
+
+/// Builds the control flow graph of the program and returns all problems
+/// found in it. An error is only returned if the bytecode can't be decoded.
+///
+/// The entry points of the control flow graph are the start of the program,
+/// the functions registered in the symbol map and the targets of all calls.
+/// Falling through into the next function is reported in the same way as
+/// falling off the end of the program. In the strict mode all backward jumps
+/// are reported, as it can't be determined statically whether the loops that
+/// they form are bounded.
+pub fn verify(
+    text: &[u8],
+    symbols: &SymbolMap,
+    strict: bool,
+) -> Result<Vec<Diagnostic>, ElfUtilsError> {
+    let instructions = decode_instructions(text)?;
+    let indices: BTreeMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.offset, index))
+        .collect();
+
+    let mut diagnostics = Vec::new();
+    let mut report = |kind: DiagnosticKind, offset: usize| {
+        diagnostics.push(Diagnostic {
+            kind,
+            location: ErrorLocation {
+                section: Some(".text".to_string()),
+                offset: Some(offset),
+                function: symbols.function_containing(offset).map(ToString::to_string),
+                ..Default::default()
+            },
+        })
+    };
+    let resolve = |target: i64| -> Result<usize, DiagnosticKind> {
+        let offset = usize::try_from(target)
+            .ok()
+            .filter(|offset| *offset < text.len())
+            .ok_or(DiagnosticKind::TargetOutOfBounds { target })?;
+        indices
+            .get(&offset)
+            .copied()
+            .ok_or(DiagnosticKind::TargetInsideInstruction { target: offset })
+    };
+
+    // First we find the targets of all jumps and calls, the functions start
+    // at the targets of the calls.
+    let mut jump_targets: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut entries: BTreeSet<usize> = symbols
+        .function_offsets()
+        .filter_map(|offset| indices.get(&offset).copied())
+        .collect();
+    if !instructions.is_empty() {
+        entries.insert(0);
+    }
+    for (index, instruction) in instructions.iter().enumerate() {
+        let target = match symbols.called_function(instruction) {
+            Some(target) => target,
+            None => match instruction.jump_target() {
+                Some(target) => target,
+                None => continue,
+            },
+        };
+        match resolve(target) {
+            Ok(target) if instruction.is_call() => {
+                entries.insert(target);
+            }
+            Ok(target) => {
+                jump_targets.insert(index, target);
+            }
+            Err(kind) => report(kind, instruction.offset),
+        }
+    }
+
+    // Then we walk all paths starting at the entry points.
+    let mut reachable = alloc::vec![false; instructions.len()];
+    let mut pending: Vec<usize> = entries.iter().copied().collect();
+    while let Some(index) = pending.pop() {
+        if core::mem::replace(&mut reachable[index], true) {
+            continue;
+        }
+        let instruction = &instructions[index];
+        if instruction.is_exit() {
+            continue;
+        }
+        if let Some(target) = jump_targets.get(&index) {
+            if strict && *target <= index {
+                report(
+                    DiagnosticKind::BackEdge {
+                        target: instructions[*target].offset,
+                    },
+                    instruction.offset,
+                );
+            }
+            pending.push(*target);
+        }
+        // Only the conditional jumps can fall through to the next instruction.
+        if instruction.opcode == BPF_JMP | BPF_JA {
+            continue;
+        }
+        let next = index + 1;
+        if next == instructions.len() || entries.contains(&next) {
+            report(DiagnosticKind::MissingExit, instruction.offset);
+        } else {
+            pending.push(next);
+        }
+    }
+
+    // Finally we report the unreachable instructions, each sequence of them
+    // is reported only once.
+    let mut index = 0;
+    while index < instructions.len() {
+        if reachable[index] {
+            index += 1;
+            continue;
+        }
+        let count = reachable[index..]
+            .iter()
+            .take_while(|reachable| !**reachable)
+            .count();
+        report(
+            DiagnosticKind::Unreachable { count },
+            instructions[index].offset,
+        );
+        index += count;
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.location.offset);
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::R_BPF_64_32,
+        elf_writer::{ElfRelocation, ElfSymbol, ElfWriter},
+    };
+    use alloc::string::{String, ToString};
+    use goblin::elf::{
+        section_header::{SHF_ALLOC, SHF_EXECINSTR, SHT_PROGBITS},
+        sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_SECTION},
+    };
+
+    fn kinds(text: &[u8], symbols: &SymbolMap, strict: bool) -> Vec<DiagnosticKind> {
+        verify(text, symbols, strict)
+            .unwrap()
+            .into_iter()
+            .map(|diagnostic| diagnostic.kind)
+            .collect()
+    }
+
+    #[test]
+    fn valid_program_has_no_diagnostics() {
+        let text = alloc::vec![
+            0xb7, 0x01, 0, 0, 0, 0, 0, 0, // mov r1, 0
+            0x18, 0x02, 0, 0, 0, 0, 0, 0, // lddw r2, 0
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x07, 0x01, 0, 0, 1, 0, 0, 0, // add r1, 1
+            0xa5, 0x01, 0xfe, 0xff, 10, 0, 0, 0, // jlt r1, 10, -2
+            0x85, 0x10, 0, 0, 1, 0, 0, 0, // call +1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            0x05, 0, 0, 0, 0, 0, 0, 0, // ja +0
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        assert_eq!(kinds(&text, &symbols, false), []);
+        assert_eq!(
+            kinds(&text, &symbols, true),
+            [DiagnosticKind::BackEdge { target: 0x18 }]
+        );
+    }
+
+    #[test]
+    fn invalid_targets_are_reported() {
+        let text = alloc::vec![
+            0x05, 0, 1, 0, 0, 0, 0, 0, // ja +1
+            0x18, 0x02, 0, 0, 0, 0, 0, 0, // lddw r2, 0
+            0, 0, 0, 0, 0, 0, 0, 0, //
+            0x85, 0x10, 0, 0, 0x10, 0, 0, 0, // call +0x10
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        let diagnostics = verify(&text, &symbols, false).unwrap();
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.kind)
+                .collect::<Vec<_>>(),
+            [
+                DiagnosticKind::TargetInsideInstruction { target: 0x10 },
+                DiagnosticKind::Unreachable { count: 3 },
+                DiagnosticKind::TargetOutOfBounds { target: 0xa0 },
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "Branch target 0x10 is in the middle of a 16-byte instruction (.text+0x0, function main)"
+        );
+    }
+
+    #[test]
+    fn missing_exits_are_reported() {
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 1, 0, 0, 0, // call +1
+            0xb7, 0, 0, 0, 0, 0, 0, 0, // mov r0, 0
+            0xb7, 0, 0, 0, 1, 0, 0, 0, // mov r0, 1
+        ];
+        let mut symbols = SymbolMap::new();
+        symbols.add_function(0, "main");
+        symbols.add_function(0x10, "callee");
+        let diagnostics = verify(&text, &symbols, false).unwrap();
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|diagnostic| (diagnostic.kind, diagnostic.location.offset))
+                .collect::<Vec<_>>(),
+            [
+                (DiagnosticKind::MissingExit, Some(0x8)),
+                (DiagnosticKind::MissingExit, Some(0x10)),
+            ]
+        );
+        assert_eq!(diagnostics[1].location.function.as_deref(), Some("callee"));
+    }
+
+    #[test]
+    fn relocated_calls_are_entry_points() {
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, // call -1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        assert_eq!(
+            kinds(&text, &SymbolMap::new(), false),
+            [DiagnosticKind::Unreachable { count: 1 }]
+        );
+        let mut symbols = SymbolMap::new();
+        symbols.add_relocated_call(0, 0x10);
+        assert_eq!(kinds(&text, &symbols, false), []);
+    }
+
+    #[test]
+    fn calls_to_other_sections_are_ignored() {
+        let mut writer = ElfWriter::new();
+        let flags = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+        let text = alloc::vec![
+            0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, // call -1
+            0x85, 0x10, 0, 0, 3, 0, 0, 0, // call +3
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let text_index = writer.add_section(".text", SHT_PROGBITS, flags, 8, 0, text);
+        let function = alloc::vec![
+            0xb7, 0, 0, 0, 1, 0, 0, 0, // mov r0, 1
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+            0x05, 0, 0, 0, 0, 0, 0, 0, // ja +0
+            0xb7, 0, 0, 0, 2, 0, 0, 0, // mov r0, 2
+            0x95, 0, 0, 0, 0, 0, 0, 0, // exit
+        ];
+        let function_index =
+            writer.add_section(".text.function", SHT_PROGBITS, flags, 8, 0, function);
+        writer.add_symbol(ElfSymbol {
+            name: "main".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: text_index as u16,
+            value: 0,
+            size: 24,
+        });
+        // Neither of the offsets of the called functions refers to .text.
+        let other = writer.add_symbol(ElfSymbol {
+            name: "other".to_string(),
+            info: STB_GLOBAL << 4 | STT_FUNC,
+            other: 0,
+            section_index: function_index as u16,
+            value: 24,
+            size: 16,
+        });
+        let function_section = writer.add_symbol(ElfSymbol {
+            name: String::new(),
+            info: STB_LOCAL << 4 | STT_SECTION,
+            other: 0,
+            section_index: function_index as u16,
+            value: 0,
+            size: 0,
+        });
+        let relocations = [(0, other), (8, function_section)]
+            .into_iter()
+            .map(|(offset, symbol_index)| ElfRelocation {
+                offset,
+                symbol_index,
+                relocation_type: R_BPF_64_32,
+                addend: 0,
+            })
+            .collect();
+        writer.add_relocations(".rel.text", text_index, relocations, false);
+
+        let diagnostics = verify_binary(&writer.write(), BinaryFileLayout::RawObjectFile, false);
+        assert_eq!(diagnostics.unwrap(), []);
+    }
+}
@@ -0,0 +1,346 @@
+// Property-based tests checking that none of the public functions of the crate
+// panic when given malformed input. Each test case takes a valid object file
+// or a binary produced from it by one of the supported workflows, corrupts it
+// using a small number of mutations and feeds it to all functions accepting
+// that kind of input. The functions are allowed to either succeed or return
+// an error, the test only fails if any of them panics.
+
+use goblin::elf::{
+    section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_PROGBITS},
+    sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT, STT_SECTION},
+};
+use micro_bpf_common::{BinaryFileLayout, TargetVM};
+use micro_bpf_elf_utils::{
+    analyse_binary_memory_access, analyse_binary_stack_usage, assemble_binary,
+    assemble_binary_with_entry_points, assemble_binary_with_version,
+    assemble_femtocontainer_binary, assemble_pre_resolved_binary, check_compatibility,
+    decode_instructions, disassemble_binary, eliminate_dead_code, extract_allowed_helpers,
+    extract_section, infer_required_helpers, link_objects, resolve_helper_symbols,
+    resolve_pre_resolved_relocations, resolve_pre_resolved_relocations_at, resolve_relocations,
+    resolve_relocations_split, strip_binary, verify_binary, ElfRelocation, ElfSymbol, ElfWriter,
+    ExtendedBinary, FCBinary, LoadAddresses, PreResolvedBinary, StreamingResolver, SymbolMap,
+    HEADER_VERSION, HEADER_VERSION_LEGACY, VM_STACK_SIZE,
+};
+use proptest::{prelude::*, sample::Index};
+
+const R_BPF_64_64: u32 = 1;
+const R_BPF_64_ABS64: u32 = 2;
+const R_BPF_64_32: u32 = 10;
+
+const LAYOUTS: [BinaryFileLayout; 5] = [
+    BinaryFileLayout::OnlyTextSection,
+    BinaryFileLayout::FemtoContainersHeader,
+    BinaryFileLayout::ExtendedHeader,
+    BinaryFileLayout::RawObjectFile,
+    BinaryFileLayout::PreResolvedRelocations,
+];
+
+/// Builds an object file similar to the ones produced by LLVM. It contains
+/// loads from all data sections and, unless it is meant for the Femto-Container
+/// layout, a call to a global function and a pointer stored in .data, which
+/// that layout doesn't support.
+fn sample_object(for_femtocontainers: bool) -> Vec<u8> {
+    let mut writer = ElfWriter::new();
+    // lddw r1, 0; lddw r2, 4; lddw r3, 0; call -1; exit; mov r0, 1; exit
+    let mut text = vec![
+        0x18, 0x01, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0x18, 0x02, 0, 0, 4, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0x18, 0x03, 0, 0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, 0, 0, 0, //
+        0x85, 0x10, 0, 0, 0xff, 0xff, 0xff, 0xff, //
+        0x95, 0, 0, 0, 0, 0, 0, 0, //
+        0xb7, 0, 0, 0, 1, 0, 0, 0, //
+        0x95, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    if for_femtocontainers {
+        // mov r0, 0 instead of the call
+        text[48..56].copy_from_slice(&[0xb7, 0, 0, 0, 0, 0, 0, 0]);
+    }
+    let text_index = writer.add_section(
+        ".text",
+        SHT_PROGBITS,
+        (SHF_ALLOC | SHF_EXECINSTR) as u64,
+        8,
+        0,
+        text,
+    );
+    let data_index = writer.add_section(
+        ".data",
+        SHT_PROGBITS,
+        (SHF_ALLOC | SHF_WRITE) as u64,
+        8,
+        0,
+        vec![7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+    );
+    let rodata_index = writer.add_section(
+        ".rodata.str1.1",
+        SHT_PROGBITS,
+        SHF_ALLOC as u64,
+        1,
+        1,
+        b"abc\0%d\n\0".to_vec(),
+    );
+    let bss_index = writer.add_nobits_section(".bss", (SHF_ALLOC | SHF_WRITE) as u64, 8, 16);
+
+    let rodata_symbol = writer.add_symbol(ElfSymbol {
+        name: String::new(),
+        info: STB_LOCAL << 4 | STT_SECTION,
+        other: 0,
+        section_index: rodata_index as u16,
+        value: 0,
+        size: 0,
+    });
+    writer.add_symbol(ElfSymbol {
+        name: "main".to_string(),
+        info: STB_GLOBAL << 4 | STT_FUNC,
+        other: 0,
+        section_index: text_index as u16,
+        value: 0,
+        size: 64,
+    });
+    let helper = writer.add_symbol(ElfSymbol {
+        name: "helper".to_string(),
+        info: STB_GLOBAL << 4 | STT_FUNC,
+        other: 0,
+        section_index: text_index as u16,
+        value: 64,
+        size: 16,
+    });
+    let counter = writer.add_symbol(ElfSymbol {
+        name: "counter".to_string(),
+        info: STB_GLOBAL << 4 | STT_OBJECT,
+        other: 0,
+        section_index: data_index as u16,
+        value: 0,
+        size: 8,
+    });
+    let zeroed = writer.add_symbol(ElfSymbol {
+        name: "zeroed".to_string(),
+        info: STB_GLOBAL << 4 | STT_OBJECT,
+        other: 0,
+        section_index: bss_index as u16,
+        value: 8,
+        size: 8,
+    });
+
+    let relocation = |offset, symbol_index, relocation_type| ElfRelocation {
+        offset,
+        symbol_index,
+        relocation_type,
+        addend: 0,
+    };
+    let mut text_relocations = vec![
+        relocation(0, counter, R_BPF_64_64),
+        relocation(16, rodata_symbol, R_BPF_64_64),
+        relocation(32, zeroed, R_BPF_64_64),
+    ];
+    if !for_femtocontainers {
+        text_relocations.push(relocation(48, helper, R_BPF_64_32));
+    }
+    writer.add_relocations(".rel.text", text_index, text_relocations, false);
+    if !for_femtocontainers {
+        writer.add_relocations(
+            ".rela.data",
+            data_index,
+            vec![ElfRelocation {
+                addend: 4,
+                ..relocation(8, rodata_symbol, R_BPF_64_ABS64)
+            }],
+            true,
+        );
+    }
+    writer.write()
+}
+
+/// Valid inputs of all kinds accepted by the public functions of the crate.
+fn seeds() -> Vec<Vec<u8>> {
+    let object = sample_object(false);
+    let (stripped, _) = strip_binary(&object).unwrap();
+    vec![
+        assemble_binary_with_version(&object, vec![], HEADER_VERSION).unwrap(),
+        assemble_femtocontainer_binary(&sample_object(true)).unwrap(),
+        assemble_pre_resolved_binary(&object).unwrap(),
+        object,
+        stripped,
+    ]
+}
+
+#[derive(Debug, Clone)]
+enum Mutation {
+    /// Overwrites a single byte
+    Byte(Index, u8),
+    /// Overwrites an aligned 4 or 8-byte field with a value likely to hit
+    /// edge cases such as overflows
+    Field(Index, bool, u64),
+    /// Drops the end of the input
+    Truncate(Index),
+}
+
+fn mutation() -> impl Strategy<Value = Mutation> {
+    let interesting = prop::sample::select(vec![
+        0,
+        1,
+        7,
+        8,
+        0x7f,
+        0x80,
+        0xff,
+        0xffff,
+        0x7fff_ffff,
+        0x8000_0000,
+        0xffff_ffff,
+        0x1_0000_0000,
+        0x7fff_ffff_ffff_ffff,
+        u64::MAX - 7,
+        u64::MAX,
+    ]);
+    prop_oneof![
+        4 => (any::<Index>(), any::<u8>()).prop_map(|(index, byte)| Mutation::Byte(index, byte)),
+        4 => (any::<Index>(), any::<bool>(), interesting)
+            .prop_map(|(index, wide, value)| Mutation::Field(index, wide, value)),
+        1 => any::<Index>().prop_map(Mutation::Truncate),
+    ]
+}
+
+fn apply(input: &mut Vec<u8>, mutation: &Mutation) {
+    if input.is_empty() {
+        return;
+    }
+    match mutation {
+        Mutation::Byte(index, byte) => {
+            let position = index.index(input.len());
+            input[position] = *byte;
+        }
+        Mutation::Field(index, wide, value) => {
+            let size = if *wide { 8 } else { 4 };
+            if input.len() < size {
+                return;
+            }
+            let position = index.index(input.len() / size) * size;
+            input[position..position + size].copy_from_slice(&value.to_le_bytes()[..size]);
+        }
+        Mutation::Truncate(index) => input.truncate(index.index(input.len())),
+    }
+}
+
+/// Calls all public functions accepting the given input, the results are
+/// ignored as the only requirement is that the functions don't panic.
+fn process(input: &[u8]) {
+    let _ = strip_binary(input);
+    let _ = assemble_binary(input);
+    let _ = assemble_binary_with_version(input, vec![], HEADER_VERSION_LEGACY);
+    let _ = assemble_binary_with_version(input, vec![], HEADER_VERSION);
+    let _ = assemble_binary_with_entry_points(input, vec![], HEADER_VERSION, &["helper", "main"]);
+    let _ = assemble_femtocontainer_binary(input);
+    let _ = assemble_pre_resolved_binary(input);
+    let _ = extract_allowed_helpers(input);
+    let _ = extract_section(".text", input);
+    let _ = SymbolMap::from_elf(input);
+    let _ = decode_instructions(input);
+    for layout in LAYOUTS {
+        let _ = disassemble_binary(input, layout);
+        let _ = infer_required_helpers(input, layout);
+        let _ = verify_binary(input, layout, true);
+        let _ = analyse_binary_stack_usage(input, layout);
+        let _ = analyse_binary_memory_access(input, layout);
+        let _ = check_compatibility(input, TargetVM::Rbpf, layout);
+    }
+
+    if let Ok(binary) = ExtendedBinary::parse(input) {
+        let _ = binary.function_names();
+        let _ = binary.entry_points();
+    }
+    if let Ok(binary) = FCBinary::parse(input) {
+        let _ = binary.function_names();
+    }
+    let _ = PreResolvedBinary::parse(input);
+    let _ = link_objects(&[input, input]);
+    if let Ok((optimised, _)) = eliminate_dead_code(input, &[]) {
+        let _ = assemble_binary(&optimised);
+        let _ = strip_binary(&optimised);
+    }
+    let _ = eliminate_dead_code(input, &["helper", "main"]);
+    if let Ok(linked) = link_objects(&[input]) {
+        let _ = strip_binary(&linked);
+        let _ = assemble_pre_resolved_binary(&linked);
+    }
+
+    let _ = resolve_relocations(&mut input.to_vec());
+    let _ = resolve_helper_symbols(&mut input.to_vec());
+    let _ = resolve_pre_resolved_relocations(&mut input.to_vec());
+    let _ = resolve_pre_resolved_relocations_at(&mut input.to_vec(), u64::MAX - 16);
+
+    let mut program = input.to_vec();
+    let addresses = LoadAddresses {
+        flash_address: 0x0800_0000,
+        ram_address: u64::MAX - 16,
+    };
+    if let Ok(ram_region) = resolve_relocations_split(&mut program, addresses) {
+        // The size of the region isn't limited, so we don't allocate it if
+        // a corrupted section header specifies a huge section.
+        let mut ram = vec![0; ram_region.size.min(1 << 16)];
+        let _ = ram_region.initialise(&program, &mut ram);
+    }
+
+    let mut memory = vec![0; input.len()];
+    let mut resolver = StreamingResolver::new(&mut memory);
+    for chunk in input.chunks(13) {
+        if resolver.push(chunk).is_err() {
+            return;
+        }
+    }
+    let _ = resolver.finish();
+}
+
+#[test]
+fn seeds_are_processed_successfully() {
+    let [extended, femtocontainer, pre_resolved, object, stripped] = &seeds()[..] else {
+        unreachable!();
+    };
+    assert!(ExtendedBinary::parse(extended).is_ok());
+    assert!(FCBinary::parse(femtocontainer).is_ok());
+    assert!(PreResolvedBinary::parse(pre_resolved).is_ok());
+    assert!(resolve_pre_resolved_relocations(&mut pre_resolved.clone()).is_ok());
+    assert!(resolve_relocations(&mut stripped.clone()).is_ok());
+    // The object file needs to be stripped first because of the .bss section.
+    assert!(resolve_relocations(&mut object.clone()).is_err());
+
+    let verified = [
+        (extended, BinaryFileLayout::ExtendedHeader),
+        (pre_resolved, BinaryFileLayout::PreResolvedRelocations),
+        (object, BinaryFileLayout::RawObjectFile),
+    ];
+    for (binary, layout) in verified {
+        assert_eq!(verify_binary(binary, layout, true).unwrap(), []);
+        let stack_usage = analyse_binary_stack_usage(binary, layout).unwrap();
+        assert!(stack_usage.check_budget(VM_STACK_SIZE).is_ok());
+        assert_eq!(
+            check_compatibility(binary, TargetVM::Rbpf, layout).unwrap(),
+            []
+        );
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1024))]
+
+    #[test]
+    fn mutated_inputs_dont_cause_panics(
+        seed in any::<Index>(),
+        mutations in prop::collection::vec(mutation(), 1..8),
+    ) {
+        let seeds = seeds();
+        let mut input = seeds[seed.index(seeds.len())].clone();
+        for mutation in &mutations {
+            apply(&mut input, mutation);
+        }
+        process(&input);
+    }
+
+    #[test]
+    fn random_inputs_dont_cause_panics(input in prop::collection::vec(any::<u8>(), 0..512)) {
+        process(&input);
+    }
+}
@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use micro_bpf_elf_utils::HEADER_VERSION;
 
 #[derive(Debug, Subcommand, PartialEq, Eq)]
 pub enum Action {
@@ -27,16 +28,84 @@ pub enum Action {
         binary_file: Option<String>,
         /// Layout of the binary file that the VM should expect.
         /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
         /// Determines which kind of postprocessing is applied to the ELF file.
         #[arg(long, default_value_t = String::from("ExtendedHeader"))]
         binary_layout: String,
-        /// Controlls which indices of helpers are made available to the VM
+        /// Controlls which indices of helpers are made available to the VM.
+        /// If not specified, the helpers called by the program are inferred
+        /// from its bytecode and only those are made available.
         #[clap(long, long, value_parser, num_args = 1.., value_delimiter = ' ')]
         helper_indices: Vec<u8>,
         /// Controlls the pipeline stage at which the helpers need to be
         /// verified
         #[arg(long, default_value_t = String::from("Runtime"))]
         helper_access_verification: String,
+        /// Reject programs with backward jumps when verifying the control
+        /// flow of the program, as they may result in unbounded loops
+        #[arg(long, default_value_t = false)]
+        strict_verification: bool,
+        /// Report the problems found when verifying the control flow of the
+        /// program as warnings instead of rejecting the program. It allows for
+        /// deploying programs which the verifier rejects incorrectly
+        #[arg(long, default_value_t = false)]
+        allow_unverified: bool,
+        /// Maximum number of bytes of stack that the program can use in its
+        /// deepest call chain, the VM provides 512 bytes by default
+        #[arg(long, default_value_t = 512)]
+        stack_budget: usize,
+        /// Removes the functions that are never called and the read-only data
+        /// that is never used from the object file before it is processed
+        #[arg(long, default_value_t = false)]
+        eliminate_dead_code: bool,
+        /// Names of the functions exported as the entry points of the program,
+        /// one of which is selected when the program is executed. If not
+        /// specified, the program starts at the beginning of the .text section.
+        /// Only supported by the ExtendedHeader layout
+        #[clap(long, value_parser, num_args = 1.., value_delimiter = ' ')]
+        entry_functions: Vec<String>,
+        /// Version of the ExtendedHeader layout of the produced binary. The
+        /// latest version is used by default, it stores the checksum of the
+        /// program and is required for e.g. zero-initialised variables and
+        /// exporting entry points. VMs which only support the legacy 32-byte
+        /// header need version 0
+        #[arg(long, default_value_t = HEADER_VERSION)]
+        header_version: u32,
+    },
+    /// Links multiple object files produced by the compilation step into a
+    /// single object file which can then be post-processed. This allows for
+    /// sharing code between programs without copying it into each source file.
+    Link {
+        /// Names of the object files to link.
+        #[clap(long, value_parser, num_args = 1.., value_delimiter = ' ')]
+        object_files: Vec<String>,
+        /// Name of the object file to be generated
+        #[arg(long, default_value_t = String::from("linked.o"))]
+        output_file: String,
+    },
+    /// Prints the disassembled bytecode of a binary produced by the postprocessing step.
+    Disassemble {
+        /// Name of the binary file to disassemble.
+        #[arg(long)]
+        binary_file: String,
+        /// Layout of the binary file.
+        /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
+        #[arg(long, default_value_t = String::from("ExtendedHeader"))]
+        binary_layout: String,
+    },
+    /// Prints the memory regions accessed by the loads and stores of a binary
+    /// produced by the postprocessing step and the accesses which can't be
+    /// attributed to any region.
+    MemoryRegions {
+        /// Name of the binary file to analyse.
+        #[arg(long)]
+        binary_file: String,
+        /// Layout of the binary file.
+        /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
+        #[arg(long, default_value_t = String::from("ExtendedHeader"))]
+        binary_layout: String,
     },
     /// Sign the eBPF binary for SUIT update protocol. Generates  the manifest,
     /// signs it and places all files in the CoAP fileserver root directory.
@@ -83,6 +152,12 @@ pub enum Action {
         # [arg(long, default_value_t = String::from("suit_manifest0.signed"))]
         suit_manifest: String,
 
+        /// Binary file referenced by the SUIT manifest. It is used for inferring
+        /// the helpers called by the program if the helper indices aren't specified
+        /// and for checking that the target VM supports all of its instructions.
+        #[arg(long)]
+        binary_file: Option<String>,
+
         /// Network interface of the machine hosting the CoAP fileserver.
         /// Used to find the IPv6 address of the fileserver.
         #[arg(long, default_value_t = String::from("wlan0"))]
@@ -98,6 +173,7 @@ pub enum Action {
 
         /// Layout of the binary file that the VM should expect.
         /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
         #[arg(long, default_value_t = String::from("ExtendedHeader"))]
         binary_layout: String,
 
@@ -106,7 +182,9 @@ pub enum Action {
         #[arg(long, short, default_value_t = 0)]
         suit_storage_slot: i32,
 
-        /// Controlls which indices of helpers are made available to the VM
+        /// Controlls which indices of helpers are made available to the VM.
+        /// If not specified, they are inferred from the bytecode of the
+        /// program in the binary file.
         #[clap(long, long, value_parser, num_args = 1.., value_delimiter = ' ')]
         helper_indices: Vec<u8>,
 
@@ -140,6 +218,7 @@ pub enum Action {
 
         /// Layout of the binary file that the VM should expect.
         /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
         #[arg(long, default_value_t = String::from("ExtendedHeader"))]
         binary_layout: String,
 
@@ -177,7 +256,9 @@ pub enum Action {
         #[arg(long, default_value_t = String::from(""))]
         host_ipv6_addr: String,
 
-        /// Controlls which indices of helpers are made available to the VM
+        /// Controlls which indices of helpers are made available to the VM.
+        /// If not specified, the helpers called by the program are inferred
+        /// from its bytecode and only those are made available.
         #[clap(long, long, value_parser, num_args = 1.., value_delimiter = ' ')]
         helper_indices: Vec<u8>,
 
@@ -185,6 +266,36 @@ pub enum Action {
         /// verified
         #[arg(long, default_value_t = String::from("Runtime"))]
         helper_access_verification: String,
+        /// Reject programs with backward jumps when verifying the control
+        /// flow of the program, as they may result in unbounded loops
+        #[arg(long, default_value_t = false)]
+        strict_verification: bool,
+        /// Report the problems found when verifying the control flow of the
+        /// program as warnings instead of rejecting the program. It allows for
+        /// deploying programs which the verifier rejects incorrectly
+        #[arg(long, default_value_t = false)]
+        allow_unverified: bool,
+        /// Maximum number of bytes of stack that the program can use in its
+        /// deepest call chain, the VM provides 512 bytes by default
+        #[arg(long, default_value_t = 512)]
+        stack_budget: usize,
+        /// Removes the functions that are never called and the read-only data
+        /// that is never used from the object file before it is processed
+        #[arg(long, default_value_t = false)]
+        eliminate_dead_code: bool,
+        /// Names of the functions exported as the entry points of the program,
+        /// one of which is selected when the program is executed. If not
+        /// specified, the program starts at the beginning of the .text section.
+        /// Only supported by the ExtendedHeader layout
+        #[clap(long, value_parser, num_args = 1.., value_delimiter = ' ')]
+        entry_functions: Vec<String>,
+        /// Version of the ExtendedHeader layout of the produced binary. The
+        /// latest version is used by default, it stores the checksum of the
+        /// program and is required for e.g. zero-initialised variables and
+        /// exporting entry points. VMs which only support the legacy 32-byte
+        /// header need version 0
+        #[arg(long, default_value_t = HEADER_VERSION)]
+        header_version: u32,
         #[arg(long, default_value_t = String::from("ExecuteRequest"))]
         helper_access_list_source: String,
         #[arg(long, default_value_t = false)]
@@ -207,6 +318,7 @@ pub enum Action {
 
         /// Layout of the binary file that the VM should expect.
         /// Available options: OnlyTextSection, FemtoContainersHeader, ExtendedHeader, RawObjectFile,
+        /// PreResolvedRelocations
         #[arg(long, default_value_t = String::from("ExtendedHeader"))]
         binary_layout: String,
 
@@ -226,7 +338,17 @@ pub enum Action {
         #[arg(long, default_value_t = String::from("ShortLived"))]
         execution_model: String,
 
-        /// Controlls which indices of helpers are made available to the VM
+        /// Binary file loaded into the SUIT storage slot. It is used for
+        /// inferring the helpers called by the program if the helper indices
+        /// aren't specified, otherwise all helpers are made available. It is
+        /// also used for checking that the target VM supports all of its
+        /// instructions.
+        #[arg(long)]
+        binary_file: Option<String>,
+
+        /// Controlls which indices of helpers are made available to the VM.
+        /// If not specified, they are inferred from the bytecode of the
+        /// program in the binary file.
         #[clap(long, long, value_parser, num_args = 1.., value_delimiter = ' ')]
         helper_indices: Vec<u8>,
         /// Controlls the pipeline stage at which the helpers need to be
@@ -235,6 +357,11 @@ pub enum Action {
         helper_access_verification: String,
         #[arg(long, default_value_t = String::from("ExecuteRequest"))]
         helper_access_list_source: String,
+        /// Entry point exported by the program that the VM should execute,
+        /// given either by its name or by its index. Finding it by name
+        /// requires the binary file.
+        #[arg(long)]
+        entry_point: Option<String>,
 
         #[arg(short)]
         jit: bool,
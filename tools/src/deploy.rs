@@ -1,8 +1,9 @@
-use micro_bpf_common::{HelperAccessListSource, HelperAccessVerification, TargetVM};
+use micro_bpf_common::{HelperAccessListSource, TargetVM};
 
 use crate::{
     compile::compile, micro_bpf_common::BinaryFileLayout, postprocessing::apply_postprocessing,
-    pull::pull, sign::sign,
+    postprocessing::check_target_compatibility, postprocessing::PostprocessingOptions, pull::pull,
+    sign::sign,
 };
 
 const TEMP_FILE: &str = "program.bin";
@@ -20,8 +21,7 @@ pub async fn deploy(
     host_ip: &str,
     board: &str,
     micro_bpf_root_dir: Option<&str>,
-    helper_indices: Vec<u8>,
-    helper_access_verification: HelperAccessVerification,
+    postprocessing_options: PostprocessingOptions,
     helper_access_list_source: HelperAccessListSource,
     erase: bool,
     for_jit: bool,
@@ -30,13 +30,15 @@ pub async fn deploy(
     let suit_manifest = &format!("suit_manifest{}.signed", suit_storage_slot);
 
     compile(bpf_source_file, Some(TEMP_FILE), out_dir)?;
-    apply_postprocessing(
+    // If the allowed helpers aren't specified, the program is only allowed
+    // to call the helpers that it uses once the dead code is removed.
+    let helper_indices = apply_postprocessing(
         &object_file_name,
         binary_layout,
         TEMP_FILE,
-        helper_indices.clone(),
-        helper_access_verification,
+        &postprocessing_options,
     )?;
+    check_target_compatibility(Some(TEMP_FILE), target, binary_layout)?;
     sign(
         host_net_if,
         board,
@@ -54,7 +56,7 @@ pub async fn deploy(
         target,
         binary_layout,
         suit_storage_slot,
-        helper_access_verification,
+        postprocessing_options.helper_access_verification,
         helper_access_list_source,
         &helper_indices,
         erase,
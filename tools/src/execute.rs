@@ -1,6 +1,5 @@
 use std::process::Command;
 
-use enum_iterator::all;
 use log::debug;
 use micro_bpf_common::{
     ExecutionModel, HelperAccessListSource, HelperAccessVerification, HelperFunctionID,
@@ -18,32 +17,29 @@ pub async fn execute(
     helper_access_verification: HelperAccessVerification,
     helper_access_list_source: HelperAccessListSource,
     helper_indices: &[u8],
+    entry_point: u8,
     jit: bool,
     jit_compile: bool,
     benchmark: bool,
 ) -> Result<String, String> {
-    // If the user doesn't specify any allowed helper indices, we allow all of them
-    // by default.
-    let helper_indices = if helper_indices.len() == 0 {
-        all::<HelperFunctionID>().collect::<Vec<HelperFunctionID>>()
-    } else {
-        helper_indices
-            .to_vec()
-            .into_iter()
-            .filter_map(|i| num::FromPrimitive::from_u8(i))
-            .collect::<Vec<HelperFunctionID>>()
-    };
+    let helper_indices = helper_indices
+        .iter()
+        .filter_map(|i| num::FromPrimitive::from_u8(*i))
+        .collect::<Vec<HelperFunctionID>>();
 
     let request = VMExecutionRequest::new(
-        VMConfiguration::new(
-            target,
-            suit_storage_slot,
-            binary_layout,
-            helper_access_verification,
-            helper_access_list_source,
-            jit,
-            jit_compile,
-        ),
+        VMConfiguration {
+            entry_point,
+            ..VMConfiguration::new(
+                target,
+                suit_storage_slot,
+                binary_layout,
+                helper_access_verification,
+                helper_access_list_source,
+                jit,
+                jit_compile,
+            )
+        },
         helper_indices,
     );
 
@@ -21,7 +21,10 @@ pub use compile::compile;
 pub use deploy::deploy;
 pub use execute::execute;
 pub use pull::pull;
-pub use postprocessing::apply_postprocessing;
+pub use postprocessing::{
+    apply_postprocessing, find_entry_point, infer_helper_indices, link_object_files,
+    PostprocessingOptions,
+};
 pub use sign::sign;
 
 pub use environment::{Environment, load_env};
@@ -19,12 +19,18 @@ use args::Action;
 use clap::Parser;
 use compile::compile;
 use deploy::deploy;
+use enum_iterator::all;
 use environment::load_env;
 use execute::execute;
 use micro_bpf_common::{
-    BinaryFileLayout, ExecutionModel, HelperAccessListSource, HelperAccessVerification, TargetVM,
+    BinaryFileLayout, ExecutionModel, HelperAccessListSource, HelperAccessVerification,
+    HelperFunctionID, TargetVM,
+};
+use micro_bpf_elf_utils::{analyse_binary_memory_access, disassemble_binary};
+use postprocessing::{
+    apply_postprocessing, check_target_compatibility, find_entry_point, infer_helper_indices,
+    link_object_files, read_bytes_from_file, PostprocessingOptions,
 };
-use postprocessing::apply_postprocessing;
 use pull::pull;
 use sign::sign;
 
@@ -38,6 +44,9 @@ async fn main() {
     let result = match &args.command {
         Action::Compile { .. } => handle_compile(&args.command, use_env),
         Action::Postprocessing { .. } => handle_postprocessing(&args.command),
+        Action::Link { .. } => handle_link(&args.command),
+        Action::Disassemble { .. } => handle_disassemble(&args.command),
+        Action::MemoryRegions { .. } => handle_memory_regions(&args.command),
         Action::Sign { .. } => handle_sign(&args.command, use_env),
         Action::Pull { .. } => handle_pull(&args.command, use_env).await,
         Action::Execute { .. } => handle_execute(&args.command, use_env).await,
@@ -109,6 +118,7 @@ async fn handle_pull(args: &Action, use_env: bool) -> Result<(), String> {
         riot_ipv6_addr,
         host_ipv6_addr,
         suit_manifest,
+        binary_file,
         host_network_interface,
         riot_network_interface,
         target,
@@ -130,6 +140,13 @@ async fn handle_pull(args: &Action, use_env: bool) -> Result<(), String> {
         HelperAccessVerification::from_str(helper_access_verification.as_str())?;
     let helper_access_list_source =
         HelperAccessListSource::from_str(helper_access_list_source.as_str())?;
+    let helper_indices = match binary_file {
+        Some(binary_file) if helper_indices.is_empty() => {
+            infer_helper_indices(binary_file, binary_file_layout)?
+        }
+        _ => helper_indices.to_vec(),
+    };
+    check_target_compatibility(binary_file.as_deref(), target_vm, binary_file_layout)?;
 
     if use_env {
         let env = load_env();
@@ -145,7 +162,7 @@ async fn handle_pull(args: &Action, use_env: bool) -> Result<(), String> {
             *suit_storage_slot as usize,
             helper_access_verification,
             helper_access_list_source,
-            helper_indices,
+            &helper_indices,
             *erase,
             *jit,
         )
@@ -163,7 +180,7 @@ async fn handle_pull(args: &Action, use_env: bool) -> Result<(), String> {
         *suit_storage_slot as usize,
         helper_access_verification,
         helper_access_list_source,
-        helper_indices,
+        &helper_indices,
         *erase,
         *jit,
     )
@@ -177,9 +194,11 @@ async fn handle_execute(args: &Action, use_env: bool) -> Result<(), String> {
         suit_storage_slot,
         host_network_interface,
         execution_model,
+        binary_file,
         helper_indices,
         helper_access_verification,
         helper_access_list_source,
+        entry_point,
         jit,
         jit_compile,
         benchmark,
@@ -195,6 +214,27 @@ async fn handle_execute(args: &Action, use_env: bool) -> Result<(), String> {
         HelperAccessVerification::from_str(helper_access_verification.as_str())?;
     let helper_access_list_source =
         HelperAccessListSource::from_str(helper_access_list_source.as_str())?;
+    // If the allowed helpers aren't specified, they are inferred from the
+    // program if it is available, otherwise all helpers are allowed.
+    let helper_indices = if !helper_indices.is_empty() {
+        helper_indices.to_vec()
+    } else if let Some(binary_file) = binary_file {
+        infer_helper_indices(binary_file, binary_file_layout)?
+    } else {
+        // The helpers can't be inferred without the program, so we make it
+        // clear that it isn't restricted to the helpers it calls.
+        eprintln!("Warning: no --binary-file given, all helpers are allowed.");
+        all::<HelperFunctionID>().map(|helper| helper.into()).collect()
+    };
+    check_target_compatibility(binary_file.as_deref(), target_vm, binary_file_layout)?;
+    // The first entry point is used by default, for programs which don't
+    // export any entry points it is the start of the .text section.
+    let entry_point = match entry_point {
+        Some(entry_point) => {
+            find_entry_point(binary_file.as_deref(), binary_file_layout, entry_point)?
+        }
+        None => 0,
+    };
 
     let response = if use_env {
         let env = load_env();
@@ -207,7 +247,8 @@ async fn handle_execute(args: &Action, use_env: bool) -> Result<(), String> {
             execution_model,
             helper_access_verification,
             helper_access_list_source,
-            helper_indices,
+            &helper_indices,
+            entry_point,
             *jit,
             *jit_compile,
             *benchmark,
@@ -223,7 +264,8 @@ async fn handle_execute(args: &Action, use_env: bool) -> Result<(), String> {
             execution_model,
             helper_access_verification,
             helper_access_list_source,
-            helper_indices,
+            &helper_indices,
+            entry_point,
             *jit,
             *jit_compile,
             *benchmark,
@@ -243,6 +285,12 @@ fn handle_postprocessing(args: &Action) -> Result<(), String> {
         binary_layout,
         helper_indices,
         helper_access_verification,
+        strict_verification,
+        allow_unverified,
+        stack_budget,
+        eliminate_dead_code,
+        entry_functions,
+        header_version,
     } = args
     else {
         return Err(format!("Invalid subcommand args: {:?}", args));
@@ -258,13 +306,81 @@ fn handle_postprocessing(args: &Action) -> Result<(), String> {
         "a.bin"
     };
 
-    apply_postprocessing(
-        source_object_file,
-        binary_layout,
-        file_name,
-        helper_indices.to_vec(),
+    // The helpers are inferred by the postprocessing step after the dead
+    // code is removed if they aren't specified.
+    let helper_indices = if helper_indices.is_empty() {
+        None
+    } else {
+        Some(helper_indices.to_vec())
+    };
+
+    let options = PostprocessingOptions {
+        helper_indices,
         helper_access_verification,
-    )
+        strict_verification: *strict_verification,
+        allow_unverified: *allow_unverified,
+        stack_budget: *stack_budget,
+        remove_dead_code: *eliminate_dead_code,
+        entry_functions: entry_functions.clone(),
+        header_version: *header_version,
+    };
+
+    apply_postprocessing(source_object_file, binary_layout, file_name, &options)?;
+    Ok(())
+}
+
+fn handle_link(args: &Action) -> Result<(), String> {
+    let Action::Link {
+        object_files,
+        output_file,
+    } = args
+    else {
+        return Err(format!("Invalid subcommand args: {:?}", args));
+    };
+
+    link_object_files(object_files, output_file)
+}
+
+fn handle_disassemble(args: &Action) -> Result<(), String> {
+    let Action::Disassemble {
+        binary_file,
+        binary_layout,
+    } = args
+    else {
+        return Err(format!("Invalid subcommand args: {:?}", args));
+    };
+
+    let binary_layout = binary_layout.as_str().parse::<BinaryFileLayout>()?;
+    let program = read_bytes_from_file(binary_file);
+
+    println!("{}", disassemble_binary(&program, binary_layout)?);
+    Ok(())
+}
+
+fn handle_memory_regions(args: &Action) -> Result<(), String> {
+    let Action::MemoryRegions {
+        binary_file,
+        binary_layout,
+    } = args
+    else {
+        return Err(format!("Invalid subcommand args: {:?}", args));
+    };
+
+    let binary_layout = binary_layout.as_str().parse::<BinaryFileLayout>()?;
+    let program = read_bytes_from_file(binary_file);
+    let report = analyse_binary_memory_access(&program, binary_layout)?;
+
+    println!("Memory regions accessed by the program:");
+    for access in &report.regions {
+        println!("  {}", access);
+    }
+    if !report.unknown.is_empty() {
+        println!("Warning: the following accesses can't be attributed to any region:");
+        for access in &report.unknown {
+            println!("  {}", access);
+        }
+    }
+    Ok(())
 }
 
 async fn handle_deploy(args: &Action, use_env: bool) -> Result<(), String> {
@@ -281,6 +397,12 @@ async fn handle_deploy(args: &Action, use_env: bool) -> Result<(), String> {
         riot_network_interface,
         helper_indices,
         helper_access_verification,
+        strict_verification,
+        allow_unverified,
+        stack_budget,
+        eliminate_dead_code,
+        entry_functions,
+        header_version,
         helper_access_list_source,
         target,
         erase,
@@ -296,6 +418,18 @@ async fn handle_deploy(args: &Action, use_env: bool) -> Result<(), String> {
         HelperAccessVerification::from_str(helper_access_verification.as_str())?;
     let helper_access_list_source =
         HelperAccessListSource::from_str(helper_access_list_source.as_str())?;
+    // The helpers are inferred once the program is compiled.
+    let helper_indices = (!helper_indices.is_empty()).then(|| helper_indices.to_vec());
+    let postprocessing_options = PostprocessingOptions {
+        helper_indices,
+        helper_access_verification,
+        strict_verification: *strict_verification,
+        allow_unverified: *allow_unverified,
+        stack_budget: *stack_budget,
+        remove_dead_code: *eliminate_dead_code,
+        entry_functions: entry_functions.clone(),
+        header_version: *header_version,
+    };
 
     if use_env {
         let env = environment::load_env();
@@ -313,8 +447,7 @@ async fn handle_deploy(args: &Action, use_env: bool) -> Result<(), String> {
             &env.host_ip,
             &env.board_name,
             Some(&env.micro_bpf_root_dir),
-            helper_indices.to_vec(),
-            helper_access_verification,
+            postprocessing_options,
             helper_access_list_source,
             *erase,
             *jit,
@@ -335,8 +468,7 @@ async fn handle_deploy(args: &Action, use_env: bool) -> Result<(), String> {
         host_ipv6_addr,
         board_name,
         None,
-        helper_indices.to_vec(),
-        helper_access_verification,
+        postprocessing_options,
         helper_access_list_source,
         *erase,
             *jit,
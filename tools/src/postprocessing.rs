@@ -1,59 +1,343 @@
 use std::{
     fs::{self, File},
     io::{Read, Write as _},
-    process::Command,
 };
 
-use micro_bpf_common::{BinaryFileLayout, HelperAccessVerification};
+use log::{debug, warn};
+use micro_bpf_common::{BinaryFileLayout, HelperAccessVerification, TargetVM, MAX_ENTRY_POINTS};
 use micro_bpf_elf_utils::{
-    assemble_binary_specifying_helpers, assemble_femtocontainer_binary, extract_section,
+    analyse_binary_memory_access, analyse_binary_stack_usage, assemble_binary_with_entry_points,
+    assemble_femtocontainer_binary, assemble_pre_resolved_binary, check_compatibility,
+    eliminate_dead_code, extract_section, infer_required_helpers, link_objects, strip_binary,
+    verify_binary, ElfUtilsError, ExtendedBinary, Incompatibility, PreResolvedBinary,
+    HEADER_VERSION_LEGACY,
 };
 
 // This module is responsible for applying different post-processing steps
 // to the input ELF file to transform it into a corresponding binary layout
 // that the VM expects to when loading the program.
+/// Options controlling how [`apply_postprocessing`] transforms and checks the
+/// program, shared by the `postprocessing` and `deploy` subcommands.
+#[derive(Debug, Clone)]
+pub struct PostprocessingOptions {
+    /// Helpers that the program is allowed to call, inferred from the program
+    /// once the dead code is removed if not specified.
+    pub helper_indices: Option<Vec<u8>>,
+    pub helper_access_verification: HelperAccessVerification,
+    /// Treats the verifier warnings as errors.
+    pub strict_verification: bool,
+    /// Logs the verifier findings as warnings instead of rejecting the program.
+    pub allow_unverified: bool,
+    /// Size of the stack of the VM in bytes.
+    pub stack_budget: usize,
+    pub remove_dead_code: bool,
+    /// Functions exported as entry points, only supported by the ExtendedHeader layout.
+    pub entry_functions: Vec<String>,
+    /// Version of the ExtendedHeader layout.
+    pub header_version: u32,
+}
+
+/// Transforms the given object file into the binary layout expected by the VM
+/// and writes it into the output file. Returns the indices of the helpers that
+/// the program is allowed to call, which are inferred from the program if
+/// they aren't specified.
 pub fn apply_postprocessing(
     source_object_file: &str,
     binary_layout: BinaryFileLayout,
     output_file_name: &str,
-    helper_indices: Vec<u8>,
-    helper_access_verification: HelperAccessVerification,
-) -> Result<(), String> {
+    options: &PostprocessingOptions,
+) -> Result<Vec<u8>, String> {
+    // Only the ExtendedHeader layout stores the table of the functions of the
+    // program, the other layouts always start at the beginning of .text.
+    if !options.entry_functions.is_empty() && binary_layout != BinaryFileLayout::ExtendedHeader {
+        return Err(format!(
+            "Entry points can only be exported in the ExtendedHeader layout, not {:?}",
+            binary_layout
+        ));
+    }
+    let entry_points = options
+        .entry_functions
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<&str>>();
+
+    let mut program_bytes = read_bytes_from_file(source_object_file);
+    // The dead code is removed from the object file itself, so that the
+    // smaller program is then processed in the same way for all layouts.
+    if options.remove_dead_code {
+        let (optimised_program, report) = eliminate_dead_code(&program_bytes, &entry_points)?;
+        debug!(
+            "Removed unused functions {:?} from the object file, saved {} bytes",
+            report.removed_functions,
+            report.saved_bytes()
+        );
+        program_bytes = optimised_program;
+    }
+
+    // If the allowed helpers aren't specified, they are inferred only after
+    // the dead code is removed, so that the helpers called exclusively by the
+    // removed functions aren't made available to the program.
+    let helper_indices = match &options.helper_indices {
+        Some(helper_indices) => helper_indices.clone(),
+        None => infer_helpers(
+            &program_bytes,
+            BinaryFileLayout::RawObjectFile,
+            source_object_file,
+        )?,
+    };
+
     let processed_program_bytes = match binary_layout {
         BinaryFileLayout::OnlyTextSection => {
-            let program_bytes = read_bytes_from_file(source_object_file);
             let text_section_bytes = extract_section(".text", &program_bytes)?;
             Vec::from(text_section_bytes)
         }
         BinaryFileLayout::ExtendedHeader => {
-            let program_bytes = read_bytes_from_file(source_object_file);
-            let relocated_program =
-                assemble_binary_specifying_helpers(&program_bytes, helper_indices.clone())?;
+            let relocated_program = assemble_binary_with_entry_points(
+                &program_bytes,
+                helper_indices.clone(),
+                options.header_version,
+                &entry_points,
+            )
+            .map_err(|error| match error {
+                // Features like the data relocations or .bss are only available
+                // in newer versions of the layout, which need to be requested.
+                ElfUtilsError::RequiresNewerVersion { version, .. } => {
+                    format!("{}, use --header-version {} or newer", error, version)
+                }
+                _ => error.to_string(),
+            })?;
             relocated_program
         }
         BinaryFileLayout::FemtoContainersHeader => {
-            let program_bytes = read_bytes_from_file(source_object_file);
             let relocated_program = assemble_femtocontainer_binary(&program_bytes)?;
             relocated_program
         }
         BinaryFileLayout::RawObjectFile => {
-            strip_binary(&source_object_file, Some(&output_file_name.to_string()))?;
-            read_bytes_from_file(output_file_name)
+            // We remove all of the debug and .BTF info from the ELF object file.
+            // It is required in order to decrease the binary size so that
+            // it can be sent directly to the target device where the relocations
+            // can be performed.
+            let (stripped_program, report) = strip_binary(&program_bytes)?;
+            debug!(
+                "Stripped {:?} from the object file, saved {} bytes ({} -> {})",
+                report.removed_sections,
+                report.saved_bytes(),
+                report.original_size,
+                report.stripped_size
+            );
+            stripped_program
         }
+        BinaryFileLayout::PreResolvedRelocations => assemble_pre_resolved_binary(&program_bytes)?,
     };
 
-    if helper_access_verification == HelperAccessVerification::AheadOfTime {
+    // The control flow of the program is checked before it is deployed so that
+    // malformed programs are rejected on the host instead of on the device.
+    let diagnostics = verify_binary(
+        &processed_program_bytes,
+        binary_layout,
+        options.strict_verification,
+    )?;
+    if !diagnostics.is_empty() && options.allow_unverified {
+        for diagnostic in &diagnostics {
+            warn!("{}", diagnostic);
+        }
+    } else if !diagnostics.is_empty() {
+        let report = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Err(format!("The program failed verification:\n{}", report));
+    }
+
+    // The VM runs the program with a fixed-size stack, so we make sure that
+    // the deepest call chain of the program doesn't overflow it.
+    let stack_usage = analyse_binary_stack_usage(&processed_program_bytes, binary_layout)?;
+    for function in &stack_usage.functions {
+        debug!(
+            "Stack usage of {}: frame {} bytes, worst case {:?} bytes",
+            function.display_name(),
+            function.frame_size,
+            function.max_depth
+        );
+    }
+    stack_usage.check_budget(options.stack_budget)?;
+
+    // The memory regions that the program accesses determine the list of
+    // regions that the VM needs to allow, the unknown accesses can only be
+    // checked at runtime.
+    let memory_access = analyse_binary_memory_access(&processed_program_bytes, binary_layout)?;
+    debug!(
+        "Memory regions accessed by the program: {:?}",
+        memory_access.regions
+    );
+    for access in &memory_access.unknown {
+        warn!("{}", access);
+    }
+
+    if options.helper_access_verification == HelperAccessVerification::AheadOfTime {
         // We first need to map our state to the structures that rbpf understands
         let helper_idxs = helper_indices
             .iter()
             .map(|id| *id as u32)
             .collect::<Vec<u32>>();
-        let interpreter = map_interpreter(binary_layout);
-        rbpf::check_helpers(&processed_program_bytes, &helper_idxs, interpreter)
+        let mut interpreter = map_interpreter(binary_layout);
+        // rbpf doesn't know about the pre-resolved layout, so we only pass it
+        // the .text section which is checked in the same way as for OnlyTextSection.
+        // The same applies to the newer versions of the ExtendedHeader layout
+        // as rbpf assumes the 32-byte header of the legacy version.
+        let program_to_check = match binary_layout {
+            BinaryFileLayout::PreResolvedRelocations => {
+                PreResolvedBinary::parse(&processed_program_bytes)?.text
+            }
+            BinaryFileLayout::ExtendedHeader if options.header_version != HEADER_VERSION_LEGACY => {
+                interpreter = rbpf::InterpreterVariant::Default;
+                ExtendedBinary::parse(&processed_program_bytes)?.text
+            }
+            _ => processed_program_bytes.clone(),
+        };
+        rbpf::check_helpers(&program_to_check, &helper_idxs, interpreter)
             .map_err(|e| format!("Error when checking helper function access: {:?}", e))?;
     }
 
-    write_binary(&processed_program_bytes, output_file_name)
+    write_binary(&processed_program_bytes, output_file_name)?;
+    Ok(helper_indices)
+}
+
+/// Links the given object files into a single object file, which can then be
+/// processed by [`apply_postprocessing`] in the same way as a single compiled
+/// source file.
+pub fn link_object_files(object_files: &[String], output_file_name: &str) -> Result<(), String> {
+    let objects = object_files
+        .iter()
+        .map(|object_file| read_bytes_from_file(object_file))
+        .collect::<Vec<Vec<u8>>>();
+    let objects = objects.iter().map(Vec::as_slice).collect::<Vec<&[u8]>>();
+    let linked = link_objects(&objects).map_err(|error| {
+        // The location of the error refers to the index of the object file.
+        match error.location().and_then(|location| location.object) {
+            Some(object) => format!("{}: {}", object_files[object], error),
+            None => error.to_string(),
+        }
+    })?;
+    debug!(
+        "Linked {} object files into {} ({} bytes)",
+        object_files.len(),
+        output_file_name,
+        linked.len()
+    );
+    write_binary(&linked, output_file_name)
+}
+
+/// Infers the indices of the helpers called by the program stored in the given
+/// file. They are used as the list of allowed helpers if it isn't specified
+/// explicitly, so that the program can only call the helpers it actually uses.
+pub fn infer_helper_indices(
+    program_file: &str,
+    binary_layout: BinaryFileLayout,
+) -> Result<Vec<u8>, String> {
+    let program_bytes = read_bytes_from_file(program_file);
+    infer_helpers(&program_bytes, binary_layout, program_file)
+}
+
+fn infer_helpers(
+    program_bytes: &[u8],
+    binary_layout: BinaryFileLayout,
+    program_file: &str,
+) -> Result<Vec<u8>, String> {
+    let required = infer_required_helpers(program_bytes, binary_layout)?;
+    // Calls to unknown helpers would be rejected by the VM, so we report them
+    // before the program is deployed.
+    required.check_unknown()?;
+    debug!(
+        "Helpers inferred from {}: {:?}",
+        program_file, required.helpers
+    );
+    Ok(required.indices())
+}
+
+/// Checks that the target VM is able to load programs with the given layout
+/// and, if the processed program is available, that it supports all of its
+/// instructions, otherwise a warning is logged. It is run before anything is
+/// sent to the device, as the VM would otherwise only reject the program once
+/// it is executed.
+pub fn check_target_compatibility(
+    program_file: Option<&str>,
+    target: TargetVM,
+    binary_layout: BinaryFileLayout,
+) -> Result<(), String> {
+    let incompatibilities = match program_file {
+        Some(program_file) => {
+            let program_bytes = read_bytes_from_file(program_file);
+            check_compatibility(&program_bytes, target, binary_layout)?
+        }
+        // Only the layout can be checked without the program, the instructions
+        // it uses are then first checked by the VM when it loads the program.
+        None if target.supports_layout(binary_layout) => {
+            warn!(
+                "The program isn't available, only the support for the {:?} layout was \
+                 checked, not whether the {} VM supports all of its instructions.",
+                binary_layout, target
+            );
+            vec![]
+        }
+        None => vec![Incompatibility::Layout {
+            target,
+            layout: binary_layout,
+        }],
+    };
+    if incompatibilities.is_empty() {
+        return Ok(());
+    }
+    let report = incompatibilities
+        .iter()
+        .map(|incompatibility| incompatibility.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    Err(format!(
+        "The program can't be executed by the {} VM:\n{}",
+        target, report
+    ))
+}
+
+/// Finds the index of the entry point exported by the program stored in the
+/// given file, which is used for selecting it in the execution request. The
+/// entry point can be given either by its name or directly by its index.
+pub fn find_entry_point(
+    program_file: Option<&str>,
+    binary_layout: BinaryFileLayout,
+    entry_point: &str,
+) -> Result<u8, String> {
+    if let Ok(index) = entry_point.parse::<u8>() {
+        if index as usize >= MAX_ENTRY_POINTS {
+            return Err(format!(
+                "Invalid entry point index {}, programs can export at most {} entry points",
+                index, MAX_ENTRY_POINTS
+            ));
+        }
+        return Ok(index);
+    }
+    if binary_layout != BinaryFileLayout::ExtendedHeader {
+        return Err(format!(
+            "Entry points can only be exported in the ExtendedHeader layout, not {:?}",
+            binary_layout
+        ));
+    }
+    let Some(program_file) = program_file else {
+        return Err(format!(
+            "The binary file is required to find the entry point {}",
+            entry_point
+        ));
+    };
+    let program_bytes = read_bytes_from_file(program_file);
+    let entry_points = ExtendedBinary::parse(&program_bytes)?.entry_points()?;
+    match entry_points.iter().position(|name| name == entry_point) {
+        Some(index) => Ok(index as u8),
+        None => Err(format!(
+            "The program doesn't export the entry point {}, exported entry points: {:?}",
+            entry_point, entry_points
+        )),
+    }
 }
 
 pub fn map_interpreter(layout: BinaryFileLayout) -> rbpf::InterpreterVariant {
@@ -62,6 +346,8 @@ pub fn map_interpreter(layout: BinaryFileLayout) -> rbpf::InterpreterVariant {
         BinaryFileLayout::ExtendedHeader => rbpf::InterpreterVariant::ExtendedHeader,
         BinaryFileLayout::RawObjectFile => rbpf::InterpreterVariant::RawObjectFile,
         BinaryFileLayout::OnlyTextSection => rbpf::InterpreterVariant::Default,
+        // Once the relocations are resolved the program is standard eBPF bytecode.
+        BinaryFileLayout::PreResolvedRelocations => rbpf::InterpreterVariant::Default,
     }
 }
 
@@ -80,33 +366,3 @@ pub fn read_bytes_from_file(source_object_file: &str) -> Vec<u8> {
     f.read(&mut buffer).expect("buffer overflow");
     buffer
 }
-
-/// Uses the strip command to remove all of the debug and .BTF info from the
-/// ELF object file. It is required in order to decrease the binary size so that
-/// it can be sent directly to the target device where the relocations can be
-/// performed.
-pub fn strip_binary(source_object_file: &str, binary_file: Option<&String>) -> Result<(), String> {
-    let file_name = if let Some(binary_file) = binary_file {
-        binary_file.clone()
-    } else {
-        "a.bin".to_string()
-    };
-
-    let result = Command::new("strip")
-        .arg(source_object_file)
-        .arg("-d")
-        .arg("-R")
-        .arg(".BTF")
-        .arg("-R")
-        .arg(".BTF.ext")
-        .arg("-o")
-        .arg(file_name)
-        .spawn()
-        .expect("Failed to compile the eBPF bytecode.")
-        .wait();
-
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Failed to strip the binary: {}", e)),
-    }
-}
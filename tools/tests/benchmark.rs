@@ -71,6 +71,13 @@ pub async fn benchmark_raw_object_file() {
     save_results("raw-object-file-results.json", results);
 }
 
+#[ignore]
+#[tokio::test]
+pub async fn benchmark_pre_resolved_relocations() {
+    let results = benchmark_layout(BinaryFileLayout::PreResolvedRelocations, TargetVM::Rbpf).await;
+    save_results("pre-resolved-relocations-results.json", results);
+}
+
 #[ignore]
 #[tokio::test]
 pub async fn benchmark_femtocontainers() {
@@ -1,7 +1,8 @@
 use std::{env, process::Command, collections::HashMap};
 
 use enum_iterator::all;
-use micro_bpf_tools::{self, deploy, execute, Environment};
+use micro_bpf_tools::{self, deploy, execute, Environment, PostprocessingOptions};
+use micro_bpf_elf_utils::{HEADER_VERSION, VM_STACK_SIZE};
 
 use micro_bpf_common::{
     BinaryFileLayout, ExecutionModel, HelperAccessListSource, HelperAccessVerification,
@@ -33,6 +34,30 @@ pub async fn test_execution(
     .await;
 }
 
+/// Similar to [`test_execution`] but deploys the program using the given
+/// version of the ExtendedHeader layout. It is used for programs which rely
+/// on features only available in newer versions of the layout.
+pub async fn test_execution_with_header_version(
+    test_program: &str,
+    layout: BinaryFileLayout,
+    header_version: u32,
+    environment: &Environment,
+) {
+    let available_helpers = all::<HelperFunctionID>()
+        .map(|e| e as u8)
+        .collect::<Vec<u8>>();
+    run_test_execution(
+        test_program,
+        layout,
+        TargetVM::Rbpf,
+        environment,
+        available_helpers,
+        header_version,
+        false,
+    )
+    .await;
+}
+
 pub async fn test_jit_execution(
     test_program: &str,
     layout: BinaryFileLayout,
@@ -113,9 +138,38 @@ pub async fn test_execution_specifying_helpers(
     environment: &Environment,
     available_helpers: Vec<u8>,
     for_jit: bool,
+) {
+    run_test_execution(
+        test_program,
+        layout,
+        target_vm,
+        environment,
+        available_helpers,
+        HEADER_VERSION,
+        for_jit,
+    )
+    .await;
+}
+
+async fn run_test_execution(
+    test_program: &str,
+    layout: BinaryFileLayout,
+    target_vm: TargetVM,
+    environment: &Environment,
+    available_helpers: Vec<u8>,
+    header_version: u32,
+    for_jit: bool,
 ) {
     // We first deploy the program on the tested microcontroller
-    let result = deploy_test_script(test_program, layout, environment, available_helpers, for_jit).await;
+    let result = deploy_test_script_with_header_version(
+        test_program,
+        layout,
+        environment,
+        available_helpers,
+        header_version,
+        for_jit,
+    )
+    .await;
     if let Err(string) = &result {
         println!("{}", string);
     }
@@ -182,6 +236,7 @@ pub async fn benchmark_fletcher_16(
         HelperAccessVerification::AheadOfTime,
         HelperAccessListSource::ExecuteRequest,
         &available_helpers,
+        0,
         jit,
         true,
         true,
@@ -360,6 +415,7 @@ pub async fn benchmark_memory_access_checks(environment: &Environment) -> HashMa
             HelperAccessVerification::AheadOfTime,
             HelperAccessListSource::ExecuteRequest,
             &available_helpers,
+            0,
             false,
             false,
             true,
@@ -394,6 +450,7 @@ pub async fn benchmark_memory_access_checks(environment: &Environment) -> HashMa
             HelperAccessVerification::AheadOfTime,
             HelperAccessListSource::ExecuteRequest,
             &available_helpers,
+            0,
             false,
             false,
             true,
@@ -467,6 +524,7 @@ pub async fn benchmark_execution(
         HelperAccessVerification::AheadOfTime,
         HelperAccessListSource::ExecuteRequest,
         &available_helpers,
+        0,
         false,
         false,
         true,
@@ -540,6 +598,7 @@ pub async fn benchmark_jit_execution(
         HelperAccessVerification::AheadOfTime,
         HelperAccessListSource::ExecuteRequest,
         &available_helpers,
+        0,
         true,
         true,
         true,
@@ -642,6 +701,27 @@ pub async fn deploy_test_script(
     environment: &Environment,
     allowed_helpers: Vec<u8>,
     for_jit: bool,
+) -> Result<(), String> {
+    deploy_test_script_with_header_version(
+        file_name,
+        layout,
+        environment,
+        allowed_helpers,
+        HEADER_VERSION,
+        for_jit,
+    )
+    .await
+}
+
+/// Similar to [`deploy_test_script`] but produces the ExtendedHeader layout
+/// using the given version.
+pub async fn deploy_test_script_with_header_version(
+    file_name: &str,
+    layout: BinaryFileLayout,
+    environment: &Environment,
+    allowed_helpers: Vec<u8>,
+    header_version: u32,
+    for_jit: bool,
 ) -> Result<(), String> {
     let file_path = format!("{}/{}", TEST_SOURCES_DIR, file_name);
     let out_dir = format!("{}/out", TEST_SOURCES_DIR);
@@ -658,8 +738,7 @@ pub async fn deploy_test_script(
         &environment.host_ip,
         &environment.board_name,
         Some(environment.micro_bpf_root_dir.as_str()),
-        allowed_helpers,
-        HelperAccessVerification::AheadOfTime,
+        test_postprocessing_options(allowed_helpers, header_version),
         HelperAccessListSource::ExecuteRequest,
         true,
         for_jit,
@@ -690,8 +769,7 @@ pub async fn deploy_test_script_into_slot(
         &environment.host_ip,
         &environment.board_name,
         Some(environment.micro_bpf_root_dir.as_str()),
-        allowed_helpers,
-        HelperAccessVerification::AheadOfTime,
+        test_postprocessing_options(allowed_helpers, HEADER_VERSION),
         HelperAccessListSource::ExecuteRequest,
         true,
         for_jit,
@@ -699,6 +777,24 @@ pub async fn deploy_test_script_into_slot(
     .await
 }
 
+/// Postprocessing options used by the tests: the helpers are checked ahead of
+/// time and the rest of the checks use their defaults.
+fn test_postprocessing_options(
+    allowed_helpers: Vec<u8>,
+    header_version: u32,
+) -> PostprocessingOptions {
+    PostprocessingOptions {
+        helper_indices: Some(allowed_helpers),
+        helper_access_verification: HelperAccessVerification::AheadOfTime,
+        strict_verification: false,
+        allow_unverified: false,
+        stack_budget: VM_STACK_SIZE,
+        remove_dead_code: false,
+        entry_functions: vec![],
+        header_version,
+    }
+}
+
 /// Reads the annotation present at the top of test source files that specifies
 /// what the expected response from the program executing with access to the CoAP
 /// network packet should be.
@@ -765,6 +861,7 @@ pub async fn execute_deployed_program_on_coap(
         HelperAccessVerification::AheadOfTime,
         HelperAccessListSource::ExecuteRequest,
         &available_helpers,
+        0,
         jit,
         true,
         false,
@@ -795,6 +892,7 @@ pub async fn execute_deployed_program_specifying_helpers(
         HelperAccessVerification::AheadOfTime,
         HelperAccessListSource::ExecuteRequest,
         &available_helpers,
+        0,
         jit,
         true,
         false,
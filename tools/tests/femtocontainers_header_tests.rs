@@ -64,6 +64,16 @@ async fn inlined_calls() {
 async fn fletcher_32_checksum() {
     test_femtocontainers_header("fletcher32_checksum_no_strlen.c").await;
 }
+
+#[tokio::test]
+async fn extern_helpers() {
+    test_femtocontainers_header("extern_helpers.c").await;
+}
+
+#[tokio::test]
+async fn bss_counters() {
+    test_femtocontainers_header("bss_counters.c").await;
+}
 // For some reason accessing coap packets for femtocontainers VM is broken
 // It always fails on the first attempt. I suspect it has something to do with
 // how the packetbuffer is initialised. The rbpf implementation works fine.
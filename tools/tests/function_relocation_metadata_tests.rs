@@ -3,10 +3,12 @@ mod common;
 use common::{
     test_execution, test_execution_accessing_coap_pkt,
     test_execution_accessing_coap_pkt_specifying_helpers, test_execution_specifying_helpers,
+    test_execution_with_header_version,
 };
 use micro_bpf_tools::load_env;
 
 use micro_bpf_common::{BinaryFileLayout, HelperFunctionID, TargetVM};
+use micro_bpf_elf_utils::{HEADER_VERSION_BSS, HEADER_VERSION_DATA_RELOCATIONS};
 
 // This module contains end-to-end integration tests of the compile-upload-
 // execute workflow of the eBPF programs on microcontrollers. It is recommended
@@ -57,6 +59,11 @@ async fn pc_relative_calls() {
     test_function_relocation_metadata("pc_relative_calls.c").await;
 }
 
+#[tokio::test]
+async fn function_sections() {
+    test_function_relocation_metadata("function_sections.c").await;
+}
+
 #[tokio::test]
 async fn inlined_calls() {
     test_function_relocation_metadata("inlined_calls.c").await;
@@ -67,6 +74,34 @@ async fn fletcher_32_checksum() {
     test_function_relocation_metadata("fletcher32_checksum.c").await;
 }
 
+// The pointers stored in .data are only patched by the VM using the table of
+// data relocations, which requires deploying with `--header-version 2` or newer.
+#[tokio::test]
+async fn data_relocations() {
+    test_function_relocation_metadata_with_header_version(
+        "data_relocations.c",
+        HEADER_VERSION_DATA_RELOCATIONS,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn global_variables() {
+    test_function_relocation_metadata("global_variables.c").await;
+}
+
+// The length of .bss is only stored in the header from `--header-version 3`.
+#[tokio::test]
+async fn bss_counters() {
+    test_function_relocation_metadata_with_header_version("bss_counters.c", HEADER_VERSION_BSS)
+        .await;
+}
+
+#[tokio::test]
+async fn extern_helpers() {
+    test_function_relocation_metadata("extern_helpers.c").await;
+}
+
 #[tokio::test]
 async fn gcoap_response_format() {
     test_function_relocation_metadata_accessing_coap_pkt("gcoap_response_format.c").await;
@@ -224,6 +259,22 @@ async fn test_function_relocation_metadata(test_program: &str) {
     test_execution(test_program, BinaryFileLayout::ExtendedHeader, &env).await;
 }
 
+// Similar to `test_function_relocation_metadata` but deploys the program using
+// the given version of the layout.
+async fn test_function_relocation_metadata_with_header_version(
+    test_program: &str,
+    header_version: u32,
+) {
+    let env = load_env();
+    test_execution_with_header_version(
+        test_program,
+        BinaryFileLayout::ExtendedHeader,
+        header_version,
+        &env,
+    )
+    .await;
+}
+
 // Similar to `test_function_relocation_metadata` but allows for restricting access
 // to helper functions.
 async fn test_function_relocation_metadata_with_helpers(
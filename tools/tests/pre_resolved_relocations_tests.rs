@@ -0,0 +1,108 @@
+mod common;
+
+use common::{test_execution, test_execution_accessing_coap_pkt};
+use micro_bpf_common::BinaryFileLayout;
+use micro_bpf_tools::load_env;
+
+// End-to-end tests of the compile-upload-execute workflow using the
+// PreResolvedRelocations layout. They use the same test sources as the
+// RawObjectFile tests as both layouts support the same set of relocations,
+// see raw_elf_file_tests.rs for the setup of the testsuite.
+//
+// The tests are ignored until the VM on the device resolves the relocation
+// table of the layout before executing the program, at the moment it runs the
+// binary using the default interpreter (see `map_interpreter`). They can be
+// run using `cargo test -- --ignored` against a VM which supports the layout.
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn printf() {
+    test_pre_resolved_relocations("printf.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn data_relocations() {
+    test_pre_resolved_relocations("data_relocations.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn global_variables() {
+    test_pre_resolved_relocations("global_variables.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn bss_counters() {
+    test_pre_resolved_relocations("bss_counters.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn extern_helpers() {
+    test_pre_resolved_relocations("extern_helpers.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn function_sections() {
+    test_pre_resolved_relocations("function_sections.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn bpf_fetch() {
+    test_pre_resolved_relocations("bpf_fetch.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn bpf_store() {
+    test_pre_resolved_relocations("bpf_store.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn bpf_strlen() {
+    test_pre_resolved_relocations("bpf_strlen.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn pc_relative_calls() {
+    test_pre_resolved_relocations("pc_relative_calls.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn inlined_calls() {
+    test_pre_resolved_relocations("inlined_calls.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn fletcher_32_checksum() {
+    test_pre_resolved_relocations("fletcher32_checksum.c").await;
+}
+
+#[tokio::test]
+#[ignore = "requires the VM to resolve the pre-resolved relocations"]
+async fn gcoap_response_format() {
+    test_pre_resolved_relocations_accessing_coap_pkt("gcoap_response_format.c").await;
+}
+
+/// Runs a test which deploys an eBPF script prepared for the
+/// [`BinaryFileLayout::PreResolvedRelocations`] layout. The device only adds
+/// the address of the loaded program to the entries of the relocation table
+/// before executing it.
+async fn test_pre_resolved_relocations(test_program: &str) {
+    let env = load_env();
+    test_execution(test_program, BinaryFileLayout::PreResolvedRelocations, &env).await;
+}
+
+async fn test_pre_resolved_relocations_accessing_coap_pkt(test_program: &str) {
+    let env = load_env();
+    test_execution_accessing_coap_pkt(test_program, BinaryFileLayout::PreResolvedRelocations, &env)
+        .await;
+}
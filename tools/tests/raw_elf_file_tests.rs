@@ -27,6 +27,16 @@ async fn data_relocations() {
     test_raw_object_file("data_relocations.c").await;
 }
 
+#[tokio::test]
+async fn bss_counters() {
+    test_raw_object_file("bss_counters.c").await;
+}
+
+#[tokio::test]
+async fn extern_helpers() {
+    test_raw_object_file("extern_helpers.c").await;
+}
+
 #[tokio::test]
 async fn bpf_fetch() {
     test_raw_object_file("bpf_fetch.c").await;
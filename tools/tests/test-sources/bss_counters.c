@@ -0,0 +1,24 @@
+// TEST_RESULT: 31
+#include <stdint.h>
+#include "helpers.h"
+
+uint32_t counter;
+uint64_t history[4];
+uint32_t initialised = 5;
+
+// This test case checks whether zero-initialised global variables are handled
+// correctly. The compiler places them in the .bss section which doesn't take
+// up any space in the object file, so they need to be allocated and zeroed
+// when the program is loaded.
+int test_bss_counters()
+{
+    for (int i = 0; i < 4; i++) {
+        counter += i + 1;
+        history[i] = counter;
+    }
+    bpf_printf("counter: %d\n", counter);
+
+    // The counter goes through 1, 3, 6 and 10, so we expect 10 + 10 + 6 + 5 = 31
+    // provided that all variables started out as zero.
+    return counter + history[3] + history[2] + initialised;
+}
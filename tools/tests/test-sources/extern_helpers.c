@@ -0,0 +1,18 @@
+// TEST_RESULT: 42
+#include <stdint.h>
+
+// The helpers are declared as extern functions instead of including helpers.h,
+// which initialises function pointers with the IDs of the helpers. The calls
+// are resolved to the helper IDs by name when the program is processed.
+extern void bpf_printf(const char *fmt, ...);
+extern int bpf_store_global(uint32_t key, uint32_t value);
+extern int bpf_fetch_global(uint32_t key, uint32_t *value);
+
+int test_extern_helpers(void *ctx)
+{
+    uint32_t value = 0;
+    bpf_store_global(7, 42);
+    bpf_fetch_global(7, &value);
+    bpf_printf("Fetched value: %d\n", value);
+    return value;
+}
@@ -0,0 +1,29 @@
+// TEST_RESULT: 246
+#include "helpers.h"
+#define SECTION(name) __attribute__((section(name), noinline))
+
+static int SECTION(".text.double_value") double_value(int x);
+int SECTION(".text.add_offset") add_offset(int x);
+
+/// Here we check if functions placed outside of the .text section are
+/// handled correctly. This is the case for programs compiled with
+/// `-ffunction-sections` or ones which put their code into custom sections.
+/// All of those sections need to be merged into a single text section and the
+/// calls between them need to be relocated accordingly.
+int test_function_sections(void *ctx)
+{
+    int value = double_value(100);
+    bpf_printf("Doubled value: %d\n", value);
+    return add_offset(value);
+}
+
+static int SECTION(".text.double_value") double_value(int x)
+{
+    return 2 * x;
+}
+
+int SECTION(".text.add_offset") add_offset(int x)
+{
+    bpf_printf("Adding offset to: %d\n", x);
+    return x + 46;
+}
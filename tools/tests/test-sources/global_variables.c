@@ -0,0 +1,21 @@
+// TEST_RESULT: 134
+#include <stdint.h>
+#include "helpers.h"
+
+int counter = 5;
+const char *names[] = {"hello", "world"};
+
+// This test case checks whether writable global variables stored in the .data
+// section and pointers stored inside of it are handled correctly. The array
+// of names contains pointers to string literals which are placed in the
+// .rodata.str1.1 section, so their addresses need to be filled in once the
+// program is loaded into memory.
+int test_global_variables()
+{
+    counter += 10;
+    bpf_printf("counter: %d\n", counter);
+    bpf_printf("names: %s %s\n", names[0], names[1]);
+
+    // The ASCII code of 'w' is 119, so we expect 119 + 15 = 134.
+    return names[1][0] + counter;
+}